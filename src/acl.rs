@@ -0,0 +1,128 @@
+//! Decoding of POSIX ACLs, stored in the `system.posix_acl_access` and
+//! `system.posix_acl_default` extended attributes, for
+//! [`DiffOptions::check_acls`](crate::DiffOptions::check_acls).
+//!
+//! The value is a little-endian version word, 2, followed by the entries, 8 bytes each: a
+//! 16-bit tag telling what the entry applies to, 16 bits of permissions, and a 32-bit user or
+//! group id, only meaningful for named users and groups.
+
+/// Name of the extended attribute holding the access ACL, checked on each access.
+pub const ACCESS_XATTR: &str = "system.posix_acl_access";
+/// Name of the extended attribute holding the default ACL of a directory, inherited by the
+/// entries created in it.
+pub const DEFAULT_XATTR: &str = "system.posix_acl_default";
+
+const VERSION: u32 = 2;
+const USER_OBJ: u16 = 0x01;
+const USER: u16 = 0x02;
+const GROUP_OBJ: u16 = 0x04;
+const GROUP: u16 = 0x08;
+const MASK: u16 = 0x10;
+const OTHER: u16 = 0x20;
+
+/// Describes the ACLs of an entry like `getfacl -c` does, from the values of its extended
+/// attributes, if it has them, like `user::rw-,user:1000:r--,group::r--,mask::r--,other::r--`.
+///
+/// Entries are separated by commas, ids are written as numbers, and the entries of the default
+/// ACL are prefixed with `default:`. Entries without extended ACLs, whose permissions are only
+/// those of their mode, are described as `none`, and malformed values are written in hex.
+pub fn describe(access: Option<&[u8]>, default: Option<&[u8]>) -> String {
+    let mut entries = Vec::new();
+    for (prefix, value) in [("", access), ("default:", default)] {
+        if let Some(value) = value {
+            match decode(value) {
+                Some(decoded) => {
+                    entries.extend(decoded.into_iter().map(|e| prefix.to_owned() + &e))
+                }
+                None => entries.push(format!(
+                    "{}{}",
+                    prefix,
+                    value
+                        .iter()
+                        .map(|b| format!("{:02x}", b))
+                        .collect::<String>()
+                )),
+            }
+        }
+    }
+    if entries.is_empty() {
+        return "none".to_owned();
+    }
+    entries.join(",")
+}
+
+fn decode(value: &[u8]) -> Option<Vec<String>> {
+    if value.len() < 4 {
+        return None;
+    }
+    let (version, entries) = value.split_at(4);
+    if u32::from_le_bytes(version.try_into().ok()?) != VERSION || entries.len() % 8 != 0 {
+        return None;
+    }
+    entries
+        .chunks(8)
+        .map(|entry| {
+            let tag = u16::from_le_bytes([entry[0], entry[1]]);
+            let perm = u16::from_le_bytes([entry[2], entry[3]]);
+            let id = u32::from_le_bytes(entry[4..8].try_into().ok()?);
+            let qualifier = match tag {
+                USER_OBJ => "user:".to_owned(),
+                USER => format!("user:{}", id),
+                GROUP_OBJ => "group:".to_owned(),
+                GROUP => format!("group:{}", id),
+                MASK => "mask:".to_owned(),
+                OTHER => "other:".to_owned(),
+                _ => return None,
+            };
+            let bit = |mask, c| if perm & mask != 0 { c } else { '-' };
+            Some(format!(
+                "{}:{}{}{}",
+                qualifier,
+                bit(4, 'r'),
+                bit(2, 'w'),
+                bit(1, 'x')
+            ))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Value of an ACL attribute made of `entries`, as tags, permissions and ids.
+    fn value(entries: &[(u16, u16, u32)]) -> Vec<u8> {
+        let mut value = VERSION.to_le_bytes().to_vec();
+        for (tag, perm, id) in entries {
+            value.extend(tag.to_le_bytes());
+            value.extend(perm.to_le_bytes());
+            value.extend(id.to_le_bytes());
+        }
+        value
+    }
+
+    #[test]
+    fn descriptions() {
+        let access = value(&[
+            (USER_OBJ, 6, u32::MAX),
+            (USER, 4, 1000),
+            (GROUP_OBJ, 4, u32::MAX),
+            (GROUP, 7, 50),
+            (MASK, 7, u32::MAX),
+            (OTHER, 0, u32::MAX),
+        ]);
+        let default = value(&[(USER_OBJ, 7, u32::MAX), (OTHER, 5, u32::MAX)]);
+        assert_eq!(
+            describe(Some(&access), Some(&default)),
+            "user::rw-,user:1000:r--,group::r--,group:50:rwx,mask::rwx,other::---,\
+             default:user::rwx,default:other::r-x"
+        );
+        assert_eq!(describe(None, None), "none");
+        assert_eq!(describe(Some(&[1, 0, 0, 0]), None), "01000000");
+        // An unknown tag.
+        assert_eq!(
+            describe(None, Some(&value(&[(0x40, 7, 0)]))),
+            "default:020000004000070000000000"
+        );
+    }
+}
@@ -0,0 +1,807 @@
+//! Comparison of trees read from tar and zip archives, for `--tar1`, `--tar2`, `--zip1` and
+//! `--zip2`, or as the merged view of an overlay, for `--lower` and `--upper`.
+//!
+//! Tar archives give no random access to their members, so each one is first indexed, reading
+//! all its headers and skipping over the content of the members. Zip archives are indexed from
+//! their central directory. The index keeps the position of the content of each regular file,
+//! which is read directly from there when it must be compared, and inflated on the fly for the
+//! deflated members of zip archives. Overlays are listed from both of their layers as they are
+//! traversed.
+//!
+//! Trees listed by a command are indexed from the manifest it prints, see [`Tree::Listing`]. Their
+//! content cannot be read, so regular files and symlinks are compared by the SHA-256 hashes of the
+//! manifest, those of the other tree being hashed as they are compared.
+//!
+//! Trees are compared by a single thread, which lists both sides of each directory from the index
+//! or the filesystem. Only the presence, type, size, content and symlink targets of entries are
+//! compared, so most of the [`DiffOptions`] are not supported.
+
+use crate::{
+    command,
+    compare::compare_readers,
+    manifest::{self, Hash},
+    Collation, CompareOpts, Diff, DiffHandler, DiffOptions, EntryType, FileCompare, FileType,
+};
+use anyhow::{bail, Context};
+use flate2::read::DeflateDecoder;
+use sha2::{Digest, Sha256};
+use std::{
+    collections::BTreeMap,
+    ffi::{OsStr, OsString},
+    fs::{read_dir, read_link, File},
+    io::{self, BufRead, BufReader, Read, Seek, SeekFrom},
+    iter::Peekable,
+    path::{Component, Path, PathBuf},
+    process::{Command, Stdio},
+    sync::Arc,
+};
+
+/// One of the trees to compare.
+#[derive(Debug, Clone)]
+pub enum Tree {
+    /// A directory, read from the filesystem.
+    Dir(PathBuf),
+    /// A tar archive, uncompressed.
+    Tar(PathBuf),
+    /// A zip archive, whose members are stored or deflated.
+    Zip(PathBuf),
+    /// The merged view of an overlay, with the changes of the upper directory applied to the
+    /// lower one, as mounted by overlayfs.
+    ///
+    /// Entries of the upper directory replace those of the same name in the lower one, except
+    /// that directories present in both are merged. A whiteout in the upper directory deletes
+    /// the entry of the lower one it names, and is not part of the merged view itself: either a
+    /// file named `.wh.<name>`, whatever its type, or a character device with device number
+    /// 0/0 named `<name>`, as overlayfs creates. A file named `.wh..wh..opq` makes its directory
+    /// opaque, hiding all the entries of the lower one. Whiteouts are only looked for in the
+    /// upper directory, and the `trusted.overlay.*` extended attributes are not read.
+    Overlay { lower: PathBuf, upper: PathBuf },
+    /// A tree listed by a command, written as on a shell command line as for
+    /// [`CompareCommand`](crate::CompareCommand), which prints a manifest of its entries, in
+    /// the format of [`manifest`](crate::manifest).
+    ///
+    /// Directories missing from the manifest but containing entries are added, and later
+    /// entries replace earlier ones for the same path. The command must exit successfully, its
+    /// errors are shown.
+    Listing(String),
+}
+
+impl Tree {
+    /// Path of the directory or of the archive, or of the upper directory of an overlay.
+    pub fn path(&self) -> &Path {
+        match self {
+            Tree::Dir(path) | Tree::Tar(path) | Tree::Zip(path) => path,
+            Tree::Overlay { upper, .. } => upper,
+            Tree::Listing(command) => Path::new(command),
+        }
+    }
+}
+
+/// Prefix of the names of whiteouts, followed by the name of the entry they delete.
+const WHITEOUT_PREFIX: &str = ".wh.";
+
+/// Name of the whiteout making its directory opaque.
+const OPAQUE_WHITEOUT: &str = ".wh..wh..opq";
+
+/// An entry of a directory.
+#[derive(Debug, Clone)]
+struct Member {
+    file_type: FileType,
+    /// Size of the content, only set for regular files.
+    size: u64,
+    /// Target of a symlink.
+    link: Option<PathBuf>,
+    /// Position of the content in the archive, only set for the regular files of archives.
+    offset: u64,
+    /// Whether the content is deflated, only set for regular files of zip archives.
+    deflated: bool,
+    /// SHA-256 of the content or of the target, only set for the entries of listings.
+    hash: Option<Hash>,
+}
+
+/// Entries of a directory, sorted by name.
+type Listing = BTreeMap<OsString, Member>;
+
+/// An entry of a directory, along with its name.
+type Named = (OsString, Member);
+
+/// A tree being compared, with the index of its archive.
+enum Source {
+    Dir(PathBuf),
+    Archive {
+        path: PathBuf,
+        /// Entries of each directory of the archive, relative to its root.
+        index: BTreeMap<PathBuf, Listing>,
+    },
+    Overlay {
+        lower: PathBuf,
+        upper: PathBuf,
+    },
+    Listing {
+        /// Entries of each directory of the listing, relative to its root.
+        index: BTreeMap<PathBuf, Listing>,
+    },
+}
+
+impl Source {
+    fn new(tree: Tree) -> anyhow::Result<Self> {
+        Ok(match tree {
+            Tree::Dir(path) => Source::Dir(path),
+            Tree::Tar(path) => {
+                let index = index(&path)
+                    .with_context(|| format!("Couldn't read archive {}", path.display()))?;
+                Source::Archive { path, index }
+            }
+            Tree::Zip(path) => {
+                let index = index_zip(&path)
+                    .with_context(|| format!("Couldn't read archive {}", path.display()))?;
+                Source::Archive { path, index }
+            }
+            Tree::Overlay { lower, upper } => Source::Overlay { lower, upper },
+            Tree::Listing(command) => {
+                let index = index_listing(&command)
+                    .with_context(|| format!("Couldn't read the listing of `{}`", command))?;
+                Source::Listing { index }
+            }
+        })
+    }
+
+    /// Lists the entries of `dir`, relative to the root.
+    fn list(&self, dir: &Path) -> io::Result<Listing> {
+        match self {
+            Source::Dir(root) => list_dir(&root.join(dir)),
+            // Directories of the archive are all indexed, even the empty ones.
+            Source::Archive { index, .. } | Source::Listing { index } => {
+                Ok(index.get(dir).cloned().unwrap_or_default())
+            }
+            Source::Overlay { lower, upper } => {
+                let mut listing = match lower_visible(upper, lower, dir)? {
+                    true => list_dir(&lower.join(dir))?,
+                    false => Listing::new(),
+                };
+                let upper_dir = upper.join(dir);
+                if !matches!(upper_dir.symlink_metadata(), Ok(m) if m.is_dir()) {
+                    return Ok(listing);
+                }
+                let upper_listing = list_dir(&upper_dir)?;
+                // Whiteouts only delete entries of the lower directory, so they are all applied
+                // before the entries of the upper one are added.
+                if upper_listing.contains_key(OsStr::new(OPAQUE_WHITEOUT)) {
+                    listing.clear();
+                }
+                let mut entries = Vec::new();
+                for (name, member) in upper_listing {
+                    if let Some(deleted) =
+                        name.to_str().and_then(|n| n.strip_prefix(WHITEOUT_PREFIX))
+                    {
+                        listing.remove(OsStr::new(deleted));
+                    } else if is_whiteout(&upper_dir.join(&name), &member)? {
+                        listing.remove(&name);
+                    } else {
+                        entries.push((name, member));
+                    }
+                }
+                listing.extend(entries);
+                Ok(listing)
+            }
+        }
+    }
+
+    /// Opens the content of the regular file `name` of `dir`.
+    fn open(&self, dir: &Path, name: &OsString, member: &Member) -> io::Result<Box<dyn BufRead>> {
+        Ok(match self {
+            Source::Dir(root) => Box::new(BufReader::new(File::open(root.join(dir).join(name))?)),
+            Source::Archive { path, .. } => {
+                let mut archive = File::open(path)?;
+                archive.seek(SeekFrom::Start(member.offset))?;
+                if member.deflated {
+                    let content = DeflateDecoder::new(BufReader::new(archive));
+                    Box::new(BufReader::new(content.take(member.size)))
+                } else {
+                    Box::new(BufReader::new(archive.take(member.size)))
+                }
+            }
+            Source::Overlay { lower, upper } => {
+                // Regular files of the merged view come from the upper directory if it has one
+                // of that name.
+                let path = upper.join(dir).join(name);
+                let path = match path.symlink_metadata() {
+                    Ok(_) => path,
+                    Err(e) if e.kind() == io::ErrorKind::NotFound => lower.join(dir).join(name),
+                    Err(e) => return Err(e),
+                };
+                Box::new(BufReader::new(File::open(path)?))
+            }
+            Source::Listing { .. } => unreachable!("members of listings are compared by hash"),
+        })
+    }
+
+    /// SHA-256 of the content of the regular file `name` of `dir`, or of the target of the
+    /// symlink, from the listing or computed.
+    fn hash(&self, dir: &Path, name: &OsString, member: &Member) -> io::Result<Hash> {
+        if let Some(hash) = member.hash {
+            return Ok(hash);
+        }
+        match &member.link {
+            // As `manifest_of` hashes them.
+            Some(target) => Ok(Sha256::digest(target.to_string_lossy().as_bytes()).into()),
+            None => Ok(manifest::hash_content(self.open(dir, name, member)?)?.1),
+        }
+    }
+}
+
+/// Lists the entries of the directory at `path`, from the filesystem.
+fn list_dir(path: &Path) -> io::Result<Listing> {
+    let mut listing = Listing::new();
+    for entry in read_dir(path)? {
+        let entry = entry?;
+        let file_type = FileType::from(entry.file_type()?);
+        let size = match file_type {
+            FileType::Regular => entry.metadata()?.len(),
+            _ => 0,
+        };
+        let link = match file_type {
+            FileType::Symlink => Some(read_link(entry.path())?),
+            _ => None,
+        };
+        let member = Member {
+            file_type,
+            size,
+            link,
+            offset: 0,
+            deflated: false,
+            hash: None,
+        };
+        listing.insert(entry.file_name(), member);
+    }
+    Ok(listing)
+}
+
+/// Whether the directory `dir` of the lower layer of an overlay is part of its merged view.
+///
+/// It is not if it does not exist, or if the upper layer replaces it or one of its parents by
+/// an entry which is not a directory, deletes it with a whiteout, or makes one of its parents
+/// opaque.
+fn lower_visible(upper: &Path, lower: &Path, dir: &Path) -> io::Result<bool> {
+    if !matches!(lower.join(dir).symlink_metadata(), Ok(m) if m.is_dir()) {
+        return Ok(false);
+    }
+    let exists = |path: PathBuf| match path.symlink_metadata() {
+        Ok(metadata) => Ok(Some(metadata)),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e),
+    };
+    let mut parent = upper.to_owned();
+    for name in dir.iter() {
+        let mut whiteout = OsString::from(WHITEOUT_PREFIX);
+        whiteout.push(name);
+        if exists(parent.join(OPAQUE_WHITEOUT))?.is_some()
+            || exists(parent.join(whiteout))?.is_some()
+        {
+            return Ok(false);
+        }
+        parent.push(name);
+        if matches!(exists(parent.clone())?, Some(metadata) if !metadata.is_dir()) {
+            return Ok(false);
+        }
+    }
+    Ok(true)
+}
+
+/// Whether the entry of the upper layer of an overlay at `path` is a character device with
+/// device number 0/0, which overlayfs creates to delete the entry of the lower layer.
+#[cfg(unix)]
+fn is_whiteout(path: &Path, member: &Member) -> io::Result<bool> {
+    use std::os::unix::fs::MetadataExt;
+    Ok(member.file_type == FileType::CharDevice && path.symlink_metadata()?.rdev() == 0)
+}
+
+#[cfg(not(unix))]
+fn is_whiteout(_path: &Path, _member: &Member) -> io::Result<bool> {
+    Ok(false)
+}
+
+/// Reads the headers of all the members of an archive.
+///
+/// Directories missing from the archive but containing members are added, and hard links are
+/// turned into regular files sharing the content of their target.
+fn index(path: &Path) -> anyhow::Result<BTreeMap<PathBuf, Listing>> {
+    let mut archive = tar::Archive::new(File::open(path)?);
+    let mut index = BTreeMap::new();
+    index.insert(PathBuf::new(), Listing::new());
+    let mut hard_links = Vec::new();
+    for entry in archive.entries_with_seek()? {
+        let entry = entry?;
+        let member_path = match relative(&entry.path()?)? {
+            Some(member_path) => member_path,
+            // The root itself, like `./`.
+            None => continue,
+        };
+        let entry_type = entry.header().entry_type();
+        let file_type = if entry_type.is_dir() {
+            FileType::Directory
+        } else if entry_type.is_file() || entry_type.is_contiguous() {
+            FileType::Regular
+        } else if entry_type.is_symlink() {
+            FileType::Symlink
+        } else if entry_type.is_hard_link() {
+            match entry.link_name()? {
+                Some(target) => hard_links.push((member_path, target.into_owned())),
+                None => bail!("Hard link {} has no target", member_path.display()),
+            }
+            continue;
+        } else if entry_type.is_gnu_sparse() {
+            bail!("Sparse member {} is not supported", member_path.display());
+        } else {
+            match special_type(entry_type) {
+                Some(file_type) => file_type,
+                // Extension headers, which are applied to the next member by `tar`.
+                None => continue,
+            }
+        };
+        let member = Member {
+            file_type,
+            size: match file_type {
+                FileType::Regular => entry.size(),
+                _ => 0,
+            },
+            link: match file_type {
+                FileType::Symlink => entry.link_name()?.map(|link| link.into_owned()),
+                _ => None,
+            },
+            offset: entry.raw_file_position(),
+            deflated: false,
+            hash: None,
+        };
+        insert(&mut index, &member_path, member);
+    }
+    for (member_path, target) in hard_links {
+        let target = relative(&target)?.unwrap_or_default();
+        let member = match (target.parent(), target.file_name()) {
+            (Some(dir), Some(name)) => index.get(dir).and_then(|listing| listing.get(name)),
+            _ => None,
+        };
+        match member {
+            Some(member) if member.file_type == FileType::Regular => {
+                let member = member.clone();
+                insert(&mut index, &member_path, member);
+            }
+            _ => bail!(
+                "Hard link {} does not point to a regular file",
+                member_path.display()
+            ),
+        }
+    }
+    Ok(index)
+}
+
+/// Reads the central directory of a zip archive.
+///
+/// Directories missing from the archive but containing members are added. Names are split on
+/// both `/` and `\`, which some archivers write on Windows, and symlinks are members whose
+/// Unix mode says so, with their target as content.
+fn index_zip(path: &Path) -> anyhow::Result<BTreeMap<PathBuf, Listing>> {
+    let mut archive = zip::ZipArchive::new(File::open(path)?)?;
+    let mut index = BTreeMap::new();
+    index.insert(PathBuf::new(), Listing::new());
+    for i in 0..archive.len() {
+        let mut entry = archive.by_index(i)?;
+        let name = entry.name().replace('\\', "/");
+        let member_path = match relative(Path::new(&name))? {
+            Some(member_path) => member_path,
+            None => continue,
+        };
+        let is_symlink = matches!(entry.unix_mode(), Some(mode) if mode & 0o170000 == 0o120000);
+        let file_type = if entry.is_dir() {
+            FileType::Directory
+        } else if is_symlink {
+            FileType::Symlink
+        } else {
+            FileType::Regular
+        };
+        let deflated = match entry.compression() {
+            zip::CompressionMethod::Stored => false,
+            zip::CompressionMethod::Deflated => true,
+            method => bail!(
+                "Member {} is compressed with {}, only stored and deflated members are supported",
+                member_path.display(),
+                method
+            ),
+        };
+        let link = match file_type {
+            FileType::Symlink => {
+                let mut target = String::new();
+                entry.read_to_string(&mut target)?;
+                Some(PathBuf::from(target))
+            }
+            _ => None,
+        };
+        let member = Member {
+            file_type,
+            size: match file_type {
+                FileType::Regular => entry.size(),
+                _ => 0,
+            },
+            link,
+            offset: entry.data_start(),
+            deflated,
+            hash: None,
+        };
+        insert(&mut index, &member_path, member);
+    }
+    Ok(index)
+}
+
+/// Runs a listing command and reads the manifest it prints, see [`Tree::Listing`].
+fn index_listing(command: &str) -> anyhow::Result<BTreeMap<PathBuf, Listing>> {
+    let words = command::split(command)?;
+    let (program, args) = match words.split_first() {
+        Some(split) => split,
+        None => bail!("Empty command"),
+    };
+    let output = Command::new(program)
+        .args(args)
+        .stdin(Stdio::null())
+        .stderr(Stdio::inherit())
+        .output()
+        .with_context(|| format!("Couldn't run listing command {}", program))?;
+    if !output.status.success() {
+        bail!("Listing command {} failed with {}", program, output.status);
+    }
+    let mut index = BTreeMap::new();
+    index.insert(PathBuf::new(), Listing::new());
+    for entry in manifest::read_manifest(&output.stdout[..])? {
+        let member = Member {
+            file_type: entry.file_type,
+            size: entry.size.unwrap_or(0),
+            link: None,
+            offset: 0,
+            deflated: false,
+            hash: entry.hash,
+        };
+        insert(&mut index, &entry.path, member);
+    }
+    Ok(index)
+}
+
+/// Path of a member relative to the root of the archive, or `None` for the root itself.
+///
+/// Members are always extracted relative to the current directory, so leading `/` are ignored.
+fn relative(path: &Path) -> anyhow::Result<Option<PathBuf>> {
+    let mut relative = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::Normal(name) => relative.push(name),
+            Component::CurDir | Component::RootDir | Component::Prefix(_) => (),
+            Component::ParentDir => bail!("Member {} is outside of the archive", path.display()),
+        }
+    }
+    Ok((!relative.as_os_str().is_empty()).then_some(relative))
+}
+
+/// Type of the special files of archives, which only exist on Unix.
+#[cfg(unix)]
+fn special_type(entry_type: tar::EntryType) -> Option<FileType> {
+    if entry_type.is_character_special() {
+        Some(FileType::CharDevice)
+    } else if entry_type.is_block_special() {
+        Some(FileType::BlockDevice)
+    } else if entry_type.is_fifo() {
+        Some(FileType::Fifo)
+    } else {
+        None
+    }
+}
+
+#[cfg(not(unix))]
+fn special_type(_entry_type: tar::EntryType) -> Option<FileType> {
+    None
+}
+
+/// Adds a member to the index, along with the directories containing it.
+fn insert(index: &mut BTreeMap<PathBuf, Listing>, path: &Path, member: Member) {
+    if member.file_type == FileType::Directory {
+        index.entry(path.to_owned()).or_default();
+    }
+    let mut path = path.to_owned();
+    let mut member = Some(member);
+    while let (Some(dir), Some(name)) = (path.parent(), path.file_name()) {
+        let listing = index.entry(dir.to_owned()).or_default();
+        match member.take() {
+            // Later members replace earlier ones, as when extracting the archive.
+            Some(member) => {
+                listing.insert(name.to_owned(), member);
+            }
+            None => {
+                if listing.contains_key(name) {
+                    break;
+                }
+                let dir_member = Member {
+                    file_type: FileType::Directory,
+                    size: 0,
+                    link: None,
+                    offset: 0,
+                    deflated: false,
+                    hash: None,
+                };
+                listing.insert(name.to_owned(), dir_member);
+            }
+        }
+        path = dir.to_owned();
+    }
+}
+
+/// Compares two trees, at least one of them being read from an archive.
+struct TreeDiff<H: DiffHandler> {
+    source1: Source,
+    source2: Source,
+    root1: PathBuf,
+    root2: PathBuf,
+    diff_handler: Arc<H>,
+    options: DiffOptions,
+}
+
+impl<H: DiffHandler> TreeDiff<H> {
+    /// Compares the directory `dir` of both trees, then its subdirectories in order, so that
+    /// diffs are reported sorted by path.
+    fn diff_dir(&self, dir: &Path) -> anyhow::Result<()> {
+        let mut content1 = self.source1.list(dir)?;
+        let mut content2 = self.source2.list(dir)?;
+        self.filter_entries(&mut content1);
+        self.filter_entries(&mut content2);
+        let mut content1 = content1.into_iter().peekable();
+        let mut content2 = content2.into_iter().peekable();
+        while let Some(pair) = next_pair(&mut content1, &mut content2) {
+            match pair {
+                (Some((name, _)), None) => self.report(Diff::InDir1Only(dir.to_owned(), name)),
+                (None, Some((name, _))) => self.report(Diff::InDir2Only(dir.to_owned(), name)),
+                (Some((name, m1)), Some((_, m2))) => self.diff_members(dir, name, m1, m2)?,
+                (None, None) => unreachable!("pairs have at least one entry"),
+            }
+        }
+        Ok(())
+    }
+
+    /// Compares two entries of `dir` named `name`.
+    fn diff_members(
+        &self,
+        dir: &Path,
+        name: OsString,
+        m1: Member,
+        m2: Member,
+    ) -> anyhow::Result<()> {
+        if m1.file_type != m2.file_type {
+            let type1 = EntryType {
+                file_type: m1.file_type,
+                size: m1.size,
+                resolved: None,
+            };
+            let type2 = EntryType {
+                file_type: m2.file_type,
+                size: m2.size,
+                resolved: None,
+            };
+            self.report(Diff::TypeMismatch(dir.to_owned(), name, type1, type2));
+            return Ok(());
+        }
+        match m1.file_type {
+            FileType::Directory => self.diff_dir(&dir.join(name))?,
+            _ if self.options.names_only => (),
+            FileType::Symlink => match (&m1.link, &m2.link) {
+                (Some(target1), Some(target2)) => {
+                    if target1 != target2 {
+                        let diff = Diff::LinkTargetsDiffer(
+                            dir.to_owned(),
+                            name,
+                            target1.clone(),
+                            target2.clone(),
+                        );
+                        self.report(diff);
+                    }
+                }
+                // Listings only give the hashes of the targets.
+                _ if m1.hash.is_some() || m2.hash.is_some() => {
+                    if self.hashes_differ(dir, &name, &m1, &m2)? {
+                        self.report(Diff::Different(dir.to_owned(), name, None));
+                    }
+                }
+                (target1, target2) => {
+                    if target1 != target2 {
+                        self.report(Diff::Different(dir.to_owned(), name, None));
+                    }
+                }
+            },
+            FileType::Regular => {
+                if m1.size != m2.size {
+                    self.report(Diff::Different(dir.to_owned(), name, None));
+                } else if m1.size != 0 && (m1.hash.is_some() || m2.hash.is_some()) {
+                    if self.hashes_differ(dir, &name, &m1, &m2)? {
+                        self.report(Diff::Different(dir.to_owned(), name, None));
+                    }
+                } else if m1.size != 0 {
+                    let opts = CompareOpts {
+                        retry: self.options.content_retry,
+                        ignore_case: self.options.ignore_content_case,
+                        len: Some(m1.size),
+                        fadvise: false,
+                        budget: self.options.byte_budget.clone(),
+                        prefix: self.options.compare_prefix,
+                        cancel: None,
+                        count_diff_bytes: false,
+                        content_type: None,
+                        deadline: None,
+                    };
+                    let mut f1 = self.source1.open(dir, &name, &m1)?;
+                    let mut f2 = self.source2.open(dir, &name, &m2)?;
+                    match compare_readers(&mut f1, &mut f2, &opts)? {
+                        FileCompare::Equal => (),
+                        FileCompare::Differ { .. } => {
+                            self.report(Diff::Different(dir.to_owned(), name, None))
+                        }
+                        FileCompare::LenDiffer => {
+                            self.report(Diff::ChangedDuringScan(dir.to_owned(), name))
+                        }
+                        FileCompare::OverBudget => {
+                            self.report(Diff::NotCompared(dir.to_owned(), name))
+                        }
+                        FileCompare::Cancelled => unreachable!("no cancel flag is given"),
+                        FileCompare::TimedOut => unreachable!("no deadline is given"),
+                        FileCompare::OtherContentType | FileCompare::ContentTypesDiffer { .. } => {
+                            unreachable!("no content type is given")
+                        }
+                    }
+                }
+            }
+            // Never opened, like in directories.
+            #[cfg(unix)]
+            FileType::Fifo | FileType::Socket => (),
+            file_type => bail!(
+                "Unimplemented filetype. File {} has type {:?}",
+                dir.join(name).display(),
+                file_type
+            ),
+        }
+        Ok(())
+    }
+
+    /// Whether the hashes of two entries of `dir` named `name` differ, at least one of them
+    /// being listed.
+    fn hashes_differ(
+        &self,
+        dir: &Path,
+        name: &OsString,
+        m1: &Member,
+        m2: &Member,
+    ) -> io::Result<bool> {
+        Ok(self.source1.hash(dir, name, m1)? != self.source2.hash(dir, name, m2)?)
+    }
+
+    /// Removes the entries that must be ignored from the content of a directory.
+    fn filter_entries(&self, content: &mut Listing) {
+        if self.options.ignore_symlinks {
+            content.retain(|_, m| m.file_type != FileType::Symlink);
+        }
+        if self.options.regular_only {
+            content.retain(|_, m| matches!(m.file_type, FileType::Directory | FileType::Regular));
+        }
+        if self.options.ignore_empty {
+            content.retain(|_, m| m.file_type != FileType::Regular || m.size > 0);
+        }
+    }
+
+    fn report(&self, diff: Diff) {
+        self.diff_handler.process(&self.root1, &self.root2, diff);
+    }
+}
+
+/// Next pair of entries with the same name, or alone on their side.
+fn next_pair(
+    content1: &mut Peekable<impl Iterator<Item = Named>>,
+    content2: &mut Peekable<impl Iterator<Item = Named>>,
+) -> Option<(Option<Named>, Option<Named>)> {
+    let order = match (content1.peek(), content2.peek()) {
+        (None, None) => return None,
+        (Some(_), None) => std::cmp::Ordering::Less,
+        (None, Some(_)) => std::cmp::Ordering::Greater,
+        (Some((name1, _)), Some((name2, _))) => name1.cmp(name2),
+    };
+    Some(match order {
+        std::cmp::Ordering::Less => (content1.next(), None),
+        std::cmp::Ordering::Greater => (None, content2.next()),
+        std::cmp::Ordering::Equal => (content1.next(), content2.next()),
+    })
+}
+
+/// Diffs two trees, each a directory, a tar or zip archive, an overlay or a listing, reporting
+/// each diff to `diff_handler`.
+///
+/// The comparison runs on the current thread, and diffs are reported sorted by path. Only
+/// `ignore_symlinks`, `regular_only`, `ignore_empty`, `names_only`, `ignore_content_case`, `retry`
+/// and `sorted` are supported among the `options`, setting any other one is an error.
+pub fn diff_trees<H: DiffHandler>(
+    tree1: Tree,
+    tree2: Tree,
+    diff_handler: Arc<H>,
+    options: &DiffOptions,
+) -> anyhow::Result<()> {
+    check_options(options)?;
+    let root1 = tree1.path().to_owned();
+    let root2 = tree2.path().to_owned();
+    let tree_diff = TreeDiff {
+        source1: Source::new(tree1)?,
+        source2: Source::new(tree2)?,
+        root1,
+        root2,
+        diff_handler,
+        options: options.clone(),
+    };
+    tree_diff.diff_dir(Path::new(""))
+}
+
+/// Fails if options that cannot apply to archives and overlays are set.
+fn check_options(options: &DiffOptions) -> anyhow::Result<()> {
+    let unsupported = [
+        (options.check_mtime, "check_mtime"),
+        (options.check_dir_mtime, "check_dir_mtime"),
+        (options.perms, "perms"),
+        (options.owner, "owner"),
+        (options.check_nlink, "check_nlink"),
+        (options.check_flags, "check_flags"),
+        (options.check_caps, "check_caps"),
+        (options.check_acls, "check_acls"),
+        (options.check_blocks, "check_blocks"),
+        (options.check_reflinks, "check_reflinks"),
+        (
+            options.flag_mtime_same_content_differs,
+            "flag_mtime_same_content_differs",
+        ),
+        (options.follow_symlink, "follow_symlink"),
+        (options.follow_inside_roots, "follow_inside_roots"),
+        (options.updates_to_second, "updates_to_second"),
+        (options.tally.is_some(), "tally"),
+        (options.dir_timings.is_some(), "dir_timings"),
+        (options.progress.is_some(), "progress"),
+        (options.checkpoint.is_some(), "checkpoint"),
+        (options.find_duplicates, "find_duplicates"),
+        (!options.renames.is_empty(), "renames"),
+        (options.fuzzy_names.is_some(), "fuzzy_names"),
+        (options.selection.is_some(), "selection"),
+        (options.sparse, "sparse"),
+        (options.trust_xattr.is_some(), "trust_xattr"),
+        (options.trust_same_inode, "trust_same_inode"),
+        (options.resolve_symlink_targets, "resolve_symlink_targets"),
+        (options.detect_case_collisions, "detect_case_collisions"),
+        (options.compare_cmd.is_some(), "compare_cmd"),
+        (!options.comparers.is_empty(), "comparers"),
+        (options.shallow, "shallow"),
+        (options.collapse, "collapse"),
+        (options.empty_first, "empty_first"),
+        (options.empty_second, "empty_second"),
+        (options.secure, "secure"),
+        (options.explain, "explain"),
+        (options.print_hashes, "print_hashes"),
+        (options.two_pass, "two_pass"),
+        (options.compare_threads > 0, "compare_threads"),
+        (options.count_diff_bytes, "count_diff_bytes"),
+        (options.content_type.is_some(), "content_type"),
+        (options.per_file_timeout.is_some(), "per_file_timeout"),
+        (options.shallowest_diff, "shallowest_diff"),
+        (options.collation != Collation::Byte, "collation"),
+        (options.timeout.is_some(), "timeout"),
+        (options.min_size.is_some(), "min_size"),
+        (options.max_size.is_some(), "max_size"),
+        (
+            options.prune_unchanged_before.is_some(),
+            "prune_unchanged_before",
+        ),
+    ];
+    for (set, name) in unsupported {
+        if set {
+            bail!(
+                "Option {} is not supported when comparing archives or overlays",
+                name
+            );
+        }
+    }
+    Ok(())
+}
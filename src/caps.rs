@@ -0,0 +1,173 @@
+//! Decoding of Linux file capabilities, stored in the `security.capability` extended attribute,
+//! for [`DiffOptions::check_caps`](crate::DiffOptions::check_caps).
+//!
+//! The value is a `vfs_cap_data` structure: a little-endian word holding the revision and the
+//! effective flag, followed by the permitted and inheritable sets, 32 bits each for the first
+//! revision and 64 bits for the others, and the owner of the user namespace for the third one.
+
+/// Name of the extended attribute holding the capabilities.
+pub const XATTR: &str = "security.capability";
+
+const REVISION_MASK: u32 = 0xff00_0000;
+const REVISION_1: u32 = 0x0100_0000;
+const REVISION_2: u32 = 0x0200_0000;
+const REVISION_3: u32 = 0x0300_0000;
+const FLAG_EFFECTIVE: u32 = 0x0000_0001;
+
+/// Names of the capabilities, by number, as in `capabilities(7)`.
+const NAMES: &[&str] = &[
+    "cap_chown",
+    "cap_dac_override",
+    "cap_dac_read_search",
+    "cap_fowner",
+    "cap_fsetid",
+    "cap_kill",
+    "cap_setgid",
+    "cap_setuid",
+    "cap_setpcap",
+    "cap_linux_immutable",
+    "cap_net_bind_service",
+    "cap_net_broadcast",
+    "cap_net_admin",
+    "cap_net_raw",
+    "cap_ipc_lock",
+    "cap_ipc_owner",
+    "cap_sys_module",
+    "cap_sys_rawio",
+    "cap_sys_chroot",
+    "cap_sys_ptrace",
+    "cap_sys_pacct",
+    "cap_sys_admin",
+    "cap_sys_boot",
+    "cap_sys_nice",
+    "cap_sys_resource",
+    "cap_sys_time",
+    "cap_sys_tty_config",
+    "cap_mknod",
+    "cap_lease",
+    "cap_audit_write",
+    "cap_audit_control",
+    "cap_setfcap",
+    "cap_mac_override",
+    "cap_mac_admin",
+    "cap_syslog",
+    "cap_wake_alarm",
+    "cap_block_suspend",
+    "cap_audit_read",
+    "cap_perfmon",
+    "cap_bpf",
+    "cap_checkpoint_restore",
+];
+
+/// Describes the capabilities of a file like `getcap` does, like `cap_net_raw=ep`, from the
+/// value of its extended attribute, if it has one.
+///
+/// Capabilities with the same flags are grouped, and groups are separated by spaces. Files
+/// without capabilities are described as `none`, and malformed values are written in hex.
+pub fn describe(value: Option<&[u8]>) -> String {
+    let value = match value {
+        Some(value) => value,
+        None => return "none".to_owned(),
+    };
+    match decode(value) {
+        Some(description) => description,
+        None => value.iter().map(|b| format!("{:02x}", b)).collect(),
+    }
+}
+
+fn decode(value: &[u8]) -> Option<String> {
+    let words: Vec<u32> = value
+        .chunks(4)
+        .map(|word| Some(u32::from_le_bytes(word.try_into().ok()?)))
+        .collect::<Option<_>>()?;
+    let magic = *words.first()?;
+    let (sets, root_id) = match (magic & REVISION_MASK, words.len()) {
+        (REVISION_1, 3) => (1, None),
+        (REVISION_2, 5) => (2, None),
+        (REVISION_3, 6) => (2, Some(words[5])),
+        _ => return None,
+    };
+    let set = |offset: usize| {
+        (0..sets).fold(0u64, |set, i| {
+            set | (u64::from(words[1 + 2 * i + offset]) << (32 * i))
+        })
+    };
+    let (permitted, inheritable) = (set(0), set(1));
+    let effective = magic & FLAG_EFFECTIVE != 0;
+    // Flags of each group, and the capabilities having them, in the order of the capabilities.
+    let mut groups: Vec<(String, Vec<String>)> = Vec::new();
+    for cap in 0..64 {
+        let (p, i) = ((permitted >> cap) & 1 == 1, (inheritable >> cap) & 1 == 1);
+        if !p && !i {
+            continue;
+        }
+        let mut flags = String::new();
+        if p && effective {
+            flags.push('e');
+        }
+        if i {
+            flags.push('i');
+        }
+        if p {
+            flags.push('p');
+        }
+        let name = match NAMES.get(cap) {
+            Some(name) => name.to_string(),
+            None => format!("cap_{}", cap),
+        };
+        match groups.iter_mut().find(|(f, _)| *f == flags) {
+            Some((_, names)) => names.push(name),
+            None => groups.push((flags, vec![name])),
+        }
+    }
+    let mut description: Vec<_> = groups
+        .into_iter()
+        .map(|(flags, names)| format!("{}={}", names.join(","), flags))
+        .collect();
+    if description.is_empty() {
+        description.push("empty".to_owned());
+    }
+    if let Some(root_id) = root_id {
+        description.push(format!("[rootid={}]", root_id));
+    }
+    Some(description.join(" "))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn value(words: &[u32]) -> Vec<u8> {
+        words.iter().flat_map(|word| word.to_le_bytes()).collect()
+    }
+
+    #[test]
+    fn describe_revisions() {
+        assert_eq!(describe(None), "none");
+        // cap_net_raw, permitted and effective.
+        assert_eq!(
+            describe(Some(&value(&[0x0200_0001, 1 << 13, 0, 0, 0]))),
+            "cap_net_raw=ep"
+        );
+        // cap_chown and cap_kill permitted, cap_kill inheritable too, and cap_bpf, past the
+        // first word, permitted.
+        assert_eq!(
+            describe(Some(&value(&[
+                0x0200_0000,
+                0b10_0001,
+                0b10_0000,
+                1 << 7,
+                0
+            ]))),
+            "cap_chown,cap_bpf=p cap_kill=ip"
+        );
+        assert_eq!(describe(Some(&value(&[0x0100_0000, 0, 1]))), "cap_chown=i");
+        assert_eq!(
+            describe(Some(&value(&[0x0300_0001, 1, 0, 0, 0, 1000]))),
+            "cap_chown=ep [rootid=1000]"
+        );
+        assert_eq!(describe(Some(&value(&[0x0200_0000, 0, 0, 0, 0]))), "empty");
+        // Malformed, with a word missing.
+        assert_eq!(describe(Some(&[0, 0, 0, 2, 1, 0])), "000000020100");
+    }
+}
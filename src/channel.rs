@@ -0,0 +1,73 @@
+//! Forwarding diffs to a channel, to consume them from another thread or an async runtime.
+
+use crate::{Diff, DiffHandler};
+use std::{
+    path::Path,
+    sync::{
+        mpsc::{Sender, SyncSender},
+        Mutex,
+    },
+};
+
+/// Sends each diff to a [`std::sync::mpsc`] channel.
+///
+/// With an unbounded channel, from [`ChannelHandler::new`], the workers never wait for the
+/// receiver, and diffs pile up in memory if it is slower than them. With a bounded one, from
+/// [`ChannelHandler::bounded`], the workers block once the channel is full, which slows the
+/// comparison down to the pace of the receiver while keeping memory bounded. A receiver running
+/// in an async runtime should then `recv` from a blocking task, like tokio's `spawn_blocking`.
+///
+/// Once the receiver is dropped, diffs are discarded, but the comparison goes on until its end.
+/// [`DiffStream`](crate::DiffStream) stops it early instead.
+///
+/// # Example
+///
+/// ```no_run
+/// use dirdiff::{diff_dirs, ChannelHandler, DiffOptions};
+/// use std::sync::{mpsc::sync_channel, Arc};
+///
+/// let (sender, receiver) = sync_channel(1024);
+/// let consumer = std::thread::spawn(move || receiver.iter().count());
+/// let handler = Arc::new(ChannelHandler::bounded(sender));
+/// diff_dirs("dir1".into(), "dir2".into(), handler, 4, &DiffOptions::default())?;
+/// // The sender was dropped along with the handler, which ends the iteration.
+/// println!("{} diffs", consumer.join().unwrap());
+/// # Ok::<(), anyhow::Error>(())
+/// ```
+#[derive(Debug)]
+pub struct ChannelHandler {
+    sender: ChannelSender,
+}
+
+#[derive(Debug)]
+enum ChannelSender {
+    /// Locked, as `Sender` can only be shared between threads since Rust 1.72.
+    Unbounded(Mutex<Sender<Diff>>),
+    Bounded(SyncSender<Diff>),
+}
+
+impl ChannelHandler {
+    /// Sends the diffs to an unbounded channel, created by [`std::sync::mpsc::channel`].
+    pub fn new(sender: Sender<Diff>) -> Self {
+        Self {
+            sender: ChannelSender::Unbounded(Mutex::new(sender)),
+        }
+    }
+
+    /// Sends the diffs to a bounded channel, created by [`std::sync::mpsc::sync_channel`].
+    pub fn bounded(sender: SyncSender<Diff>) -> Self {
+        Self {
+            sender: ChannelSender::Bounded(sender),
+        }
+    }
+}
+
+impl DiffHandler for ChannelHandler {
+    fn process(&self, _root1: &Path, _root2: &Path, diff: Diff) {
+        // Fails only if the receiver was dropped, nobody wants the diff anymore.
+        let _ = match &self.sender {
+            ChannelSender::Unbounded(sender) => sender.lock().unwrap().send(diff),
+            ChannelSender::Bounded(sender) => sender.send(diff),
+        };
+    }
+}
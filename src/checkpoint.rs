@@ -0,0 +1,111 @@
+//! Record of the top-level directories fully compared, to resume an interrupted comparison,
+//! for [`DiffOptions::checkpoint`](crate::DiffOptions::checkpoint).
+
+use std::{
+    collections::{HashMap, HashSet},
+    ffi::{OsStr, OsString},
+    fs::{File, OpenOptions},
+    io::{self, BufRead, BufReader, Write},
+    path::{Component, Path, PathBuf},
+    sync::Mutex,
+};
+
+/// Top-level directories whose whole subtree was compared, kept in a file.
+///
+/// The file holds the name of one such directory per line, and a line is appended as soon as
+/// the last directory of its subtree is done, so that it is up to date whenever the comparison
+/// is interrupted. Names which are not valid UTF-8 or contain a newline are never recorded, and
+/// their directories are compared again on every run.
+#[derive(Debug)]
+pub struct Checkpoint {
+    path: PathBuf,
+    file: Mutex<File>,
+    /// Directories recorded by a previous run, skipped by this one.
+    done: HashSet<OsString>,
+    /// Number of directories of each subtree being compared whose processing is not over.
+    pending: Mutex<HashMap<OsString, usize>>,
+}
+
+impl Checkpoint {
+    /// Loads the directories recorded in the file at `path`, if it exists, and appends those
+    /// completed from now on to it.
+    pub fn open(path: PathBuf) -> io::Result<Self> {
+        let mut done = HashSet::new();
+        match File::open(&path) {
+            Ok(file) => {
+                for line in BufReader::new(file).lines() {
+                    let line = line?;
+                    if !line.is_empty() {
+                        done.insert(line.into());
+                    }
+                }
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => (),
+            Err(e) => return Err(e),
+        }
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        Ok(Self {
+            path,
+            file: Mutex::new(file),
+            done,
+            pending: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// Path of the file the directories are recorded in.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Number of directories recorded by a previous run, and skipped by this one.
+    pub fn skipped(&self) -> usize {
+        self.done.len()
+    }
+
+    /// Whether the top-level entry `name` was recorded by a previous run.
+    pub(crate) fn is_done(&self, name: &OsStr) -> bool {
+        self.done.contains(name)
+    }
+
+    /// Counts `dir` as pending in its subtree, before it is pushed to a stack.
+    pub(crate) fn started(&self, dir: &Path) {
+        if let Some(top) = top_level(dir) {
+            *self.pending.lock().unwrap().entry(top).or_default() += 1;
+        }
+    }
+
+    /// Counts `dir` as done, once the directories it found are counted as pending, and records
+    /// its subtree if it was the last directory pending in it.
+    pub(crate) fn finished(&self, dir: &Path) -> io::Result<()> {
+        let top = match top_level(dir) {
+            Some(top) => top,
+            None => return Ok(()),
+        };
+        let mut pending = self.pending.lock().unwrap();
+        let count = pending
+            .get_mut(&top)
+            .expect("directories are counted when pushed");
+        *count -= 1;
+        if *count > 0 {
+            return Ok(());
+        }
+        pending.remove(&top);
+        drop(pending);
+        match top.to_str() {
+            Some(name) if !name.contains(['\n', '\r']) => {
+                let mut file = self.file.lock().unwrap();
+                writeln!(file, "{}", name)?;
+                file.flush()
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+/// First component of `dir`, or `None` for the roots.
+fn top_level(dir: &Path) -> Option<OsString> {
+    match dir.components().next() {
+        Some(Component::Normal(name)) => Some(name.to_owned()),
+        _ => None,
+    }
+}
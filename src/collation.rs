@@ -0,0 +1,82 @@
+//! Order of the paths of the diffs reported sorted, for
+//! [`DiffOptions::collation`](crate::DiffOptions::collation).
+//!
+//! Directories are always listed and paired in byte order, which only depends on the names.
+//! The collation only changes the order the sorted diffs are reported in.
+
+use std::{cmp::Ordering, path::Path};
+
+/// How the paths of the diffs reported sorted are ordered.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Collation {
+    /// By the bytes of their names, like `sort` under the `C` locale.
+    #[default]
+    Byte,
+    /// By the collation of the `LC_COLLATE` locale of the process, see [`set_locale_from_env`].
+    ///
+    /// Paths are compared component by component, so that the entries of a directory stay
+    /// right after it. Names equal for the locale are ordered by their bytes. Only supported on
+    /// Unix, and the same as `Byte` elsewhere.
+    Locale,
+}
+
+impl Collation {
+    /// Orders two paths relative to the roots.
+    pub fn compare(self, path1: &Path, path2: &Path) -> Ordering {
+        match self {
+            Collation::Byte => path1.cmp(path2),
+            Collation::Locale => {
+                let mut components1 = path1.iter();
+                let mut components2 = path2.iter();
+                loop {
+                    match (components1.next(), components2.next()) {
+                        (None, None) => return Ordering::Equal,
+                        (None, Some(_)) => return Ordering::Less,
+                        (Some(_), None) => return Ordering::Greater,
+                        (Some(name1), Some(name2)) => {
+                            let order = collate(name1, name2).then_with(|| name1.cmp(name2));
+                            if order != Ordering::Equal {
+                                return order;
+                            }
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Sets the `LC_COLLATE` locale of the process from the environment, that is from the
+/// `LC_ALL`, `LC_COLLATE` or `LANG` variables, as C programs do on startup.
+///
+/// Processes otherwise use the `C` locale, which orders names by their bytes. Changing the
+/// locale is not thread-safe, so this must be called before any thread is spawned. Does
+/// nothing but on Unix.
+pub fn set_locale_from_env() {
+    #[cfg(unix)]
+    {
+        let locale = std::ffi::CString::default();
+        // SAFETY: the locale is an empty NUL-terminated string, and no other thread runs yet.
+        unsafe { libc::setlocale(libc::LC_COLLATE, locale.as_ptr()) };
+    }
+}
+
+#[cfg(unix)]
+fn collate(name1: &std::ffi::OsStr, name2: &std::ffi::OsStr) -> Ordering {
+    use std::{ffi::CString, os::unix::ffi::OsStrExt};
+    // Names never contain NUL bytes on Unix.
+    let (c1, c2) = match (
+        CString::new(name1.as_bytes()),
+        CString::new(name2.as_bytes()),
+    ) {
+        (Ok(c1), Ok(c2)) => (c1, c2),
+        _ => return name1.cmp(name2),
+    };
+    // SAFETY: both are valid NUL-terminated strings.
+    unsafe { libc::strcoll(c1.as_ptr(), c2.as_ptr()) }.cmp(&0)
+}
+
+#[cfg(not(unix))]
+fn collate(name1: &std::ffi::OsStr, name2: &std::ffi::OsStr) -> Ordering {
+    name1.cmp(name2)
+}
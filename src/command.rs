@@ -0,0 +1,164 @@
+//! External commands comparing files, for `--compare-cmd`, or listing a tree, for
+//! `--exec-listing`.
+//!
+//! A command is written as on a shell command line: arguments are separated by spaces, and can be
+//! quoted with single or double quotes, or have a character escaped with a backslash. No shell is
+//! involved though, so pipes, redirections or variables have no special meaning. In each argument,
+//! `%1` and `%2` are replaced by the paths of the files, and `%%` by a literal `%`.
+
+use anyhow::bail;
+use std::{
+    ffi::OsString,
+    io,
+    path::Path,
+    process::{Command, Stdio},
+    sync::atomic::{AtomicBool, Ordering},
+    thread,
+    time::Duration,
+};
+
+/// Longest time between two checks of whether a comparison command is done, when it can be
+/// cancelled.
+const MAX_POLL: Duration = Duration::from_millis(50);
+
+/// A command telling whether two files are equal, through its exit code.
+#[derive(Debug, Clone)]
+pub struct CompareCommand {
+    program: String,
+    args: Vec<String>,
+}
+
+impl CompareCommand {
+    /// Parses a command line, failing on unclosed quotes and unknown placeholders.
+    pub fn parse(command: &str) -> anyhow::Result<Self> {
+        let words = split(command)?;
+        for word in &words {
+            let mut rest = word.as_str();
+            while let Some(i) = rest.find('%') {
+                match rest[i + 1..].chars().next() {
+                    Some('1' | '2' | '%') => rest = &rest[i + 2..],
+                    _ => bail!(
+                        "Unknown placeholder in command argument `{}`, expected %1, %2 or %%",
+                        word
+                    ),
+                }
+            }
+        }
+        let mut words = words.into_iter();
+        let program = match words.next() {
+            Some(program) => program,
+            None => bail!("Empty command"),
+        };
+        Ok(Self {
+            program,
+            args: words.collect(),
+        })
+    }
+
+    /// Runs the command on two files, returning whether it exited successfully.
+    ///
+    /// Its output is discarded, while its errors are shown. The command is killed once `cancel`
+    /// is set, if given, in which case `None` is returned.
+    pub fn run(
+        &self,
+        path1: &Path,
+        path2: &Path,
+        cancel: Option<&AtomicBool>,
+    ) -> io::Result<Option<bool>> {
+        let error = |e: io::Error| {
+            io::Error::new(
+                e.kind(),
+                format!("Couldn't run comparison command {}: {}", self.program, e),
+            )
+        };
+        let mut child = Command::new(substitute(&self.program, path1, path2))
+            .args(self.args.iter().map(|arg| substitute(arg, path1, path2)))
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .spawn()
+            .map_err(error)?;
+        let cancel = match cancel {
+            Some(cancel) => cancel,
+            None => return Ok(Some(child.wait().map_err(error)?.success())),
+        };
+        // Polled rather than waited for, so that the flag is seen. Quick commands are only
+        // waited for a little, while slow ones are polled less often.
+        let mut poll = Duration::from_micros(100);
+        loop {
+            if let Some(status) = child.try_wait().map_err(error)? {
+                return Ok(Some(status.success()));
+            }
+            if cancel.load(Ordering::SeqCst) {
+                // It may have exited meanwhile.
+                let _ = child.kill();
+                child.wait().map_err(error)?;
+                return Ok(None);
+            }
+            thread::sleep(poll);
+            poll = (poll * 2).min(MAX_POLL);
+        }
+    }
+}
+
+/// Replaces the placeholders of a parsed argument by the paths of the files.
+fn substitute(arg: &str, path1: &Path, path2: &Path) -> OsString {
+    let mut result = OsString::new();
+    let mut rest = arg;
+    while let Some(i) = rest.find('%') {
+        result.push(&rest[..i]);
+        // Placeholders were checked when parsing.
+        match &rest[i + 1..i + 2] {
+            "1" => result.push(path1),
+            "2" => result.push(path2),
+            _ => result.push("%"),
+        }
+        rest = &rest[i + 2..];
+    }
+    result.push(rest);
+    result
+}
+
+/// Splits a command line into words, failing on unclosed quotes.
+pub(crate) fn split(command: &str) -> anyhow::Result<Vec<String>> {
+    let mut words = Vec::new();
+    let mut word: Option<String> = None;
+    let mut chars = command.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            c if c.is_whitespace() => {
+                words.extend(word.take());
+            }
+            '\'' => {
+                let word = word.get_or_insert_with(String::new);
+                loop {
+                    match chars.next() {
+                        Some('\'') => break,
+                        Some(c) => word.push(c),
+                        None => bail!("Unclosed single quote in command"),
+                    }
+                }
+            }
+            '"' => {
+                let word = word.get_or_insert_with(String::new);
+                loop {
+                    match chars.next() {
+                        Some('"') => break,
+                        Some('\\') => match chars.next() {
+                            Some(c) => word.push(c),
+                            None => bail!("Unclosed double quote in command"),
+                        },
+                        Some(c) => word.push(c),
+                        None => bail!("Unclosed double quote in command"),
+                    }
+                }
+            }
+            '\\' => match chars.next() {
+                Some(c) => word.get_or_insert_with(String::new).push(c),
+                None => bail!("Command ends with an unfinished escape sequence"),
+            },
+            c => word.get_or_insert_with(String::new).push(c),
+        }
+    }
+    words.extend(word);
+    Ok(words)
+}
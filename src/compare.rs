@@ -0,0 +1,634 @@
+//! Comparison of the content of two files.
+
+use crate::{sniff, ContentType, FileReader, RetryPolicy};
+use std::{
+    fs::File,
+    io::{self, BufRead, BufReader},
+    path::Path,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc,
+    },
+    time::Instant,
+};
+
+/// Options of [`files_equal`].
+#[derive(Debug, Clone, Default)]
+pub struct CompareOpts {
+    /// Retrying of the IO operations failing with transient errors.
+    pub retry: RetryPolicy,
+    /// Compare ASCII letters case-insensitively.
+    pub ignore_case: bool,
+    /// Length both files are expected to have, usually read from their metadata.
+    ///
+    /// When set, bytes past it are never compared, and files not ending there are
+    /// reported as [`FileCompare::LenDiffer`].
+    pub len: Option<u64>,
+    /// Tell the kernel that files are read sequentially, and that they can be dropped from the
+    /// page cache once compared. Only used on Linux.
+    pub fadvise: bool,
+    /// Bytes left to read, shared by all the comparisons drawing from the same budget.
+    ///
+    /// Each chunk compared is charged twice, once per file, before being compared. Once the
+    /// budget cannot cover a chunk, the comparison stops with [`FileCompare::OverBudget`], even
+    /// midway through the files.
+    pub budget: Option<Arc<AtomicU64>>,
+    /// Only compare the first bytes of the files, up to this number, and consider them equal if
+    /// these are, whatever follows.
+    pub prefix: Option<u64>,
+    /// Flag stopping the comparison with [`FileCompare::Cancelled`] once set, checked before
+    /// each chunk, so that comparing a large file does not hold up a stop.
+    pub cancel: Option<Arc<AtomicBool>>,
+    /// Compare the files to the end even once they differ, counting the differing bytes.
+    ///
+    /// Without `len`, files of different lengths are compared too, the tail of the longest one
+    /// counting as differing whole.
+    pub count_diff_bytes: bool,
+    /// Only compare files of this content type, sniffed from the first bytes read.
+    ///
+    /// Files which are both of another type are left uncompared, as
+    /// [`FileCompare::OtherContentType`], and files of different types, one of them being this
+    /// one, are reported as [`FileCompare::ContentTypesDiffer`].
+    pub content_type: Option<ContentType>,
+    /// Instant past which the comparison stops with [`FileCompare::TimedOut`].
+    ///
+    /// Checked before each chunk, like `cancel`: a read cannot be interrupted, so that one
+    /// blocking, like on a stuck network mount, is only noticed once it returns, if ever.
+    pub deadline: Option<Instant>,
+}
+
+/// Outcome of [`files_equal`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileCompare {
+    Equal,
+    /// The files differ, first at `first_offset`, with `differing` bytes differing if they were
+    /// counted.
+    Differ {
+        first_offset: u64,
+        differing: Option<u64>,
+    },
+    /// The files are the same up to the end of the shortest one, or one of them
+    /// does not have the expected length.
+    LenDiffer,
+    /// The budget ran out before the files were found to differ or compared whole.
+    OverBudget,
+    /// The cancel flag was set before the files were found to differ or compared whole, so
+    /// that they are neither equal nor different.
+    Cancelled,
+    /// The deadline passed before the files were found to differ or compared whole.
+    TimedOut,
+    /// Neither file has the content type looked for, so they were not compared.
+    OtherContentType,
+    /// The files have different content types, as MIME types, or `None` if unknown, one of
+    /// them being the one looked for.
+    ContentTypesDiffer {
+        type1: Option<&'static str>,
+        type2: Option<&'static str>,
+    },
+}
+
+/// Compares the content of two files, reading them side by side.
+///
+/// When opening or reading them fails with a transient error, both are opened again and
+/// compared from the start, as long as `opts.retry` allows.
+pub fn files_equal(a: &Path, b: &Path, opts: &CompareOpts) -> io::Result<FileCompare> {
+    reopened_files_equal(
+        opts,
+        || Ok((File::open(a)?, File::open(b)?)),
+        |(f1, f2), opts| open_files_equal(f1, f2, opts),
+    )
+}
+
+/// Compares with `compare` the content of the files opened by `open`, opening them again to
+/// compare them from the start whenever opening or reading them fails with a transient error,
+/// as long as `opts.retry` allows.
+pub(crate) fn reopened_files_equal<T>(
+    opts: &CompareOpts,
+    mut open: impl FnMut() -> io::Result<T>,
+    compare: impl Fn(T, &CompareOpts) -> io::Result<FileCompare>,
+) -> io::Result<FileCompare> {
+    // Failed reads are not retried in place, but by starting over.
+    let once = CompareOpts {
+        retry: RetryPolicy::default(),
+        ..opts.clone()
+    };
+    opts.retry.retry(|| compare(open()?, &once))
+}
+
+/// Same as [`files_equal`], for files that are already open, so that failed reads are retried
+/// in place rather than by opening them again.
+pub fn open_files_equal<R: FileReader>(
+    f1: R,
+    f2: R,
+    opts: &CompareOpts,
+) -> io::Result<FileCompare> {
+    let mut f1 = BufReader::new(f1);
+    let mut f2 = BufReader::new(f2);
+    if opts.fadvise {
+        for file in [f1.get_ref(), f2.get_ref()]
+            .into_iter()
+            .filter_map(R::os_file)
+        {
+            advise_sequential(file);
+        }
+    }
+    let result = compare_readers(&mut f1, &mut f2, opts);
+    if opts.fadvise {
+        // Only once the files are read, or the pages would be dropped as they are read ahead.
+        for file in [f1.get_ref(), f2.get_ref()]
+            .into_iter()
+            .filter_map(R::os_file)
+        {
+            advise_dontneed(file);
+        }
+    }
+    result
+}
+
+/// Compares the content of two readers, like open files from their current position.
+pub fn compare_readers<R1: BufRead, R2: BufRead>(
+    f1: &mut R1,
+    f2: &mut R2,
+    opts: &CompareOpts,
+) -> io::Result<FileCompare> {
+    let retry = opts.retry;
+    let mut offset = 0;
+    // First differing offset and number of differing bytes, when counting them.
+    let mut differ: Option<(u64, u64)> = None;
+    let compared = |differ: Option<(u64, u64)>| match differ {
+        Some((first_offset, differing)) => FileCompare::Differ {
+            first_offset,
+            differing: Some(differing),
+        },
+        None => FileCompare::Equal,
+    };
+    // Whether the budget covers reading `cost` more bytes, which are then charged.
+    let charge = |cost: u64| match &opts.budget {
+        Some(budget) => budget
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |left| {
+                left.checked_sub(cost)
+            })
+            .is_ok(),
+        None => true,
+    };
+    loop {
+        if opts.prefix == Some(offset) {
+            return Ok(compared(differ));
+        }
+        if matches!(&opts.cancel, Some(cancel) if cancel.load(Ordering::SeqCst)) {
+            return Ok(FileCompare::Cancelled);
+        }
+        if matches!(opts.deadline, Some(deadline) if Instant::now() >= deadline) {
+            return Ok(FileCompare::TimedOut);
+        }
+        retry.retry(|| f1.fill_buf().map(|s| s.len()))?;
+        retry.retry(|| f2.fill_buf().map(|s| s.len()))?;
+        // Already filled, so that this does not read again, unless at the end.
+        let s1 = f1.fill_buf()?;
+        let s2 = f2.fill_buf()?;
+        if let (Some(content_type), 0) = (&opts.content_type, offset) {
+            let (type1, type2) = (sniff::sniff(s1), sniff::sniff(s2));
+            let (matches1, matches2) = (content_type.matches(type1), content_type.matches(type2));
+            let (type1, type2) = (type1.map(|t| t.mime_type()), type2.map(|t| t.mime_type()));
+            if !matches1 && !matches2 {
+                return Ok(FileCompare::OtherContentType);
+            } else if type1 != type2 {
+                return Ok(FileCompare::ContentTypesDiffer { type1, type2 });
+            }
+        }
+        if (s1.is_empty() || s2.is_empty()) && opts.count_diff_bytes && opts.len.is_none() {
+            // Only one of them is empty, or both are once the tail is consumed.
+            let (len1, len2) = (s1.len(), s2.len());
+            let tail = (len1 + len2) as u64;
+            if tail > 0 {
+                if !charge(tail) {
+                    return Ok(FileCompare::OverBudget);
+                }
+                differ.get_or_insert((offset, 0)).1 += tail;
+                f1.consume(len1);
+                f2.consume(len2);
+                offset += tail;
+                continue;
+            }
+        }
+        if s1.is_empty() || s2.is_empty() {
+            let expected_end = match opts.len {
+                Some(len) => offset == len,
+                None => true,
+            };
+            return Ok(if s1.is_empty() && s2.is_empty() && expected_end {
+                compared(differ)
+            } else {
+                FileCompare::LenDiffer
+            });
+        }
+        if opts.len == Some(offset) {
+            return Ok(FileCompare::LenDiffer);
+        }
+        let mut common_size = std::cmp::min(s1.len(), s2.len());
+        if let Some(len) = opts.len {
+            // Bytes past `len` are never compared.
+            common_size = std::cmp::min(common_size as u64, len - offset) as usize;
+        }
+        if let Some(prefix) = opts.prefix {
+            common_size = std::cmp::min(common_size as u64, prefix - offset) as usize;
+        }
+        if !charge(2 * common_size as u64) {
+            return Ok(FileCompare::OverBudget);
+        }
+        let (s1, s2) = (&s1[..common_size], &s2[..common_size]);
+        let same = if opts.ignore_case {
+            s1.eq_ignore_ascii_case(s2)
+        } else {
+            s1 == s2
+        };
+        if !same {
+            // Only looked for once the chunks are known to differ, comparing slices is faster.
+            let differs = |(b1, b2): (&u8, &u8)| {
+                if opts.ignore_case {
+                    !b1.eq_ignore_ascii_case(b2)
+                } else {
+                    b1 != b2
+                }
+            };
+            let first_offset =
+                offset + s1.iter().zip(s2).position(differs).unwrap_or_default() as u64;
+            if !opts.count_diff_bytes {
+                return Ok(FileCompare::Differ {
+                    first_offset,
+                    differing: None,
+                });
+            }
+            let differing = s1.iter().zip(s2).filter(|&pair| differs(pair)).count();
+            differ.get_or_insert((first_offset, 0)).1 += differing as u64;
+        }
+        f1.consume(common_size);
+        f2.consume(common_size);
+        offset += common_size as u64;
+    }
+}
+
+/// Hints that `file` is read sequentially, so that more of it is read ahead.
+///
+/// Errors are ignored, as this is only a hint.
+#[cfg(target_os = "linux")]
+fn advise_sequential(file: &File) {
+    use std::os::unix::io::AsRawFd;
+    // SAFETY: the file descriptor is valid, owned by `file`.
+    unsafe { libc::posix_fadvise(file.as_raw_fd(), 0, 0, libc::POSIX_FADV_SEQUENTIAL) };
+}
+
+/// Hints that the content of `file` is not needed anymore, so that it leaves the page cache.
+///
+/// Errors are ignored, as this is only a hint.
+#[cfg(target_os = "linux")]
+fn advise_dontneed(file: &File) {
+    use std::os::unix::io::AsRawFd;
+    // SAFETY: as above.
+    unsafe { libc::posix_fadvise(file.as_raw_fd(), 0, 0, libc::POSIX_FADV_DONTNEED) };
+}
+
+#[cfg(not(target_os = "linux"))]
+fn advise_sequential(_file: &File) {}
+
+#[cfg(not(target_os = "linux"))]
+fn advise_dontneed(_file: &File) {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{fs, io::Read, time::Duration};
+
+    /// Compares files with `content1` and `content2`, written to a temporary directory.
+    fn compare_files(
+        name: &str,
+        content1: &[u8],
+        content2: &[u8],
+        opts: &CompareOpts,
+    ) -> FileCompare {
+        let dir =
+            std::env::temp_dir().join(format!("dirdiff-compare-{}-{name}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let (a, b) = (dir.join("a"), dir.join("b"));
+        fs::write(&a, content1).unwrap();
+        fs::write(&b, content2).unwrap();
+        let res = files_equal(&a, &b, opts).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+        res
+    }
+
+    #[test]
+    fn equal() {
+        let opts = CompareOpts::default();
+        assert_eq!(
+            compare_files("equal", b"abc", b"abc", &opts),
+            FileCompare::Equal
+        );
+        assert_eq!(compare_files("empty", b"", b"", &opts), FileCompare::Equal);
+        let opts = CompareOpts {
+            ignore_case: true,
+            ..opts
+        };
+        assert_eq!(
+            compare_files("case", b"abc", b"ABC", &opts),
+            FileCompare::Equal
+        );
+    }
+
+    #[test]
+    fn content_differ() {
+        let opts = CompareOpts::default();
+        assert_eq!(
+            compare_files("differ", b"abcdef", b"abcxef", &opts),
+            FileCompare::Differ {
+                first_offset: 3,
+                differing: None
+            }
+        );
+        // Past the first buffer.
+        let (mut large1, mut large2) = (vec![0; 100_000], vec![0; 100_000]);
+        large1[70_000] = 1;
+        large2[70_000] = 2;
+        assert_eq!(
+            compare_files("large", &large1, &large2, &opts),
+            FileCompare::Differ {
+                first_offset: 70_000,
+                differing: None
+            }
+        );
+    }
+
+    #[test]
+    fn content_type() {
+        let opts = CompareOpts {
+            content_type: Some(ContentType::parse("image").unwrap()),
+            ..CompareOpts::default()
+        };
+        let png = b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR";
+        let mut other_png = png.to_vec();
+        other_png.push(0);
+        assert_eq!(
+            compare_files("same_png", png, png, &opts),
+            FileCompare::Equal
+        );
+        // Compared whole, whatever their sizes.
+        assert_eq!(
+            compare_files("png", png, &other_png, &opts),
+            FileCompare::LenDiffer
+        );
+        assert_eq!(
+            compare_files("text", b"abc", b"abd", &opts),
+            FileCompare::OtherContentType
+        );
+        assert_eq!(
+            compare_files("png_text", png, b"abc", &opts),
+            FileCompare::ContentTypesDiffer {
+                type1: Some("image/png"),
+                type2: None
+            }
+        );
+    }
+
+    #[test]
+    fn count_diff_bytes() {
+        let opts = CompareOpts {
+            count_diff_bytes: true,
+            ..CompareOpts::default()
+        };
+        assert_eq!(
+            compare_files("one", b"abcdef", b"abcxef", &opts),
+            FileCompare::Differ {
+                first_offset: 3,
+                differing: Some(1)
+            }
+        );
+        // Counted past the first buffer.
+        let (large1, mut large2) = (vec![0; 100_000], vec![0; 100_000]);
+        for byte in large2.iter_mut().skip(10).step_by(1000) {
+            *byte = 1;
+        }
+        assert_eq!(
+            compare_files("many", &large1, &large2, &opts),
+            FileCompare::Differ {
+                first_offset: 10,
+                differing: Some(100)
+            }
+        );
+        // The tail of the longest file differs whole.
+        assert_eq!(
+            compare_files("tail", b"abc", b"abxdef", &opts),
+            FileCompare::Differ {
+                first_offset: 2,
+                differing: Some(4)
+            }
+        );
+        assert_eq!(
+            compare_files("prefix", b"abc", b"abcdef", &opts),
+            FileCompare::Differ {
+                first_offset: 3,
+                differing: Some(3)
+            }
+        );
+    }
+
+    #[test]
+    fn len_differ() {
+        let opts = CompareOpts::default();
+        assert_eq!(
+            compare_files("prefix", b"abc", b"abcd", &opts),
+            FileCompare::LenDiffer
+        );
+        // Not the expected length, on both sides.
+        let opts = CompareOpts {
+            len: Some(2),
+            ..opts
+        };
+        assert_eq!(
+            compare_files("len", b"abc", b"abc", &opts),
+            FileCompare::LenDiffer
+        );
+    }
+
+    #[test]
+    fn fadvise() {
+        let opts = CompareOpts {
+            fadvise: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            compare_files("fadvise_equal", b"abc", b"abc", &opts),
+            FileCompare::Equal
+        );
+        assert_eq!(
+            compare_files("fadvise_differ", b"abcdef", b"abcxef", &opts),
+            FileCompare::Differ {
+                first_offset: 3,
+                differing: None
+            }
+        );
+        assert_eq!(
+            compare_files("fadvise_len", b"abc", b"abcd", &opts),
+            FileCompare::LenDiffer
+        );
+    }
+
+    /// Compares `content1` and `content2`, read as files of `len` bytes.
+    fn compare(content1: &[u8], content2: &[u8], len: u64) -> FileCompare {
+        let opts = CompareOpts {
+            len: Some(len),
+            ..Default::default()
+        };
+        compare_readers(
+            &mut BufReader::new(content1),
+            &mut BufReader::new(content2),
+            &opts,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn files_changed_during_scan() {
+        assert_eq!(compare(b"abc", b"abc", 3), FileCompare::Equal);
+        assert_eq!(
+            compare(b"abc", b"abd", 3),
+            FileCompare::Differ {
+                first_offset: 2,
+                differing: None
+            }
+        );
+        // A file grew after its metadata were read.
+        assert_eq!(compare(b"abcd", b"abc", 3), FileCompare::LenDiffer);
+        assert_eq!(compare(b"abcd", b"abcd", 3), FileCompare::LenDiffer);
+        // A file was truncated.
+        assert_eq!(compare(b"ab", b"abc", 3), FileCompare::LenDiffer);
+        // Bytes added past the expected length are not compared.
+        let long = vec![b'x'; 100_000];
+        let mut grown = long.clone();
+        grown.push(b'y');
+        assert_eq!(compare(&long, &grown, 100_000), FileCompare::LenDiffer);
+    }
+
+    /// Reads zeros forever, setting `cancel` once `after` bytes were read.
+    struct Endless<'a> {
+        read: usize,
+        after: usize,
+        cancel: &'a AtomicBool,
+    }
+
+    impl Read for Endless<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.read += buf.len();
+            if self.read >= self.after {
+                self.cancel.store(true, Ordering::SeqCst);
+            }
+            buf.fill(0);
+            Ok(buf.len())
+        }
+    }
+
+    #[test]
+    fn cancelled() {
+        let cancel = Arc::new(AtomicBool::new(false));
+        let opts = CompareOpts {
+            cancel: Some(cancel.clone()),
+            ..Default::default()
+        };
+        let endless = |after| {
+            BufReader::new(Endless {
+                read: 0,
+                after,
+                cancel: &cancel,
+            })
+        };
+        // Both would be read forever, and are equal as far as they are read.
+        let result = compare_readers(&mut endless(1 << 20), &mut endless(usize::MAX), &opts);
+        assert_eq!(result.unwrap(), FileCompare::Cancelled);
+    }
+
+    /// Reads zeros forever, sleeping before each read, like a file on a slow network mount.
+    struct Slow;
+
+    impl Read for Slow {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            std::thread::sleep(Duration::from_millis(5));
+            let n = buf.len().min(16);
+            buf[..n].fill(0);
+            Ok(n)
+        }
+    }
+
+    #[test]
+    fn timed_out() {
+        let opts = CompareOpts {
+            deadline: Some(Instant::now() + Duration::from_millis(50)),
+            ..Default::default()
+        };
+        let start = Instant::now();
+        let result = compare_readers(&mut BufReader::new(Slow), &mut BufReader::new(Slow), &opts);
+        assert_eq!(result.unwrap(), FileCompare::TimedOut);
+        assert!(start.elapsed() < Duration::from_secs(5));
+        // Files compared before the deadline are not affected.
+        let opts = CompareOpts {
+            deadline: Some(Instant::now() + Duration::from_secs(60)),
+            ..Default::default()
+        };
+        let result = compare_readers(&mut &b"abc"[..], &mut &b"abd"[..], &opts);
+        assert_eq!(
+            result.unwrap(),
+            FileCompare::Differ {
+                first_offset: 2,
+                differing: None
+            }
+        );
+    }
+
+    /// Reads `content`, failing with an interrupted read once `read` bytes were read while
+    /// `failures` is not zero, decrementing it.
+    struct Flaky<'a> {
+        content: &'a [u8],
+        read: usize,
+        failures: &'a std::cell::Cell<u32>,
+    }
+
+    impl Read for Flaky<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            if self.read > 0 && self.failures.get() > 0 {
+                self.failures.set(self.failures.get() - 1);
+                return Err(io::ErrorKind::Interrupted.into());
+            }
+            let n = self.content.len().min(buf.len()).min(4);
+            buf[..n].copy_from_slice(&self.content[..n]);
+            self.content = &self.content[n..];
+            self.read += n;
+            Ok(n)
+        }
+    }
+
+    #[test]
+    fn content_reads_are_retried_from_the_start() {
+        let compare_flaky = |failures: u32, retries: u32| {
+            let failures = std::cell::Cell::new(failures);
+            let mut opens = 0;
+            let opts = CompareOpts {
+                retry: RetryPolicy::new(retries),
+                ..Default::default()
+            };
+            let open = || {
+                opens += 1;
+                let flaky = Flaky {
+                    content: b"0123456789",
+                    read: 0,
+                    failures: &failures,
+                };
+                Ok((BufReader::new(flaky), BufReader::new(&b"0123456789"[..])))
+            };
+            let compare =
+                |(mut f1, mut f2), opts: &CompareOpts| compare_readers(&mut f1, &mut f2, opts);
+            let result = reopened_files_equal(&opts, open, compare);
+            (result.map_err(|e| e.kind()), opens)
+        };
+        assert_eq!(compare_flaky(2, 2), (Ok(FileCompare::Equal), 3));
+        assert_eq!(compare_flaky(3, 2), (Err(io::ErrorKind::Interrupted), 3));
+        assert_eq!(compare_flaky(0, 0), (Ok(FileCompare::Equal), 1));
+    }
+}
@@ -0,0 +1,300 @@
+//! Comparison strategies chosen by the extension of files, for `--comparer`.
+//!
+//! A [`ContentComparer`] replaces the comparison of the content of regular files whose
+//! extension it is registered for in [`Comparers`], or of all the other files if it is the
+//! fallback, even when their sizes differ. If it fails because a file is malformed, like a `.gz`
+//! file that is not gzipped, the files are compared byte by byte instead. Comparers stop once
+//! the cancel flag of their options is set, failing with [`Cancelled`].
+
+use crate::{compare::compare_readers, files_equal, manifest::Hash, CompareOpts, FileCompare};
+use sha2::Digest;
+use std::{
+    collections::BTreeMap,
+    fmt,
+    fs::File,
+    io::{self, BufReader, Read},
+    path::Path,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+};
+
+/// Size of the chunks of the files read whole, between which the cancel flag is checked.
+const READ_CHUNK: u64 = 1 << 20;
+
+/// A strategy telling whether the content of two files is equal.
+pub trait ContentComparer: fmt::Debug + Send + Sync {
+    /// Whether both files are equal.
+    ///
+    /// Errors of kind [`io::ErrorKind::InvalidData`], [`io::ErrorKind::InvalidInput`] or
+    /// [`io::ErrorKind::UnexpectedEof`] mean that a file is malformed for this strategy, which
+    /// makes the files be compared byte by byte. A [`Cancelled`] error, once `opts.cancel` is
+    /// set, leaves the files uncompared. Other errors end the comparison.
+    fn equal(&self, path1: &Path, path2: &Path, opts: &CompareOpts) -> io::Result<bool>;
+}
+
+/// Compares files byte by byte, as when no strategy is registered.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct RawBytes;
+
+impl ContentComparer for RawBytes {
+    fn equal(&self, path1: &Path, path2: &Path, opts: &CompareOpts) -> io::Result<bool> {
+        let opts = CompareOpts {
+            len: None,
+            ..opts.clone()
+        };
+        verdict(files_equal(path1, path2, &opts)?)
+    }
+}
+
+/// Compares the decompressed content of gzipped files, whatever their compression level.
+///
+/// Files made of several gzip members are decompressed whole.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Gzip;
+
+impl ContentComparer for Gzip {
+    fn equal(&self, path1: &Path, path2: &Path, opts: &CompareOpts) -> io::Result<bool> {
+        let open = |path| -> io::Result<_> {
+            let file = opts.retry.retry(|| File::open(path))?;
+            Ok(BufReader::new(flate2::read::MultiGzDecoder::new(
+                BufReader::new(file),
+            )))
+        };
+        // The decompressed size is unknown.
+        let opts = CompareOpts {
+            len: None,
+            ..opts.clone()
+        };
+        verdict(compare_readers(
+            &mut open(path1)?,
+            &mut open(path2)?,
+            &opts,
+        )?)
+    }
+}
+
+/// Compares the SHA-256 hashes of the content of files, reading them one after the other rather
+/// than side by side.
+///
+/// Both files are read whole, even when they differ early, but only one at a time, which spares
+/// seeking back and forth between them on spinning disks.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Sha256;
+
+impl ContentComparer for Sha256 {
+    fn equal(&self, path1: &Path, path2: &Path, opts: &CompareOpts) -> io::Result<bool> {
+        Ok(sha256(path1, opts)? == sha256(path2, opts)?)
+    }
+}
+
+/// SHA-256 hash of the content of the file at `path`, with ASCII letters lowercased if
+/// `opts.ignore_case` is set.
+fn sha256(path: &Path, opts: &CompareOpts) -> io::Result<Hash> {
+    let mut file = opts.retry.retry(|| File::open(path))?;
+    let mut hasher = sha2::Sha256::new();
+    let mut chunk = Vec::new();
+    loop {
+        check_cancelled(opts)?;
+        chunk.clear();
+        if (&mut file).take(READ_CHUNK).read_to_end(&mut chunk)? == 0 {
+            break;
+        }
+        if opts.ignore_case {
+            chunk.make_ascii_lowercase();
+        }
+        hasher.update(&chunk);
+    }
+    Ok(hasher.finalize().into())
+}
+
+/// Compares files as sets of lines, whatever their order, like sorted allow-lists.
+///
+/// Both files are read whole and their lines sorted, which takes memory of the order of twice
+/// their size. Files larger than `max_size` are malformed for this strategy, so that they are
+/// compared byte by byte. The last line is the same with or without a trailing newline, while
+/// empty lines count.
+#[derive(Debug, Clone, Copy)]
+pub struct SortedLines {
+    /// Size of the largest files read, in bytes.
+    pub max_size: u64,
+}
+
+impl ContentComparer for SortedLines {
+    fn equal(&self, path1: &Path, path2: &Path, opts: &CompareOpts) -> io::Result<bool> {
+        let read = |path: &Path| -> io::Result<Vec<u8>> {
+            let mut content = read_whole(path, self.max_size, opts)?;
+            if opts.ignore_case {
+                content.make_ascii_lowercase();
+            }
+            Ok(content)
+        };
+        let (content1, content2) = (read(path1)?, read(path2)?);
+        Ok(sorted_lines(&content1) == sorted_lines(&content2))
+    }
+}
+
+/// Error of a [`ContentComparer`] stopped as the cancel flag of its options was set.
+#[derive(Debug)]
+pub struct Cancelled;
+
+impl fmt::Display for Cancelled {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "the comparison was cancelled")
+    }
+}
+
+impl std::error::Error for Cancelled {}
+
+/// Whether `e` is a [`Cancelled`] error.
+pub fn is_cancelled(e: &io::Error) -> bool {
+    e.get_ref().is_some_and(|inner| inner.is::<Cancelled>())
+}
+
+/// Fails with [`Cancelled`] if the cancel flag of `opts` is set.
+fn check_cancelled(opts: &CompareOpts) -> io::Result<()> {
+    match &opts.cancel {
+        Some(cancel) if cancel.load(Ordering::SeqCst) => Err(io::Error::other(Cancelled)),
+        _ => Ok(()),
+    }
+}
+
+/// Whether `result`, the outcome of a byte comparison, means the files are equal, failing with
+/// [`Cancelled`] if it was cancelled.
+fn verdict(result: FileCompare) -> io::Result<bool> {
+    match result {
+        FileCompare::Cancelled => Err(io::Error::other(Cancelled)),
+        result => Ok(result == FileCompare::Equal),
+    }
+}
+
+/// Compares UTF-8 files case-insensitively, for all of Unicode, like `Straße` and `STRASSE`.
+///
+/// Both files are read whole, which takes memory of the order of twice their size, as case
+/// folding can change the length of the text. Characters are folded to the lowercase of their
+/// uppercase, which matches the full case folding of Unicode but for a few characters, like
+/// the Turkish dotless `ı` folded to `i`. Files larger than `max_size`, or which are not valid
+/// UTF-8, are malformed for this strategy, so that they are compared byte by byte, with a
+/// warning for the latter, unless they are only counted.
+#[derive(Debug, Clone)]
+pub struct UnicodeCaseless {
+    /// Size of the largest files read, in bytes.
+    pub max_size: u64,
+    /// Number of the files which are not valid UTF-8, counted instead of warning about each.
+    pub not_utf8: Option<Arc<AtomicU64>>,
+}
+
+impl ContentComparer for UnicodeCaseless {
+    fn equal(&self, path1: &Path, path2: &Path, opts: &CompareOpts) -> io::Result<bool> {
+        let read = |path: &Path| -> io::Result<String> {
+            String::from_utf8(read_whole(path, self.max_size, opts)?).map_err(|_| {
+                match &self.not_utf8 {
+                    Some(count) => {
+                        count.fetch_add(1, Ordering::Relaxed);
+                    }
+                    None => eprintln!(
+                        "Warning: {} is not valid UTF-8, compared byte by byte",
+                        path.display()
+                    ),
+                }
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("{} is not valid UTF-8", path.display()),
+                )
+            })
+        };
+        let (content1, content2) = (read(path1)?, read(path2)?);
+        Ok(content1 == content2 || case_folded(&content1).eq(case_folded(&content2)))
+    }
+}
+
+/// Characters of `text` folded to the lowercase of their uppercase.
+fn case_folded(text: &str) -> impl Iterator<Item = char> + '_ {
+    text.chars()
+        .flat_map(char::to_uppercase)
+        .flat_map(char::to_lowercase)
+}
+
+/// Reads the file at `path` whole, failing as malformed if it is larger than `max_size`.
+fn read_whole(path: &Path, max_size: u64, opts: &CompareOpts) -> io::Result<Vec<u8>> {
+    let file = opts.retry.retry(|| File::open(path))?;
+    let mut content = Vec::new();
+    // One more byte, to tell files larger than the maximum.
+    let mut file = file.take(max_size + 1);
+    loop {
+        check_cancelled(opts)?;
+        if (&mut file).take(READ_CHUNK).read_to_end(&mut content)? == 0 {
+            break;
+        }
+    }
+    if content.len() as u64 > max_size {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("{} is larger than {} bytes", path.display(), max_size),
+        ));
+    }
+    Ok(content)
+}
+
+/// Lines of `content`, without their newline, sorted.
+fn sorted_lines(content: &[u8]) -> Vec<&[u8]> {
+    // An empty file has no lines, rather than an empty one.
+    if content.is_empty() {
+        return Vec::new();
+    }
+    let content = content.strip_suffix(b"\n").unwrap_or(content);
+    let mut lines: Vec<_> = content.split(|&b| b == b'\n').collect();
+    lines.sort_unstable();
+    lines
+}
+
+/// Strategies registered by extension.
+#[derive(Debug, Clone, Default)]
+pub struct Comparers {
+    /// Extensions are lowercase and without a leading dot.
+    by_extension: BTreeMap<String, Arc<dyn ContentComparer>>,
+    /// Strategy of the files whose extension has none registered.
+    fallback: Option<Arc<dyn ContentComparer>>,
+}
+
+impl Comparers {
+    /// Registers `comparer` for the files ending in `.extension`, whatever its case.
+    ///
+    /// A comparer already registered for the extension is replaced.
+    pub fn insert(&mut self, extension: &str, comparer: Arc<dyn ContentComparer>) {
+        let extension = extension.trim_start_matches('.').to_ascii_lowercase();
+        self.by_extension.insert(extension, comparer);
+    }
+
+    /// Registers `comparer` for the files whose extension has none registered, including the
+    /// files without an extension.
+    pub fn set_fallback(&mut self, comparer: Arc<dyn ContentComparer>) {
+        self.fallback = Some(comparer);
+    }
+
+    /// Comparer registered for the extension of `path`, or the fallback, if any.
+    pub fn get(&self, path: &Path) -> Option<&dyn ContentComparer> {
+        let extension = path
+            .extension()
+            .and_then(|extension| extension.to_str())
+            .map(str::to_ascii_lowercase);
+        extension
+            .and_then(|extension| self.by_extension.get(&extension))
+            .or(self.fallback.as_ref())
+            .map(|comparer| &**comparer)
+    }
+
+    /// Whether no comparer is registered.
+    pub fn is_empty(&self) -> bool {
+        self.by_extension.is_empty() && self.fallback.is_none()
+    }
+}
+
+/// Whether an error of a [`ContentComparer`] means that a file is malformed for it.
+pub fn is_malformed(e: &io::Error) -> bool {
+    matches!(
+        e.kind(),
+        io::ErrorKind::InvalidData | io::ErrorKind::InvalidInput | io::ErrorKind::UnexpectedEof
+    )
+}
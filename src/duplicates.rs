@@ -0,0 +1,197 @@
+//! Detection of the files present in one dir. only whose content is present in the other one
+//! under another path, for [`DiffOptions::find_duplicates`](crate::DiffOptions::find_duplicates).
+//!
+//! The candidates are the regular files reported as present in one dir. only, along with the
+//! files under the directories reported so. They are grouped by size, then by a hash of their
+//! content, and the files sharing a hash are compared byte by byte before being paired, so
+//! that hash collisions are never reported as duplicates.
+
+use crate::{files_equal, CompareOpts, Diff, FileCompare};
+use std::{
+    collections::{hash_map::DefaultHasher, BTreeMap},
+    fs::{self, File},
+    hash::Hasher,
+    io::{self, Read},
+    path::{Path, PathBuf},
+};
+
+/// Files of each side sharing a property, like their size.
+type Sides = (Vec<PathBuf>, Vec<PathBuf>);
+
+/// Finds the files under `only1` in `root1` with the same content as files under `only2` in
+/// `root2`, all paths being relative to the roots.
+///
+/// Each set of files with the same content is reported as pairs, in the order of their paths:
+/// each file of the first dir. with the first file of the second dir., then each other file of
+/// the second dir. with the first file of the first dir. Every file of the set is thus reported
+/// once, along with one of its duplicates. Empty files are left out, as they are all equal.
+pub fn find(
+    root1: &Path,
+    root2: &Path,
+    only1: Vec<PathBuf>,
+    only2: Vec<PathBuf>,
+    follow_symlink: bool,
+    opts: &CompareOpts,
+) -> io::Result<Vec<Diff>> {
+    let mut by_size: BTreeMap<u64, Sides> = BTreeMap::new();
+    for path in only1 {
+        walk(root1, path, follow_symlink, &mut |path, len| {
+            by_size.entry(len).or_default().0.push(path)
+        })?;
+    }
+    for path in only2 {
+        walk(root2, path, follow_symlink, &mut |path, len| {
+            by_size.entry(len).or_default().1.push(path)
+        })?;
+    }
+    let mut found = Vec::new();
+    for (len, (files1, files2)) in by_size {
+        if files1.is_empty() || files2.is_empty() {
+            continue;
+        }
+        let mut by_hash: BTreeMap<u64, Sides> = BTreeMap::new();
+        for path in files1 {
+            if let Some(hash) = hash(&root1.join(&path), opts)? {
+                by_hash.entry(hash).or_default().0.push(path);
+            }
+        }
+        for path in files2 {
+            if let Some(hash) = hash(&root2.join(&path), opts)? {
+                by_hash.entry(hash).or_default().1.push(path);
+            }
+        }
+        for (files1, files2) in by_hash.into_values() {
+            if files1.is_empty() || files2.is_empty() {
+                continue;
+            }
+            let opts = CompareOpts {
+                len: Some(len),
+                ..opts.clone()
+            };
+            for (mut files1, mut files2) in same_content(root1, root2, files1, files2, &opts)? {
+                files1.sort();
+                files2.sort();
+                for file1 in &files1 {
+                    found.push((file1.clone(), files2[0].clone()));
+                }
+                for file2 in &files2[1..] {
+                    found.push((files1[0].clone(), file2.clone()));
+                }
+            }
+        }
+    }
+    found.sort();
+    Ok(found
+        .into_iter()
+        .map(|(path1, path2)| {
+            let name = path1.file_name().unwrap_or_default().to_owned();
+            let dir = path1.parent().map(Path::to_owned).unwrap_or_default();
+            Diff::ContentMovedOrRenamed(dir, name, path2)
+        })
+        .collect())
+}
+
+/// Calls `found` with the path and length of each nonempty regular file at or under `path`.
+///
+/// Entries that vanished since they were listed are left out.
+fn walk(
+    root: &Path,
+    path: PathBuf,
+    follow_symlink: bool,
+    found: &mut dyn FnMut(PathBuf, u64),
+) -> io::Result<()> {
+    let full_path = root.join(&path);
+    let meta = if follow_symlink {
+        fs::metadata(&full_path)
+    } else {
+        fs::symlink_metadata(&full_path)
+    };
+    let meta = match meta {
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        meta => meta?,
+    };
+    if meta.is_file() && meta.len() > 0 {
+        found(path, meta.len());
+    } else if meta.is_dir() {
+        let entries = match fs::read_dir(&full_path) {
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+            entries => entries?,
+        };
+        for entry in entries {
+            walk(root, path.join(entry?.file_name()), follow_symlink, found)?;
+        }
+    }
+    Ok(())
+}
+
+/// Hash of the content of the file at `path`, or `None` if it vanished.
+fn hash(path: &Path, opts: &CompareOpts) -> io::Result<Option<u64>> {
+    let mut file = match opts.retry.retry(|| File::open(path)) {
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        file => file?,
+    };
+    let mut hasher = DefaultHasher::new();
+    let mut buf = vec![0; 64 * 1024];
+    loop {
+        let n = opts.retry.retry(|| file.read(&mut buf))?;
+        if n == 0 {
+            return Ok(Some(hasher.finish()));
+        }
+        hasher.write(&buf[..n]);
+    }
+}
+
+/// Splits files sharing a hash into the sets of files with the same content, keeping those
+/// with files on both sides.
+fn same_content(
+    root1: &Path,
+    root2: &Path,
+    files1: Vec<PathBuf>,
+    files2: Vec<PathBuf>,
+    opts: &CompareOpts,
+) -> io::Result<Vec<Sides>> {
+    // Full path of the first file of each set, to compare the others with, and its files.
+    let mut sets: Vec<(PathBuf, Sides)> = Vec::new();
+    let files = files1
+        .into_iter()
+        .map(|path| (true, path))
+        .chain(files2.into_iter().map(|path| (false, path)));
+    'files: for (first, path) in files {
+        let full_path = if first {
+            root1.join(&path)
+        } else {
+            root2.join(&path)
+        };
+        let mut set = None;
+        for (i, (other, _)) in sets.iter().enumerate() {
+            match files_equal(&full_path, other, opts) {
+                Ok(FileCompare::Equal) => {
+                    set = Some(i);
+                    break;
+                }
+                Ok(_) => (),
+                // Vanished since it was hashed.
+                Err(e) if e.kind() == io::ErrorKind::NotFound => continue 'files,
+                Err(e) => return Err(e),
+            }
+        }
+        let i = match set {
+            Some(i) => i,
+            None => {
+                sets.push((full_path, Sides::default()));
+                sets.len() - 1
+            }
+        };
+        let sides = &mut sets[i].1;
+        if first {
+            sides.0.push(path);
+        } else {
+            sides.1.push(path);
+        }
+    }
+    Ok(sets
+        .into_iter()
+        .map(|(_, sides)| sides)
+        .filter(|(files1, files2)| !files1.is_empty() && !files2.is_empty())
+        .collect())
+}
@@ -162,7 +162,22 @@ impl From<fs::FileType> for FileType {
             }
         };
 
-        #[cfg(not(unix))]
+        // Directory junctions are reported as directory symlinks, and are treated as such.
+        #[cfg(windows)]
+        let result = {
+            use std::os::windows::fs::FileTypeExt;
+            if ft.is_symlink_dir() || ft.is_symlink_file() {
+                FileType::Symlink
+            } else if ft.is_file() {
+                FileType::Regular
+            } else if ft.is_dir() {
+                FileType::Directory
+            } else {
+                unreachable!("file_type_enum: unexpected file type: {:?}.", ft)
+            }
+        };
+
+        #[cfg(not(any(unix, windows)))]
         let result = {
             if ft.is_file() {
                 FileType::Regular
@@ -0,0 +1,438 @@
+//! The filesystem the directories are read from, for [`diff_dirs_in`](crate::diff_dirs_in).
+//!
+//! [`RealFs`] reads them from the OS, as [`diff_dirs`](crate::diff_dirs) does. [`MemoryFs`]
+//! holds a tree in memory instead, where errors can be injected and entries changed while it
+//! is being compared, so that the behaviour of the workers in such cases can be tested
+//! deterministically.
+//!
+//! Only the listing of directories, the types, sizes and mtimes of entries, the content of
+//! regular files and the targets of symlinks are read through a [`FileSystem`]. The options
+//! reading more than that from the real filesystem are not supported by
+//! [`diff_dirs_in`](crate::diff_dirs_in).
+
+use crate::FileType;
+use std::{
+    collections::BTreeMap,
+    ffi::OsString,
+    fmt,
+    fs::{self, File, OpenOptions},
+    io::{self, Cursor, Read},
+    path::{Component, Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/// Operations the workers read the trees with.
+pub trait FileSystem: Send + Sync + 'static {
+    type File: FileReader;
+
+    /// Names and types of the entries of the directory at `dir`, in any order, without
+    /// following symlinks.
+    fn list_dir(&self, dir: &Path) -> io::Result<Vec<(OsString, FileType)>>;
+
+    /// Metadata of the entry at `path`, without following it if it is a symlink.
+    fn metadata(&self, path: &Path) -> io::Result<FileMetadata>;
+
+    /// Opens the regular file at `path` for reading, without following it if it is a symlink.
+    ///
+    /// Fails with an error wrapping [`TypeChanged`] if the entry is not a regular file anymore,
+    /// like when it was replaced since its directory was listed.
+    fn open(&self, path: &Path) -> io::Result<Self::File>;
+
+    /// Target of the symlink at `path`.
+    fn read_link(&self, path: &Path) -> io::Result<PathBuf>;
+}
+
+/// Error opening a regular file which is not one anymore, like when it was replaced by a
+/// symlink since its directory was listed.
+#[derive(Debug)]
+pub struct TypeChanged;
+
+impl fmt::Display for TypeChanged {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "The file was replaced by an entry of another type")
+    }
+}
+
+impl std::error::Error for TypeChanged {}
+
+/// Whether `e` comes from opening a regular file which is not one anymore.
+pub(crate) fn is_type_changed(e: &io::Error) -> bool {
+    e.get_ref().is_some_and(|inner| inner.is::<TypeChanged>())
+}
+
+/// Fails with [`TypeChanged`] if `opened`, a file opened without following symlinks, turns
+/// out not to be a regular file, or if opening it failed as it is a symlink.
+pub(crate) fn checked_regular(opened: io::Result<File>) -> io::Result<File> {
+    let changed = || io::Error::other(TypeChanged);
+    let file = match opened {
+        #[cfg(unix)]
+        Err(e) if e.raw_os_error() == Some(libc::ELOOP) => return Err(changed()),
+        opened => opened?,
+    };
+    if !file.metadata()?.is_file() {
+        return Err(changed());
+    }
+    Ok(file)
+}
+
+/// An open regular file, as read by [`open_files_equal`](crate::open_files_equal).
+pub trait FileReader: Read {
+    /// The file of the OS, if it is one, to tell the kernel how it is read under
+    /// [`CompareOpts::fadvise`](crate::CompareOpts::fadvise).
+    fn os_file(&self) -> Option<&File> {
+        None
+    }
+}
+
+impl FileReader for File {
+    fn os_file(&self) -> Option<&File> {
+        Some(self)
+    }
+}
+
+impl FileReader for Cursor<Arc<[u8]>> {}
+
+/// Metadata of an entry, as read by [`FileSystem::metadata`].
+#[derive(Debug, Clone)]
+pub struct FileMetadata(Metadata);
+
+#[derive(Debug, Clone)]
+enum Metadata {
+    /// Read from the OS, along with the permissions, owner, number of links, blocks and inode.
+    Real(fs::Metadata),
+    Other {
+        file_type: FileType,
+        len: u64,
+        modified: SystemTime,
+    },
+}
+
+impl FileMetadata {
+    /// Metadata of an entry which is not on the real filesystem, of size `len`, which is only
+    /// compared for regular files.
+    pub fn new(file_type: FileType, len: u64, modified: SystemTime) -> Self {
+        Self(Metadata::Other {
+            file_type,
+            len,
+            modified,
+        })
+    }
+
+    pub fn file_type(&self) -> FileType {
+        match &self.0 {
+            Metadata::Real(metadata) => metadata.file_type().into(),
+            Metadata::Other { file_type, .. } => *file_type,
+        }
+    }
+
+    pub fn is_file(&self) -> bool {
+        self.file_type() == FileType::Regular
+    }
+
+    pub fn len(&self) -> u64 {
+        match &self.0 {
+            Metadata::Real(metadata) => metadata.len(),
+            Metadata::Other { len, .. } => *len,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Fails where the mtime is not supported.
+    pub fn modified(&self) -> io::Result<SystemTime> {
+        match &self.0 {
+            Metadata::Real(metadata) => metadata.modified(),
+            Metadata::Other { modified, .. } => Ok(*modified),
+        }
+    }
+
+    /// Metadata read from the OS, only for entries of the real filesystem.
+    pub(crate) fn real(&self) -> Option<&fs::Metadata> {
+        match &self.0 {
+            Metadata::Real(metadata) => Some(metadata),
+            Metadata::Other { .. } => None,
+        }
+    }
+}
+
+impl From<fs::Metadata> for FileMetadata {
+    fn from(metadata: fs::Metadata) -> Self {
+        Self(Metadata::Real(metadata))
+    }
+}
+
+/// The filesystem of the OS.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct RealFs;
+
+impl FileSystem for RealFs {
+    type File = File;
+
+    fn list_dir(&self, dir: &Path) -> io::Result<Vec<(OsString, FileType)>> {
+        fs::read_dir(dir)?
+            .map(|r| r.and_then(|e| Ok((e.file_name(), e.file_type()?.into()))))
+            .collect()
+    }
+
+    fn metadata(&self, path: &Path) -> io::Result<FileMetadata> {
+        fs::symlink_metadata(path).map(FileMetadata::from)
+    }
+
+    fn open(&self, path: &Path) -> io::Result<File> {
+        let mut options = OpenOptions::new();
+        options.read(true);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::OpenOptionsExt;
+            options.custom_flags(libc::O_NOFOLLOW);
+        }
+        checked_regular(options.open(path))
+    }
+
+    fn read_link(&self, path: &Path) -> io::Result<PathBuf> {
+        fs::read_link(path)
+    }
+}
+
+/// A tree of entries held in memory.
+///
+/// Entries are added by their paths, which are all taken from the root of this tree, whether
+/// they are absolute or not, the missing directories leading to them being added along. Their
+/// mtime is the Unix epoch unless set otherwise. The tree can be changed at any time, including
+/// while it is compared, like from a [`DiffHandler`](crate::DiffHandler).
+#[derive(Debug)]
+pub struct MemoryFs {
+    root: Mutex<Node>,
+    /// Errors to fail the next operations on some paths with, along with how many times.
+    failures: Mutex<BTreeMap<PathBuf, (io::ErrorKind, usize)>>,
+}
+
+#[derive(Debug)]
+struct Node {
+    modified: SystemTime,
+    kind: NodeKind,
+}
+
+#[derive(Debug)]
+enum NodeKind {
+    Dir(BTreeMap<OsString, Node>),
+    File(Arc<[u8]>),
+    Symlink(PathBuf),
+}
+
+impl Node {
+    fn new(kind: NodeKind) -> Self {
+        Self {
+            modified: UNIX_EPOCH,
+            kind,
+        }
+    }
+
+    fn file_type(&self) -> FileType {
+        match self.kind {
+            NodeKind::Dir(_) => FileType::Directory,
+            NodeKind::File(_) => FileType::Regular,
+            NodeKind::Symlink(_) => FileType::Symlink,
+        }
+    }
+}
+
+/// Names of the components of `path`, from the root of a [`MemoryFs`].
+fn names(path: &Path) -> impl Iterator<Item = &std::ffi::OsStr> {
+    path.components().filter_map(|c| match c {
+        Component::Normal(name) => Some(name),
+        _ => None,
+    })
+}
+
+fn not_found(path: &Path) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::NotFound,
+        format!("{} does not exist", path.display()),
+    )
+}
+
+impl Default for MemoryFs {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MemoryFs {
+    /// A tree with only its root, an empty directory.
+    pub fn new() -> Self {
+        Self {
+            root: Mutex::new(Node::new(NodeKind::Dir(BTreeMap::new()))),
+            failures: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    /// Adds the directory at `path`, if it is not there already.
+    pub fn add_dir(&self, path: impl AsRef<Path>) {
+        self.with_dir(path.as_ref(), |_| ());
+    }
+
+    /// Adds the regular file at `path`, replacing any entry there.
+    pub fn add_file(&self, path: impl AsRef<Path>, content: impl AsRef<[u8]>) {
+        self.insert(path.as_ref(), NodeKind::File(content.as_ref().into()));
+    }
+
+    /// Adds a symlink to `target` at `path`, replacing any entry there.
+    pub fn add_symlink(&self, path: impl AsRef<Path>, target: impl Into<PathBuf>) {
+        self.insert(path.as_ref(), NodeKind::Symlink(target.into()));
+    }
+
+    /// Removes the entry at `path`, and all it contains, returning whether it was there.
+    pub fn remove(&self, path: impl AsRef<Path>) -> bool {
+        let path = path.as_ref();
+        let name = match path.file_name() {
+            Some(name) => name,
+            None => return false,
+        };
+        let mut root = self.root.lock().unwrap();
+        match Self::find(&mut root, path.parent().unwrap_or(Path::new(""))) {
+            Some(Node {
+                kind: NodeKind::Dir(entries),
+                ..
+            }) => entries.remove(name).is_some(),
+            _ => false,
+        }
+    }
+
+    /// Sets the mtime of the entry at `path`, returning whether it was there.
+    pub fn set_modified(&self, path: impl AsRef<Path>, modified: SystemTime) -> bool {
+        let mut root = self.root.lock().unwrap();
+        match Self::find(&mut root, path.as_ref()) {
+            Some(node) => {
+                node.modified = modified;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Fails the next `times` operations on the entry at `path` with an error of kind `kind`,
+    /// whether they list, read the metadata of, open, or read the target of the entry.
+    pub fn fail(&self, path: impl AsRef<Path>, kind: io::ErrorKind, times: usize) {
+        let path = names(path.as_ref()).collect();
+        self.failures.lock().unwrap().insert(path, (kind, times));
+    }
+
+    /// Fails with the error injected for `path`, if any is left.
+    fn injected(&self, path: &Path) -> io::Result<()> {
+        let path: PathBuf = names(path).collect();
+        let mut failures = self.failures.lock().unwrap();
+        match failures.get_mut(&path) {
+            Some((kind, times)) if *times > 0 => {
+                *times -= 1;
+                Err(io::Error::new(
+                    *kind,
+                    format!("Injected error on {}", path.display()),
+                ))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn find<'a>(root: &'a mut Node, path: &Path) -> Option<&'a mut Node> {
+        let mut node = root;
+        for name in names(path) {
+            node = match &mut node.kind {
+                NodeKind::Dir(entries) => entries.get_mut(name)?,
+                _ => return None,
+            };
+        }
+        Some(node)
+    }
+
+    /// Calls `f` with the entries of the directory at `path`, adding it and its parents if they
+    /// are missing, and replacing any entry of another type on the way.
+    fn with_dir<T>(&self, path: &Path, f: impl FnOnce(&mut BTreeMap<OsString, Node>) -> T) -> T {
+        let mut root = self.root.lock().unwrap();
+        let mut node = &mut *root;
+        for name in names(path) {
+            let entries = match &mut node.kind {
+                NodeKind::Dir(entries) => entries,
+                _ => unreachable!("only directories are descended into"),
+            };
+            node = entries
+                .entry(name.to_owned())
+                .or_insert_with(|| Node::new(NodeKind::Dir(BTreeMap::new())));
+            if !matches!(node.kind, NodeKind::Dir(_)) {
+                *node = Node::new(NodeKind::Dir(BTreeMap::new()));
+            }
+        }
+        match &mut node.kind {
+            NodeKind::Dir(entries) => f(entries),
+            _ => unreachable!("the node was made a directory"),
+        }
+    }
+
+    fn insert(&self, path: &Path, kind: NodeKind) {
+        let name = path
+            .file_name()
+            .expect("the root of a MemoryFs is always a directory");
+        let parent = path.parent().unwrap_or(Path::new(""));
+        self.with_dir(parent, |entries| {
+            entries.insert(name.to_owned(), Node::new(kind));
+        });
+    }
+
+    /// Calls `f` with the entry at `path`, failing if it is missing or if an error is injected.
+    fn with_node<T>(&self, path: &Path, f: impl FnOnce(&Node) -> io::Result<T>) -> io::Result<T> {
+        self.injected(path)?;
+        let mut root = self.root.lock().unwrap();
+        match Self::find(&mut root, path) {
+            Some(node) => f(node),
+            None => Err(not_found(path)),
+        }
+    }
+}
+
+impl FileSystem for MemoryFs {
+    type File = Cursor<Arc<[u8]>>;
+
+    fn list_dir(&self, dir: &Path) -> io::Result<Vec<(OsString, FileType)>> {
+        self.with_node(dir, |node| match &node.kind {
+            NodeKind::Dir(entries) => Ok(entries
+                .iter()
+                .map(|(name, node)| (name.clone(), node.file_type()))
+                .collect()),
+            _ => Err(io::Error::other(format!(
+                "{} is not a directory",
+                dir.display()
+            ))),
+        })
+    }
+
+    fn metadata(&self, path: &Path) -> io::Result<FileMetadata> {
+        self.with_node(path, |node| {
+            let len = match &node.kind {
+                NodeKind::File(content) => content.len() as u64,
+                NodeKind::Symlink(target) => target.as_os_str().len() as u64,
+                NodeKind::Dir(_) => 0,
+            };
+            Ok(FileMetadata::new(node.file_type(), len, node.modified))
+        })
+    }
+
+    fn open(&self, path: &Path) -> io::Result<Self::File> {
+        self.with_node(path, |node| match &node.kind {
+            NodeKind::File(content) => Ok(Cursor::new(content.clone())),
+            _ => Err(io::Error::other(TypeChanged)),
+        })
+    }
+
+    fn read_link(&self, path: &Path) -> io::Result<PathBuf> {
+        self.with_node(path, |node| match &node.kind {
+            NodeKind::Symlink(target) => Ok(target.clone()),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("{} is not a symlink", path.display()),
+            )),
+        })
+    }
+}
@@ -0,0 +1,69 @@
+//! Reading of inode flags, like immutable or append-only, for `--check-flags`.
+//!
+//! Flags are only read on Linux, through the `FS_IOC_GETFLAGS` ioctl. Elsewhere, and on
+//! filesystems without them, files are considered not to have any.
+
+use std::{io, path::Path};
+
+/// `_IOR('f', 1, long)`, missing from the `libc` crate.
+///
+/// Its encoding differs on a few architectures, like MIPS, PowerPC and SPARC.
+#[cfg(all(
+    target_os = "linux",
+    not(any(
+        target_arch = "mips",
+        target_arch = "mips64",
+        target_arch = "powerpc",
+        target_arch = "powerpc64",
+        target_arch = "sparc64"
+    ))
+))]
+const FS_IOC_GETFLAGS: u32 =
+    (2 << 30) | ((std::mem::size_of::<libc::c_long>() as u32) << 16) | ((b'f' as u32) << 8) | 1;
+
+#[cfg(all(
+    target_os = "linux",
+    any(
+        target_arch = "mips",
+        target_arch = "mips64",
+        target_arch = "powerpc",
+        target_arch = "powerpc64",
+        target_arch = "sparc64"
+    )
+))]
+const FS_IOC_GETFLAGS: u32 =
+    (2 << 29) | ((std::mem::size_of::<libc::c_long>() as u32) << 16) | ((b'f' as u32) << 8) | 1;
+
+/// Returns the flags of a file, without following symlinks.
+///
+/// Returns `None` if its filesystem does not support them.
+#[cfg(target_os = "linux")]
+pub fn get(path: &Path) -> io::Result<Option<u32>> {
+    use std::{
+        fs::OpenOptions,
+        os::unix::{fs::OpenOptionsExt, io::AsRawFd},
+    };
+
+    // Opened without blocking, in case it is a FIFO replacing the file since its listing.
+    let file = OpenOptions::new()
+        .read(true)
+        .custom_flags(libc::O_NOFOLLOW | libc::O_NONBLOCK)
+        .open(path)?;
+    // The kernel writes an `int`, whatever the size in the ioctl number.
+    let mut flags: libc::c_int = 0;
+    // SAFETY: the file descriptor is valid, and `flags` is valid for writes.
+    let result = unsafe { libc::ioctl(file.as_raw_fd(), FS_IOC_GETFLAGS as _, &mut flags) };
+    if result < 0 {
+        let e = io::Error::last_os_error();
+        return match e.raw_os_error() {
+            Some(libc::ENOTTY) | Some(libc::ENOTSUP) | Some(libc::EINVAL) => Ok(None),
+            _ => Err(e),
+        };
+    }
+    Ok(Some(flags as u32))
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn get(_path: &Path) -> io::Result<Option<u32>> {
+    Ok(None)
+}
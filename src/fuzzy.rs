@@ -0,0 +1,109 @@
+//! Pairing of the entries present in one dir. only whose names are close, for
+//! [`DiffOptions::fuzzy_names`](crate::DiffOptions::fuzzy_names).
+//!
+//! Names are compared by their Levenshtein distance, the number of characters to insert,
+//! delete or substitute to turn one into the other, like 1 from `report-final.pdf` to
+//! `report_final.pdf`. Names which are not valid Unicode are compared lossily.
+
+use std::ffi::OsStr;
+
+/// Pairs the names of `names1` with those of `names2` within `max_distance` of each other, as
+/// their indices and their distance, leaving out the pairs `compatible` rejects.
+///
+/// Each name is paired at most once. The closest pairs are taken first, and pairs at the same
+/// distance are taken in the order of the names of `names1`, then of those of `names2`, so
+/// that a name with several close matches is paired with the closest one, or with the first
+/// one in byte order among those at the same distance.
+pub fn closest_pairs(
+    names1: &[&OsStr],
+    names2: &[&OsStr],
+    max_distance: usize,
+    compatible: impl Fn(usize, usize) -> bool,
+) -> Vec<(usize, usize, usize)> {
+    let chars2: Vec<Vec<char>> = names2
+        .iter()
+        .map(|name| name.to_string_lossy().chars().collect())
+        .collect();
+    let mut candidates = Vec::new();
+    for (i, name1) in names1.iter().enumerate() {
+        let chars1: Vec<char> = name1.to_string_lossy().chars().collect();
+        for (j, chars2) in chars2.iter().enumerate() {
+            // The distance is at least the difference of the lengths.
+            if chars1.len().abs_diff(chars2.len()) > max_distance || !compatible(i, j) {
+                continue;
+            }
+            let distance = levenshtein(&chars1, chars2);
+            if distance <= max_distance {
+                candidates.push((distance, names1[i], names2[j], i, j));
+            }
+        }
+    }
+    candidates.sort();
+    let mut paired1 = vec![false; names1.len()];
+    let mut paired2 = vec![false; names2.len()];
+    let mut pairs = Vec::new();
+    for (distance, _, _, i, j) in candidates {
+        if !paired1[i] && !paired2[j] {
+            paired1[i] = true;
+            paired2[j] = true;
+            pairs.push((i, j, distance));
+        }
+    }
+    pairs
+}
+
+/// Levenshtein distance between two strings, as characters.
+fn levenshtein(a: &[char], b: &[char]) -> usize {
+    // Distances from the prefixes of `a` to the prefix of `b` being looked at.
+    let mut row: Vec<usize> = (0..=a.len()).collect();
+    for (j, cb) in b.iter().enumerate() {
+        let mut diagonal = row[0];
+        row[0] = j + 1;
+        for (i, ca) in a.iter().enumerate() {
+            let substitution = diagonal + usize::from(ca != cb);
+            diagonal = row[i + 1];
+            row[i + 1] = substitution.min(row[i] + 1).min(diagonal + 1);
+        }
+    }
+    row[a.len()]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chars(s: &str) -> Vec<char> {
+        s.chars().collect()
+    }
+
+    #[test]
+    fn distance() {
+        assert_eq!(levenshtein(&chars("kitten"), &chars("sitting")), 3);
+        assert_eq!(levenshtein(&chars(""), &chars("abc")), 3);
+        assert_eq!(
+            levenshtein(&chars("report-final.pdf"), &chars("report_final.pdf")),
+            1
+        );
+        assert_eq!(levenshtein(&chars("é"), &chars("e")), 1);
+    }
+
+    #[test]
+    fn ties() {
+        let names = |names: &[&'static str]| -> Vec<&OsStr> {
+            names.iter().map(|name| OsStr::new(*name)).collect()
+        };
+        // `ab` is 1 away from both, `abc` taking its closest match first.
+        let pairs = closest_pairs(
+            &names(&["ab", "abc"]),
+            &names(&["abd", "abc"]),
+            2,
+            |_, _| true,
+        );
+        assert_eq!(pairs, [(1, 1, 0), (0, 0, 1)]);
+        // At the same distance, the first name of the second side by byte order is taken.
+        let pairs = closest_pairs(&names(&["a"]), &names(&["c", "b"]), 1, |_, _| true);
+        assert_eq!(pairs, [(0, 1, 1)]);
+        let pairs = closest_pairs(&names(&["a"]), &names(&["b"]), 1, |_, _| false);
+        assert!(pairs.is_empty());
+    }
+}
@@ -0,0 +1,140 @@
+//! Itemized changes in the style of `rsync --itemize-changes`, for `--format itemize`.
+//!
+//! Each diff is described by the change rsync would make to turn the second directory into
+//! the first one, as an 11-character string `YXcstpoguax`:
+//!
+//! - `Y` is `>` for a regular file to transfer, `c` for another entry to create or change,
+//!   and `.` for an entry whose attributes only differ. Entries of the second dir. only are
+//!   written `*deleting` instead of the whole string.
+//! - `X` is the type of the entry in the first dir.: `f` for a regular file, `d` for a
+//!   directory, `L` for a symlink, `D` for a device and `S` for a FIFO or a socket. Entries
+//!   of archives are always `f`, as their types are not kept along with the diffs.
+//! - New entries, and those of another type in the second dir., are `+++++++++`.
+//! - Otherwise `c` is set when the content or the target of a symlink differs, `t` when the
+//!   mtime differs, along with `c` when it makes the files different, and with `>` or `c` for
+//!   the entries newer in the first dir., `p` when the permissions differ, both `o` and `g`
+//!   when the owner differs, as diffs do not tell the user from the group, `a` when the ACLs
+//!   differ, and `x` when the capabilities differ, as they are extended attributes.
+//!
+//! The `s` and `u` columns are never set: files of different sizes are only reported as
+//! differing, and the access time is not compared. The other kinds of diff, which rsync has no
+//! column for, are written as unchanged, like `.f.........` with `-ii`, and the records which
+//! are not differences are left out.
+
+use dirdiff::{Diff, FileType};
+use std::path::Path;
+
+/// Itemized change of `diff`, whose entries of the first dir. are under `root1`, or `None` for
+/// the records which are not differences, like those of `--show-resolved`.
+pub fn itemized(diff: &Diff, root1: &Path) -> Option<String> {
+    let path = diff.path();
+    let file_type = || {
+        root1
+            .join(&path)
+            .symlink_metadata()
+            .map_or(FileType::Regular, |m| m.file_type().into())
+    };
+    // Whether the entry would be created or have its content changed, besides the columns.
+    let (file_type, columns, changed) = match diff {
+        Diff::ResolvedLinks(..) | Diff::ContentMovedOrRenamed(..) | Diff::Hashes(..) => {
+            return None
+        }
+        Diff::InDir2Only(..) => return Some("*deleting  ".to_owned()),
+        Diff::InDir1Only(..) => (file_type(), "+++++++++", true),
+        Diff::TypeMismatch(_, _, type1, _) => (type1.file_type(), "+++++++++", true),
+        Diff::Different(..) | Diff::LinkTargetsDiffer(..) | Diff::ContentTypesDiffer(..) => {
+            (file_type(), "c........", true)
+        }
+        Diff::DifferentMTime(..) => (file_type(), "c.t......", true),
+        Diff::NewerInFirst(..) => (file_type(), "..t......", true),
+        Diff::SameButDifferentMTime(..) => (file_type(), "..t......", false),
+        Diff::PermsDiffer(..) => (file_type(), "...p.....", false),
+        Diff::OwnerDiffer(..) => (file_type(), "....og...", false),
+        Diff::AclDiffer(..) => (file_type(), ".......a.", false),
+        Diff::CapsDiffer(..) => (file_type(), "........x", false),
+        _ => (file_type(), ".........", false),
+    };
+    let update = match file_type {
+        _ if !changed => '.',
+        FileType::Regular => '>',
+        _ => 'c',
+    };
+    Some(format!("{}{}{}", update, type_char(file_type), columns))
+}
+
+fn type_char(file_type: FileType) -> char {
+    match file_type {
+        FileType::Regular => 'f',
+        FileType::Directory => 'd',
+        FileType::Symlink => 'L',
+        #[cfg(unix)]
+        FileType::BlockDevice | FileType::CharDevice => 'D',
+        #[cfg(unix)]
+        FileType::Fifo | FileType::Socket => 'S',
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{ffi::OsString, fs, path::PathBuf};
+
+    #[test]
+    fn diffs() {
+        let root = std::env::temp_dir().join(format!("dirdiff-itemize-{}", std::process::id()));
+        fs::create_dir_all(root.join("sub").join("d")).unwrap();
+        fs::write(root.join("sub").join("f"), "").unwrap();
+        let (sub, f, d) = (
+            PathBuf::from("sub"),
+            OsString::from("f"),
+            OsString::from("d"),
+        );
+        let itemized = |diff| itemized(&diff, &root);
+        let cases = [
+            (Diff::InDir1Only(sub.clone(), f.clone()), ">f+++++++++"),
+            (Diff::InDir1Only(sub.clone(), d.clone()), "cd+++++++++"),
+            (Diff::InDir2Only(sub.clone(), f.clone()), "*deleting  "),
+            (Diff::Different(sub.clone(), f.clone(), None), ">fc........"),
+            (Diff::DifferentMTime(sub.clone(), f.clone()), ">fc.t......"),
+            (
+                Diff::SameButDifferentMTime(sub.clone(), f.clone()),
+                ".f..t......",
+            ),
+            (Diff::NewerInFirst(sub.clone(), f.clone()), ">f..t......"),
+            (Diff::PermsDiffer(sub.clone(), d.clone()), ".d...p....."),
+            (
+                Diff::OwnerDiffer(sub.clone(), f.clone(), (0, 0), (1000, 1000)),
+                ".f....og...",
+            ),
+            (
+                Diff::CapsDiffer(sub.clone(), f.clone(), "none".into(), "cap_chown=ep".into()),
+                ".f........x",
+            ),
+            (
+                Diff::SparsenessDiffer(sub.clone(), f.clone()),
+                ".f.........",
+            ),
+            (
+                Diff::NlinkDiffer(sub.clone(), f.clone(), 1, 2),
+                ".f.........",
+            ),
+            // Not there anymore, as if it was in an archive.
+            (
+                Diff::Different(sub.clone(), "gone".into(), None),
+                ">fc........",
+            ),
+        ];
+        for (diff, expected) in cases {
+            assert_eq!(itemized(diff).as_deref(), Some(expected));
+        }
+        let not_diffs = [
+            Diff::ResolvedLinks(sub.clone(), f.clone(), None, None),
+            Diff::ContentMovedOrRenamed(sub.clone(), f.clone(), "g".into()),
+            Diff::Hashes(sub.clone(), f.clone(), [0; 32], [1; 32]),
+        ];
+        for diff in not_diffs {
+            assert_eq!(itemized(diff), None);
+        }
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
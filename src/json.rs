@@ -0,0 +1,248 @@
+//! Minimal helpers to write JSON output, and to read back its records.
+
+use std::{
+    collections::BTreeMap,
+    fmt::Write,
+    iter::Peekable,
+    str::Chars,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+/// Returns `s` as a quoted and escaped JSON string.
+pub fn string(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            // Writing to a `String` cannot fail.
+            c if c.is_control() => {
+                let _ = write!(out, "\\u{:04x}", c as u32);
+            }
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+/// Returns the fields of `record`, a JSON object, whose values are strings, or `None` if it is
+/// not a valid object. Fields of other types are left out.
+pub fn string_fields(record: &str) -> Option<BTreeMap<String, String>> {
+    let mut chars = record.trim().chars().peekable();
+    let mut fields = BTreeMap::new();
+    if chars.next() != Some('{') {
+        return None;
+    }
+    skip_whitespace(&mut chars);
+    if chars.peek() == Some(&'}') {
+        chars.next();
+    } else {
+        loop {
+            skip_whitespace(&mut chars);
+            let name = parse_string(&mut chars)?;
+            skip_whitespace(&mut chars);
+            if chars.next() != Some(':') {
+                return None;
+            }
+            skip_whitespace(&mut chars);
+            if chars.peek() == Some(&'"') {
+                fields.insert(name, parse_string(&mut chars)?);
+            } else {
+                skip_value(&mut chars)?;
+            }
+            skip_whitespace(&mut chars);
+            match chars.next()? {
+                ',' => continue,
+                '}' => break,
+                _ => return None,
+            }
+        }
+    }
+    chars.next().is_none().then_some(fields)
+}
+
+fn skip_whitespace(chars: &mut Peekable<Chars>) {
+    while chars.next_if(|c| c.is_ascii_whitespace()).is_some() {}
+}
+
+/// Parses a JSON string, quotes included.
+fn parse_string(chars: &mut Peekable<Chars>) -> Option<String> {
+    if chars.next() != Some('"') {
+        return None;
+    }
+    let mut s = String::new();
+    // UTF-16 units of consecutive `\u` escapes, decoded together so that surrogate pairs join.
+    let mut units = Vec::new();
+    let decoded = |units: &mut Vec<u16>| -> String {
+        char::decode_utf16(units.drain(..))
+            .map(|c| c.unwrap_or(char::REPLACEMENT_CHARACTER))
+            .collect()
+    };
+    loop {
+        let c = match chars.next()? {
+            '"' => break,
+            '\\' => match chars.next()? {
+                'u' => {
+                    let hex: String = (0..4).filter_map(|_| chars.next()).collect();
+                    units.push(u16::from_str_radix(&hex, 16).ok()?);
+                    continue;
+                }
+                c @ ('"' | '\\' | '/') => c,
+                'b' => '\u{8}',
+                'f' => '\u{c}',
+                'n' => '\n',
+                'r' => '\r',
+                't' => '\t',
+                _ => return None,
+            },
+            c => c,
+        };
+        s.push_str(&decoded(&mut units));
+        s.push(c);
+    }
+    s.push_str(&decoded(&mut units));
+    Some(s)
+}
+
+/// Skips a JSON value which is not a string, like a number or a nested object.
+fn skip_value(chars: &mut Peekable<Chars>) -> Option<()> {
+    let mut depth = 0;
+    while let Some(&c) = chars.peek() {
+        match c {
+            '"' => {
+                parse_string(chars)?;
+                continue;
+            }
+            '{' | '[' => depth += 1,
+            '}' | ']' if depth == 0 => return Some(()),
+            '}' | ']' => depth -= 1,
+            ',' if depth == 0 => return Some(()),
+            _ => (),
+        }
+        chars.next();
+    }
+    None
+}
+
+/// Returns `records`, flat JSON objects, as a single array, with a field per line.
+pub fn pretty_array(records: &[String]) -> String {
+    if records.is_empty() {
+        return "[]".to_owned();
+    }
+    let mut out = String::from("[\n");
+    for (i, record) in records.iter().enumerate() {
+        if i > 0 {
+            out.push_str(",\n");
+        }
+        out.push_str("  ");
+        let mut in_string = false;
+        let mut escaped = false;
+        for c in record.chars() {
+            match c {
+                _ if escaped => escaped = false,
+                '\\' if in_string => escaped = true,
+                '"' => in_string = !in_string,
+                _ if in_string => (),
+                '{' => {
+                    out.push_str("{\n    ");
+                    continue;
+                }
+                '}' => {
+                    out.push_str("\n  }");
+                    continue;
+                }
+                ',' => {
+                    out.push_str(",\n    ");
+                    continue;
+                }
+                ':' => {
+                    out.push_str(": ");
+                    continue;
+                }
+                _ => (),
+            }
+            out.push(c);
+        }
+    }
+    out.push_str("\n]");
+    out
+}
+
+/// A JSON array written record by record, as they come, which tells whether a comma is needed
+/// before the next record.
+#[derive(Debug, Default)]
+pub struct ArrayStream {
+    /// Whether a record was written already.
+    started: AtomicBool,
+}
+
+impl ArrayStream {
+    /// The opening of the array, written before any record.
+    pub const START: &'static str = "[\n";
+
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The separator to write before the next record: nothing before the first one, and a
+    /// comma before the others. Must be called while holding the lock of the output, so that
+    /// the first record written is the one without a comma, whatever the thread writing it.
+    pub fn separator(&self) -> &'static str {
+        match self.started.swap(true, Ordering::SeqCst) {
+            false => "",
+            true => ",\n",
+        }
+    }
+
+    /// The closing of the array, written after all the records.
+    pub fn end(&self) -> &'static str {
+        match self.started.load(Ordering::SeqCst) {
+            false => "]",
+            true => "\n]",
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn array_stream() {
+        let array = ArrayStream::new();
+        assert_eq!(array.end(), "]");
+        assert_eq!(array.separator(), "");
+        assert_eq!(array.separator(), ",\n");
+        assert_eq!(array.separator(), ",\n");
+        assert_eq!(array.end(), "\n]");
+    }
+
+    #[test]
+    fn pretty() {
+        assert_eq!(pretty_array(&[]), "[]");
+        let records = [
+            r#"{"kind":"only1","path":"a,b:{c}\"d"}"#.to_owned(),
+            r#"{"kind":"nlink","path":"\\","nlink1":1,"nlink2":2}"#.to_owned(),
+        ];
+        assert_eq!(
+            pretty_array(&records),
+            r#"[
+  {
+    "kind": "only1",
+    "path": "a,b:{c}\"d"
+  },
+  {
+    "kind": "nlink",
+    "path": "\\",
+    "nlink1": 1,
+    "nlink2": 2
+  }
+]"#
+        );
+    }
+}
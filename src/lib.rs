@@ -0,0 +1,2991 @@
+//! Efficient computation of the differences between two directories.
+//!
+//! This is the library behind the `dirdiff` binary. Directories are compared by [`diff_dirs`],
+//! which reports each [`Diff`] to a [`DiffHandler`], or by [`DiffStream`], which yields them
+//! as an iterator. [`ChannelHandler`] sends them to a channel instead. [`diff_dirs`] then returns
+//! the totals of the comparison as [`Stats`]. [`diff_dirs_in`] reads the directories through a
+//! [`FileSystem`], like the [`MemoryFs`] held in memory for tests.
+//!
+//! Trees read from tar or zip archives, or as the merged view of an overlay, are compared by
+//! [`diff_trees`], which supports fewer options.
+
+use anyhow::{bail, Context};
+use crossbeam_deque::{Steal, Stealer, Worker};
+use crossbeam_utils::Backoff;
+use std::fs::canonicalize;
+use std::sync::atomic::AtomicBool;
+use std::{
+    borrow::Cow,
+    cmp::Reverse,
+    collections::{BTreeSet, BinaryHeap},
+    ffi::OsString,
+    fmt, io,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        mpsc::{channel, sync_channel, RecvTimeoutError, SyncSender},
+        Arc, Mutex,
+    },
+    thread,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+mod archive;
+pub use archive::{diff_trees, Tree};
+mod channel;
+pub use channel::ChannelHandler;
+mod checkpoint;
+pub use checkpoint::Checkpoint;
+mod collation;
+pub use collation::{set_locale_from_env, Collation};
+mod command;
+pub use command::CompareCommand;
+mod acl;
+mod caps;
+mod comparer;
+pub use comparer::{
+    Cancelled, Comparers, ContentComparer, Gzip, RawBytes, Sha256, SortedLines, UnicodeCaseless,
+};
+mod compare;
+pub use compare::{files_equal, open_files_equal, CompareOpts, FileCompare};
+mod duplicates;
+mod file_type_enum;
+mod filesystem;
+use filesystem::{checked_regular, is_type_changed};
+pub use filesystem::{FileMetadata, FileReader, FileSystem, MemoryFs, RealFs, TypeChanged};
+mod flags;
+pub use file_type_enum::FileType;
+mod fuzzy;
+pub mod manifest;
+mod rename;
+pub use rename::RenameMap;
+mod retry;
+pub use retry::RetryPolicy;
+mod metadata;
+mod open_files;
+pub use open_files::OpenFiles;
+mod progress;
+pub use progress::{Progress, ProgressSnapshot};
+mod secure;
+use secure::DirFd;
+mod selection;
+pub use selection::PathSelection;
+mod reflink;
+mod sniff;
+pub use sniff::ContentType;
+mod sparse;
+mod stream;
+pub use stream::DiffStream;
+mod tee;
+pub use tee::TeeHandler;
+mod xattr;
+
+struct StackUnit {
+    dir: PathBuf,
+    /// Open parent directories of `dir` in both trees, only under `--secure`.
+    parent_fds: Option<Arc<(DirFd, DirFd)>>,
+}
+
+/// Extraction of useful metadata for iterated files.
+#[derive(Debug)]
+struct FileT {
+    name: OsString,
+    file_type: FileType,
+    path: PathBuf,
+    /// Whether the entry is a symlink that was followed, to `path`.
+    followed: bool,
+}
+
+impl FileT {
+    /// Create new extraction from the entry `name` of `dir`, of type `file_type`.
+    ///
+    /// If [follow_link] flag is set and the entry is a symbolic link, then path and type of
+    /// target file are cached. With `within`, symlinks resolving outside of it are left as
+    /// symlinks. Links are only followed on the real filesystem.
+    fn new<F: FileSystem>(
+        fs: &F,
+        dir: &Path,
+        name: OsString,
+        file_type: FileType,
+        follow_link: bool,
+        within: Option<&Path>,
+    ) -> io::Result<Self> {
+        let path = dir.join(&name);
+        if follow_link && file_type == FileType::Symlink {
+            let target = resolve_symlinks(&path).map_err(|e| {
+                io::Error::new(
+                    e.kind(),
+                    format!("Error while following link {}: {}", path.display(), e),
+                )
+            })?;
+            if matches!(within, Some(root) if !target.starts_with(root)) {
+                return Ok(FileT {
+                    name,
+                    file_type,
+                    path,
+                    followed: false,
+                });
+            }
+            Ok(FileT {
+                name,
+                file_type: fs.metadata(&target)?.file_type(),
+                path: target,
+                followed: true,
+            })
+        } else {
+            Ok(FileT {
+                name,
+                file_type,
+                path,
+                followed: false,
+            })
+        }
+    }
+
+    /// Returns file name. If structure contains symlink, then its name will be returned instead of its target name.
+    fn filename(&self) -> OsString {
+        self.name.clone()
+    }
+
+    /// Path to file. If structure contains symlink, then path to its target is returned.
+    fn path(&self) -> &PathBuf {
+        &self.path
+    }
+
+    fn file_type(&self) -> FileType {
+        self.file_type
+    }
+
+    /// Path the entry resolved to, if it is a symlink that was followed.
+    fn resolved(&self) -> Option<PathBuf> {
+        self.followed.then(|| self.path.clone())
+    }
+}
+
+/// Whether `e` was caused by a path longer than the OS accepts, like over `PATH_MAX`.
+#[cfg(unix)]
+fn is_name_too_long(e: &io::Error) -> bool {
+    e.raw_os_error() == Some(libc::ENAMETOOLONG)
+}
+
+#[cfg(windows)]
+fn is_name_too_long(e: &io::Error) -> bool {
+    const ERROR_FILENAME_EXCED_RANGE: i32 = 206;
+    e.raw_os_error() == Some(ERROR_FILENAME_EXCED_RANGE)
+}
+
+#[cfg(not(any(unix, windows)))]
+fn is_name_too_long(_e: &io::Error) -> bool {
+    false
+}
+
+/// Whether two files are the same one, reached through two paths, like hard links.
+fn same_inode(m1: &FileMetadata, m2: &FileMetadata) -> bool {
+    matches!((metadata::inode(m1), metadata::inode(m2)), (Some(i1), Some(i2)) if i1 == i2)
+}
+
+/// Whether any of the causes of `e` is a path longer than the OS accepts.
+fn caused_by_name_too_long(e: &anyhow::Error) -> bool {
+    e.chain()
+        .any(|cause| cause.downcast_ref().is_some_and(is_name_too_long))
+}
+
+/// Resolves all symbolic links in `path`, however many levels deep.
+///
+/// This is used both for the arguments under `-H` and for directories' content under `-L`.
+pub fn resolve_symlinks(path: &Path) -> std::io::Result<PathBuf> {
+    canonicalize(path).map(strip_verbatim)
+}
+
+/// Removes the `\\?\` prefix that [`canonicalize`] adds on Windows, when the path is valid without it.
+#[cfg(windows)]
+fn strip_verbatim(path: PathBuf) -> PathBuf {
+    // Without the prefix, paths are limited to MAX_PATH characters.
+    const MAX_PATH: usize = 260;
+    let stripped = match path.to_str() {
+        Some(s) if s.starts_with(r"\\?\UNC\") => format!(r"\\{}", &s[8..]),
+        Some(s) if s.starts_with(r"\\?\") && s[4..].chars().nth(1) == Some(':') => {
+            s[4..].to_owned()
+        }
+        _ => return path,
+    };
+    if stripped.len() < MAX_PATH {
+        stripped.into()
+    } else {
+        path
+    }
+}
+
+#[cfg(not(windows))]
+fn strip_verbatim(path: PathBuf) -> PathBuf {
+    path
+}
+
+struct StackHandle {
+    own: Worker<StackUnit>,
+    stealers: Vec<Stealer<StackUnit>>,
+    /// Number of units pushed to any of the stacks whose processing is not over.
+    ///
+    /// A unit is counted from before it is pushed until after the units it pushes are counted,
+    /// so this only drops to 0 once all the work is done, wherever the units are.
+    pending: Arc<AtomicUsize>,
+    abort: Arc<AtomicBool>,
+}
+
+impl StackHandle {
+    /// Creates the handles of `n_threads` workers, which stop as soon as `abort` is set.
+    fn new(n_threads: u16, traversal: Traversal, abort: Arc<AtomicBool>) -> Vec<Self> {
+        let mut workers = Vec::new();
+        let mut stealers = vec![Vec::new(); n_threads as usize];
+        for i in 0..n_threads {
+            let w = match traversal {
+                Traversal::DepthFirst => Worker::new_lifo(),
+                Traversal::BreadthFirst => Worker::new_fifo(),
+            };
+            for (j, stlrs) in stealers.iter_mut().enumerate() {
+                if (i as usize) != j {
+                    stlrs.push(w.stealer());
+                }
+            }
+            workers.push(w);
+        }
+        let pending = Arc::new(AtomicUsize::new(0));
+        let mut res = Vec::new();
+        for (w, stealers) in workers.into_iter().zip(stealers) {
+            res.push(Self {
+                own: w,
+                stealers,
+                pending: Arc::clone(&pending),
+                abort: Arc::clone(&abort),
+            })
+        }
+        res
+    }
+}
+
+/// A difference between the two directories.
+///
+/// Each diff holds the directory of the entry, relative to the roots, and the entry's name.
+#[derive(Debug, Clone)]
+// TODO check rewrite using reference to pathbuf
+pub enum Diff {
+    InDir1Only(PathBuf, OsString),
+    InDir2Only(PathBuf, OsString),
+    /// Entries whose content differs, along with the number of differing bytes of regular
+    /// files when counted under `count_diff_bytes`.
+    Different(PathBuf, OsString, Option<u64>),
+    /// Regular files whose mtime differs, whatever their content, reported as different under
+    /// `mtime_is_diff`, with the kind of [`Diff::Different`].
+    DifferentMTime(PathBuf, OsString),
+    SameButDifferentMTime(PathBuf, OsString),
+    SparsenessDiffer(PathBuf, OsString),
+    ChangedDuringScan(PathBuf, OsString),
+    PermsDiffer(PathBuf, OsString),
+    /// Entries with different owners, along with the user and group ids of each.
+    OwnerDiffer(PathBuf, OsString, (u32, u32), (u32, u32)),
+    /// Entries with the same name but different types, like a directory and a symlink. The
+    /// type of a symlink followed by `follow_symlink` is the one of its target.
+    TypeMismatch(PathBuf, OsString, EntryType, EntryType),
+    /// Regular files with the same content but a different number of hard links.
+    NlinkDiffer(PathBuf, OsString, u64, u64),
+    /// Two entries of a directory, in either tree, whose names only differ by case.
+    CaseCollision(PathBuf, OsString, OsString),
+    /// Directories whose entries differ by name or type, along with their number of entries.
+    EntriesDiffer(PathBuf, OsString, u64, u64),
+    /// Regular files with the same content but different inode flags, like immutable.
+    FlagsDiffer(PathBuf, OsString, u32, u32),
+    /// A directory removed from one tree after its parent was listed, so not compared.
+    Vanished(PathBuf, OsString, Side),
+    /// Regular files with the same content but a different number of allocated blocks.
+    BlocksDiffer(PathBuf, OsString, u64, u64),
+    /// Paths the symlinks followed on either side resolved to, for an entry reported by other
+    /// diffs. Only found with `show_resolved`, and always after the entry's other diffs.
+    ResolvedLinks(PathBuf, OsString, Option<PathBuf>, Option<PathBuf>),
+    /// An entry whose path is too long for the OS to open it, so not compared.
+    PathTooLong(PathBuf, OsString),
+    /// A regular file present in the first dir. only, whose content is the same as the one of
+    /// a file present in the second dir. only, at the given path relative to the root. Only
+    /// found with `find_duplicates`, after all the other diffs.
+    ContentMovedOrRenamed(PathBuf, OsString, PathBuf),
+    /// Regular files whose Linux capabilities differ, described in the first and second
+    /// directory like `cap_net_raw=ep`, or as `none`.
+    CapsDiffer(PathBuf, OsString, String, String),
+    /// SHA-256 hashes of the content of regular files in the first and second directory,
+    /// whether they are equal or not. Only found with `print_hashes`, before the other diffs
+    /// of the files.
+    Hashes(PathBuf, OsString, manifest::Hash, manifest::Hash),
+    /// Regular files left uncompared, or compared partway, once the byte budget ran out, or
+    /// as the comparison stopped, like when `timeout` ran out.
+    NotCompared(PathBuf, OsString),
+    /// Regular files whose comparison was stopped as it took longer than `per_file_timeout`.
+    CompareTimedOut(PathBuf, OsString),
+    /// A directory with at least one differing entry, reported instead of the diffs of its
+    /// entries. Only found with `dir_changed_only`.
+    DirChanged(PathBuf, OsString),
+    /// An entry whose mtime is newer in the first dir. than in the second one, whatever their
+    /// content. Only found with `updates_to_second`.
+    NewerInFirst(PathBuf, OsString),
+    /// Symlinks pointing to different targets, as read in the first and second directory.
+    LinkTargetsDiffer(PathBuf, OsString, PathBuf, PathBuf),
+    /// Regular files or directories whose POSIX ACLs differ, described in the first and second
+    /// directory like `user::rw-,user:1000:r--,group::r--,mask::r--,other::r--`, or as `none`.
+    AclDiffer(PathBuf, OsString, String, String),
+    /// A regular file replaced by an entry of another type, like a symlink, on either side
+    /// between the listing of its directory and the comparison of its content, so not compared.
+    RacedTypeChange(PathBuf, OsString),
+    /// Entries of a directory present in one dir. only each, of the same type, whose names are
+    /// within `fuzzy_names` edits of each other: the name in the first dir., the one in the
+    /// second dir. and their Levenshtein distance. Reported instead of [`Diff::InDir1Only`] and
+    /// [`Diff::InDir2Only`], only with `fuzzy_names`. An entry with several close matches is
+    /// paired with the closest one, the first one by name among those at the same distance.
+    PossibleRename(PathBuf, OsString, OsString, usize),
+    /// Regular files whose content types differ, sniffed from their first bytes, as the MIME
+    /// types in the first and second directory, or `unknown`. Only found with `content_type`,
+    /// when one of them has the type looked for.
+    ContentTypesDiffer(PathBuf, OsString, String, String),
+    /// Regular files with the same content, only one of which shares physical extents with
+    /// other files, like a reflink clone against a full copy, as whether each one does. Only
+    /// found with `check_reflinks`.
+    ExtentSharingDiffer(PathBuf, OsString, bool, bool),
+    /// Regular files whose content differs although they have the same size and mtime, as
+    /// when a tool preserving mtimes wrote different data, or after corruption or tampering.
+    /// Reported after their [`Diff::Different`], only with `flag_mtime_same_content_differs`.
+    SuspiciousMtimeMatch(PathBuf, OsString),
+}
+
+/// One of the two directories being compared.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Side {
+    First,
+    Second,
+}
+
+/// Order in which directories are traversed, see [`DiffOptions::traversal`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Traversal {
+    /// Each worker goes on with the last subdirectory it found.
+    #[default]
+    DepthFirst,
+    /// Each worker goes on with the first subdirectory it found that is still waiting.
+    BreadthFirst,
+}
+
+/// Resolution mtimes are compared at, see [`DiffOptions::mtime_granularity`].
+///
+/// Granularities are ordered from the finest to the coarsest.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default)]
+pub enum MtimeGranularity {
+    /// Whatever the filesystems provide, up to nanoseconds.
+    #[default]
+    Nanoseconds,
+    Microseconds,
+    Milliseconds,
+    Seconds,
+}
+
+impl MtimeGranularity {
+    /// Granularity of the mtimes of the filesystem `path` is on, the finest one at least as
+    /// coarse as their resolution, or `None` if it cannot be told.
+    ///
+    /// Filesystems are told apart by the type `statfs` gives, so that this is only supported on
+    /// Linux, and only for filesystems whose resolution does not depend on how they are mounted
+    /// or on a server: NFS, SMB and FUSE filesystems are not known, for instance. FAT, whose
+    /// resolution is 2 seconds, gets [`MtimeGranularity::Seconds`].
+    pub fn detect(path: &Path) -> Option<Self> {
+        metadata::mtime_granularity(path)
+    }
+
+    /// Rounds `time` down to a multiple of the granularity since the epoch.
+    pub fn truncate(self, time: SystemTime) -> SystemTime {
+        let step = match self {
+            MtimeGranularity::Nanoseconds => return time,
+            MtimeGranularity::Microseconds => 1_000,
+            MtimeGranularity::Milliseconds => 1_000_000,
+            MtimeGranularity::Seconds => 1_000_000_000,
+        };
+        match time.duration_since(UNIX_EPOCH) {
+            Ok(since) => {
+                let nanos = since.subsec_nanos();
+                UNIX_EPOCH + Duration::new(since.as_secs(), nanos - nanos % step)
+            }
+            // Rounding down before the epoch takes the time further from it.
+            Err(e) => {
+                let before = e.duration();
+                let nanos = before.subsec_nanos();
+                let rounded = nanos + (step - nanos % step) % step;
+                UNIX_EPOCH - Duration::new(before.as_secs(), rounded)
+            }
+        }
+    }
+}
+
+/// Short names of all the kinds of diff, as returned by [`Diff::kind`].
+pub const DIFF_KINDS: &[&str] = &[
+    "different",
+    "only1",
+    "only2",
+    "mtime",
+    "sparseness",
+    "changed",
+    "perms",
+    "owner",
+    "type",
+    "nlink",
+    "case_collision",
+    "entries",
+    "flags",
+    "vanished",
+    "blocks",
+    "not_compared",
+    "resolved",
+    "path_too_long",
+    "moved",
+    "caps",
+    "hashes",
+    "dir_changed",
+    "newer",
+    "link_target",
+    "acl",
+    "raced_type",
+    "possible_rename",
+    "content_type",
+    "extent_sharing",
+    "compare_timed_out",
+    "suspicious_mtime",
+];
+
+impl Diff {
+    /// Short name of the kind of diff, used by machine-readable outputs.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Diff::InDir1Only(..) => "only1",
+            Diff::InDir2Only(..) => "only2",
+            Diff::Different(..) | Diff::DifferentMTime(..) => "different",
+            Diff::SameButDifferentMTime(..) => "mtime",
+            Diff::SparsenessDiffer(..) => "sparseness",
+            Diff::ChangedDuringScan(..) => "changed",
+            Diff::PermsDiffer(..) => "perms",
+            Diff::OwnerDiffer(..) => "owner",
+            Diff::TypeMismatch(..) => "type",
+            Diff::NlinkDiffer(..) => "nlink",
+            Diff::CaseCollision(..) => "case_collision",
+            Diff::EntriesDiffer(..) => "entries",
+            Diff::FlagsDiffer(..) => "flags",
+            Diff::Vanished(..) => "vanished",
+            Diff::BlocksDiffer(..) => "blocks",
+            Diff::NotCompared(..) => "not_compared",
+            Diff::CompareTimedOut(..) => "compare_timed_out",
+            Diff::SuspiciousMtimeMatch(..) => "suspicious_mtime",
+            Diff::ResolvedLinks(..) => "resolved",
+            Diff::PathTooLong(..) => "path_too_long",
+            Diff::ContentMovedOrRenamed(..) => "moved",
+            Diff::CapsDiffer(..) => "caps",
+            Diff::Hashes(..) => "hashes",
+            Diff::DirChanged(..) => "dir_changed",
+            Diff::NewerInFirst(..) => "newer",
+            Diff::LinkTargetsDiffer(..) => "link_target",
+            Diff::AclDiffer(..) => "acl",
+            Diff::RacedTypeChange(..) => "raced_type",
+            Diff::PossibleRename(..) => "possible_rename",
+            Diff::ContentTypesDiffer(..) => "content_type",
+            Diff::ExtentSharingDiffer(..) => "extent_sharing",
+        }
+    }
+
+    /// Path of the entry, relative to the roots.
+    pub fn path(&self) -> PathBuf {
+        let (dir, file) = match self {
+            Diff::InDir1Only(dir, file)
+            | Diff::InDir2Only(dir, file)
+            | Diff::Different(dir, file, _)
+            | Diff::DifferentMTime(dir, file)
+            | Diff::SameButDifferentMTime(dir, file)
+            | Diff::SparsenessDiffer(dir, file)
+            | Diff::ChangedDuringScan(dir, file)
+            | Diff::PermsDiffer(dir, file)
+            | Diff::OwnerDiffer(dir, file, _, _)
+            | Diff::TypeMismatch(dir, file, _, _)
+            | Diff::NlinkDiffer(dir, file, _, _)
+            | Diff::CaseCollision(dir, file, _)
+            | Diff::EntriesDiffer(dir, file, _, _)
+            | Diff::FlagsDiffer(dir, file, _, _)
+            | Diff::Vanished(dir, file, _)
+            | Diff::BlocksDiffer(dir, file, _, _)
+            | Diff::ResolvedLinks(dir, file, _, _)
+            | Diff::NotCompared(dir, file)
+            | Diff::CompareTimedOut(dir, file)
+            | Diff::SuspiciousMtimeMatch(dir, file)
+            | Diff::PathTooLong(dir, file)
+            | Diff::DirChanged(dir, file)
+            | Diff::NewerInFirst(dir, file)
+            | Diff::RacedTypeChange(dir, file)
+            | Diff::LinkTargetsDiffer(dir, file, _, _)
+            | Diff::AclDiffer(dir, file, _, _)
+            | Diff::ContentTypesDiffer(dir, file, _, _)
+            | Diff::ExtentSharingDiffer(dir, file, _, _)
+            | Diff::ContentMovedOrRenamed(dir, file, _)
+            | Diff::CapsDiffer(dir, file, _, _)
+            | Diff::Hashes(dir, file, _, _)
+            | Diff::PossibleRename(dir, file, _, _) => (dir, file),
+        };
+        dir.join(file)
+    }
+}
+
+/// Type of an entry, as reported by [`Diff::TypeMismatch`].
+#[derive(Debug, Clone)]
+pub struct EntryType {
+    file_type: FileType,
+    /// Size of the entry, only set for regular files.
+    size: u64,
+    /// Path the entry resolved to, if it is a symlink followed by `follow_symlink`, whose type
+    /// is the one of its target.
+    resolved: Option<PathBuf>,
+}
+
+impl EntryType {
+    /// Type of the entry, or of its target if it is a followed symlink.
+    pub fn file_type(&self) -> FileType {
+        self.file_type
+    }
+
+    /// Path the entry resolved to, if it is a followed symlink.
+    pub fn resolved(&self) -> Option<&Path> {
+        self.resolved.as_deref()
+    }
+}
+
+impl fmt::Display for EntryType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        // Same phrasing as GNU diff, which tells empty files apart.
+        match self.file_type {
+            FileType::Regular if self.size == 0 => write!(f, "regular empty file"),
+            file_type => write!(f, "{}", file_type),
+        }
+    }
+}
+
+/// Outcome of the comparison of two files' content.
+enum Content {
+    Same,
+    Different,
+    /// A file did not have the size its metadata announced, it was modified while being read.
+    Changed,
+    /// The byte budget ran out, or the comparison was cancelled, before the files were
+    /// compared whole.
+    NotCompared,
+    /// Neither file has the content type looked for.
+    OtherType,
+    /// The comparison took longer than `per_file_timeout`.
+    TimedOut,
+}
+
+/// Rule deciding the [`Content`] of two regular files, printed by `explain`.
+enum Rule {
+    Command,
+    Comparer,
+    /// Sizes in the first and second directory.
+    SizesDiffer(u64, u64),
+    Empty,
+    SameInode,
+    TrustedXattr,
+    Hashes,
+    Bytes(FileCompare),
+}
+
+impl fmt::Display for Rule {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Rule::Command => write!(f, "by comparison command"),
+            Rule::Comparer => write!(f, "by comparer of the extension"),
+            Rule::SizesDiffer(size1, size2) => {
+                write!(f, "by size, {} bytes against {}", size1, size2)
+            }
+            Rule::Empty => write!(f, "by size, both empty"),
+            Rule::SameInode => write!(f, "by inode, the same file on both sides"),
+            Rule::TrustedXattr => write!(f, "by trusted extended attribute"),
+            Rule::Hashes => write!(f, "by SHA-256 hash"),
+            Rule::Bytes(FileCompare::Equal) => write!(f, "by full byte comparison"),
+            Rule::Bytes(FileCompare::Differ {
+                first_offset,
+                differing,
+            }) => {
+                write!(f, "by byte comparison, first at offset {}", first_offset)?;
+                match differing {
+                    Some(differing) => write!(f, ", {} bytes differing", differing),
+                    None => Ok(()),
+                }
+            }
+            Rule::Bytes(FileCompare::LenDiffer) => {
+                write!(
+                    f,
+                    "by byte comparison, a file did not have its expected size"
+                )
+            }
+            Rule::Bytes(FileCompare::OverBudget) => {
+                write!(f, "by byte comparison, cut short by the byte budget")
+            }
+            Rule::Bytes(FileCompare::Cancelled) => {
+                write!(f, "by byte comparison, cancelled as the comparison stopped")
+            }
+            Rule::Bytes(FileCompare::TimedOut) => {
+                write!(f, "by byte comparison, stopped as it timed out")
+            }
+            Rule::Bytes(FileCompare::OtherContentType) => {
+                write!(f, "by content type, neither being the one looked for")
+            }
+            Rule::Bytes(FileCompare::ContentTypesDiffer { type1, type2 }) => write!(
+                f,
+                "by content type, {} against {}",
+                type1.unwrap_or("unknown"),
+                type2.unwrap_or("unknown")
+            ),
+        }
+    }
+}
+
+/// Receives the diffs found by the workers, from several threads at once.
+pub trait DiffHandler {
+    fn process(&self, root1: &Path, root2: &Path, diff: Diff);
+
+    /// Called under `two_pass`, once all the structural diffs were processed and before any diff
+    /// of the content pass is.
+    fn structure_done(&self) {}
+
+    /// Called once the comparison is over, to write out any buffered output.
+    fn finish(&self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Tallies of the work a comparison would do, for `--count-only`.
+#[derive(Debug, Default)]
+pub struct Tally {
+    /// Pairs of regular files whose content would be compared.
+    file_pairs: AtomicU64,
+    /// Entries present in only one of the directories.
+    single_side: AtomicU64,
+    /// Bytes that would be read, at most, to compare the pairs of files.
+    ///
+    /// Files of different sizes are known to differ without being read, so they are not counted.
+    bytes: AtomicU64,
+}
+
+impl fmt::Display for Tally {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(
+            f,
+            "Pairs of files to compare:\t{}",
+            self.file_pairs.load(Ordering::SeqCst)
+        )?;
+        writeln!(
+            f,
+            "Present in one dir. only:\t{}",
+            self.single_side.load(Ordering::SeqCst)
+        )?;
+        write!(f, "Bytes to read:\t{}", self.bytes.load(Ordering::SeqCst))
+    }
+}
+
+/// Slowest directories of a comparison, for `--per-dir-timing`.
+///
+/// Only the `top` slowest ones are kept, so that memory does not grow with the number of
+/// directories. The time of a directory is the time spent listing it and comparing its
+/// entries, but not the content of its subdirectories.
+#[derive(Debug)]
+pub struct DirTimings {
+    top: usize,
+    /// Time spent, path and number of entries, the fastest first.
+    slowest: Mutex<BinaryHeap<Reverse<(Duration, PathBuf, u64)>>>,
+}
+
+impl DirTimings {
+    /// Keeps the `top` slowest directories.
+    pub fn new(top: usize) -> Self {
+        Self {
+            top,
+            slowest: Mutex::new(BinaryHeap::with_capacity(top + 1)),
+        }
+    }
+
+    fn record(&self, dir: PathBuf, entries: u64, time: Duration) {
+        let mut slowest = self.slowest.lock().unwrap();
+        if slowest.len() == self.top
+            && matches!(slowest.peek(), Some(fastest) if fastest.0 .0 >= time)
+        {
+            return;
+        }
+        slowest.push(Reverse((time, dir, entries)));
+        if slowest.len() > self.top {
+            slowest.pop();
+        }
+    }
+}
+
+impl fmt::Display for DirTimings {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut slowest: Vec<_> = self
+            .slowest
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|r| r.0.clone())
+            .collect();
+        slowest.sort_by(|d1, d2| d2.cmp(d1));
+        write!(f, "Slowest directories:")?;
+        for (time, dir, entries) in slowest {
+            // The roots have an empty path.
+            let dir = if dir.as_os_str().is_empty() {
+                Path::new(".")
+            } else {
+                &dir
+            };
+            write!(
+                f,
+                "\n{:.3}s\t{} entries\t{}",
+                time.as_secs_f64(),
+                entries,
+                dir.display()
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// Error of a comparison stopped by [`DiffOptions::timeout`].
+///
+/// The diffs found until then were reported, but the others were not looked for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TimedOut;
+
+impl fmt::Display for TimedOut {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "The comparison timed out, only the differences found so far were reported"
+        )
+    }
+}
+
+impl std::error::Error for TimedOut {}
+
+/// Totals of a comparison, returned by [`diff_dirs`].
+///
+/// Only the kinds of diffs with a field of their own are counted, the handler sees all of them.
+/// A comparison ending on an error returns that error rather than totals, so that every error
+/// a returned `Stats` does not count in [`errors`](Stats::errors) made the comparison fail.
+///
+/// # Example
+///
+/// ```
+/// use dirdiff::{diff_dirs, ChannelHandler, DiffOptions, Stats};
+/// use std::{fs, sync::{mpsc::channel, Arc}};
+///
+/// # let root = std::env::temp_dir().join(format!("dirdiff-stats-{}", std::process::id()));
+/// let (dir1, dir2) = (root.join("dir1"), root.join("dir2"));
+/// for dir in [&dir1, &dir2] {
+///     fs::create_dir_all(dir.join("sub"))?;
+///     fs::write(dir.join("sub/same"), "12345678")?;
+/// }
+/// fs::write(dir1.join("changed"), "abcd")?;
+/// fs::write(dir2.join("changed"), "abXd")?;
+/// fs::write(dir1.join("removed"), "")?;
+///
+/// let handler = Arc::new(ChannelHandler::new(channel().0));
+/// let stats = diff_dirs(dir1, dir2, handler, 4, &DiffOptions::default())?;
+/// let expected = Stats {
+///     different: 1,
+///     only1: 1,
+///     // Both sides of `sub/same`, and those of `changed` up to its first difference.
+///     bytes_read: 2 * 8 + 2 * 2,
+///     files_compared: 2,
+///     dirs_visited: 2,
+///     ..Stats::default()
+/// };
+/// assert_eq!(stats, expected);
+/// # fs::remove_dir_all(root)?;
+/// # Ok::<(), anyhow::Error>(())
+/// ```
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Stats {
+    /// Diffs of kind `different` or `link_target`.
+    pub different: u64,
+    /// Diffs of kind `only1`.
+    pub only1: u64,
+    /// Diffs of kind `only2`.
+    pub only2: u64,
+    /// Diffs of kind `mtime`.
+    pub mtime: u64,
+    /// Errors the comparison went on after: the directories that could not be listed as they
+    /// vanished, reported as [`Diff::Vanished`]. Any other error ends the comparison.
+    pub errors: u64,
+    /// Bytes of regular files compared byte by byte, on both sides.
+    ///
+    /// Only the bytes up to the first difference are counted, although more may be read
+    /// ahead, and none of files that changed during the comparison or were cut short by
+    /// `byte_budget`.
+    pub bytes_read: u64,
+    /// Pairs of regular files whose content was compared, by any means.
+    pub files_compared: u64,
+    /// Pairs of directories listed, the roots included.
+    pub dirs_visited: u64,
+}
+
+impl Stats {
+    fn add(&mut self, other: &Stats) {
+        self.different += other.different;
+        self.only1 += other.only1;
+        self.only2 += other.only2;
+        self.mtime += other.mtime;
+        self.errors += other.errors;
+        self.bytes_read += other.bytes_read;
+        self.files_compared += other.files_compared;
+        self.dirs_visited += other.dirs_visited;
+    }
+}
+
+/// Options controlling how the directories are compared.
+#[derive(Debug, Clone, Default)]
+pub struct DiffOptions {
+    /// Report files whose content is the same but whose mtime differs.
+    pub check_mtime: bool,
+    /// Report directories whose mtime differs.
+    pub check_dir_mtime: bool,
+    /// Report the regular files whose mtime differs as [`Diff::DifferentMTime`], of the kind of
+    /// files whose content differs, rather than as [`Diff::SameButDifferentMTime`]. Only used
+    /// with `check_mtime`, directories are still reported as the latter by `check_dir_mtime`.
+    pub mtime_is_diff: bool,
+    /// Resolution both mtimes are rounded down to before `check_mtime` and `check_dir_mtime`
+    /// compare them, for filesystems storing them at different resolutions.
+    pub mtime_granularity: MtimeGranularity,
+    /// Never look at mtimes, whatever the other options.
+    ///
+    /// Takes precedence over `check_mtime`, `check_dir_mtime` and `prune_unchanged_before`,
+    /// so that no diff and no decision to skip a directory ever depends on an mtime.
+    pub ignore_mtime: bool,
+    /// Report files and directories whose permissions differ.
+    pub perms: bool,
+    /// Permission bits compared by `perms`, all of them if `None`.
+    pub perm_mask: Option<u32>,
+    /// Report files and directories whose owner differs.
+    pub owner: bool,
+    /// Report regular files whose number of hard links differs.
+    pub check_nlink: bool,
+    /// Report regular files whose inode flags differ, like immutable or append-only.
+    ///
+    /// Only supported on Linux. Flags are not compared on filesystems without them, which is
+    /// warned about once.
+    pub check_flags: bool,
+    /// Report regular files whose Linux capabilities differ, as stored in their
+    /// `security.capability` extended attribute. Only supported on Linux.
+    pub check_caps: bool,
+    /// Report regular files and directories whose POSIX ACLs differ, as stored in their
+    /// `system.posix_acl_access` extended attribute, along with `system.posix_acl_default`
+    /// for directories. Only supported on Linux.
+    pub check_acls: bool,
+    /// Report regular files whose number of allocated blocks differs.
+    pub check_blocks: bool,
+    /// Report regular files with the same content, only one of which shares physical extents
+    /// with other files, like a reflink clone against a full copy.
+    ///
+    /// Only supported on Linux, on filesystems reporting shared extents, like Btrfs and XFS.
+    /// Sharing is not compared on other filesystems, which is warned about once.
+    pub check_reflinks: bool,
+    /// Also report regular files whose content differs although they have the same size and
+    /// mtime, as a [`Diff::SuspiciousMtimeMatch`].
+    ///
+    /// Mtimes are compared at `mtime_granularity`. Only files whose bytes were compared are
+    /// reported, not those found different by `compare_cmd` or by `comparers`.
+    pub flag_mtime_same_content_differs: bool,
+    /// Follow the symlinks met while traversing the directories.
+    pub follow_symlink: bool,
+    /// Under `follow_symlink`, only follow the symlinks resolving inside their root, and keep
+    /// the others as symlinks, compared by their target.
+    pub follow_inside_roots: bool,
+    /// When set, only count the work to do instead of comparing files' content.
+    pub tally: Option<Arc<Tally>>,
+    /// When set, records how long each directory took to compare.
+    pub dir_timings: Option<Arc<DirTimings>>,
+    /// When set, counts the directories and files compared, and tracks the directories being
+    /// processed, for another thread to report the progress of the comparison.
+    pub progress: Option<Arc<Progress>>,
+    /// When set, skips the top-level directories it recorded as compared, and records those
+    /// compared from now on, to resume an interrupted comparison.
+    ///
+    /// Only the subtrees of the directories present in both roots are skipped, the other
+    /// entries of the roots are compared again. A subtree is recorded once its diffs are all
+    /// passed to the handler, which must not keep them in memory for it to be accurate.
+    /// Cannot be combined with `sorted`, `two_pass`, `compare_threads` and `find_duplicates`,
+    /// which report diffs once the subtrees are done.
+    pub checkpoint: Option<Arc<Checkpoint>>,
+    /// Report each directory with a differing entry once, as a [`Diff::DirChanged`], instead
+    /// of the diffs of its entries.
+    ///
+    /// An entry differs when any diff would be reported about it: present on one side only,
+    /// of different types or contents, or with different compared metadata. Entries are
+    /// compared until the first one differs, and the remaining files of the directory are
+    /// then left uncompared. Subdirectories present on both sides are still compared, each
+    /// reported on its own if its entries differ, so that a change deep in a tree only reports
+    /// the directory it is in. The roots are reported with an empty path. Cannot be combined
+    /// with `two_pass` and `compare_threads`, which compare files after their directory.
+    pub dir_changed_only: bool,
+    /// Only report what copying the newer entries of the first dir. onto the second one would
+    /// update: the entries present in the first dir. only, and those present on both sides,
+    /// but not both directories, whose mtime is strictly newer in the first dir., as
+    /// [`Diff::NewerInFirst`].
+    ///
+    /// Their content is never compared, and entries whose mtimes are equal, at
+    /// `mtime_granularity`, or newer in the second dir., are left out, as are those present
+    /// in the second dir. only. Directories present on both sides are compared as usual, so
+    /// that the options checking them still report their diffs.
+    pub updates_to_second: bool,
+    /// Retrying of the IO operations failing with transient errors, like listing directories
+    /// and reading metadata, but not reading the content of files.
+    pub retry: RetryPolicy,
+    /// Retrying of the comparisons of regular files' content failing with transient errors.
+    ///
+    /// Both files are then opened again, and compared from the start. Also used by
+    /// `comparers` and `find_duplicates`, but not by `compare_cmd`.
+    pub content_retry: RetryPolicy,
+    /// Report files whose content is the same but whose holes differ.
+    pub sparse: bool,
+    /// Compare ASCII letters of files' content case-insensitively.
+    pub ignore_content_case: bool,
+    /// Hint the kernel about how files are read when comparing their content.
+    pub fadvise: bool,
+    /// Bytes left to read when comparing files' content, shared by all the workers, and by all
+    /// the comparisons given clones of the same options. Unlimited if `None`.
+    ///
+    /// Once it runs out, regular files are reported as [`Diff::NotCompared`] instead of being
+    /// read, including the ones it ran out in the middle of, which are never reported as
+    /// different unless a difference was found before. Files compared by `compare_cmd` or by
+    /// `comparers` are not counted.
+    pub byte_budget: Option<Arc<AtomicU64>>,
+    /// Only compare the first bytes of regular files, up to this number, and consider files of
+    /// the same size equal if these are.
+    ///
+    /// This samples the files, like to check their headers, rather than compares them: files
+    /// differing past these bytes are reported as equal. Not used by `compare_cmd` and
+    /// `comparers`.
+    pub compare_prefix: Option<u64>,
+    /// Read regular files whole even once they differ, to count their differing bytes, held by
+    /// [`Diff::Different`].
+    ///
+    /// Files of different sizes are read too, the tail of the largest one counting as differing
+    /// whole, so that this reads much more. Files compared by `compare_cmd` or by `comparers`
+    /// are not counted.
+    pub count_diff_bytes: bool,
+    /// Only compare the content of regular files of this type, sniffed from their first bytes,
+    /// and report files of different types, one of them being this one, as
+    /// [`Diff::ContentTypesDiffer`].
+    ///
+    /// Pairs of files of other types are left out, along with the diffs about their metadata
+    /// found after their content. Files of different sizes are read too, to sniff their types.
+    /// Files compared by `compare_cmd` or by `comparers` are not sniffed.
+    pub content_type: Option<ContentType>,
+    /// Extended attribute holding a hash of files' content, trusted when equal on both sides.
+    pub trust_xattr: Option<String>,
+    /// Consider regular files with the same device and inode numbers on both sides equal,
+    /// without reading them, as they are the same file. Only supported on Unix.
+    pub trust_same_inode: bool,
+    /// Limit on the number of files and directories opened at once by the workers to list
+    /// directories and compare files.
+    ///
+    /// The directories kept open by `secure` are not counted. Not used by
+    /// [`diff_trees`](crate::diff_trees), which opens few files.
+    pub open_files: Option<Arc<OpenFiles>>,
+    /// Compare symlinks by the location they resolve to, rather than by their target's text.
+    pub resolve_symlink_targets: bool,
+    /// Skip the symlinks met while traversing the directories.
+    pub ignore_symlinks: bool,
+    /// Skip the entries that are neither directories nor regular files.
+    pub regular_only: bool,
+    /// Skip the empty regular files, on each side on its own.
+    ///
+    /// An empty file is thus neither compared nor reported when it is missing from the other
+    /// side, but a nonempty file is reported as present in one dir. only when the other side
+    /// has an empty file with the same name.
+    pub ignore_empty: bool,
+    /// Skip the regular files smaller than this many bytes.
+    ///
+    /// Pairs of files are only skipped if both are out of the range of `min_size` and
+    /// `max_size`: a file in range on one side only is reported as different from the other,
+    /// as their sizes differ. Files present on one side only are skipped if out of range.
+    pub min_size: Option<u64>,
+    /// Skip the regular files larger than this many bytes, like `min_size`.
+    pub max_size: Option<u64>,
+    /// Report entries of a same directory whose names only differ by case.
+    pub detect_case_collisions: bool,
+    /// Only compare the presence and type of entries, never their content.
+    pub names_only: bool,
+    /// Command telling whether regular files are equal, replacing the comparison of their content.
+    pub compare_cmd: Option<CompareCommand>,
+    /// Strategies comparing the content of regular files, by extension. Unused with `compare_cmd`.
+    pub comparers: Comparers,
+    /// Report directories whose entries differ as a whole, rather than each entry.
+    pub shallow: bool,
+    /// Report directories without any entry name in common between both sides as a whole,
+    /// rather than each entry as present in one dir. only.
+    ///
+    /// Empty directories are not reported, nor are directories sharing a single name, even
+    /// with a different type on each side. Entries ignored by `ignore_symlinks`,
+    /// `regular_only` and `ignore_empty` are not counted.
+    pub collapse: bool,
+    /// Compare the first directory with an empty tree, instead of the second root.
+    ///
+    /// All the entries of the first directory are reported as present in it only,
+    /// recursing into its subdirectories.
+    pub empty_second: bool,
+    /// Compare an empty tree with the second directory, instead of the first root.
+    ///
+    /// The converse of `empty_second`, both can be set at once.
+    pub empty_first: bool,
+    /// Skip the pairs of subdirectories whose mtimes are both older than this time, without
+    /// listing them, trusting that nothing changed under them.
+    ///
+    /// This is a heuristic, which misses diffs whenever it is wrong: the mtime of a directory
+    /// only changes when entries are added to it, removed from it or renamed in it, not when a
+    /// file in it is modified in place, nor when anything changes in its subdirectories, and
+    /// it can be set to any value, like by `touch` or when extracting archives. The roots are
+    /// always compared.
+    pub prune_unchanged_before: Option<SystemTime>,
+    /// Report the diffs sorted by path once all the workers are done, rather than as they are found.
+    pub sorted: bool,
+    /// Order of the paths of the diffs reported under `sorted`. Not supported by
+    /// [`diff_trees`](crate::diff_trees).
+    pub collation: Collation,
+    /// Open each directory and file relative to its parent, without following symlinks, so that
+    /// the directories cannot be redirected elsewhere while they are compared.
+    ///
+    /// Only supported on Linux, and incompatible with `follow_symlink`, `sparse`, `trust_xattr`,
+    /// `compare_cmd`, `resolve_symlink_targets`, `empty_first` and `empty_second`, which all use
+    /// paths, and with `two_pass`.
+    pub secure: bool,
+    /// Print to stderr, for each pair of regular files, which rule decided whether they are equal.
+    pub explain: bool,
+    /// Compare the directories in two passes: first the names, types and sizes of all the
+    /// entries, then the content and metadata of the regular files of the same size.
+    ///
+    /// The diffs of the first pass are all reported before [`DiffHandler::structure_done`] is
+    /// called, and those of the second pass after, so that structural diffs come quickly
+    /// on slow storage. The pairs of files left for the second pass are kept in memory
+    /// meanwhile. Files compared by `compare_cmd` or `comparers` are all left for the second
+    /// pass, since their sizes do not tell them apart. Incompatible with `secure`, which
+    /// compares files relative to their open parent.
+    pub two_pass: bool,
+    /// Order in which each worker processes the directories it found.
+    ///
+    /// The subdirectories of a directory are queued before its files are compared, so that
+    /// idle workers can steal them meanwhile. Workers steal the oldest directories of the
+    /// others whatever the order, so that it is only roughly followed with several threads.
+    /// Depth-first keeps few directories queued, while breadth-first queues whole levels of
+    /// the trees, which takes more memory on wide trees, but reports the diffs near the roots
+    /// first.
+    pub traversal: Traversal,
+    /// Only report the shallowest diff, stopping once no shallower one can be found.
+    ///
+    /// The depth of a diff is the number of components of its path relative to the roots.
+    /// The diffs found are kept instead of being reported, and directories whose entries
+    /// cannot be shallower than the diff kept are skipped, so that the traversal ends soon
+    /// after a diff is found near the roots. The kept diff is reported once the workers are
+    /// done. Of several diffs at the same depth, the first one found is kept, which depends on
+    /// the threads. Requires [`Traversal::BreadthFirst`], and cannot be combined with
+    /// `sorted`, `two_pass` and `find_duplicates`.
+    pub shallowest_diff: bool,
+    /// Report where the symlinks followed by `follow_symlink` resolved to, as a
+    /// [`Diff::ResolvedLinks`] after the other diffs of their entry.
+    pub show_resolved: bool,
+    /// Once the comparison is over, look for the regular files present in one dir. only whose
+    /// content is present in the other dir. under another path, and report them as
+    /// [`Diff::ContentMovedOrRenamed`].
+    ///
+    /// The files under the directories present in one dir. only are looked at too. The paths
+    /// of all these entries are kept in memory meanwhile, and the files of the same size on
+    /// both sides are all read to be hashed. Files are opened by path, even with `secure`.
+    pub find_duplicates: bool,
+    /// Entries of the first dir. compared with an entry of another name in the second dir.
+    ///
+    /// Diffs are reported with the paths of the first dir., even those about entries present
+    /// in the second dir. only, but for the paths of [`Diff::ContentMovedOrRenamed`].
+    pub renames: RenameMap,
+    /// Compare the content of regular files by their SHA-256 hashes, and report them for each
+    /// pair as [`Diff::Hashes`], whether the files are equal or not.
+    ///
+    /// Both files are read whole, even when their sizes differ. The hashes replace the other
+    /// ways of comparing the content: `compare_cmd`, `comparers`, `trust_same_inode` and
+    /// `trust_xattr` are not used, nor are `ignore_content_case` and `byte_budget`. Files not
+    /// compared, like under `names_only`, are not hashed.
+    pub print_hashes: bool,
+    /// Report the entries of a directory present in one dir. only each whose names are within
+    /// this Levenshtein distance of each other as [`Diff::PossibleRename`], rather than as
+    /// present in one dir. only.
+    ///
+    /// The entries present in one dir. only are held until all those of their directory are
+    /// found, and each of them is compared with all those of the other side, so this is
+    /// quadratic in their number. Entries paired this way are not recursed into, nor looked at
+    /// by `find_duplicates`.
+    pub fuzzy_names: Option<usize>,
+    /// Number of threads comparing the content of regular files, apart from the threads
+    /// listing directories, or 0 for the latter to compare files themselves.
+    ///
+    /// Directory threads then send the pairs of regular files to a bounded queue, so that
+    /// reading large files never holds the traversal up, and only wait for the file threads
+    /// once the queue is full. Not used under `two_pass`, whose content pass already compares
+    /// files apart from the traversal.
+    pub compare_threads: u16,
+    /// Stop the comparison once it has run for this long, failing with [`TimedOut`] after
+    /// reporting the diffs found until then.
+    ///
+    /// Workers only stop between operations, so that one blocked listing a directory or
+    /// reading a file, like on an unresponsive network mount, still holds the comparison up
+    /// until it returns. Not supported by [`diff_trees`](crate::diff_trees).
+    pub timeout: Option<Duration>,
+    /// Stop comparing the content of a pair of regular files once it has run for this long,
+    /// reporting them as [`Diff::CompareTimedOut`] and going on with the other files.
+    ///
+    /// The time is checked between the chunks read, as reads cannot be interrupted: a file
+    /// whose read blocks, like on a stuck network mount, still holds its worker up until the
+    /// read returns, which makes this best-effort for files blocking on their first read.
+    /// Files compared by `compare_cmd` or by `comparers` are not bounded.
+    pub per_file_timeout: Option<Duration>,
+    /// Only compare the entries at these paths, and those beneath them, see
+    /// [`PathSelection`]. Not supported by [`diff_trees`](crate::diff_trees).
+    pub selection: Option<PathSelection>,
+}
+
+/// Pair of regular files of a directory, left for another worker to compare.
+type FilePair = (PathBuf, FileT, FileT);
+
+struct DirWorker<H: DiffHandler, F: FileSystem> {
+    /// Filesystem both trees are read from.
+    fs: Arc<F>,
+    root1: PathBuf,
+    root2: PathBuf,
+    /// Fully resolved roots, only needed by `--resolve-symlink-targets` and
+    /// `follow_inside_roots`.
+    canonical_roots: Option<(PathBuf, PathBuf)>,
+    stack: StackHandle,
+    diff_handler: Arc<H>,
+    options: DiffOptions,
+    /// Diffs found by this worker, kept until the end when sorting them.
+    ///
+    /// Each worker has its own, so that no lock is taken when a diff is found.
+    found: Vec<Diff>,
+    /// Open directories being processed in both trees, only under `--secure`.
+    dir_fds: Option<Arc<(DirFd, DirFd)>>,
+    /// Number of diffs found by this worker, to tell whether an entry was reported.
+    reported: u64,
+    /// Pairs of regular files left for the content pass, under `two_pass`.
+    deferred: Vec<FilePair>,
+    /// Queue of the pairs of regular files for the file threads, under `compare_threads`.
+    compare_queue: Option<SyncSender<FilePair>>,
+    /// Totals of this worker, added up with those of the others once they are done.
+    stats: Stats,
+    /// Number of entries of the last directory processed, paired across both trees.
+    entries: u64,
+    /// Paths of the entries present in each dir. only, under `find_duplicates`.
+    single_side: (Vec<PathBuf>, Vec<PathBuf>),
+    /// Whether the diffs found are only counted, while comparing the entries of a directory
+    /// under `dir_changed_only`.
+    muted: bool,
+    /// The shallowest diff found by all the workers, under `shallowest_diff`.
+    shallowest: Option<Arc<Shallowest>>,
+}
+
+/// The shallowest diff found so far, shared by all the workers under
+/// [`DiffOptions::shallowest_diff`].
+struct Shallowest {
+    /// Depth of `diff`, or `usize::MAX` while none was found, read without taking the lock.
+    depth: AtomicUsize,
+    diff: Mutex<Option<Diff>>,
+}
+
+impl Shallowest {
+    fn new() -> Self {
+        Self {
+            depth: AtomicUsize::new(usize::MAX),
+            diff: Mutex::new(None),
+        }
+    }
+
+    /// Keeps `diff` if it is shallower than the diff kept so far.
+    fn offer(&self, diff: Diff) {
+        let depth = diff.path().components().count();
+        let mut kept = self.diff.lock().unwrap();
+        if depth < self.depth.load(Ordering::SeqCst) {
+            self.depth.store(depth, Ordering::SeqCst);
+            *kept = Some(diff);
+        }
+    }
+
+    /// Whether the diffs about the entries of `dir`, a path relative to the roots, are no
+    /// shallower than the diff kept.
+    fn too_deep(&self, dir: &Path) -> bool {
+        dir.components().count() + 1 >= self.depth.load(Ordering::SeqCst)
+    }
+}
+
+impl<H: DiffHandler, F: FileSystem> DirWorker<H, F> {
+    fn new(
+        fs: Arc<F>,
+        root1: PathBuf,
+        root2: PathBuf,
+        canonical_roots: Option<(PathBuf, PathBuf)>,
+        diff_handler: Arc<H>,
+        stack: StackHandle,
+        options: DiffOptions,
+    ) -> Self {
+        Self {
+            fs,
+            root1,
+            root2,
+            canonical_roots,
+            stack,
+            diff_handler,
+            options,
+            found: Vec::new(),
+            dir_fds: None,
+            reported: 0,
+            deferred: Vec::new(),
+            compare_queue: None,
+            stats: Stats::default(),
+            entries: 0,
+            single_side: Default::default(),
+            muted: false,
+            shallowest: None,
+        }
+    }
+
+    fn run(&mut self) -> anyhow::Result<()> {
+        loop {
+            if self.stack.abort.load(Ordering::SeqCst) {
+                return Ok(());
+            }
+            if let Some(su) = self.stack.own.pop() {
+                if matches!(&self.shallowest, Some(shallowest) if shallowest.too_deep(&su.dir)) {
+                    self.stack.pending.fetch_sub(1, Ordering::SeqCst);
+                    continue;
+                }
+                let tracked = self.options.dir_timings.is_some()
+                    || self.options.checkpoint.is_some()
+                    || self.options.progress.is_some();
+                let dir = tracked.then(|| su.dir.clone());
+                if let (Some(progress), Some(dir)) = (&self.options.progress, &dir) {
+                    progress.dir_started(dir);
+                }
+                let start = Instant::now();
+                let result = self.process_path(su);
+                if let (Some(progress), Some(dir)) = (&self.options.progress, &dir) {
+                    progress.dir_done(dir);
+                }
+                match result {
+                    Ok(()) => {
+                        if let (Some(checkpoint), Some(dir)) = (&self.options.checkpoint, &dir) {
+                            if let Err(e) = checkpoint.finished(dir) {
+                                self.stack.abort.store(true, Ordering::SeqCst);
+                                return Err(anyhow::Error::new(e).context(format!(
+                                    "Couldn't write to {}",
+                                    checkpoint.path().display()
+                                )));
+                            }
+                        }
+                        if let (Some(timings), Some(dir)) = (&self.options.dir_timings, dir) {
+                            timings.record(dir, self.entries, start.elapsed());
+                        }
+                        // Only now that the subdirectories it found are counted.
+                        self.stack.pending.fetch_sub(1, Ordering::SeqCst);
+                        continue;
+                    }
+                    e@Err(_) => {
+                        self.stack.abort.store(true, Ordering::SeqCst);
+                        return e;
+                    }
+                };
+                
+            }
+            let empty_backoff = Backoff::new();
+            let retry_backoff = Backoff::new();
+            loop {
+                if self.stack.abort.load(Ordering::SeqCst) {
+                    return Ok(());
+                }
+                let stollen: Steal<_> = self.stack.stealers.iter().map(|s| s.steal()).collect();
+                match stollen {
+                    Steal::Retry => {
+                        retry_backoff.snooze();
+                    }
+                    Steal::Empty => {
+                        // Units being processed by others may still push more of them.
+                        if self.stack.pending.load(Ordering::SeqCst) == 0 {
+                            return Ok(());
+                        }
+                        empty_backoff.snooze();
+                    }
+                    Steal::Success(su) => {
+                        self.stack.own.push(su);
+                        break;
+                    }
+                }
+            }
+        }
+    }
+
+    fn process_diff(&mut self, diff: Diff) {
+        // Reported for all the files, whether they differ or not.
+        if !matches!(diff, Diff::Hashes(..)) {
+            self.reported += 1;
+        }
+        if self.muted {
+            return;
+        }
+        match diff {
+            Diff::Different(..)
+            | Diff::DifferentMTime(..)
+            | Diff::LinkTargetsDiffer(..)
+            | Diff::ContentTypesDiffer(..) => self.stats.different += 1,
+            Diff::InDir1Only(..) => self.stats.only1 += 1,
+            Diff::InDir2Only(..) => self.stats.only2 += 1,
+            Diff::SameButDifferentMTime(..) => self.stats.mtime += 1,
+            Diff::Vanished(..) => self.stats.errors += 1,
+            _ => (),
+        }
+        if let Some(shallowest) = &self.shallowest {
+            shallowest.offer(diff);
+            return;
+        }
+        match &self.options.tally {
+            Some(tally) => {
+                if let Diff::InDir1Only(..) | Diff::InDir2Only(..) = diff {
+                    tally.single_side.fetch_add(1, Ordering::SeqCst);
+                }
+            }
+            None if self.options.sorted => self.found.push(diff),
+            None => self.diff_handler.process(&self.root1, &self.root2, diff),
+        }
+    }
+
+    /// Returns the diffs found by the worker sorted by path, if they were kept.
+    fn into_sorted(self) -> Vec<(PathBuf, Diff)> {
+        let mut found: Vec<_> = self.found.into_iter().map(|d| (d.path(), d)).collect();
+        // The sort is stable, so that diffs about the same entry keep the order they were found in.
+        found.sort_by(|(path1, _), (path2, _)| path1.cmp(path2));
+        found
+    }
+
+    fn push_to_stack(&mut self, dir: PathBuf) {
+        self.stack.pending.fetch_add(1, Ordering::SeqCst);
+        if let Some(checkpoint) = &self.options.checkpoint {
+            checkpoint.started(&dir);
+        }
+        self.stack.own.push(StackUnit {
+            dir,
+            parent_fds: self.dir_fds.clone(),
+        })
+    }
+
+    /// Opens `dir` in both trees for `--secure`, relative to its parents unless it is the root.
+    fn open_dirs(
+        &self,
+        dir: &Path,
+        parent_fds: Option<&(DirFd, DirFd)>,
+    ) -> (io::Result<DirFd>, io::Result<DirFd>) {
+        let retry = self.options.retry;
+        let open = |root: &Path, dir: &Path, parent: Option<&DirFd>| {
+            let result = match (parent, dir.file_name()) {
+                (Some(parent), Some(name)) => retry.retry(|| parent.open_dir(name)),
+                _ => retry.retry(|| DirFd::open(root)),
+            };
+            // The kind is kept, to tell vanished directories apart.
+            result.map_err(|e| {
+                let path = root.join(dir);
+                io::Error::new(e.kind(), format!("Couldn't open {}: {}", path.display(), e))
+            })
+        };
+        let dir2 = self.second_path(dir);
+        (
+            open(&self.root1, dir, parent_fds.map(|(fd1, _)| fd1)),
+            open(&self.root2, &dir2, parent_fds.map(|(_, fd2)| fd2)),
+        )
+    }
+
+    /// Path in the second tree of the entry at `path` in the first one, which differ under
+    /// `renames`.
+    fn second_path<'a>(&self, path: &'a Path) -> Cow<'a, Path> {
+        if self.options.renames.is_empty() {
+            path.into()
+        } else {
+            self.options.renames.second_path(path).into()
+        }
+    }
+
+    /// Lists the content of `dir` in both trees, opening it first under `--secure`.
+    fn list_dirs(
+        &mut self,
+        dir: &Path,
+        parent_fds: Option<&(DirFd, DirFd)>,
+    ) -> (io::Result<Vec<FileT>>, io::Result<Vec<FileT>>) {
+        if self.options.secure {
+            let (fd1, fd2) = match self.open_dirs(dir, parent_fds) {
+                (Ok(fd1), Ok(fd2)) => (fd1, fd2),
+                (r1, r2) => return (r1.map(|_| Vec::new()), r2.map(|_| Vec::new())),
+            };
+            // Listed through their file descriptors, whatever happened to their paths since.
+            let content = (
+                self.list_dir(&fd1.path(), Side::First),
+                self.list_dir(&fd2.path(), Side::Second),
+            );
+            self.dir_fds = Some(Arc::new((fd1, fd2)));
+            return content;
+        }
+        let content1 = if self.options.empty_first {
+            Ok(Vec::new())
+        } else {
+            self.list_dir(&PathBuf::from_iter([&self.root1, dir]), Side::First)
+        };
+        let content2 = if self.options.empty_second {
+            Ok(Vec::new())
+        } else {
+            self.list_dir(&self.root2.join(self.second_path(dir)), Side::Second)
+        };
+        (content1, content2)
+    }
+
+    /// Reports the sides where `dir` could not be listed as [`Diff::Vanished`], when it was
+    /// removed since its parent was listed, `dir` as [`Diff::PathTooLong`] when its path is
+    /// too long on either side, and fails on other errors.
+    fn process_vanished(
+        &mut self,
+        dir: &Path,
+        errors: [(Side, Option<io::Error>); 2],
+    ) -> anyhow::Result<()> {
+        let name = dir.file_name().map(OsString::from).unwrap_or_default();
+        let parent = dir.parent().map(Path::to_owned).unwrap_or_default();
+        let mut too_long = false;
+        for (side, error) in errors {
+            match error {
+                // The roots were checked to exist, they are never reported.
+                Some(e) if e.kind() == io::ErrorKind::NotFound && dir.file_name().is_some() => {
+                    self.process_diff(Diff::Vanished(parent.clone(), name.clone(), side));
+                }
+                Some(e) if is_name_too_long(&e) && dir.file_name().is_some() => too_long = true,
+                Some(e) => return Err(e.into()),
+                None => (),
+            }
+        }
+        // Only once when too long on both sides.
+        if too_long {
+            self.process_diff(Diff::PathTooLong(parent, name));
+        }
+        Ok(())
+    }
+
+    /// Lists the content of `dir`, listing it again from scratch if a transient error occurs.
+    fn list_dir(&self, dir: &Path, side: Side) -> io::Result<Vec<FileT>> {
+        let _permit = self
+            .options
+            .open_files
+            .as_ref()
+            .map(|files| files.acquire(1));
+        let follow_symlink = self.options.follow_symlink;
+        let within = match (&self.canonical_roots, side) {
+            _ if !self.options.follow_inside_roots => None,
+            (Some((root1, _)), Side::First) => Some(root1.as_path()),
+            (Some((_, root2)), Side::Second) => Some(root2.as_path()),
+            (None, _) => None,
+        };
+        self.options.retry.retry(|| {
+            self.fs
+                .list_dir(dir)?
+                .into_iter()
+                .map(|(name, file_type)| {
+                    FileT::new(&*self.fs, dir, name, file_type, follow_symlink, within)
+                })
+                .collect()
+        })
+    }
+
+    /// Reports the differences of permissions, owner, and of mtime if `check_mtime` is set,
+    /// between two entries named `name` in `dir`.
+    fn compare_metadata(
+        &mut self,
+        dir: &Path,
+        name: OsString,
+        meta1: &FileMetadata,
+        meta2: &FileMetadata,
+        check_mtime: bool,
+    ) -> io::Result<()> {
+        let perm_mask = self.options.perm_mask.unwrap_or(metadata::ALL_PERMS);
+        if self.options.perms && !metadata::same_perms(meta1, meta2, perm_mask) {
+            self.process_diff(Diff::PermsDiffer(dir.to_owned(), name.clone()));
+        }
+        if let (true, Some(owner1), Some(owner2)) = (
+            self.options.owner,
+            metadata::owner(meta1),
+            metadata::owner(meta2),
+        ) {
+            if owner1 != owner2 {
+                self.process_diff(Diff::OwnerDiffer(
+                    dir.to_owned(),
+                    name.clone(),
+                    owner1,
+                    owner2,
+                ));
+            }
+        }
+        let granularity = self.options.mtime_granularity;
+        if check_mtime
+            && !self.options.ignore_mtime
+            && granularity.truncate(meta1.modified()?) != granularity.truncate(meta2.modified()?)
+        {
+            if self.options.mtime_is_diff && meta1.is_file() {
+                self.process_diff(Diff::DifferentMTime(dir.to_owned(), name));
+            } else {
+                self.process_diff(Diff::SameButDifferentMTime(dir.to_owned(), name));
+            }
+        }
+        Ok(())
+    }
+
+    /// Whether both entries have the same mtime, at `mtime_granularity`.
+    fn same_mtime(&self, meta1: &FileMetadata, meta2: &FileMetadata) -> bool {
+        let granularity = self.options.mtime_granularity;
+        match (meta1.modified(), meta2.modified()) {
+            (Ok(mtime1), Ok(mtime2)) => {
+                granularity.truncate(mtime1) == granularity.truncate(mtime2)
+            }
+            _ => false,
+        }
+    }
+
+    /// Whether both files have the same value for the extended attribute given to `--trust-xattr`.
+    fn same_trusted_xattr(&self, path1: &Path, path2: &Path) -> io::Result<bool> {
+        let name = match &self.options.trust_xattr {
+            Some(name) => name,
+            None => return Ok(false),
+        };
+        let retry = self.options.retry;
+        let value1 = retry.retry(|| xattr::get(path1, name))?;
+        let value2 = retry.retry(|| xattr::get(path2, name))?;
+        Ok(matches!((value1, value2), (Some(v1), Some(v2)) if v1 == v2))
+    }
+
+    /// Compares the content of two files, whose size was `len` when their metadata were read,
+    /// or `None` for files of different sizes, only compared to count their differing bytes.
+    fn compare_content(&self, e1: &FileT, e2: &FileT, len: Option<u64>) -> io::Result<FileCompare> {
+        let opts = CompareOpts {
+            retry: self.options.content_retry,
+            ignore_case: self.options.ignore_content_case,
+            len,
+            fadvise: self.options.fadvise,
+            budget: self.options.byte_budget.clone(),
+            prefix: self.options.compare_prefix,
+            cancel: Some(self.stack.abort.clone()),
+            count_diff_bytes: self.options.count_diff_bytes,
+            content_type: self.options.content_type.clone(),
+            deadline: self
+                .options
+                .per_file_timeout
+                .map(|timeout| Instant::now() + timeout),
+        };
+        if let Some(budget) = &opts.budget {
+            // No need to open the files to find that out.
+            if budget.load(Ordering::Relaxed) == 0 {
+                return Ok(FileCompare::OverBudget);
+            }
+        }
+        // Opened without following symlinks, so that a file replaced since it was listed is
+        // not compared.
+        match &self.dir_fds {
+            Some(fds) => {
+                let (name1, name2) = (e1.filename(), e2.filename());
+                compare::reopened_files_equal(
+                    &opts,
+                    || {
+                        Ok((
+                            checked_regular(fds.0.open_file(&name1))?,
+                            checked_regular(fds.1.open_file(&name2))?,
+                        ))
+                    },
+                    |(f1, f2), opts| open_files_equal(f1, f2, opts),
+                )
+            }
+            None => compare::reopened_files_equal(
+                &opts,
+                || Ok((self.fs.open(e1.path())?, self.fs.open(e2.path())?)),
+                |(f1, f2), opts| open_files_equal(f1, f2, opts),
+            ),
+        }
+    }
+
+    /// Hashes the content of two files with SHA-256.
+    fn hash_content(&self, e1: &FileT, e2: &FileT) -> io::Result<(manifest::Hash, manifest::Hash)> {
+        let retry = self.options.retry;
+        let ((_, hash1), (_, hash2)) = match &self.dir_fds {
+            Some(fds) => {
+                let (name1, name2) = (e1.filename(), e2.filename());
+                let f1 = retry.retry(|| checked_regular(fds.0.open_file(&name1)))?;
+                let f2 = retry.retry(|| checked_regular(fds.1.open_file(&name2)))?;
+                (manifest::hash_content(f1)?, manifest::hash_content(f2)?)
+            }
+            None => {
+                let f1 = retry.retry(|| self.fs.open(e1.path()))?;
+                let f2 = retry.retry(|| self.fs.open(e2.path()))?;
+                (manifest::hash_content(f1)?, manifest::hash_content(f2)?)
+            }
+        };
+        Ok((hash1, hash2))
+    }
+
+    /// Compares two files with the comparer registered for their extension, if any.
+    ///
+    /// Returns `None` if there is none, or if a file is malformed for it.
+    fn compare_with_comparer(&self, e1: &FileT, e2: &FileT) -> io::Result<Option<Content>> {
+        let comparer = match self.options.comparers.get(e1.path()) {
+            Some(comparer) => comparer,
+            None => return Ok(None),
+        };
+        let opts = CompareOpts {
+            retry: self.options.content_retry,
+            ignore_case: self.options.ignore_content_case,
+            len: None,
+            fadvise: self.options.fadvise,
+            budget: None,
+            prefix: None,
+            cancel: Some(self.stack.abort.clone()),
+            count_diff_bytes: false,
+            content_type: None,
+            deadline: None,
+        };
+        match comparer.equal(e1.path(), e2.path(), &opts) {
+            Ok(true) => Ok(Some(Content::Same)),
+            Ok(false) => Ok(Some(Content::Different)),
+            Err(e) if comparer::is_cancelled(&e) => Ok(Some(Content::NotCompared)),
+            Err(e) if comparer::is_malformed(&e) => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Removes the entries that must be ignored from the content of a directory.
+    fn filter_entries(&self, content: &mut Vec<FileT>) -> io::Result<()> {
+        if self.options.ignore_symlinks {
+            content.retain(|e| e.file_type() != FileType::Symlink);
+        }
+        if self.options.regular_only {
+            content.retain(|e| matches!(e.file_type(), FileType::Directory | FileType::Regular));
+        }
+        if self.options.ignore_empty {
+            let retry = self.options.retry;
+            let mut kept = Vec::with_capacity(content.len());
+            for e in content.drain(..) {
+                if e.file_type() != FileType::Regular
+                    || !retry.retry(|| self.fs.metadata(e.path()))?.is_empty()
+                {
+                    kept.push(e);
+                }
+            }
+            *content = kept;
+        }
+        Ok(())
+    }
+
+    fn process_path(&mut self, su: StackUnit) -> anyhow::Result<()> {
+        let StackUnit { dir, parent_fds } = su;
+        self.entries = 0;
+        // dbg!(&dir);
+        let (content1, content2) = self.list_dirs(&dir, parent_fds.as_deref());
+        let (mut dir_content1, mut dir_content2) = match (content1, content2) {
+            (Ok(content1), Ok(content2)) => (content1, content2),
+            (r1, r2) => {
+                let errors = [(Side::First, r1.err()), (Side::Second, r2.err())];
+                return self.process_vanished(&dir, errors);
+            }
+        };
+        self.stats.dirs_visited += 1;
+        self.filter_entries(&mut dir_content1)?;
+        self.filter_entries(&mut dir_content2)?;
+        let (len1, len2) = (dir_content1.len() as u64, dir_content2.len() as u64);
+        if self.options.detect_case_collisions {
+            // Collisions present in both trees are only reported once.
+            let mut collisions = case_collisions(&dir_content1);
+            collisions.extend(case_collisions(&dir_content2));
+            for (name1, name2) in collisions {
+                self.process_diff(Diff::CaseCollision(dir.clone(), name1, name2));
+            }
+        }
+        // Pairs of directories are processed first.
+        // This minimizes time spent with an empty stack
+        // in case work needs to be stollen by others.
+        let renames = self.options.renames.renames_in(&dir);
+        let mut pairs = pair_entries(dir_content1, dir_content2, renames);
+        if let (Some(checkpoint), true) = (&self.options.checkpoint, dir.as_os_str().is_empty()) {
+            pairs.retain(|pair| match pair {
+                (Some(e1), Some(e2)) => {
+                    let dirs = e1.file_type() == FileType::Directory
+                        && e2.file_type() == FileType::Directory;
+                    !dirs || !checkpoint.is_done(&e1.filename())
+                }
+                _ => true,
+            });
+        }
+        if let Some(selection) = &self.options.selection {
+            if !selection.covers(&dir) {
+                pairs = self.select_entries(&dir, pairs);
+            }
+        }
+        self.entries = pairs.len() as u64;
+        if self.options.min_size.is_some() || self.options.max_size.is_some() {
+            let mut kept = Vec::with_capacity(pairs.len());
+            for pair in pairs {
+                if !self.is_out_of_size_range(&pair)? {
+                    kept.push(pair);
+                }
+            }
+            pairs = kept;
+        }
+        let (subdirs, others): (Vec<_>, Vec<_>) = pairs.into_iter().partition(|pair| match pair {
+            (Some(e1), Some(e2)) => {
+                e1.file_type() == FileType::Directory && e2.file_type() == FileType::Directory
+            }
+            _ => false,
+        });
+        if self.options.collapse && len1 + len2 > 0 {
+            let disjoint = subdirs.is_empty()
+                && others
+                    .iter()
+                    .all(|pair| !matches!(pair, (Some(_), Some(_))));
+            if disjoint {
+                self.process_entries_differ(&dir, len1, len2);
+                return Ok(());
+            }
+        }
+        if self.options.shallow {
+            let same_entries = others.iter().all(
+                |pair| matches!(pair, (Some(e1), Some(e2)) if e1.file_type() == e2.file_type()),
+            );
+            if !same_entries {
+                self.process_entries_differ(&dir, len1, len2);
+            }
+            for (e1, e2) in subdirs.into_iter().filter_map(|(e1, e2)| e1.zip(e2)) {
+                if !self.is_pruned(&e1, &e2)? {
+                    self.push_to_stack(dir.join(e1.filename()));
+                }
+            }
+            return Ok(());
+        }
+        if self.options.dir_changed_only {
+            return self.process_dir_changed(&dir, subdirs, others);
+        }
+        let others = match self.options.fuzzy_names {
+            Some(max_distance) => self.pair_fuzzy_names(&dir, others, max_distance),
+            None => others,
+        };
+        for pair in subdirs.into_iter().chain(others) {
+            let resolved = self.resolved_links(pair.0.as_ref(), pair.1.as_ref());
+            let name = match &pair {
+                (Some(e), _) | (None, Some(e)) => e.filename(),
+                (None, None) => unreachable!("pairs have at least one entry"),
+            };
+            let reported = self.reported;
+            match pair {
+                (Some(e1), Some(e2)) => match self.process_entries(&dir, e1, e2) {
+                    Err(e) if caused_by_name_too_long(&e) => {
+                        self.process_diff(Diff::PathTooLong(dir.clone(), name.clone()))
+                    }
+                    result => result?,
+                },
+                (Some(e1), None) => {
+                    let path = dir.join(e1.filename());
+                    if self.options.empty_second
+                        && e1.file_type() == FileType::Directory
+                        && self.descends_into(&path)
+                    {
+                        self.push_to_stack(path);
+                    }
+                    if self.options.find_duplicates {
+                        self.single_side.0.push(dir.join(e1.filename()));
+                    }
+                    self.process_diff(Diff::InDir1Only(dir.clone(), e1.filename()))
+                }
+                (None, Some(e2)) => {
+                    let path = dir.join(e2.filename());
+                    if self.options.empty_first
+                        && e2.file_type() == FileType::Directory
+                        && self.descends_into(&path)
+                    {
+                        self.push_to_stack(path);
+                    }
+                    if self.options.find_duplicates {
+                        let path = self.second_path(&dir).join(e2.filename());
+                        self.single_side.1.push(path);
+                    }
+                    if !self.options.updates_to_second {
+                        self.process_diff(Diff::InDir2Only(dir.clone(), e2.filename()))
+                    }
+                }
+                (None, None) => unreachable!("pairs have at least one entry"),
+            }
+            self.report_resolved_links(&dir, name, resolved, reported);
+        }
+        Ok(())
+    }
+
+    /// Reports the entries of `dir` present in one dir. only whose names are within
+    /// `max_distance` of each other as [`Diff::PossibleRename`], returning the other pairs.
+    fn pair_fuzzy_names(
+        &mut self,
+        dir: &Path,
+        pairs: Vec<(Option<FileT>, Option<FileT>)>,
+        max_distance: usize,
+    ) -> Vec<(Option<FileT>, Option<FileT>)> {
+        let mut pairs: Vec<_> = pairs.into_iter().map(Some).collect();
+        // Index of each entry present in one dir. only among the pairs, with its name and type.
+        let (mut only1, mut only2) = (Vec::new(), Vec::new());
+        for (k, pair) in pairs.iter().enumerate() {
+            match pair {
+                Some((Some(e1), None)) => only1.push((k, e1.filename(), e1.file_type())),
+                Some((None, Some(e2))) => only2.push((k, e2.filename(), e2.file_type())),
+                _ => (),
+            }
+        }
+        let names1: Vec<_> = only1.iter().map(|(_, name, _)| name.as_os_str()).collect();
+        let names2: Vec<_> = only2.iter().map(|(_, name, _)| name.as_os_str()).collect();
+        let renames = fuzzy::closest_pairs(&names1, &names2, max_distance, |i, j| {
+            only1[i].2 == only2[j].2
+        });
+        for (i, j, distance) in renames {
+            let (k1, name1, _) = &only1[i];
+            let (k2, name2, _) = &only2[j];
+            pairs[*k1] = None;
+            pairs[*k2] = None;
+            let diff = Diff::PossibleRename(dir.to_owned(), name1.clone(), name2.clone(), distance);
+            self.process_diff(diff);
+        }
+        pairs.into_iter().flatten().collect()
+    }
+
+    /// Paths the entries resolved to if they are followed symlinks, only under `show_resolved`.
+    fn resolved_links(
+        &self,
+        e1: Option<&FileT>,
+        e2: Option<&FileT>,
+    ) -> (Option<PathBuf>, Option<PathBuf>) {
+        if self.options.show_resolved {
+            (e1.and_then(FileT::resolved), e2.and_then(FileT::resolved))
+        } else {
+            (None, None)
+        }
+    }
+
+    /// Reports where the entry `name` of `dir` resolved to, if it is a followed symlink on either
+    /// side and diffs were reported about it since `reported` of them were.
+    fn report_resolved_links(
+        &mut self,
+        dir: &Path,
+        name: OsString,
+        resolved: (Option<PathBuf>, Option<PathBuf>),
+        reported: u64,
+    ) {
+        if self.reported > reported && (resolved.0.is_some() || resolved.1.is_some()) {
+            let (resolved1, resolved2) = resolved;
+            self.process_diff(Diff::ResolvedLinks(
+                dir.to_owned(),
+                name,
+                resolved1,
+                resolved2,
+            ));
+        }
+    }
+
+    /// Keeps the pairs of entries of `dir` which are listed in `selection`, or which lead to
+    /// listed ones, descending right away into the latter if they are directories on both
+    /// sides, without comparing them.
+    fn select_entries(
+        &mut self,
+        dir: &Path,
+        pairs: Vec<(Option<FileT>, Option<FileT>)>,
+    ) -> Vec<(Option<FileT>, Option<FileT>)> {
+        let selection = match &self.options.selection {
+            Some(selection) => selection,
+            None => return pairs,
+        };
+        let mut kept = Vec::new();
+        let mut traversed = Vec::new();
+        for pair in pairs {
+            let path = match &pair {
+                (Some(e), _) | (None, Some(e)) => dir.join(e.filename()),
+                (None, None) => unreachable!("pairs have at least one entry"),
+            };
+            if selection.is_listed(&path) {
+                kept.push(pair);
+            } else if selection.leads_to_listed(&path) {
+                match pair {
+                    (Some(e1), Some(e2))
+                        if e1.file_type() == FileType::Directory
+                            && e2.file_type() == FileType::Directory =>
+                    {
+                        traversed.push(path)
+                    }
+                    // Reported as usual, along with the listed entries beneath.
+                    pair => kept.push(pair),
+                }
+            }
+        }
+        for path in traversed {
+            self.push_to_stack(path);
+        }
+        kept
+    }
+
+    /// Whether the subdirectory at `path` is descended into, which only those listed or leading
+    /// to listed ones are under a selection without recursion.
+    fn descends_into(&self, path: &Path) -> bool {
+        match &self.options.selection {
+            Some(selection) => selection.descends_into(path),
+            None => true,
+        }
+    }
+
+    /// Whether a pair of entries must be skipped, as all of them are regular files out of the
+    /// range of `min_size` and `max_size`.
+    ///
+    /// A file in range on one side only is kept, to be reported as different.
+    fn is_out_of_size_range(&self, pair: &(Option<FileT>, Option<FileT>)) -> io::Result<bool> {
+        let retry = self.options.retry;
+        for e in [&pair.0, &pair.1].into_iter().flatten() {
+            if e.file_type() != FileType::Regular {
+                return Ok(false);
+            }
+            let len = retry.retry(|| self.fs.metadata(e.path()))?.len();
+            let too_small = matches!(self.options.min_size, Some(min) if len < min);
+            let too_large = matches!(self.options.max_size, Some(max) if len > max);
+            if !too_small && !too_large {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+
+    /// Whether two directories must not be descended into, as both are older than
+    /// `prune_unchanged_before`.
+    fn is_pruned(&self, e1: &FileT, e2: &FileT) -> io::Result<bool> {
+        let reference = match self.options.prune_unchanged_before {
+            Some(reference) if !self.options.ignore_mtime => reference,
+            _ => return Ok(false),
+        };
+        let retry = self.options.retry;
+        let mtime1 = retry.retry(|| self.fs.metadata(e1.path())?.modified())?;
+        let mtime2 = retry.retry(|| self.fs.metadata(e2.path())?.modified())?;
+        Ok(mtime1 < reference && mtime2 < reference)
+    }
+
+    /// Reports `dir` as a whole, with its number of entries on each side.
+    fn process_entries_differ(&mut self, dir: &Path, len1: u64, len2: u64) {
+        // The roots are reported with an empty path.
+        let name = dir.file_name().map(OsString::from).unwrap_or_default();
+        let parent = dir.parent().map(Path::to_owned).unwrap_or_default();
+        self.process_diff(Diff::EntriesDiffer(parent, name, len1, len2));
+    }
+
+    /// Compares the entries of `dir` under `dir_changed_only`, until one of them differs, then
+    /// only descends into the remaining subdirectories.
+    fn process_dir_changed(
+        &mut self,
+        dir: &Path,
+        subdirs: Vec<(Option<FileT>, Option<FileT>)>,
+        others: Vec<(Option<FileT>, Option<FileT>)>,
+    ) -> anyhow::Result<()> {
+        let reported = self.reported;
+        self.muted = true;
+        let mut changed = false;
+        for pair in subdirs.into_iter().chain(others) {
+            let result = match pair {
+                (Some(e1), Some(e2)) if changed => {
+                    if e1.file_type() == FileType::Directory
+                        && e2.file_type() == FileType::Directory
+                        && !self.is_pruned(&e1, &e2)?
+                    {
+                        self.push_to_stack(dir.join(e1.filename()));
+                    }
+                    continue;
+                }
+                _ if changed => break,
+                (Some(e1), Some(e2)) => {
+                    let name = e1.filename();
+                    match self.process_entries(dir, e1, e2) {
+                        Err(e) if caused_by_name_too_long(&e) => {
+                            self.process_diff(Diff::PathTooLong(dir.to_owned(), name));
+                            Ok(())
+                        }
+                        result => result,
+                    }
+                }
+                (Some(_), None) | (None, Some(_)) => {
+                    self.reported += 1;
+                    Ok(())
+                }
+                (None, None) => unreachable!("pairs have at least one entry"),
+            };
+            if let Err(e) = result {
+                self.muted = false;
+                return Err(e);
+            }
+            changed = self.reported > reported;
+        }
+        self.muted = false;
+        if changed {
+            // The roots are reported with an empty path.
+            let name = dir.file_name().map(OsString::from).unwrap_or_default();
+            let parent = dir.parent().map(Path::to_owned).unwrap_or_default();
+            self.process_diff(Diff::DirChanged(parent, name));
+        }
+        Ok(())
+    }
+
+    /// Processes two entries of `dir` with the same name.
+    fn process_entries(&mut self, dir: &Path, e1: FileT, e2: FileT) -> anyhow::Result<()> {
+        let retry = self.options.retry;
+        let ft1 = e1.file_type();
+        let ft2 = e2.file_type();
+        let both_dirs = ft1 == FileType::Directory && ft2 == FileType::Directory;
+        if self.options.updates_to_second && !both_dirs {
+            return self.process_update(dir, e1, e2);
+        }
+        if ft1 != ft2 {
+            return self.process_type_mismatch(dir, e1, e2);
+        }
+        if self.options.names_only && ft1 != FileType::Directory {
+            // Entries of the same type are equal, whatever their content.
+            return Ok(());
+        }
+        match ft1 {
+            FileType::Directory => {
+                if self.options.perms || self.options.owner || self.options.check_dir_mtime {
+                    let e1_meta = retry.retry(|| self.fs.metadata(e1.path()))?;
+                    let e2_meta = retry.retry(|| self.fs.metadata(e2.path()))?;
+                    self.compare_metadata(
+                        dir,
+                        e1.filename(),
+                        &e1_meta,
+                        &e2_meta,
+                        self.options.check_dir_mtime,
+                    )?;
+                }
+                if self.options.check_acls {
+                    self.compare_acls(dir, &e1, &e2, true)?;
+                }
+                let mut p = dir.to_owned();
+                p.push(e1.filename());
+                if self.is_pruned(&e1, &e2)? || !self.descends_into(&p) {
+                    return Ok(());
+                }
+                self.push_to_stack(p);
+            }
+            // This can only been reached
+            // when symlikns are not followed
+            FileType::Symlink => {
+                // Junctions' targets are verbatim paths, while symlinks' are not.
+                let target1 = strip_verbatim(retry.retry(|| self.fs.read_link(e1.path()))?);
+                let target2 = strip_verbatim(retry.retry(|| self.fs.read_link(e2.path()))?);
+                let roots = self.canonical_roots.as_ref();
+                let same_target = match roots.filter(|_| self.options.resolve_symlink_targets) {
+                    Some((root1, root2)) => {
+                        let resolved1 = resolved_target(root1, e1.path());
+                        let resolved2 = resolved_target(root2, e2.path());
+                        match (resolved1, resolved2) {
+                            (Some(resolved1), Some(resolved2)) => resolved1 == resolved2,
+                            // Broken links are only equal to broken links with the same target.
+                            (None, None) => target1 == target2,
+                            _ => false,
+                        }
+                    }
+                    None => target1 == target2,
+                };
+                if !same_target {
+                    self.process_diff(Diff::LinkTargetsDiffer(
+                        dir.to_owned(),
+                        e1.filename(),
+                        target1,
+                        target2,
+                    ));
+                }
+            }
+            FileType::Regular if self.options.two_pass => self.defer_files(dir, e1, e2)?,
+            FileType::Regular => match &self.compare_queue {
+                // The file threads only stop early on abort, which stops this one too.
+                Some(queue) => {
+                    let _ = queue.send((dir.to_owned(), e1, e2));
+                }
+                None => self.process_files(dir, e1, e2)?,
+            },
+            // Their content only exists while some process writes to them, and opening them
+            // could block forever: they are equal as long as both are there.
+            #[cfg(unix)]
+            FileType::Fifo | FileType::Socket => (),
+            _ => {
+                let mut p = dir.to_owned();
+                p.push(e1.filename());
+                bail!(
+                    "Unimplemented filetype. File {} has type {:?}",
+                    p.display(),
+                    ft1
+                );
+            }
+        }
+        Ok(())
+    }
+
+    /// Processes two entries of `dir` with the same name, not both directories, under
+    /// `updates_to_second`.
+    fn process_update(&mut self, dir: &Path, e1: FileT, e2: FileT) -> anyhow::Result<()> {
+        let retry = self.options.retry;
+        let granularity = self.options.mtime_granularity;
+        let mtime1 = granularity.truncate(retry.retry(|| self.fs.metadata(e1.path()))?.modified()?);
+        let mtime2 = granularity.truncate(retry.retry(|| self.fs.metadata(e2.path()))?.modified()?);
+        if mtime1 > mtime2 {
+            self.process_diff(Diff::NewerInFirst(dir.to_owned(), e1.filename()));
+        }
+        Ok(())
+    }
+
+    /// Reports two entries of `dir` with the same name if their ACLs differ, along with their
+    /// default ones if they are directories.
+    fn compare_acls(&mut self, dir: &Path, e1: &FileT, e2: &FileT, is_dir: bool) -> io::Result<()> {
+        let retry = self.options.retry;
+        let read = |path: &Path| -> io::Result<_> {
+            let access = retry.retry(|| xattr::get(path, acl::ACCESS_XATTR))?;
+            let default = match is_dir {
+                true => retry.retry(|| xattr::get(path, acl::DEFAULT_XATTR))?,
+                false => None,
+            };
+            Ok((access, default))
+        };
+        let (access1, default1) = read(e1.path())?;
+        let (access2, default2) = read(e2.path())?;
+        if (&access1, &default1) != (&access2, &default2) {
+            self.process_diff(Diff::AclDiffer(
+                dir.to_owned(),
+                e1.filename(),
+                acl::describe(access1.as_deref(), default1.as_deref()),
+                acl::describe(access2.as_deref(), default2.as_deref()),
+            ));
+        }
+        Ok(())
+    }
+
+    /// Processes two entries of `dir` with the same name but different types.
+    fn process_type_mismatch(&mut self, dir: &Path, e1: FileT, e2: FileT) -> anyhow::Result<()> {
+        let type1 = self.entry_type(&e1)?;
+        let type2 = self.entry_type(&e2)?;
+        self.process_diff(Diff::TypeMismatch(
+            dir.to_owned(),
+            e1.filename(),
+            type1,
+            type2,
+        ));
+        Ok(())
+    }
+
+    /// Describes an entry for a [`Diff::TypeMismatch`].
+    fn entry_type(&self, entry: &FileT) -> io::Result<EntryType> {
+        let size = match entry.file_type() {
+            FileType::Regular => self
+                .options
+                .retry
+                .retry(|| self.fs.metadata(entry.path()))?
+                .len(),
+            _ => 0,
+        };
+        Ok(EntryType {
+            file_type: entry.file_type(),
+            size,
+            resolved: entry.resolved(),
+        })
+    }
+
+    /// Processes two regular files of `dir` with the same name during the structural pass of
+    /// `two_pass`: they are only compared now if their sizes are enough to tell them apart.
+    fn defer_files(&mut self, dir: &Path, e1: FileT, e2: FileT) -> anyhow::Result<()> {
+        let retry = self.options.retry;
+        let len1 = retry.retry(|| self.fs.metadata(e1.path()))?.len();
+        let len2 = retry.retry(|| self.fs.metadata(e2.path()))?.len();
+        // Files of different sizes can be equal for the command or the comparer.
+        let by_size =
+            self.options.compare_cmd.is_none() && self.options.comparers.get(e1.path()).is_none();
+        if by_size && len1 != len2 {
+            self.process_files(dir, e1, e2)
+        } else {
+            self.deferred.push((dir.to_owned(), e1, e2));
+            Ok(())
+        }
+    }
+
+    /// Compares the pairs of regular files taken from `next` until it returns `None`, for the
+    /// content pass of `two_pass` and for the file threads of `compare_threads`.
+    fn run_deferred(&mut self, mut next: impl FnMut() -> Option<FilePair>) -> anyhow::Result<()> {
+        loop {
+            if self.stack.abort.load(Ordering::SeqCst) {
+                return Ok(());
+            }
+            let (dir, e1, e2) = match next() {
+                Some(pair) => pair,
+                None => return Ok(()),
+            };
+            let resolved = self.resolved_links(Some(&e1), Some(&e2));
+            let (name, reported) = (e1.filename(), self.reported);
+            if let Err(e) = self.process_files(&dir, e1, e2) {
+                self.stack.abort.store(true, Ordering::SeqCst);
+                return Err(e);
+            }
+            self.report_resolved_links(&dir, name, resolved, reported);
+        }
+    }
+
+    /// Processes two regular files of `dir` with the same name.
+    fn process_files(&mut self, dir: &Path, e1: FileT, e2: FileT) -> anyhow::Result<()> {
+        let retry = self.options.retry;
+        let e1_meta = retry.retry(|| self.fs.metadata(e1.path()))?;
+        let e2_meta = retry.retry(|| self.fs.metadata(e2.path()))?;
+        if !e1_meta.is_file() || !e2_meta.is_file() {
+            self.process_diff(Diff::RacedTypeChange(dir.to_owned(), e1.filename()));
+            return Ok(());
+        }
+        if let Some(tally) = &self.options.tally {
+            tally.file_pairs.fetch_add(1, Ordering::SeqCst);
+            if e1_meta.len() == e2_meta.len() {
+                tally.bytes.fetch_add(2 * e1_meta.len(), Ordering::SeqCst);
+            }
+            return Ok(());
+        }
+        self.stats.files_compared += 1;
+        if let Some(progress) = &self.options.progress {
+            progress.file_done();
+        }
+        // Held until all the checks opening the files are done, while diffs are reported.
+        let open_files = self.options.open_files.clone();
+        let _permit = open_files.as_ref().map(|files| files.acquire(2));
+        let mut hashes = None;
+        let (content, rule) = if self.options.print_hashes {
+            let (hash1, hash2) = match self.hash_content(&e1, &e2) {
+                Err(e) if is_type_changed(&e) => {
+                    self.process_diff(Diff::RacedTypeChange(dir.to_owned(), e1.filename()));
+                    return Ok(());
+                }
+                result => result?,
+            };
+            self.stats.bytes_read += e1_meta.len() + e2_meta.len();
+            hashes = Some((hash1, hash2));
+            if hash1 == hash2 {
+                (Content::Same, Rule::Hashes)
+            } else {
+                (Content::Different, Rule::Hashes)
+            }
+        } else if let Some(command) = &self.options.compare_cmd {
+            // Files of different sizes can be equal for the command.
+            match command.run(e1.path(), e2.path(), Some(&self.stack.abort))? {
+                Some(true) => (Content::Same, Rule::Command),
+                Some(false) => (Content::Different, Rule::Command),
+                None => (Content::NotCompared, Rule::Command),
+            }
+        } else if let Some(content) = self.compare_with_comparer(&e1, &e2)? {
+            (content, Rule::Comparer)
+        } else if e1_meta.len() != e2_meta.len()
+            && !self.options.count_diff_bytes
+            && self.options.content_type.is_none()
+        {
+            (
+                Content::Different,
+                Rule::SizesDiffer(e1_meta.len(), e2_meta.len()),
+            )
+        } else if e1_meta.is_empty() && e2_meta.is_empty() {
+            // No need to open empty files.
+            (Content::Same, Rule::Empty)
+        } else if self.options.trust_same_inode && same_inode(&e1_meta, &e2_meta) {
+            (Content::Same, Rule::SameInode)
+        } else if self.same_trusted_xattr(e1.path(), e2.path())? {
+            (Content::Same, Rule::TrustedXattr)
+        } else {
+            // Files of different sizes are only compared to count their differing bytes, or to
+            // sniff their types.
+            let len = (e1_meta.len() == e2_meta.len()).then_some(e1_meta.len());
+            let result = match self.compare_content(&e1, &e2, len) {
+                Err(e) if is_type_changed(&e) => {
+                    self.process_diff(Diff::RacedTypeChange(dir.to_owned(), e1.filename()));
+                    return Ok(());
+                }
+                result => result?,
+            };
+            self.stats.bytes_read += match result {
+                FileCompare::Equal => 2 * e1_meta.len(),
+                FileCompare::Differ {
+                    differing: Some(_), ..
+                } => e1_meta.len() + e2_meta.len(),
+                FileCompare::Differ { first_offset, .. } => 2 * first_offset,
+                FileCompare::LenDiffer
+                | FileCompare::OverBudget
+                | FileCompare::Cancelled
+                | FileCompare::TimedOut
+                | FileCompare::OtherContentType
+                | FileCompare::ContentTypesDiffer { .. } => 0,
+            };
+            let content = match result {
+                FileCompare::Equal => Content::Same,
+                FileCompare::Differ { .. } | FileCompare::ContentTypesDiffer { .. } => {
+                    Content::Different
+                }
+                // Files of different sizes, one ending where the other differs.
+                FileCompare::LenDiffer if len.is_none() => Content::Different,
+                // Both files must end where their metadata said they would.
+                FileCompare::LenDiffer => Content::Changed,
+                FileCompare::OtherContentType => Content::OtherType,
+                FileCompare::OverBudget => Content::NotCompared,
+                FileCompare::TimedOut => Content::TimedOut,
+                // The worker stops right after, but the files are not equal for all that.
+                FileCompare::Cancelled => Content::NotCompared,
+            };
+            (content, Rule::Bytes(result))
+        };
+        if self.options.explain {
+            let outcome = match content {
+                Content::Same => "equal",
+                Content::Different => "different",
+                Content::Changed => "changed during scan",
+                Content::NotCompared => "not compared",
+                Content::OtherType => "of another content type",
+                Content::TimedOut => "timed out",
+            };
+            let path = dir.join(e1.filename());
+            eprintln!("{}: {} {}", path.display(), outcome, rule);
+        }
+        if let Some((hash1, hash2)) = hashes {
+            self.process_diff(Diff::Hashes(dir.to_owned(), e1.filename(), hash1, hash2));
+        }
+        match content {
+            Content::Same => (),
+            Content::Different => {
+                let suspicious = self.options.flag_mtime_same_content_differs
+                    && matches!(rule, Rule::Bytes(FileCompare::Differ { .. }))
+                    && e1_meta.len() == e2_meta.len()
+                    && self.same_mtime(&e1_meta, &e2_meta);
+                let (dir, name) = (dir.to_owned(), e1.filename());
+                let suspicious =
+                    suspicious.then(|| Diff::SuspiciousMtimeMatch(dir.clone(), name.clone()));
+                let diff = match rule {
+                    Rule::Bytes(FileCompare::ContentTypesDiffer { type1, type2 }) => {
+                        let described = |t: Option<&str>| t.unwrap_or("unknown").to_owned();
+                        Diff::ContentTypesDiffer(dir, name, described(type1), described(type2))
+                    }
+                    Rule::Bytes(FileCompare::Differ { differing, .. }) => {
+                        Diff::Different(dir, name, differing)
+                    }
+                    _ => Diff::Different(dir, name, None),
+                };
+                self.process_diff(diff);
+                if let Some(suspicious) = suspicious {
+                    self.process_diff(suspicious);
+                }
+                return Ok(());
+            }
+            Content::Changed => {
+                self.process_diff(Diff::ChangedDuringScan(dir.to_owned(), e1.filename()));
+                return Ok(());
+            }
+            Content::NotCompared => {
+                self.process_diff(Diff::NotCompared(dir.to_owned(), e1.filename()));
+                return Ok(());
+            }
+            Content::OtherType => return Ok(()),
+            Content::TimedOut => {
+                self.process_diff(Diff::CompareTimedOut(dir.to_owned(), e1.filename()));
+                return Ok(());
+            }
+        }
+        if self.options.sparse && sparse::same_layout(e1.path(), e2.path())? == Some(false) {
+            self.process_diff(Diff::SparsenessDiffer(dir.to_owned(), e1.filename()));
+        }
+        if self.options.check_nlink {
+            if let (Some(n1), Some(n2)) = (metadata::nlink(&e1_meta), metadata::nlink(&e2_meta)) {
+                if n1 != n2 {
+                    self.process_diff(Diff::NlinkDiffer(dir.to_owned(), e1.filename(), n1, n2));
+                }
+            }
+        }
+        if self.options.check_blocks {
+            if let (Some(b1), Some(b2)) = (metadata::blocks(&e1_meta), metadata::blocks(&e2_meta)) {
+                if b1 != b2 {
+                    self.process_diff(Diff::BlocksDiffer(dir.to_owned(), e1.filename(), b1, b2));
+                }
+            }
+        }
+        if self.options.check_reflinks {
+            let path1 = e1.path();
+            let shared1 = retry.retry(|| reflink::has_shared_extents(path1))?;
+            let path2 = e2.path();
+            let shared2 = retry.retry(|| reflink::has_shared_extents(path2))?;
+            match (shared1, shared2) {
+                (Some(s1), Some(s2)) => {
+                    if s1 != s2 {
+                        let diff = Diff::ExtentSharingDiffer(dir.to_owned(), e1.filename(), s1, s2);
+                        self.process_diff(diff);
+                    }
+                }
+                _ => warn_reflinks_unsupported(&dir.join(e1.filename())),
+            }
+        }
+        if self.options.check_flags {
+            let path1 = e1.path();
+            let flags1 = retry.retry(|| flags::get(path1))?;
+            let path2 = e2.path();
+            let flags2 = retry.retry(|| flags::get(path2))?;
+            match (flags1, flags2) {
+                (Some(f1), Some(f2)) => {
+                    if f1 != f2 {
+                        self.process_diff(Diff::FlagsDiffer(dir.to_owned(), e1.filename(), f1, f2));
+                    }
+                }
+                _ => warn_flags_unsupported(&dir.join(e1.filename())),
+            }
+        }
+        if self.options.check_caps {
+            let path1 = e1.path();
+            let caps1 = retry.retry(|| xattr::get(path1, caps::XATTR))?;
+            let path2 = e2.path();
+            let caps2 = retry.retry(|| xattr::get(path2, caps::XATTR))?;
+            if caps1 != caps2 {
+                self.process_diff(Diff::CapsDiffer(
+                    dir.to_owned(),
+                    e1.filename(),
+                    caps::describe(caps1.as_deref()),
+                    caps::describe(caps2.as_deref()),
+                ));
+            }
+        }
+        if self.options.check_acls {
+            self.compare_acls(dir, &e1, &e2, false)?;
+        }
+        self.compare_metadata(
+            dir,
+            e1.filename(),
+            &e1_meta,
+            &e2_meta,
+            self.options.check_mtime,
+        )?;
+        Ok(())
+    }
+}
+
+/// Warns that flags are not compared for `path`, only for the first such file.
+fn warn_flags_unsupported(path: &Path) {
+    static WARNED: AtomicBool = AtomicBool::new(false);
+    if !WARNED.swap(true, Ordering::SeqCst) {
+        eprintln!(
+            "Warning: no support for flags on the filesystem of {}, they are not compared there",
+            path.display()
+        );
+    }
+}
+
+/// Warns that extent sharing is not compared for `path`, only for the first such file.
+fn warn_reflinks_unsupported(path: &Path) {
+    static WARNED: AtomicBool = AtomicBool::new(false);
+    if !WARNED.swap(true, Ordering::SeqCst) {
+        eprintln!(
+            "Warning: no support for reading shared extents on the filesystem of {}, \
+             reflinks are not compared there",
+            path.display()
+        );
+    }
+}
+
+/// Resolved target of a symlink, for `--resolve-symlink-targets`, or `None` if the link is broken.
+///
+/// Targets inside of `root`, which must be fully resolved, are made relative to it, so that links
+/// to the same location in both directories are equal.
+fn resolved_target(root: &Path, link: &Path) -> Option<PathBuf> {
+    let target = resolve_symlinks(link).ok()?;
+    Some(match target.strip_prefix(root) {
+        Ok(relative) => relative.to_owned(),
+        Err(_) => target,
+    })
+}
+
+/// Pairs of names of entries that only differ by case, for `--detect-case-collisions`.
+///
+/// When more than two names collide, each one is paired with the next in order.
+fn case_collisions(content: &[FileT]) -> BTreeSet<(OsString, OsString)> {
+    let mut names: Vec<_> = content
+        .iter()
+        .map(|e| {
+            let name = e.filename();
+            (name.to_string_lossy().to_lowercase(), name)
+        })
+        .collect();
+    names.sort_unstable();
+    names
+        .windows(2)
+        .filter(|w| w[0].0 == w[1].0)
+        .map(|w| (w[0].1.clone(), w[1].1.clone()))
+        .collect()
+}
+
+/// Pairs the entries of two directories by name, but for the `renames` of the first
+/// directory's entries, given as pairs of names in the first and second directory.
+///
+/// Each pair holds at least one entry.
+fn pair_entries(
+    mut content1: Vec<FileT>,
+    mut content2: Vec<FileT>,
+    renames: &[(OsString, OsString)],
+) -> Vec<(Option<FileT>, Option<FileT>)> {
+    // Taken out first, so that they are never paired with an entry of the same name.
+    let mut renamed = Vec::with_capacity(renames.len());
+    for (old, new) in renames {
+        let take = |content: &mut Vec<FileT>, name: &OsString| {
+            let i = content.iter().position(|e| e.filename() == *name)?;
+            Some(content.swap_remove(i))
+        };
+        let pair = (take(&mut content1, old), take(&mut content2, new));
+        if pair.0.is_some() || pair.1.is_some() {
+            renamed.push(pair);
+        }
+    }
+    content1.sort_unstable_by_key(|e| e.filename());
+    content2.sort_unstable_by_key(|e| e.filename());
+    let mut pairs = Vec::with_capacity(std::cmp::max(content1.len(), content2.len()));
+    let mut content1 = content1.into_iter().peekable();
+    let mut content2 = content2.into_iter().peekable();
+    loop {
+        let order = match (content1.peek(), content2.peek()) {
+            (None, None) => {
+                pairs.append(&mut renamed);
+                return pairs;
+            }
+            (Some(_), None) => std::cmp::Ordering::Less,
+            (None, Some(_)) => std::cmp::Ordering::Greater,
+            (Some(e1), Some(e2)) => e1.filename().cmp(&e2.filename()),
+        };
+        pairs.push(match order {
+            std::cmp::Ordering::Less => (content1.next(), None),
+            std::cmp::Ordering::Greater => (None, content2.next()),
+            std::cmp::Ordering::Equal => (content1.next(), content2.next()),
+        });
+    }
+}
+
+/// Diffs `dir1` and `dir2` using `n_threads` workers, reporting each diff to `diff_handler`.
+///
+/// Returns the totals of the comparison once it is over.
+pub fn diff_dirs<H: DiffHandler + Send + Sync + 'static>(
+    dir1: PathBuf,
+    dir2: PathBuf,
+    diff_handler: Arc<H>,
+    n_threads: u16,
+    options: &DiffOptions,
+) -> anyhow::Result<Stats> {
+    diff_dirs_until(
+        Arc::new(RealFs),
+        dir1,
+        dir2,
+        diff_handler,
+        n_threads,
+        options,
+        Default::default(),
+    )
+}
+
+/// Same as [`diff_dirs`], reading `dir1` and `dir2` from `fs`.
+///
+/// Only the listing of directories, the types, sizes and mtimes of entries, the content of
+/// regular files and the targets of symlinks are read through `fs`, so that the options reading
+/// more than that from the real filesystem are not supported, even with [`RealFs`]: setting one
+/// of them is an error.
+///
+/// # Example
+///
+/// ```
+/// use dirdiff::{diff_dirs_in, ChannelHandler, Diff, DiffOptions, MemoryFs};
+/// use std::sync::{mpsc::channel, Arc};
+///
+/// let fs = MemoryFs::new();
+/// fs.add_file("dir1/file", "a");
+/// fs.add_file("dir2/file", "b");
+/// let (sender, receiver) = channel();
+/// let handler = Arc::new(ChannelHandler::new(sender));
+/// let options = DiffOptions::default();
+/// let stats = diff_dirs_in(Arc::new(fs), "dir1".into(), "dir2".into(), handler, 4, &options)?;
+/// assert_eq!(stats.different, 1);
+/// assert!(matches!(receiver.recv()?, Diff::Different(..)));
+/// # Ok::<(), anyhow::Error>(())
+/// ```
+pub fn diff_dirs_in<F: FileSystem, H: DiffHandler + Send + Sync + 'static>(
+    fs: Arc<F>,
+    dir1: PathBuf,
+    dir2: PathBuf,
+    diff_handler: Arc<H>,
+    n_threads: u16,
+    options: &DiffOptions,
+) -> anyhow::Result<Stats> {
+    check_fs_options(options)?;
+    diff_dirs_until(
+        fs,
+        dir1,
+        dir2,
+        diff_handler,
+        n_threads,
+        options,
+        Default::default(),
+    )
+}
+
+/// Fails if options reading the trees other than through a [`FileSystem`] are set.
+fn check_fs_options(options: &DiffOptions) -> anyhow::Result<()> {
+    let unsupported = [
+        (options.perms, "perms"),
+        (options.owner, "owner"),
+        (options.check_nlink, "check_nlink"),
+        (options.check_blocks, "check_blocks"),
+        (options.trust_same_inode, "trust_same_inode"),
+        (options.check_flags, "check_flags"),
+        (options.check_caps, "check_caps"),
+        (options.check_acls, "check_acls"),
+        (options.check_reflinks, "check_reflinks"),
+        (options.follow_symlink, "follow_symlink"),
+        (options.follow_inside_roots, "follow_inside_roots"),
+        (options.find_duplicates, "find_duplicates"),
+        (options.sparse, "sparse"),
+        (options.trust_xattr.is_some(), "trust_xattr"),
+        (options.resolve_symlink_targets, "resolve_symlink_targets"),
+        (options.compare_cmd.is_some(), "compare_cmd"),
+        (!options.comparers.is_empty(), "comparers"),
+        (options.secure, "secure"),
+    ];
+    for (set, name) in unsupported {
+        if set {
+            bail!("Option {} is not supported with a FileSystem", name);
+        }
+    }
+    Ok(())
+}
+
+/// Same as [`diff_dirs_in`], but stops early once `abort` is set.
+fn diff_dirs_until<F: FileSystem, H: DiffHandler + Send + Sync + 'static>(
+    fs: Arc<F>,
+    dir1: PathBuf,
+    dir2: PathBuf,
+    diff_handler: Arc<H>,
+    n_threads: u16,
+    options: &DiffOptions,
+    abort: Arc<AtomicBool>,
+) -> anyhow::Result<Stats> {
+    if options.dir_changed_only && (options.two_pass || options.compare_threads > 0) {
+        bail!("dir_changed_only cannot be combined with two_pass and compare_threads");
+    }
+    if options.checkpoint.is_some()
+        && (options.sorted
+            || options.two_pass
+            || options.compare_threads > 0
+            || options.find_duplicates)
+    {
+        bail!("A checkpoint cannot be combined with sorted, two_pass, compare_threads and find_duplicates");
+    }
+    if options.shallowest_diff {
+        if options.traversal != Traversal::BreadthFirst {
+            bail!("shallowest_diff requires a breadth-first traversal");
+        }
+        if options.sorted || options.two_pass || options.find_duplicates {
+            bail!("shallowest_diff cannot be combined with sorted, two_pass and find_duplicates");
+        }
+    }
+    let shallowest = options.shallowest_diff.then(|| Arc::new(Shallowest::new()));
+    // An empty tree is never listed, nor are its symlinks paired.
+    let canonical = |dir: &Path, empty| {
+        if empty {
+            Ok(dir.to_owned())
+        } else {
+            resolve_symlinks(dir)
+        }
+    };
+    let canonical_roots = if options.resolve_symlink_targets || options.follow_inside_roots {
+        Some((
+            canonical(&dir1, options.empty_first)?,
+            canonical(&dir2, options.empty_second)?,
+        ))
+    } else {
+        None
+    };
+    let timed_out = Arc::new(AtomicBool::new(false));
+    // Dropped once the workers are done, which ends the watchdog early.
+    let watchdog = options.timeout.map(|timeout| {
+        let (done, wait) = channel::<()>();
+        let (abort, timed_out) = (abort.clone(), timed_out.clone());
+        thread::spawn(move || {
+            if wait.recv_timeout(timeout) == Err(RecvTimeoutError::Timeout) {
+                timed_out.store(true, Ordering::SeqCst);
+                abort.store(true, Ordering::SeqCst);
+            }
+        });
+        done
+    });
+    let mut compare_joins = Vec::new();
+    let compare_queue = if options.compare_threads > 0 && !options.two_pass {
+        let (sender, receiver) = sync_channel(2 * options.compare_threads as usize);
+        let receiver = Arc::new(Mutex::new(receiver));
+        // Their stacks stay empty, only their abort flag is used.
+        let stack_handlers =
+            StackHandle::new(options.compare_threads, options.traversal, abort.clone());
+        for sh in stack_handlers {
+            let mut worker = DirWorker::new(
+                fs.clone(),
+                dir1.clone(),
+                dir2.clone(),
+                canonical_roots.clone(),
+                diff_handler.clone(),
+                sh,
+                options.clone(),
+            );
+            worker.shallowest = shallowest.clone();
+            let receiver = Arc::clone(&receiver);
+            // Receiving fails once all the directory workers are done and dropped their sender.
+            let next = move || receiver.lock().unwrap().recv().ok();
+            compare_joins.push(thread::spawn(move || {
+                worker.run_deferred(next).map(|()| worker)
+            }));
+        }
+        Some(sender)
+    } else {
+        None
+    };
+    let stack_handlers = StackHandle::new(n_threads, options.traversal, abort);
+    let mut first = true;
+    let mut joins = Vec::new();
+    for sh in stack_handlers {
+        let mut worker = DirWorker::new(
+            fs.clone(),
+            dir1.clone(),
+            dir2.clone(),
+            canonical_roots.clone(),
+            diff_handler.clone(),
+            sh,
+            options.clone(),
+        );
+        worker.compare_queue = compare_queue.clone();
+        worker.shallowest = shallowest.clone();
+        if first {
+            worker.push_to_stack(PathBuf::new());
+            first = false;
+        }
+        joins.push(thread::spawn(move || {
+            let result = worker.run();
+            worker.compare_queue = None;
+            result.map(|()| worker)
+        }));
+    }
+    drop(compare_queue);
+    let mut workers = Vec::new();
+    for j in joins.into_iter().chain(compare_joins) {
+        workers.push(j.join().unwrap()?);
+    }
+    if options.two_pass {
+        // All the structural diffs are found once all the workers are done with the directories.
+        let deferred: Vec<_> = workers
+            .iter_mut()
+            .flat_map(|worker| worker.deferred.drain(..))
+            .collect();
+        diff_handler.structure_done();
+        let queue = Arc::new(Mutex::new(deferred.into_iter()));
+        let mut joins = Vec::new();
+        for mut worker in workers {
+            let queue = Arc::clone(&queue);
+            joins.push(thread::spawn(move || {
+                worker
+                    .run_deferred(|| queue.lock().unwrap().next())
+                    .map(|()| worker)
+            }));
+        }
+        workers = Vec::new();
+        for j in joins {
+            workers.push(j.join().unwrap()?);
+        }
+    }
+    drop(watchdog);
+    let mut stats = Stats::default();
+    let (mut only1, mut only2) = (Vec::new(), Vec::new());
+    for worker in &mut workers {
+        stats.add(&worker.stats);
+        only1.append(&mut worker.single_side.0);
+        only2.append(&mut worker.single_side.1);
+    }
+    let found: Vec<_> = workers.into_iter().map(DirWorker::into_sorted).collect();
+    if options.sorted {
+        match options.collation {
+            Collation::Byte => merge_sorted(found, |diff| diff_handler.process(&dir1, &dir2, diff)),
+            collation => {
+                let mut found: Vec<_> = found.into_iter().flatten().collect();
+                // The sort is stable, and all the diffs about an entry come from the same list.
+                found.sort_by(|(path1, _), (path2, _)| collation.compare(path1, path2));
+                for (_, diff) in found {
+                    diff_handler.process(&dir1, &dir2, diff);
+                }
+            }
+        }
+    }
+    if timed_out.load(Ordering::SeqCst) {
+        return Err(TimedOut.into());
+    }
+    if let Some(diff) = shallowest.and_then(|shallowest| shallowest.diff.lock().unwrap().take()) {
+        diff_handler.process(&dir1, &dir2, diff);
+    }
+    // An empty tree has no file for the other one to duplicate.
+    if options.find_duplicates && !options.empty_first && !options.empty_second {
+        let opts = CompareOpts {
+            retry: options.content_retry,
+            fadvise: options.fadvise,
+            ..Default::default()
+        };
+        let duplicates =
+            duplicates::find(&dir1, &dir2, only1, only2, options.follow_symlink, &opts)
+                .context("Couldn't look for duplicates")?;
+        for diff in duplicates {
+            diff_handler.process(&dir1, &dir2, diff);
+        }
+    }
+    Ok(stats)
+}
+
+/// Merges lists of diffs sorted by path, passing the diffs to `process` in order.
+///
+/// All the diffs about an entry are found by the same worker, so they are in the same list,
+/// and their order is kept.
+fn merge_sorted(lists: Vec<Vec<(PathBuf, Diff)>>, mut process: impl FnMut(Diff)) {
+    let mut lists: Vec<_> = lists.into_iter().map(|list| list.into_iter()).collect();
+    let mut heads = BinaryHeap::new();
+    let mut pending = Vec::with_capacity(lists.len());
+    for (i, list) in lists.iter_mut().enumerate() {
+        if let Some((path, diff)) = list.next() {
+            heads.push(Reverse((path, i)));
+            pending.push(Some(diff));
+        } else {
+            pending.push(None);
+        }
+    }
+    while let Some(Reverse((_, i))) = heads.pop() {
+        if let Some(diff) = pending[i].take() {
+            process(diff);
+        }
+        if let Some((path, diff)) = lists[i].next() {
+            heads.push(Reverse((path, i)));
+            pending[i] = Some(diff);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Procfs is not a known filesystem, and missing roots have none.
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn unknown_mtime_granularity() {
+        assert_eq!(MtimeGranularity::detect(Path::new("/proc")), None);
+        assert_eq!(MtimeGranularity::detect(Path::new("/missing/root")), None);
+    }
+
+    #[test]
+    fn merge_sorted_lists() {
+        let diff = |dir: &str, file: &str| Diff::InDir1Only(dir.into(), file.into());
+        let lists = vec![
+            vec![
+                diff("", "b"),
+                diff("d", "a"),
+                diff("d", "a"),
+                diff("d", "c"),
+            ],
+            vec![],
+            vec![diff("", "a"), diff("", "c"), diff("d", "b")],
+            vec![diff("", "d")],
+        ];
+        // Sorting all the diffs at once, as a single buffer shared by the workers would.
+        let mut expected: Vec<_> = lists.iter().flatten().map(Diff::path).collect();
+        expected.sort();
+        let lists = lists
+            .into_iter()
+            .map(|list| list.into_iter().map(|d| (d.path(), d)).collect())
+            .collect();
+        let mut merged = Vec::new();
+        merge_sorted(lists, |diff| merged.push(diff.path()));
+        assert_eq!(merged, expected);
+    }
+
+    #[test]
+    fn dir_timings() {
+        let timings = DirTimings::new(2);
+        let ms = Duration::from_millis;
+        timings.record(PathBuf::new(), 3, ms(20));
+        timings.record("fast".into(), 1, ms(10));
+        timings.record("slow".into(), 50, ms(1500));
+        timings.record("faster".into(), 1, ms(5));
+        assert_eq!(
+            timings.to_string(),
+            "Slowest directories:\n1.500s\t50 entries\tslow\n0.020s\t3 entries\t."
+        );
+    }
+
+    #[test]
+    fn shallowest() {
+        let shallowest = Shallowest::new();
+        assert!(!shallowest.too_deep(Path::new("a/b/c")));
+        shallowest.offer(Diff::InDir1Only("a/b".into(), "f".into()));
+        // Entries of `a/b` are at the depth of the diff kept, which is not replaced by them.
+        assert!(shallowest.too_deep(Path::new("a/b")));
+        assert!(!shallowest.too_deep(Path::new("a")));
+        shallowest.offer(Diff::InDir1Only("a".into(), "g".into()));
+        shallowest.offer(Diff::InDir2Only("b".into(), "h".into()));
+        shallowest.offer(Diff::InDir2Only("".into(), "i".into()));
+        assert!(shallowest.too_deep(Path::new("")));
+        assert_eq!(
+            shallowest.diff.lock().unwrap().as_ref().map(Diff::path),
+            Some(PathBuf::from("i"))
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn resolve_symlinks_of_arguments() {
+        use std::os::unix::fs::symlink;
+        let dir = std::env::temp_dir().join(format!("dirdiff-resolve-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(dir.join("tree/sub")).unwrap();
+        let tree = dir.join("tree").canonicalize().unwrap();
+        // A link to a link to the tree is resolved completely.
+        symlink(&tree, dir.join("link")).unwrap();
+        symlink(dir.join("link"), dir.join("link2")).unwrap();
+        assert_eq!(resolve_symlinks(&dir.join("link2")).unwrap(), tree);
+        // So are links in the parent directories of the path.
+        assert_eq!(
+            resolve_symlinks(&dir.join("link2/sub")).unwrap(),
+            tree.join("sub")
+        );
+        assert_eq!(strip_verbatim(tree.clone()), tree);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[cfg(windows)]
+    #[test]
+    fn strip_verbatim_prefix() {
+        assert_eq!(
+            strip_verbatim(PathBuf::from(r"\\?\C:\Users\dir")),
+            PathBuf::from(r"C:\Users\dir")
+        );
+        assert_eq!(
+            strip_verbatim(PathBuf::from(r"\\?\UNC\server\share\dir")),
+            PathBuf::from(r"\\server\share\dir")
+        );
+        // Kept when the path would be too long without it.
+        let long = format!(r"\\?\C:\{}", "x".repeat(300));
+        assert_eq!(strip_verbatim(PathBuf::from(&long)), PathBuf::from(&long));
+        let dir = std::env::temp_dir();
+        let resolved = resolve_symlinks(&dir).unwrap();
+        assert!(!resolved.to_str().unwrap().starts_with(r"\\?\"));
+        assert_eq!(resolved, strip_verbatim(dir.canonicalize().unwrap()));
+    }
+}
@@ -4,8 +4,8 @@
 //!
 //! By default, **dirdiff** treats symbolic links as a third file type along with regular files and directories.
 //! A symbolic link can only be equal to another symbolic link that has the same name and points to files with the
-//! same name and location relative to the link itself. At this stage link couldn't be compared to other files
-//! or directories.
+//! same name and location relative to the link itself. A symbolic link and another type of file with the same name
+//! are reported as `[Types differ]`.
 //!
 //! **For example:**
 //! ```
@@ -61,423 +61,4222 @@
 //! separately.
 //!
 //! When the *-H* option is passed, the program arguments are unwinded only if they contain a link.
+//!
+//! ## Symbolic links resolution
+//!
+//! Both *-H* (for the arguments) and *-L* (for the entries met while traversing the directories) resolve
+//! links in the same way: every link is resolved completely, whatever the number of levels, so that a link to a
+//! link to a file is treated as that file. Links appearing in the parent directories of the resolved path are
+//! resolved as well.
+//!
+//! An argument that is itself a link to a directory is always traversed, even without *-H*, since reading it
+//! follows the link. *-H* replaces it by its fully resolved path before the traversal starts, and *-L* does not
+//! resolve the arguments a second time: it only applies to their content.
+//!
+//! On Windows, resolved paths are shown without the `\\?\` prefix whenever they can be expressed without it.
+//!
+//! ## Windows junctions
+//!
+//! On Windows, directory junctions are handled like symbolic links to directories: by default they are compared
+//! to other links by their target, and under *-L* they are followed. Other kinds of reparse points, like
+//! deduplicated or cloud placeholder files, are treated as the regular files or directories they stand for.
+//! Targets are compared without their `\\?\` prefix, so that a junction and a symbolic link to the same
+//! absolute path are equal.
+//!
+//! ## Files modified during the comparison
+//!
+//! When a file turns out not to have the size read from its metadata, it was modified while it was being compared:
+//! it is reported as `[Changed during scan]` rather than as equal or different. This detection is best-effort, a file
+//! modified without changing its size, or before its metadata are read, goes unnoticed.
 
 use anyhow::bail;
 use anyhow::Context;
-use clap::Parser;
-use crossbeam_deque::{Steal, Stealer, Worker};
-use crossbeam_utils::Backoff;
-use std::fs::canonicalize;
-use std::fs::{read_link, DirEntry, Metadata};
-use std::sync::atomic::AtomicBool;
+use clap::{ArgGroup, Parser, ValueEnum};
+use dirdiff::{
+    diff_dirs, diff_trees, manifest, resolve_symlinks, set_locale_from_env, Checkpoint, Collation,
+    CompareCommand, Comparers, ContentComparer, ContentType, Diff, DiffHandler, DiffOptions,
+    DirTimings, EntryType, Gzip, MtimeGranularity, OpenFiles, PathSelection, Progress, RawBytes,
+    RenameMap, RetryPolicy, Sha256, Side, SortedLines, TimedOut, Traversal, Tree, UnicodeCaseless,
+    DIFF_KINDS,
+};
 use std::{
-    ffi::OsString,
+    collections::{BTreeMap, BTreeSet},
+    ffi::{OsStr, OsString},
     fs::{read_dir, File},
-    io::{BufRead, BufReader},
+    io::{self, BufRead, BufReader, BufWriter, ErrorKind, Stdout, Write},
     path::{Path, PathBuf},
+    process::ExitCode,
     sync::{
-        atomic::{AtomicU16, Ordering},
-        Arc,
+        atomic::{AtomicU64, Ordering},
+        mpsc::{channel, RecvTimeoutError, Sender},
+        Arc, Mutex,
     },
     thread,
+    time::{Duration, Instant},
 };
 
-mod file_type_enum;
-use file_type_enum::FileType;
+mod fingerprint;
+use fingerprint::{bytes, fingerprint, path_id};
+mod itemize;
+use itemize::itemized;
+mod json;
+mod merkle;
+mod owners;
+mod quote;
+mod sqlite;
+use quote::{escaped, quoted};
+mod sync;
+use sync::{Action, ApplyOpts};
+mod template;
+use template::Template;
+
+/// How the paths of entries are printed.
+#[derive(Debug, Clone)]
+struct PathStyle {
+    /// Join paths with the root they are in, rather than printing them relative to the roots.
+    full: bool,
+    /// Removed from the start of relative paths, when they start with it.
+    strip_prefix: Option<PathBuf>,
+    /// Prepended to relative paths, after `strip_prefix` is removed.
+    prefix: Option<PathBuf>,
+    /// Directory the printed paths are relative to, canonicalized, rather than to the roots.
+    relative_base: Option<PathBuf>,
+    /// The roots of the pair being diffed, along with their paths relative to `relative_base`.
+    rebased_roots: Vec<(PathBuf, PathBuf)>,
+}
+
+impl PathStyle {
+    /// Same style, for the pair of `root1` and `root2`: fails if `relative_base` is set and a
+    /// root cannot be made relative to it.
+    fn for_roots(&self, root1: &Path, root2: &Path) -> anyhow::Result<Self> {
+        let mut style = self.clone();
+        let base = match &self.relative_base {
+            Some(base) => base,
+            None => return Ok(style),
+        };
+        for root in [root1, root2] {
+            // The empty tree has no entries to print.
+            if root == Path::new(EMPTY_TREE) {
+                continue;
+            }
+            let canonical = root
+                .canonicalize()
+                .with_context(|| format!("Couldn't resolve {}", root.display()))?;
+            let rebased = match relative_to(&canonical, base) {
+                Some(rebased) => rebased,
+                None => bail!(
+                    "Cannot print the paths of {} relative to {}",
+                    root.display(),
+                    base.display()
+                ),
+            };
+            style.rebased_roots.push((root.to_owned(), rebased));
+        }
+        Ok(style)
+    }
+
+    /// Path to print for the entry at `path`, relative to the roots, found in `root`.
+    fn printed(&self, root: &Path, path: &Path) -> PathBuf {
+        if self.full {
+            return root.join(path);
+        }
+        if let Some((_, rebased)) = self.rebased_roots.iter().find(|(r, _)| r == root) {
+            return rebased.join(path);
+        }
+        let path = match &self.strip_prefix {
+            // Only whole components are stripped.
+            Some(strip_prefix) => path.strip_prefix(strip_prefix).unwrap_or(path),
+            None => path,
+        };
+        match &self.prefix {
+            Some(prefix) => prefix.join(path),
+            None => path.to_owned(),
+        }
+    }
+}
 
-struct StackUnit {
-    dir: PathBuf,
+/// Path leading from `base` to `path`, both absolute, going up with `..` as needed, or `None`
+/// if they do not share a root, like paths on different Windows drives.
+fn relative_to(path: &Path, base: &Path) -> Option<PathBuf> {
+    let mut components = path.components().peekable();
+    let mut base_components = base.components().peekable();
+    let mut common = 0;
+    while let (Some(c), Some(b)) = (components.peek(), base_components.peek()) {
+        if c != b {
+            break;
+        }
+        components.next();
+        base_components.next();
+        common += 1;
+    }
+    if common == 0 {
+        return None;
+    }
+    let mut relative: PathBuf = base_components.map(|_| "..").collect();
+    relative.extend(components);
+    Some(relative)
 }
 
-/// Extraction of useful metadata for iterated files.
-#[derive(Debug)]
-struct FileT {
-    entry: DirEntry,
-    file_type: FileType,
-    path: Option<PathBuf>,
+struct GrepableHandler {
+    /// Printed before each diff, to tell apart the pairs of a `--pairs-from` run.
+    prefix: Option<String>,
+    paths: PathStyle,
+    /// Format of the records, replacing the default one.
+    template: Option<Arc<Template>>,
+    /// Tags replacing the default ones, by kind of diff.
+    tags: Arc<BTreeMap<&'static str, String>>,
+    /// Diffs tagged `Expected diff`.
+    expected: Arc<ExpectedDiffs>,
+    /// How the paths which are not valid UTF-8 are written, if set.
+    non_utf8: Option<NonUtf8>,
 }
 
-impl FileT {
-    /// Create new extraction from directory entry.
-    ///
-    /// It will check file type, and if [follow_link] flag is set and passed entry points to the symbolic link,
-    /// then path and type of target file are cached.
-    fn new(entry: DirEntry, follow_link: bool) -> Self {
-        let mut file_type = entry.file_type().unwrap();
-        if follow_link && file_type.is_symlink() {
-            let mut path = entry.path();
-            path = canonicalize(&path)
-                .with_context(|| format!("Error while following link {}", &path.display()))
-                .unwrap();
-            file_type = path.metadata().unwrap().file_type();
-            FileT {
-                entry,
-                file_type: file_type.into(),
-                path: Some(path),
+impl GrepableHandler {
+    fn new(
+        prefix: Option<String>,
+        paths: PathStyle,
+        template: Option<Arc<Template>>,
+        tags: Arc<BTreeMap<&'static str, String>>,
+        expected: Arc<ExpectedDiffs>,
+        non_utf8: Option<NonUtf8>,
+    ) -> Self {
+        Self {
+            prefix,
+            paths,
+            template,
+            tags,
+            expected,
+            non_utf8,
+        }
+    }
+
+    /// `path`, the path of a record, as written between quotes if `quote` is set, or in the
+    /// placeholders of a template otherwise.
+    fn path_text(&self, path: &Path, quote: bool) -> Vec<u8> {
+        let name = path.as_os_str();
+        let mode = match (name.to_str(), self.non_utf8) {
+            (Some(_), _) if quote => NonUtf8::Escape,
+            (Some(_), _) => NonUtf8::Bytes,
+            // By default, escaped in records, and replaced in templates as by `Path::display`.
+            (None, None) if quote => NonUtf8::Escape,
+            (None, None) => NonUtf8::Lossy,
+            (None, Some(mode)) => mode,
+        };
+        let text = match mode {
+            NonUtf8::Escape | NonUtf8::Skip => escaped(name).into_bytes(),
+            NonUtf8::Lossy if quote => escaped(OsStr::new(&*name.to_string_lossy())).into_bytes(),
+            NonUtf8::Lossy => name.to_string_lossy().into_owned().into_bytes(),
+            NonUtf8::Bytes => bytes(name).into_owned(),
+        };
+        match quote {
+            true => [&b"\""[..], &text, b"\""].concat(),
+            false => text,
+        }
+    }
+}
+
+/// Tags of the grepable output, by kind of diff, unless replaced by the `--tag-*` options.
+const GREPABLE_TAGS: &[(&str, &str)] = &[
+    ("different", "Files differ"),
+    ("only1", "Present in first dir. only"),
+    ("only2", "Present in second dir. only"),
+    ("mtime", "Differ by mtime only"),
+    ("sparseness", "Differ by holes only"),
+    ("changed", "Changed during scan"),
+    ("perms", "Permissions differ"),
+    ("owner", "Owner differs"),
+    ("type", "Types differ"),
+    ("nlink", "Link counts differ"),
+    ("case_collision", "Case collision"),
+    ("entries", "Entries differ"),
+    ("flags", "Flags differ"),
+    ("vanished", "Vanished during scan"),
+    ("blocks", "Blocks differ"),
+    ("not_compared", "Not compared"),
+    ("resolved", "Resolved links"),
+    ("path_too_long", "Path too long"),
+    ("moved", "Moved or renamed"),
+    ("caps", "Capabilities differ"),
+    ("dir_changed", "Directory changed"),
+    ("newer", "Newer in first dir."),
+    ("link_target", "Link targets differ"),
+    ("acl", "ACLs differ"),
+    ("raced_type", "Type changed during scan"),
+    ("possible_rename", "Possibly renamed"),
+    ("content_type", "Content types differ"),
+    ("extent_sharing", "Extent sharing differs"),
+    ("compare_timed_out", "Comparison timed out"),
+    ("suspicious_mtime", "Differ despite same mtime"),
+];
+
+/// Default tag of the diffs of kind `kind` in the grepable output.
+fn grepable_tag(kind: &str) -> &'static str {
+    GREPABLE_TAGS
+        .iter()
+        .find(|(k, _)| *k == kind)
+        .map(|(_, tag)| *tag)
+        .expect("all the kinds have a tag")
+}
+
+/// Describes the type of an entry, along with where it resolved to if it is a followed symlink.
+fn described(entry_type: &EntryType) -> String {
+    match entry_type.resolved() {
+        Some(resolved) => format!(
+            "symbolic link to a {} ({})",
+            entry_type,
+            quoted(resolved.as_os_str())
+        ),
+        None => entry_type.to_string(),
+    }
+}
+
+impl DiffHandler for GrepableHandler {
+    fn process(&self, root1: &Path, root2: &Path, diff: Diff) {
+        let kind = diff.kind();
+        let expected = self.expected.contains(&diff);
+        // Full paths point to the first dir., unless the file is only in the second one.
+        let mut detail = None;
+        let (root, dir, file) = match diff {
+            Diff::DifferentMTime(dir, file) => {
+                detail = Some("by mtime".to_owned());
+                (root1, dir, file)
             }
-        } else {
-            FileT {
-                entry,
-                file_type: file_type.into(),
-                path: None,
+            Diff::Different(dir, file, differing) => {
+                detail = differing.map(|differing| format!("in {} bytes", differing));
+                (root1, dir, file)
+            }
+            Diff::InDir1Only(dir, file) => (root1, dir, file),
+            Diff::InDir2Only(dir, file) => (root2, dir, file),
+            Diff::SameButDifferentMTime(dir, file) => (root1, dir, file),
+            Diff::SparsenessDiffer(dir, file) => (root1, dir, file),
+            Diff::ChangedDuringScan(dir, file) => (root1, dir, file),
+            Diff::RacedTypeChange(dir, file) => (root1, dir, file),
+            Diff::NotCompared(dir, file) => (root1, dir, file),
+            Diff::CompareTimedOut(dir, file) => (root1, dir, file),
+            Diff::SuspiciousMtimeMatch(dir, file) => (root1, dir, file),
+            Diff::PathTooLong(dir, file) => (root1, dir, file),
+            Diff::DirChanged(dir, file) => (root1, dir, file),
+            Diff::NewerInFirst(dir, file) => (root1, dir, file),
+            Diff::PermsDiffer(dir, file) => (root1, dir, file),
+            Diff::OwnerDiffer(dir, file, (uid1, gid1), (uid2, gid2)) => {
+                detail = Some(format!(
+                    "is owned by {} in first dir. while by {} in second dir.",
+                    owners::owner(uid1, gid1),
+                    owners::owner(uid2, gid2)
+                ));
+                (root1, dir, file)
+            }
+            Diff::TypeMismatch(dir, file, type1, type2) => {
+                detail = Some(format!(
+                    "is a {} in first dir. while a {} in second dir.",
+                    described(&type1),
+                    described(&type2)
+                ));
+                (root1, dir, file)
+            }
+            Diff::NlinkDiffer(dir, file, n1, n2) => {
+                detail = Some(format!(
+                    "has {} links in first dir. while {} in second dir.",
+                    n1, n2
+                ));
+                (root1, dir, file)
+            }
+            Diff::CaseCollision(dir, file, other) => {
+                detail = Some(format!("collides with {}", quoted(&other)));
+                (root1, dir, file)
+            }
+            Diff::EntriesDiffer(dir, file, n1, n2) => {
+                detail = Some(format!(
+                    "has {} entries in first dir. while {} in second dir.",
+                    n1, n2
+                ));
+                (root1, dir, file)
+            }
+            Diff::FlagsDiffer(dir, file, f1, f2) => {
+                detail = Some(format!(
+                    "has flags {:#x} in first dir. while {:#x} in second dir.",
+                    f1, f2
+                ));
+                (root1, dir, file)
+            }
+            Diff::BlocksDiffer(dir, file, b1, b2) => {
+                detail = Some(format!(
+                    "has {} blocks in first dir. while {} in second dir.",
+                    b1, b2
+                ));
+                (root1, dir, file)
+            }
+            Diff::ExtentSharingDiffer(dir, file, shared1, _) => {
+                let (with, without) = match shared1 {
+                    true => ("first", "second"),
+                    false => ("second", "first"),
+                };
+                detail = Some(format!(
+                    "shares extents in {} dir. while not in {} dir.",
+                    with, without
+                ));
+                (root1, dir, file)
+            }
+            Diff::ResolvedLinks(dir, file, resolved1, resolved2) => {
+                let (text, root) = match (resolved1, resolved2) {
+                    (Some(p1), Some(p2)) => (
+                        format!(
+                            "resolves to {} in first dir. while to {} in second dir.",
+                            p1.display(),
+                            p2.display()
+                        ),
+                        root1,
+                    ),
+                    (Some(p1), None) => {
+                        (format!("resolves to {} in first dir.", p1.display()), root1)
+                    }
+                    (None, Some(p2)) => (
+                        format!("resolves to {} in second dir.", p2.display()),
+                        root2,
+                    ),
+                    (None, None) => unreachable!("resolved links have at least one side"),
+                };
+                detail = Some(text);
+                (root, dir, file)
+            }
+            Diff::CapsDiffer(dir, file, caps1, caps2) => {
+                detail = Some(format!(
+                    "are {} in first dir. while {} in second dir.",
+                    caps1, caps2
+                ));
+                (root1, dir, file)
+            }
+            Diff::AclDiffer(dir, file, acl1, acl2) => {
+                detail = Some(format!(
+                    "are {} in first dir. while {} in second dir.",
+                    acl1, acl2
+                ));
+                (root1, dir, file)
+            }
+            Diff::ContentTypesDiffer(dir, file, type1, type2) => {
+                detail = Some(format!(
+                    "is {} in first dir. while {} in second dir.",
+                    type1, type2
+                ));
+                (root1, dir, file)
+            }
+            Diff::LinkTargetsDiffer(dir, file, target1, target2) => {
+                detail = Some(format!(
+                    "{} -> {}",
+                    quoted(target1.as_os_str()),
+                    quoted(target2.as_os_str())
+                ));
+                (root1, dir, file)
             }
+            Diff::ContentMovedOrRenamed(dir, file, other) => {
+                let other = self.paths.printed(root2, &other);
+                detail = Some(format!(
+                    "has the same content as {}",
+                    quoted(other.as_os_str())
+                ));
+                (root1, dir, file)
+            }
+            Diff::Hashes(dir, file, hash1, hash2) => {
+                // Like `sha256sum`, rather than tagged like the diffs.
+                let p = self.paths.printed(root1, &dir.join(file));
+                let (hash1, hash2) = (manifest::hex(&hash1), manifest::hex(&hash2));
+                match &self.prefix {
+                    Some(prefix) => println!("{}\t{} {} {}", prefix, hash1, hash2, p.display()),
+                    None => println!("{} {} {}", hash1, hash2, p.display()),
+                }
+                return;
+            }
+            Diff::PossibleRename(dir, file, name2, distance) => {
+                let other = self.paths.printed(root2, &dir.join(name2));
+                detail = Some(format!(
+                    "as {}, {} edits away",
+                    quoted(other.as_os_str()),
+                    distance
+                ));
+                (root1, dir, file)
+            }
+            Diff::Vanished(dir, file, Side::First) => {
+                detail = Some("from first dir.".to_owned());
+                (root1, dir, file)
+            }
+            Diff::Vanished(dir, file, Side::Second) => {
+                detail = Some("from second dir.".to_owned());
+                (root2, dir, file)
+            }
+        };
+        let diff_type = match self.tags.get(kind) {
+            _ if expected => "Expected diff",
+            Some(tag) => tag,
+            None => grepable_tag(kind),
+        };
+        let mut path = dir;
+        path.push(file);
+        let p = self.paths.printed(root, &path);
+        if self.non_utf8 == Some(NonUtf8::Skip) && p.to_str().is_none() {
+            eprintln!(
+                "Warning: skipped a diff about {}, whose path is not valid UTF-8",
+                quoted(p.as_os_str())
+            );
+            return;
+        }
+        let mut record = match &self.prefix {
+            Some(prefix) => format!("{}\t", prefix).into_bytes(),
+            None => Vec::new(),
+        };
+        if let Some(template) = &self.template {
+            record.extend(template.render(diff_type, &path, &p, root1, root2, |path| {
+                self.path_text(path, false)
+            }));
+        } else {
+            let detail = detail.map(|d| format!("\t{}", d)).unwrap_or_default();
+            record.extend(format!("[{}]\t", diff_type).into_bytes());
+            record.extend(self.path_text(&p, true));
+            record.extend(format!("{}\n", detail).into_bytes());
+        }
+        io::stdout()
+            .lock()
+            .write_all(&record)
+            .expect("failed printing to stdout");
+    }
+
+    fn structure_done(&self) {
+        // Templates only describe diffs.
+        if self.template.is_some() {
+            return;
+        }
+        match &self.prefix {
+            Some(prefix) => println!("{}\t[Structure compared]", prefix),
+            None => println!("[Structure compared]"),
         }
     }
+}
 
-    /// Returns file name. If structure contains symlink, then its name will be returned instead of its target name.
-    fn filename(&self) -> OsString {
-        self.entry.file_name()
+/// Writes the diffs as the changes rsync would make, for `--format itemize`.
+struct ItemizeHandler {
+    /// Printed before each diff, to tell apart the pairs of a `--pairs-from` run.
+    prefix: Option<String>,
+    paths: PathStyle,
+}
+
+impl ItemizeHandler {
+    fn new(prefix: Option<String>, paths: PathStyle) -> Self {
+        Self { prefix, paths }
     }
+}
 
-    /// Path to file. If structure contains symlink, then path to its target is returned at constant time.
-    fn path(&mut self) -> &PathBuf {
-        if let None = &self.path {
-            self.path = Some(self.entry.path());
+impl DiffHandler for ItemizeHandler {
+    fn process(&self, root1: &Path, root2: &Path, diff: Diff) {
+        let item = match itemized(&diff, root1) {
+            Some(item) => item,
+            None => return,
+        };
+        let root = match diff {
+            Diff::InDir2Only(..) => root2,
+            _ => root1,
+        };
+        let p = quoted(self.paths.printed(root, &diff.path()).as_os_str());
+        match &self.prefix {
+            Some(prefix) => println!("{}\t{} {}", prefix, item, p),
+            None => println!("{} {}", item, p),
         }
-        self.path.as_ref().unwrap()
+    }
+}
+
+/// Number of diffs of each kind written, in the order of [`DIFF_KINDS`], for the JSON summary.
+struct KindCounts(Vec<AtomicU64>);
+
+impl KindCounts {
+    fn new() -> Self {
+        Self(DIFF_KINDS.iter().map(|_| AtomicU64::new(0)).collect())
     }
 
-    /// Metadata of the file. If structure contains symlink, then metadata to its target is returned.
-    fn metadata(&self) -> Metadata {
-        match &self.path {
-            Some(p) => p.metadata().unwrap(),
-            None => self.entry.metadata().unwrap(),
+    fn add(&self, kind: &str) {
+        if let Some(i) = DIFF_KINDS.iter().position(|&k| k == kind) {
+            self.0[i].fetch_add(1, Ordering::SeqCst);
         }
     }
 
-    fn file_type(&self) -> FileType {
-        self.file_type
+    /// The summary record, with the `summary` kind and a field per kind of diff.
+    fn summary(&self) -> String {
+        let mut record = String::from("{\"kind\":\"summary\"");
+        for (kind, count) in DIFF_KINDS.iter().zip(&self.0) {
+            record.push_str(&format!(",\"{}\":{}", kind, count.load(Ordering::SeqCst)));
+        }
+        record.push('}');
+        record
     }
 }
 
-struct StackHandle {
-    own: Worker<StackUnit>,
-    stealers: Vec<Stealer<StackUnit>>,
-    non_idle: Arc<AtomicU16>,
-    abort: Arc<AtomicBool>,
+/// Writes diffs as JSON objects, one per line.
+struct JsonHandler {
+    /// Line of the pair being diffed, for `--pairs-from` runs.
+    pair: Option<usize>,
+    paths: PathStyle,
+    /// Flush the output after each record, rather than when the buffer is full.
+    flush: bool,
+    /// Add the identifier of the path to each record, for `--stable-ids`.
+    stable_ids: bool,
+    /// Diffs whose records get `"expected":true`.
+    expected: Arc<ExpectedDiffs>,
+    /// Shared by the handlers of all the pairs.
+    counts: Arc<KindCounts>,
+    /// Records are written whole while holding the lock, so that they don't interleave.
+    out: Mutex<BufWriter<Stdout>>,
+    framing: JsonFraming,
+}
+
+/// How the records of the JSON output are put together.
+#[derive(Clone)]
+enum JsonFraming {
+    /// Written one per line.
+    Lines,
+    /// Kept rather than written, to be written as a single array once all the pairs are
+    /// compared, for `--json-pretty`.
+    Pending(Arc<Mutex<Vec<String>>>),
+    /// Written in a single array as they come, for `--json-array-stream`.
+    Array(Arc<json::ArrayStream>),
+}
+
+impl JsonHandler {
+    fn new(
+        pair: Option<usize>,
+        paths: PathStyle,
+        flush: bool,
+        stable_ids: bool,
+        expected: Arc<ExpectedDiffs>,
+        counts: Arc<KindCounts>,
+        framing: JsonFraming,
+    ) -> Self {
+        Self {
+            pair,
+            paths,
+            flush,
+            stable_ids,
+            expected,
+            counts,
+            out: Mutex::new(BufWriter::new(io::stdout())),
+            framing,
+        }
+    }
+
+    fn write(&self, record: &str) {
+        let mut out = self.out.lock().unwrap();
+        match &self.framing {
+            JsonFraming::Lines => out.write_all(record.as_bytes()),
+            JsonFraming::Pending(pending) => {
+                pending.lock().unwrap().push(record.trim_end().to_owned());
+                return;
+            }
+            JsonFraming::Array(array) => {
+                write!(out, "{}{}", array.separator(), record.trim_end())
+            }
+        }
+        .and_then(|()| if self.flush { out.flush() } else { Ok(()) })
+        .expect("failed printing to stdout");
+    }
 }
 
-impl StackHandle {
-    fn new(n_threads: u16) -> Vec<Self> {
-        let mut workers = Vec::new();
-        let mut stealers = vec![Vec::new(); n_threads as usize];
-        for i in 0..n_threads {
-            let w = Worker::new_lifo();
-            for (j, stlrs) in stealers.iter_mut().enumerate() {
-                if (i as usize) != j {
-                    stlrs.push(w.stealer());
+impl DiffHandler for JsonHandler {
+    fn process(&self, root1: &Path, root2: &Path, diff: Diff) {
+        let root = match diff {
+            Diff::InDir2Only(..)
+            | Diff::Vanished(_, _, Side::Second)
+            | Diff::ResolvedLinks(_, _, None, _) => root2,
+            _ => root1,
+        };
+        let path = self.paths.printed(root, &diff.path());
+        self.counts.add(diff.kind());
+        let mut record = String::from("{");
+        if let Some(pair) = self.pair {
+            record.push_str(&format!("\"pair\":{},", pair));
+        }
+        record.push_str(&format!(
+            "\"kind\":{},\"path\":{}",
+            json::string(diff.kind()),
+            json::string(&path.to_string_lossy())
+        ));
+        if self.stable_ids {
+            record.push_str(&format!(",\"id\":\"{}\"", path_id(&diff.path())));
+        }
+        if self.expected.contains(&diff) {
+            record.push_str(",\"expected\":true");
+        }
+        match &diff {
+            Diff::TypeMismatch(_, _, type1, type2) => {
+                record.push_str(&format!(
+                    ",\"type1\":{},\"type2\":{}",
+                    json::string(&type1.to_string()),
+                    json::string(&type2.to_string())
+                ));
+                let fields = [("resolved1", type1), ("resolved2", type2)];
+                for (field, entry_type) in fields {
+                    if let Some(resolved) = entry_type.resolved() {
+                        record.push_str(&format!(
+                            ",\"{}\":{}",
+                            field,
+                            json::string(&resolved.to_string_lossy())
+                        ));
+                    }
                 }
             }
-            workers.push(w);
-        }
-        let non_idle = Arc::new(n_threads.into());
-        let abort = Arc::new(false.into());
-        let mut res = Vec::new();
-        for (w, stealers) in workers.into_iter().zip(stealers) {
-            res.push(Self {
-                own: w,
-                stealers,
-                non_idle: Arc::clone(&non_idle),
-                abort: Arc::clone(&abort),
-            })
+            Diff::DifferentMTime(..) => record.push_str(",\"mtime_only\":true"),
+            Diff::Different(_, _, Some(differing)) => {
+                record.push_str(&format!(",\"differing_bytes\":{}", differing))
+            }
+            Diff::NlinkDiffer(_, _, n1, n2) => {
+                record.push_str(&format!(",\"nlink1\":{},\"nlink2\":{}", n1, n2))
+            }
+            Diff::OwnerDiffer(_, _, (uid1, gid1), (uid2, gid2)) => record.push_str(&format!(
+                ",\"uid1\":{},\"gid1\":{},\"uid2\":{},\"gid2\":{}",
+                uid1, gid1, uid2, gid2
+            )),
+            Diff::EntriesDiffer(_, _, n1, n2) => {
+                record.push_str(&format!(",\"entries1\":{},\"entries2\":{}", n1, n2))
+            }
+            Diff::FlagsDiffer(_, _, f1, f2) => {
+                record.push_str(&format!(",\"flags1\":{},\"flags2\":{}", f1, f2))
+            }
+            Diff::BlocksDiffer(_, _, b1, b2) => {
+                record.push_str(&format!(",\"blocks1\":{},\"blocks2\":{}", b1, b2))
+            }
+            Diff::ExtentSharingDiffer(_, _, s1, s2) => {
+                record.push_str(&format!(",\"shared1\":{},\"shared2\":{}", s1, s2))
+            }
+            Diff::LinkTargetsDiffer(_, _, target1, target2) => record.push_str(&format!(
+                ",\"target1\":{},\"target2\":{}",
+                json::string(&target1.to_string_lossy()),
+                json::string(&target2.to_string_lossy())
+            )),
+            Diff::ResolvedLinks(_, _, resolved1, resolved2) => {
+                let fields = [("resolved1", resolved1), ("resolved2", resolved2)];
+                for (field, resolved) in fields {
+                    if let Some(resolved) = resolved {
+                        record.push_str(&format!(
+                            ",\"{}\":{}",
+                            field,
+                            json::string(&resolved.to_string_lossy())
+                        ));
+                    }
+                }
+            }
+            Diff::Vanished(_, _, side) => {
+                let side = match side {
+                    Side::First => 1,
+                    Side::Second => 2,
+                };
+                record.push_str(&format!(",\"side\":{}", side))
+            }
+            Diff::CaseCollision(_, _, other) => record.push_str(&format!(
+                ",\"other\":{}",
+                json::string(&other.to_string_lossy())
+            )),
+            Diff::CapsDiffer(_, _, caps1, caps2) => record.push_str(&format!(
+                ",\"caps1\":{},\"caps2\":{}",
+                json::string(caps1),
+                json::string(caps2)
+            )),
+            Diff::AclDiffer(_, _, acl1, acl2) => record.push_str(&format!(
+                ",\"acl1\":{},\"acl2\":{}",
+                json::string(acl1),
+                json::string(acl2)
+            )),
+            Diff::ContentTypesDiffer(_, _, type1, type2) => record.push_str(&format!(
+                ",\"content_type1\":{},\"content_type2\":{}",
+                json::string(type1),
+                json::string(type2)
+            )),
+            Diff::ContentMovedOrRenamed(_, _, other) => record.push_str(&format!(
+                ",\"path2\":{}",
+                json::string(&self.paths.printed(root2, other).to_string_lossy())
+            )),
+            Diff::Hashes(_, _, hash1, hash2) => record.push_str(&format!(
+                ",\"hash1\":\"{}\",\"hash2\":\"{}\"",
+                manifest::hex(hash1),
+                manifest::hex(hash2)
+            )),
+            Diff::PossibleRename(dir, _, name2, distance) => record.push_str(&format!(
+                ",\"path2\":{},\"distance\":{}",
+                json::string(
+                    &self
+                        .paths
+                        .printed(root2, &dir.join(name2))
+                        .to_string_lossy()
+                ),
+                distance
+            )),
+            _ => (),
         }
-        res
+        record.push_str("}\n");
+        self.write(&record);
+    }
+
+    fn structure_done(&self) {
+        let record = match self.pair {
+            Some(pair) => format!("{{\"pair\":{},\"kind\":\"structure_done\"}}\n", pair),
+            None => "{\"kind\":\"structure_done\"}\n".to_owned(),
+        };
+        self.write(&record);
+    }
+
+    fn finish(&self) -> io::Result<()> {
+        self.out.lock().unwrap().flush()
     }
 }
 
-#[derive(Debug)]
-// TODO check rewrite using reference to pathbuf
-enum Diff {
-    InDir1Only(PathBuf, OsString),
-    InDir2Only(PathBuf, OsString),
-    Different(PathBuf, OsString),
-    SameButDifferentMTime(PathBuf, OsString),
+/// Inserts the diffs in a SQLite database, for `--sqlite`.
+struct SqliteHandler {
+    paths: PathStyle,
+    /// Renames of `--rename-map`, to read the stats of the entries in the second dir.
+    renames: RenameMap,
+    database: Arc<Mutex<sqlite::Database>>,
+    /// The first error inserting a diff, after which the others are dropped.
+    error: Mutex<Option<rusqlite::Error>>,
+}
+
+impl SqliteHandler {
+    fn new(paths: PathStyle, renames: RenameMap, database: Arc<Mutex<sqlite::Database>>) -> Self {
+        Self {
+            paths,
+            renames,
+            database,
+            error: Mutex::new(None),
+        }
+    }
 }
 
-trait DiffHandler {
-    fn process(&self, root1: &Path, root2: &Path, diff: Diff);
+impl DiffHandler for SqliteHandler {
+    fn process(&self, root1: &Path, root2: &Path, diff: Diff) {
+        if self.error.lock().unwrap().is_some() {
+            return;
+        }
+        let root = match diff {
+            Diff::InDir2Only(..)
+            | Diff::Vanished(_, _, Side::Second)
+            | Diff::ResolvedLinks(_, _, None, _) => root2,
+            _ => root1,
+        };
+        let path = diff.path();
+        // Of the entry the first one is compared with, which may have another name.
+        let path2 = match &diff {
+            Diff::PossibleRename(dir, _, name2, _) => self.renames.second_path(dir).join(name2),
+            _ => self.renames.second_path(&path),
+        };
+        let row = sqlite::Row {
+            kind: diff.kind(),
+            path: self
+                .paths
+                .printed(root, &path)
+                .to_string_lossy()
+                .into_owned(),
+            detail: sqlite::detail(&diff),
+            stats1: sqlite::entry_stats(&root1.join(&path)),
+            stats2: sqlite::entry_stats(&root2.join(path2)),
+        };
+        // Only taken now, so that the workers read the stats of their entries side by side.
+        let mut error = self.error.lock().unwrap();
+        if error.is_none() {
+            if let Err(e) = self.database.lock().unwrap().insert(&row) {
+                *error = Some(e);
+            }
+        }
+    }
+
+    fn finish(&self) -> io::Result<()> {
+        let result = match self.error.lock().unwrap().take() {
+            Some(e) => Err(e),
+            None => self.database.lock().unwrap().commit(),
+        };
+        result.map_err(|e| io::Error::other(format!("Couldn't insert the diffs: {}", e)))
+    }
 }
 
-struct DirWorker<H: DiffHandler> {
+/// Buffers the diffs, and passes them on to the output grouped by kind once the comparison is over.
+///
+/// Diffs are expected to come sorted by path, and stay so within each group.
+struct GroupedHandler {
+    output: Box<dyn DiffHandler + Send + Sync>,
     root1: PathBuf,
     root2: PathBuf,
-    stack: StackHandle,
-    diff_handler: Arc<H>,
-    check_mtime: bool,
-    follow_symlink: bool,
+    /// Order of the kinds of diff.
+    kind_order: Vec<String>,
+    diffs: Mutex<Vec<Diff>>,
 }
 
-impl<H: DiffHandler> DirWorker<H> {
+impl GroupedHandler {
     fn new(
+        output: Box<dyn DiffHandler + Send + Sync>,
         root1: PathBuf,
         root2: PathBuf,
-        diff_handler: Arc<H>,
-        stack: StackHandle,
-        check_mtime: bool,
-        follow_symlink: bool,
+        kind_order: Vec<String>,
     ) -> Self {
         Self {
+            output,
             root1,
             root2,
-            stack,
-            diff_handler,
-            check_mtime,
-            follow_symlink,
+            kind_order,
+            diffs: Mutex::new(Vec::new()),
         }
     }
+}
 
-    fn run(&mut self) -> anyhow::Result<()> {
-        loop {
-            if self.stack.abort.load(Ordering::SeqCst) {
-                return Ok(());
-            }
-            if let Some(su) = self.stack.own.pop() {
-                match self.process_path(su.dir) {
-                    Ok(()) => continue,
-                    e@Err(_) => {
-                        self.stack.abort.store(true, Ordering::SeqCst);
-                        return e;
-                    }
+impl DiffHandler for GroupedHandler {
+    fn process(&self, _root1: &Path, _root2: &Path, diff: Diff) {
+        self.diffs.lock().unwrap().push(diff);
+    }
+
+    fn finish(&self) -> io::Result<()> {
+        let mut diffs = std::mem::take(&mut *self.diffs.lock().unwrap());
+        // The sort is stable, so that diffs stay sorted by path within each group.
+        diffs.sort_by_key(|diff| self.kind_order.iter().position(|kind| kind == diff.kind()));
+        for diff in diffs {
+            self.output.process(&self.root1, &self.root2, diff);
+        }
+        self.output.finish()
+    }
+}
+
+/// Passes the diffs on to the output, then prints a fingerprint of the differing paths of each
+/// top-level directory once the comparison is over.
+struct FingerprintHandler {
+    output: Box<dyn DiffHandler + Send + Sync>,
+    /// Line of the pair being diffed, for `--pairs-from` runs.
+    pair: Option<usize>,
+    json: bool,
+    /// Differing paths, by top-level directory. Entries of the roots are under `.`.
+    changed: Mutex<BTreeMap<OsString, BTreeSet<PathBuf>>>,
+}
+
+impl FingerprintHandler {
+    fn new(output: Box<dyn DiffHandler + Send + Sync>, pair: Option<usize>, json: bool) -> Self {
+        Self {
+            output,
+            pair,
+            json,
+            changed: Mutex::new(BTreeMap::new()),
+        }
+    }
+}
+
+impl DiffHandler for FingerprintHandler {
+    fn process(&self, root1: &Path, root2: &Path, diff: Diff) {
+        let path = diff.path();
+        let mut components = path.iter();
+        let dir = match (components.next(), components.next()) {
+            (Some(dir), Some(_)) => dir.to_owned(),
+            _ => OsString::from("."),
+        };
+        let mut changed = self.changed.lock().unwrap();
+        changed.entry(dir).or_default().insert(path);
+        drop(changed);
+        self.output.process(root1, root2, diff);
+    }
+
+    fn structure_done(&self) {
+        self.output.structure_done()
+    }
+
+    fn finish(&self) -> io::Result<()> {
+        self.output.finish()?;
+        let changed = std::mem::take(&mut *self.changed.lock().unwrap());
+        let mut out = io::stdout().lock();
+        for (dir, paths) in changed {
+            let (dir, n, hash) = (dir.to_string_lossy(), paths.len(), fingerprint(&paths));
+            if self.json {
+                let pair = match self.pair {
+                    Some(pair) => format!("\"pair\":{},", pair),
+                    None => String::new(),
                 };
-                
-            }
-            //TODO(arthur): better ordering
-            self.stack.non_idle.fetch_sub(1, Ordering::SeqCst);
-            let empty_backoff = Backoff::new();
-            let retry_backoff = Backoff::new();
-            loop {
-                if self.stack.abort.load(Ordering::SeqCst) {
-                    return Ok(());
-                }
-                let stollen: Steal<_> = self.stack.stealers.iter().map(|s| s.steal()).collect();
-                match stollen {
-                    Steal::Retry => {
-                        retry_backoff.snooze();
-                    }
-                    Steal::Empty => {
-                        if self.stack.non_idle.load(Ordering::SeqCst) == 0 {
-                            return Ok(());
-                        }
-                        empty_backoff.snooze();
-                    }
-                    Steal::Success(su) => {
-                        self.stack.non_idle.fetch_add(1, Ordering::SeqCst);
-                        self.stack.own.push(su);
-                        break;
-                    }
+                let dir = json::string(&dir);
+                writeln!(
+                    out,
+                    "{{{}\"dir\":{},\"changed\":{},\"fingerprint\":\"{}\"}}",
+                    pair, dir, n, hash
+                )?;
+            } else {
+                if let Some(pair) = self.pair {
+                    write!(out, "{}\t", pair)?;
                 }
+                writeln!(out, "{}: changed={} fingerprint={}", dir, n, hash)?;
             }
         }
+        out.flush()
     }
+}
 
-    fn process_diff(&mut self, diff: Diff) {
-        self.diff_handler.process(&self.root1, &self.root2, diff)
-    }
+/// Collects the entries of a tree compared with an empty one, then prints the hash of the tree
+/// once the walk is over, instead of the diffs, for `--merkle-root`.
+struct MerkleHandler {
+    root: PathBuf,
+    follow_symlink: bool,
+    n_threads: usize,
+    /// Entries found, relative to the root.
+    paths: Mutex<Vec<PathBuf>>,
+    /// Count the entries that could not be walked rather than printing them, for
+    /// `--quiet-errors`.
+    quiet_errors: bool,
+    /// Number of entries that could not be walked, like directories vanishing during the walk.
+    unwalked: AtomicU64,
+}
 
-    fn push_to_stack(&mut self, dir: PathBuf) {
-        self.stack.own.push(StackUnit { dir })
+impl MerkleHandler {
+    fn new(root: PathBuf, follow_symlink: bool, n_threads: usize, quiet_errors: bool) -> Self {
+        Self {
+            root,
+            follow_symlink,
+            n_threads,
+            paths: Mutex::new(Vec::new()),
+            quiet_errors,
+            unwalked: AtomicU64::new(0),
+        }
     }
+}
 
-    fn process_path(&mut self, dir: PathBuf) -> anyhow::Result<()> {
-        // dbg!(&dir);
-        let dir1 = PathBuf::from_iter([&self.root1, &dir]);
-        let dir2 = PathBuf::from_iter([&self.root2, &dir]);
-        let mut dir_content1 = read_dir(&dir1)?
-            .map(|r| r.map(|e| FileT::new(e, self.follow_symlink)))
-            .collect::<Result<Vec<_>, _>>()?;
-        let mut dir_content2 = read_dir(&dir2)?
-            .map(|r| r.map(|e| FileT::new(e, self.follow_symlink)))
-            .collect::<Result<Vec<_>, _>>()?;
-        // Our for of file_type_enul puts dir first. 
-        // This minimizes time spent with an empty stack
-        // in case work needs to be stollen by others.
-        dir_content1.sort_unstable_by_key(|e| (e.file_type(), e.filename()));
-        dir_content2.sort_unstable_by_key(|e| (e.file_type(), e.filename()));
-        loop {
-            if dir_content1.is_empty() {
-                for e in dir_content2 {
-                    self.process_diff(Diff::InDir2Only(dir.clone(), e.filename()))
+impl DiffHandler for MerkleHandler {
+    fn process(&self, root1: &Path, _root2: &Path, diff: Diff) {
+        match diff {
+            Diff::InDir1Only(..) => self.paths.lock().unwrap().push(diff.path()),
+            Diff::ResolvedLinks(..) => (),
+            _ => {
+                if !self.quiet_errors {
+                    eprintln!("Error: couldn't walk {}", root1.join(diff.path()).display());
                 }
-                return Ok(());
+                self.unwalked.fetch_add(1, Ordering::SeqCst);
             }
-            if dir_content2.is_empty() {
-                for e in dir_content1 {
-                    self.process_diff(Diff::InDir1Only(dir.clone(), e.filename()))
-                }
-                return Ok(());
-            }
-            let e1 = dir_content1.last().unwrap();
-            let e2 = dir_content2.last().unwrap();
-            let ft1 = e1.file_type();
-            let ft2 = e2.file_type();
-            match (ft1, e1.filename()).cmp(&(ft2, e2.filename())) {
-                std::cmp::Ordering::Less => {
-                    let e = dir_content2.pop().unwrap();
-                    self.process_diff(Diff::InDir2Only(dir.clone(), e.filename()));
-                    continue;
-                }
-                std::cmp::Ordering::Greater => {
-                    let e = dir_content1.pop().unwrap();
-                    self.process_diff(Diff::InDir1Only(dir.clone(), e.filename()));
-                    continue;
-                }
-                std::cmp::Ordering::Equal => {
-                    let mut e1 = dir_content1.pop().unwrap();
-                    let mut e2 = dir_content2.pop().unwrap();
-                    assert_eq!(ft1, ft2);
-                    match ft1 {
-                        FileType::Directory => {
-                            let mut p = dir.clone();
-                            p.push(e1.filename());
-                            self.push_to_stack(p);
-                        }
-                        // This can only been reached
-                        // when symlikns are not followed
-                        FileType::Symlink => {
-                            if read_link(e1.path())? != read_link(e2.path())? {
-                                self.process_diff(Diff::Different(dir.clone(), e1.filename()));
-                            }
-                        }
-                        FileType::Regular => {
-                            let e1_meta = e1.metadata();
-                            let e2_meta = e2.metadata();
-                            let same_content = if e1_meta.len() != e2_meta.len() {
-                                false
-                            } else {
-                                let mut f1 = BufReader::new(File::open(e1.path())?);
-                                let mut f2 = BufReader::new(File::open(e2.path())?);
-                                loop {
-                                    let s1 = f1.fill_buf()?;
-                                    let s2 = f2.fill_buf()?;
-                                    if s1.is_empty() {
-                                        break s2.is_empty();
-                                    }
-                                    let common_size = std::cmp::min(s1.len(), s2.len());
-                                    if s1[..common_size] != s2[..common_size] {
-                                        break false;
-                                    } else {
-                                        f1.consume(common_size);
-                                        f2.consume(common_size);
-                                    }
-                                }
-                            };
-                            if !same_content {
-                                self.process_diff(Diff::Different(dir.clone(), e1.filename()));
-                            } else if self.check_mtime
-                                && (e1_meta.modified()? != e2_meta.modified()?)
-                            {
-                                self.process_diff(Diff::SameButDifferentMTime(
-                                    dir.clone(),
-                                    e2.filename(),
-                                ));
-                            }
-                        }
-                        _ => {
-                            let mut p = dir;
-                            p.push(e1.filename());
-                            bail!(
-                                "Unimplemented filetype. File {} has type {:?}",
-                                p.display(),
-                                ft1
-                            );
-                        }
-                    }
-                }
+        }
+    }
+
+    fn finish(&self) -> io::Result<()> {
+        match self.unwalked.load(Ordering::SeqCst) {
+            0 => (),
+            n if self.quiet_errors => {
+                return Err(io::Error::other(format!(
+                    "{} entries could not be read, no hash of the tree is printed",
+                    n
+                )))
+            }
+            _ => {
+                return Err(io::Error::other(
+                    "Some entries couldn't be walked, no hash of the tree is printed",
+                ))
             }
         }
+        let paths = std::mem::take(&mut *self.paths.lock().unwrap());
+        let hash = merkle::root_hash(&self.root, paths, self.follow_symlink, self.n_threads)?;
+        println!("{}", manifest::hex(&hash));
+        Ok(())
     }
 }
 
-struct GrepableHandler;
+/// Widest bar of `--diffstat`, the one of the directory with the most diffs.
+const DIFFSTAT_WIDTH: u64 = 40;
 
-impl GrepableHandler {
-    fn new() -> Self {
-        Self
+/// Passes the diffs on to the output, then prints the number of diffs of each top-level
+/// directory, along with a bar, to stderr once the comparison is over.
+struct DiffstatHandler {
+    output: Box<dyn DiffHandler + Send + Sync>,
+    /// Line of the pair being diffed, for `--pairs-from` runs.
+    pair: Option<usize>,
+    /// Numbers of entries changed, present in the second dir. only, and present in the first
+    /// dir. only, by top-level directory. Entries of the roots are under `.`.
+    stats: Mutex<BTreeMap<OsString, [u64; 3]>>,
+}
+
+impl DiffstatHandler {
+    fn new(output: Box<dyn DiffHandler + Send + Sync>, pair: Option<usize>) -> Self {
+        Self {
+            output,
+            pair,
+            stats: Mutex::new(BTreeMap::new()),
+        }
     }
 }
 
-impl DiffHandler for GrepableHandler {
-    fn process(&self, _root1: &Path, _root2: &Path, diff: Diff) {
-        let (diff_type, mut p, file) = match diff {
-            Diff::Different(dir, file) => ("Files differ", dir, file),
-            Diff::InDir1Only(dir, file) => ("Present in first dir. only", dir, file),
-            Diff::InDir2Only(dir, file) => ("Present in second dir. only", dir, file),
-            Diff::SameButDifferentMTime(dir, file) => ("Differ by mtime only", dir, file),
+impl DiffHandler for DiffstatHandler {
+    fn process(&self, root1: &Path, root2: &Path, diff: Diff) {
+        let column = match diff {
+            // Not differences of their own.
+            Diff::ResolvedLinks(..) | Diff::ContentMovedOrRenamed(..) | Diff::Hashes(..) => None,
+            Diff::InDir2Only(..) => Some(1),
+            Diff::InDir1Only(..) => Some(2),
+            _ => Some(0),
         };
-        p.push(file);
-        println!("[{}]\t{:?}", diff_type, p.display());
+        if let Some(column) = column {
+            let path = diff.path();
+            let mut components = path.iter();
+            let dir = match (components.next(), components.next()) {
+                (Some(dir), Some(_)) => dir.to_owned(),
+                _ => OsString::from("."),
+            };
+            self.stats.lock().unwrap().entry(dir).or_default()[column] += 1;
+        }
+        self.output.process(root1, root2, diff);
+    }
+
+    fn structure_done(&self) {
+        self.output.structure_done()
+    }
+
+    fn finish(&self) -> io::Result<()> {
+        self.output.finish()?;
+        let stats = std::mem::take(&mut *self.stats.lock().unwrap());
+        let busiest = stats.values().map(|s| s.iter().sum()).max().unwrap_or(0);
+        let name_width = stats.keys().map(|dir| dir.to_string_lossy().len()).max();
+        let count_width = busiest.to_string().len();
+        let mut totals = [0; 3];
+        let mut out = io::stderr().lock();
+        for (dir, counts) in &stats {
+            if let Some(pair) = self.pair {
+                write!(out, "{}\t", pair)?;
+            }
+            let total: u64 = counts.iter().sum();
+            let mut bar = String::new();
+            for ((n, c), total) in counts.iter().zip(['~', '+', '-']).zip(&mut totals) {
+                *total += n;
+                for _ in 0..bar_length(*n, busiest) {
+                    bar.push(c);
+                }
+            }
+            writeln!(
+                out,
+                " {:name_width$} | {:>count_width$} {}",
+                dir.to_string_lossy(),
+                total,
+                bar,
+                name_width = name_width.unwrap_or(0),
+                count_width = count_width
+            )?;
+        }
+        if let Some(pair) = self.pair {
+            write!(out, "{}\t", pair)?;
+        }
+        let [changed, added, removed] = totals;
+        writeln!(
+            out,
+            " {} directories, {} changed (~), {} added (+), {} removed (-)",
+            stats.len(),
+            changed,
+            added,
+            removed
+        )?;
+        out.flush()
     }
 }
 
-/// Output the diff of two directories.
-///
-/// Intended to be efficient and usable on very large directories.
-///
-/// Not intended to output the diff of files' content.
-#[derive(Debug, Parser)]
-#[command(author, version)]
-struct CliArgs {
-    /// First directory to diff from.
-    dir1: PathBuf,
-    /// Second directory to diff from.
-    dir2: PathBuf,
-    #[arg(short, long)]
-    /// Number of parallel threads to use.
-    ///
-    /// Use 0 or no option for auto-detection.
-    jobs: Option<u16>,
-    /// Whether to check if the mtime is different.
-    ///
-    /// Only applies to file whose content is otherwise the same,
-    /// and gets its specific output tag: `[Differ by mtime only]`.
-    #[arg(long)]
-    check_mtime: bool,
-    /// Whether to follow symlinks when comparing directories' content
-    #[arg(short = 'L', long)]
-    follow_symlink: bool,
-    /// Whether to follow symlinks for program's arguments.
-    #[arg(short = 'H')]
-    follow_symlink_args: bool,
+/// Length of the part of a `--diffstat` bar standing for `n` diffs: one character per diff,
+/// unless the busiest directory has more than [`DIFFSTAT_WIDTH`] of them, in which case all the
+/// bars are scaled down for its own to fit, keeping at least one character for any diff.
+fn bar_length(n: u64, busiest: u64) -> u64 {
+    if n == 0 || busiest <= DIFFSTAT_WIDTH {
+        return n;
+    }
+    (n * DIFFSTAT_WIDTH / busiest).max(1)
 }
 
-fn main() -> anyhow::Result<()> {
-    let unwind_path = |path: PathBuf| {
-        path.canonicalize()
-            .context(format!("Couldn't unwind path {}.", path.display()))
-    };
-    let cli_args: CliArgs = CliArgs::parse();
-    let n_threads = match cli_args.jobs {
-        Some(u) if u > 0 => u,
-        _ => thread::available_parallelism()
-            .context("Could not determine available parallelisme, specify the -j option with a non zero value.")?
-            .get() as _,
-    };
-    let h = Arc::new(GrepableHandler::new());
-    let stack_handlers = StackHandle::new(n_threads);
-    let mut first = true;
-    let mut joins = Vec::new();
-    let (dir1, dir2) = if cli_args.follow_symlink_args {
-        (unwind_path(cli_args.dir1)?, unwind_path(cli_args.dir2)?)
-    } else {
-        (cli_args.dir1, cli_args.dir2)
-    };
-    for sh in stack_handlers {
-        let mut worker = DirWorker::new(
-            dir1.clone(),
-            dir2.clone(),
-            h.clone(),
-            sh,
-            cli_args.check_mtime,
-            cli_args.follow_symlink,
-        );
-        if first {
-            worker.push_to_stack(PathBuf::new());
-            first = false;
+/// Counts the diffs of each directory, then prints the directories containing some once the
+/// comparison is over, instead of the diffs.
+struct DirsOnlyHandler {
+    /// Line of the pair being diffed, for `--pairs-from` runs.
+    pair: Option<usize>,
+    json: bool,
+    /// Whether to only print the directories none of whose subdirectories contain diffs.
+    leaf: bool,
+    counts: bool,
+    /// Number of diffs of the entries of each directory, not counting their subdirectories.
+    /// The roots have an empty path.
+    changed: Mutex<BTreeMap<PathBuf, u64>>,
+}
+
+impl DirsOnlyHandler {
+    fn new(pair: Option<usize>, json: bool, leaf: bool, counts: bool) -> Self {
+        Self {
+            pair,
+            json,
+            leaf,
+            counts,
+            changed: Mutex::new(BTreeMap::new()),
         }
-        joins.push(thread::spawn(move || worker.run()));
     }
-    for j in joins {
-        j.join().unwrap()?;
+}
+
+impl DiffHandler for DirsOnlyHandler {
+    fn process(&self, _root1: &Path, _root2: &Path, diff: Diff) {
+        // Not differences, only details of the diffs before them.
+        if let Diff::ResolvedLinks(..) | Diff::Hashes(..) = diff {
+            return;
+        }
+        let path = diff.path();
+        let dir = path.parent().unwrap_or(Path::new("")).to_owned();
+        *self.changed.lock().unwrap().entry(dir).or_default() += 1;
+    }
+
+    fn finish(&self) -> io::Result<()> {
+        let changed = std::mem::take(&mut *self.changed.lock().unwrap());
+        let mut totals: BTreeMap<&Path, u64> = BTreeMap::new();
+        for (dir, n) in &changed {
+            for ancestor in dir.ancestors() {
+                *totals.entry(ancestor).or_default() += n;
+            }
+        }
+        let parents: BTreeSet<&Path> = totals.keys().filter_map(|dir| dir.parent()).collect();
+        let mut out = io::stdout().lock();
+        for (dir, n) in &totals {
+            if self.leaf && parents.contains(dir) {
+                continue;
+            }
+            let dir = match dir.as_os_str().is_empty() {
+                true => ".".into(),
+                false => dir.to_string_lossy(),
+            };
+            if self.json {
+                let pair = match self.pair {
+                    Some(pair) => format!("\"pair\":{},", pair),
+                    None => String::new(),
+                };
+                let dir = json::string(&dir);
+                writeln!(out, "{{{}\"dir\":{},\"changed\":{}}}", pair, dir, n)?;
+            } else {
+                if let Some(pair) = self.pair {
+                    write!(out, "{}\t", pair)?;
+                }
+                if self.counts {
+                    writeln!(out, "{}: changed={}", dir, n)?;
+                } else {
+                    writeln!(out, "{}", dir)?;
+                }
+            }
+        }
+        out.flush()
+    }
+}
+
+/// Writes the actions making the second directory match the first one, instead of the diffs.
+struct SyncHandler {
+    format: SyncFormat,
+    /// Number of entries removed by the actions written.
+    removals: AtomicU64,
+    /// Actions are written whole while holding the lock, so that they don't interleave.
+    out: Mutex<BufWriter<Stdout>>,
+    /// Lines of the touches, written at the end, so that copying entries into a directory
+    /// afterwards does not change its mtime back.
+    touches: Mutex<Vec<Vec<u8>>>,
+    /// Where the actions are kept rather than written, to be applied once the comparison is
+    /// over, for `--apply`.
+    pending: Option<Arc<Mutex<Vec<Action>>>>,
+}
+
+impl SyncHandler {
+    fn new(format: SyncFormat, pending: Option<Arc<Mutex<Vec<Action>>>>) -> Self {
+        Self {
+            format,
+            removals: AtomicU64::new(0),
+            out: Mutex::new(BufWriter::new(io::stdout())),
+            touches: Mutex::new(Vec::new()),
+            pending,
+        }
+    }
+}
+
+/// Applies the actions found by a [`SyncHandler`], writing each one out as it is run.
+///
+/// Actions are run in the order of their paths, and the mtimes are set last, so that copying
+/// entries into a directory does not change its mtime back.
+fn apply_actions(
+    mut actions: Vec<Action>,
+    format: SyncFormat,
+    opts: &ApplyOpts,
+) -> anyhow::Result<()> {
+    actions.sort_by(|a1, a2| {
+        let key = |a: &Action| (matches!(a, Action::Touch { .. }), a.target().to_owned());
+        key(a1).cmp(&key(a2))
+    });
+    let mut kept = 0;
+    let mut out = io::stdout().lock();
+    for action in actions {
+        let applied = action
+            .apply(opts)
+            .with_context(|| format!("Couldn't sync {}", action.target().display()))?;
+        if applied || matches!(action, Action::Skip { .. }) {
+            let line = match format {
+                SyncFormat::Sh => action.sh(),
+                SyncFormat::Json => action.json().into_bytes(),
+            };
+            out.write_all(&line).context("Couldn't write the output")?;
+        } else {
+            kept += 1;
+        }
+    }
+    if kept > 0 {
+        eprintln!(
+            "Kept {} entries present in the second directory only, --delete removes them.",
+            kept
+        );
+    }
+    Ok(())
+}
+
+impl DiffHandler for SyncHandler {
+    fn process(&self, root1: &Path, root2: &Path, diff: Diff) {
+        let action = match Action::new(root1, root2, &diff) {
+            Some(action) => action,
+            None => return,
+        };
+        if let Some(pending) = &self.pending {
+            pending.lock().unwrap().push(action);
+            return;
+        }
+        if action.is_removal() {
+            self.removals.fetch_add(1, Ordering::SeqCst);
+        }
+        let line = match self.format {
+            SyncFormat::Sh => action.sh(),
+            SyncFormat::Json => action.json().into_bytes(),
+        };
+        if let Action::Touch { .. } = action {
+            self.touches.lock().unwrap().push(line);
+            return;
+        }
+        self.out
+            .lock()
+            .unwrap()
+            .write_all(&line)
+            .expect("failed printing to stdout");
+    }
+
+    fn finish(&self) -> io::Result<()> {
+        let mut out = self.out.lock().unwrap();
+        for line in self.touches.lock().unwrap().drain(..) {
+            out.write_all(&line)?;
+        }
+        out.flush()?;
+        let removals = self.removals.load(Ordering::SeqCst);
+        if removals > 0 {
+            eprintln!(
+                "Warning: the sync actions remove {} entries present in the second directory only, \
+                 with their content. Review them before running them.",
+                removals
+            );
+        }
+        Ok(())
+    }
+}
+
+/// Drops all the diffs, for `--assert-identical`, whose output is only the number of failing
+/// diffs counted by [`StatusHandler`].
+struct SilentHandler;
+
+impl DiffHandler for SilentHandler {
+    fn process(&self, _root1: &Path, _root2: &Path, _diff: Diff) {}
+}
+
+/// Counts the failing diffs, for the exit code, and passes diffs on to the output.
+struct StatusHandler {
+    output: Box<dyn DiffHandler + Send + Sync>,
+    /// Kinds of the diffs that are failures.
+    fail_on: Vec<String>,
+    /// Kind of the only diffs passed on to the output, for `--only-in`.
+    only_kind: Option<&'static str>,
+    /// Leave the entries that could not be read out of the output, for `--quiet-errors`.
+    quiet_errors: bool,
+    /// Diffs which are never failures.
+    expected: Arc<ExpectedDiffs>,
+    /// Diffs left out, as found by a previous run.
+    baseline: Arc<Baseline>,
+    failures: AtomicU64,
+}
+
+impl StatusHandler {
+    fn new(
+        output: Box<dyn DiffHandler + Send + Sync>,
+        fail_on: Vec<String>,
+        only_kind: Option<&'static str>,
+        quiet_errors: bool,
+        expected: Arc<ExpectedDiffs>,
+        baseline: Arc<Baseline>,
+    ) -> Self {
+        Self {
+            output,
+            fail_on,
+            only_kind,
+            quiet_errors,
+            expected,
+            baseline,
+            failures: 0.into(),
+        }
+    }
+
+    /// Number of failing diffs processed.
+    fn failures(&self) -> u64 {
+        self.failures.load(Ordering::SeqCst)
+    }
+}
+
+impl DiffHandler for StatusHandler {
+    fn process(&self, root1: &Path, root2: &Path, diff: Diff) {
+        if self.baseline.contains(&diff) {
+            return;
+        }
+        if self.fail_on.iter().any(|kind| kind == diff.kind()) && !self.expected.contains(&diff) {
+            self.failures.fetch_add(1, Ordering::SeqCst);
+        }
+        // Only once the exit code accounts for it.
+        if matches!(self.only_kind, Some(kind) if kind != diff.kind()) {
+            return;
+        }
+        if self.quiet_errors && matches!(diff, Diff::Vanished(..)) {
+            return;
+        }
+        self.output.process(root1, root2, diff)
+    }
+
+    fn structure_done(&self) {
+        self.output.structure_done()
+    }
+
+    fn finish(&self) -> io::Result<()> {
+        self.output.finish()
+    }
+}
+
+/// Paths relative to the roots whose content or mtime is expected to differ, for
+/// `--expected-diff-from`.
+#[derive(Debug, Default)]
+struct ExpectedDiffs(BTreeSet<PathBuf>);
+
+impl ExpectedDiffs {
+    /// Reads the paths of a file, one per line.
+    fn read(path: &Path) -> anyhow::Result<Self> {
+        Ok(Self(read_paths(path)?.into_iter().collect()))
+    }
+
+    /// Whether `diff` is an expected one: files differing by content or mtime at one of the
+    /// paths.
+    fn contains(&self, diff: &Diff) -> bool {
+        matches!(diff, Diff::Different(..) | Diff::SameButDifferentMTime(..))
+            && self.0.contains(&diff.path())
+    }
+}
+
+/// Diffs found by a previous run, as their kinds and paths relative to the roots, left out of
+/// the output, for `--suppress-from`.
+#[derive(Debug, Default)]
+struct Baseline(BTreeSet<(String, PathBuf)>);
+
+impl Baseline {
+    /// Reads the diffs of the output of a previous run, grepable or JSON, whose tags were
+    /// replaced by `tags` if any.
+    fn read(path: &Path, tags: &BTreeMap<&'static str, String>) -> anyhow::Result<Self> {
+        let file = File::open(path).with_context(|| format!("Couldn't open {}", path.display()))?;
+        let mut diffs = BTreeSet::new();
+        for (i, line) in BufReader::new(file).lines().enumerate() {
+            let line = line.with_context(|| format!("Couldn't read {}", path.display()))?;
+            let parsed = Self::parse(&line, tags).with_context(|| {
+                format!(
+                    "Couldn't parse line {} of {} as a diff",
+                    i + 1,
+                    path.display()
+                )
+            })?;
+            diffs.extend(parsed);
+        }
+        Ok(Self(diffs))
+    }
+
+    /// Parses a line of grepable or JSON output, as the diffs it may stand for.
+    fn parse(
+        line: &str,
+        tags: &BTreeMap<&'static str, String>,
+    ) -> anyhow::Result<Vec<(String, PathBuf)>> {
+        let line = line.trim();
+        // The brackets and commas around the records of `--json-array-stream`.
+        let record = line.trim_end_matches(',');
+        if record.is_empty() || record == "[" || record == "]" {
+            return Ok(Vec::new());
+        } else if record.starts_with('{') {
+            let fields = match json::string_fields(record) {
+                Some(fields) => fields,
+                None => bail!("invalid JSON record"),
+            };
+            return Ok(match (fields.get("kind"), fields.get("path")) {
+                // Like the summary and the other records which are not diffs.
+                (Some(kind), Some(path)) if DIFF_KINDS.contains(&kind.as_str()) => {
+                    vec![(kind.clone(), PathBuf::from(path))]
+                }
+                _ => Vec::new(),
+            });
+        }
+        // Records of `--pairs-from` runs start with the line of their pair.
+        let record = match line.split_once('\t') {
+            Some((pair, record)) if pair.bytes().all(|b| b.is_ascii_digit()) => record,
+            _ => line,
+        };
+        let (tag, rest) = match record.strip_prefix('[').and_then(|r| r.split_once("]\t")) {
+            Some(split) => split,
+            None if record.trim_end() == "[Structure compared]" => return Ok(Vec::new()),
+            None if is_hashes_line(record) => return Ok(Vec::new()),
+            None => bail!("neither a grepable nor a JSON record"),
+        };
+        let path = match quote::unquoted(rest) {
+            Some((path, _)) => PathBuf::from(path),
+            None => bail!("invalid quoted path"),
+        };
+        let kinds: Vec<&str> = match tag {
+            // Tagged in place of their own tag.
+            "Expected diff" => vec!["different", "mtime"],
+            tag => match tags.iter().find(|(_, t)| *t == tag) {
+                Some((kind, _)) => vec![kind],
+                None => match GREPABLE_TAGS.iter().find(|(_, t)| *t == tag) {
+                    Some((kind, _)) => vec![kind],
+                    None => bail!("unknown tag `{}`", tag),
+                },
+            },
+        };
+        Ok(kinds
+            .into_iter()
+            .map(|kind| (kind.to_owned(), path.clone()))
+            .collect())
+    }
+
+    fn contains(&self, diff: &Diff) -> bool {
+        self.0.contains(&(diff.kind().to_owned(), diff.path()))
+    }
+}
+
+/// Whether `line` is a line of `--print-hashes`, the hashes of both files followed by a path.
+fn is_hashes_line(line: &str) -> bool {
+    let mut words = line.splitn(3, ' ');
+    let is_hash = |word: Option<&str>| matches!(word, Some(word) if word.len() == 64 && word.bytes().all(|b| b.is_ascii_hexdigit()));
+    is_hash(words.next()) && is_hash(words.next()) && words.next().is_some()
+}
+
+/// Reads the paths of a file, one per line, skipping empty lines.
+fn read_paths(path: &Path) -> anyhow::Result<Vec<PathBuf>> {
+    let file = File::open(path).with_context(|| format!("Couldn't open {}", path.display()))?;
+    let mut paths = Vec::new();
+    for line in BufReader::new(file).lines() {
+        let line = line.with_context(|| format!("Couldn't read {}", path.display()))?;
+        if !line.is_empty() {
+            paths.push(PathBuf::from(line));
+        }
+    }
+    Ok(paths)
+}
+
+/// Sides of `--only-in`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum OnlyIn {
+    /// Entries present in the first dir. only.
+    First,
+    /// Entries present in the second dir. only.
+    Second,
+    /// Regular files present in both dirs. with a different content.
+    Both,
+}
+
+impl OnlyIn {
+    /// Kind of the diffs shown.
+    fn kind(self) -> &'static str {
+        match self {
+            OnlyIn::First => "only1",
+            OnlyIn::Second => "only2",
+            OnlyIn::Both => "different",
+        }
+    }
+}
+
+/// How paths which are not valid UTF-8 are written, for `--non-utf8`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum NonUtf8 {
+    /// Escaped, so that the bytes of the path can be told back: each byte which is not part
+    /// of valid UTF-8 is written `\xHH`, `HH` being two uppercase hexadecimal digits,
+    /// backslashes and double quotes are written `\\` and `\"`, NUL, tab, newline and carriage
+    /// return `\0`, `\t`, `\n` and `\r`, and other control characters `\u{X}`, `X` being their
+    /// code point in lowercase hexadecimal. Other characters are written as is. Paths of
+    /// templates are escaped the same, without the quotes.
+    Escape,
+    /// With the bytes which are not part of valid UTF-8 replaced by U+FFFD, which cannot be
+    /// told back.
+    Lossy,
+    /// As their raw bytes, without any escape even between quotes, for pipelines splitting
+    /// records on NUL bytes, like with the template `{path}\0`.
+    Bytes,
+    /// Left out of the output, with a warning to stderr. Their diffs still count for the exit
+    /// code.
+    Skip,
+}
+
+/// Keys the output can be grouped by, for `--group-by`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum GroupBy {
+    /// The kind of diff, like `different` or `only1`.
+    Kind,
+}
+
+/// Orders of `--traversal`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum TraversalOrder {
+    /// Depth-first.
+    Dfs,
+    /// Breadth-first.
+    Bfs,
+}
+
+/// Formats of `--progress-format`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum ProgressFormat {
+    /// A line like `123 directories and 456 files compared, in a/b, c`.
+    Text,
+    /// A JSON object like `{"dirs_done":123,"files_done":456,"active":["a/b","c"]}`.
+    Json,
+}
+
+/// Orders of `--collation`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum CollationOrder {
+    /// By the bytes of the names.
+    Byte,
+    /// By the collation of the locale.
+    Locale,
+}
+
+/// Resolutions of `--mtime-granularity`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum Granularity {
+    /// Nanoseconds.
+    Ns,
+    /// Microseconds.
+    Us,
+    /// Milliseconds.
+    Ms,
+    /// Seconds.
+    S,
+}
+
+/// Formats of the actions of `--emit-sync-script`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum SyncFormat {
+    /// A POSIX shell script, with one command per line.
+    Sh,
+    /// One JSON object per line, with the field `action`, one of `copy`, `replace`, `remove`,
+    /// `touch` and `skip`, along with `from` and `to` for the actions copying or touching
+    /// entries, `path` for the others, and `kind` for `skip`.
+    Json,
+}
+
+/// Output formats, for `--format`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum Format {
+    /// One line per diff, made of a tag in brackets and a path, separated by a tab.
+    Grepable,
+    /// One JSON object per line, with the fields `kind` and `path`, along with `pair` for
+    /// `--pairs-from` runs, `type1` and `type2` for the `type` kind, along with `resolved1` or
+    /// `resolved2` for the sides that are symlinks followed by `-L`, `uid1`, `gid1`, `uid2`
+    /// and `gid2`, the numeric ids, for the `owner` kind, `nlink1` and `nlink2` for the
+    /// `nlink` kind, `other` for the `case_collision` kind, `entries1` and `entries2` for the
+    /// `entries` kind, `flags1` and `flags2` for the `flags` kind,
+    /// `side`, 1 or 2, for the `vanished` kind, `blocks1` and `blocks2` for the `blocks` kind,
+    /// `mtime_only`, `true`, for the `different` kind under `--mtime-is-diff`, `resolved1` or
+    /// `resolved2`, or both, for the `resolved` kind, `path2`, the path of the duplicate in
+    /// the second dir., for the `moved` kind, `caps1` and `caps2` for the `caps` kind, `hash1`
+    /// and `hash2` for the `hashes` kind, `target1` and `target2` for the `link_target` kind,
+    /// `acl1` and `acl2` for the `acl` kind, `differing_bytes` for the `different` kind under
+    /// `--count-diff-bytes`, `path2`, the path in the second dir., and `distance` for the
+    /// `possible_rename` kind, `content_type1` and `content_type2` for the `content_type` kind,
+    /// and `shared1` and `shared2`, booleans, for the `extent_sharing` kind. The output ends
+    /// with a `summary` record, see `--no-summary`, or with an `error` record if the comparison
+    /// fails, with the fields `message` and `errno`, the error number of the OS, if it has one.
+    Json,
+    /// One line per diff, made of the change rsync would make to the second dir. to turn it
+    /// into the first one, as printed by `rsync --itemize-changes`, and a path.
+    ///
+    /// The change is an 11-character string `YXcstpoguax`, like `>fc........` for a file to
+    /// transfer, or `*deleting` for an entry of the second dir. only. Only the `c`, `t`, `p`,
+    /// `o`, `g`, `a` and `x` columns can be set, and kinds of diff rsync has no column for are
+    /// written as unchanged, like `.f.........`. The records of `--show-resolved`,
+    /// `--find-duplicates` and `--print-hashes` are left out.
+    Itemize,
+}
+
+/// Output the diff of two directories.
+///
+/// Intended to be efficient and usable on very large directories.
+///
+/// Not intended to output the diff of files' content.
+///
+/// The defaults of some options can be set through environment variables,
+/// which are overridden by the options given on the command line.
+#[derive(Debug, Parser)]
+#[command(author, version)]
+// The trees which are not plain directories, which options needing one conflict with.
+#[command(group(
+    ArgGroup::new("non_dir_trees")
+        .multiple(true)
+        .args(["tar1", "tar2", "zip1", "zip2", "upper", "exec_listing"])
+))]
+// The options checking more than the presence, types and content of the entries, or changing
+// how their content is compared, which the options comparing less conflict with.
+#[command(group(
+    ArgGroup::new("checks")
+        .multiple(true)
+        .args([
+            "check_mtime",
+            "check_dir_mtime",
+            "perms",
+            "owner",
+            "check_nlink",
+            "check_flags",
+            "check_caps",
+            "acls",
+            "check_blocks",
+            "check_reflinks",
+            "flag_mtime_same_content_differs",
+            "sparse",
+            "ignore_content_case",
+            "ignore_content_case_unicode",
+            "trust_xattr",
+            "resolve_symlink_targets",
+        ])
+))]
+struct CliArgs {
+    /// First directory to diff from.
+    #[arg(required_unless_present = "pairs_from")]
+    dir1: Option<PathBuf>,
+    /// Second directory to diff from.
+    ///
+    /// `/dev/null` stands for an empty tree, as with `--empty-second`, on all platforms.
+    #[arg(required_unless_present_any = ["pairs_from", "empty_second", "upper", "exec_listing", "merkle_root"])]
+    dir2: Option<PathBuf>,
+    /// Diff several pairs of directories, read from the given file.
+    ///
+    /// Each line of the file holds two directories separated by a tab.
+    /// Pairs are diffed one after the other, and each diff is prefixed by the
+    /// line number of its pair. The exit code is 1 if any pair differs.
+    #[arg(long, conflicts_with_all = ["dir1", "dir2"])]
+    pairs_from: Option<PathBuf>,
+    /// Compare the first directory with an empty tree, instead of a second directory.
+    ///
+    /// Every entry of the first directory is reported as present in it only, recursing into
+    /// subdirectories, which inventories a tree. Same as giving `/dev/null` as second directory.
+    #[arg(long, conflicts_with_all = ["dir2", "pairs_from"])]
+    empty_second: bool,
+    /// Print a hash of the whole tree of the first directory, instead of the diffs.
+    ///
+    /// The hash only depends on the names, types and content of the entries, symlinks being
+    /// hashed as their target unless `--follow-symlink` is given, so that two trees with the
+    /// same hash are most likely the same. Metadata like mtimes, permissions and owners is left
+    /// out. The tree is walked by the workers, then the content of the files is hashed in
+    /// parallel, and the hashes are joined bottom-up. Hashes are SHA-256 hashes, printed as hex
+    /// digits.
+    #[arg(
+        long,
+        conflicts_with_all = [
+            "dir2",
+            "pairs_from",
+            "upper",
+            "exec_listing",
+            "empty_second",
+            "tar1",
+            "zip1",
+            "count_only",
+            "dir_changed_only",
+            "dirs_only",
+            "dirs_only_leaf",
+            "emit_sync_script",
+            "apply",
+            "find_duplicates",
+            "checkpoint",
+            "updates_to_second",
+        ]
+    )]
+    merkle_root: bool,
+    /// Compare roots that do not exist as empty directories, rather than failing.
+    ///
+    /// A missing first directory makes every entry of the second one reported as present in
+    /// it only, and the other way around, as if `/dev/null` was given instead. Roots that exist
+    /// but cannot be read are still errors. Also applies to the pairs of `--pairs-from`.
+    #[arg(long)]
+    missing_as_empty: bool,
+    /// Read the first tree from an uncompressed tar archive, rather than from a directory.
+    ///
+    /// Roots that are files ending in `.tar` are read as archives without this flag. Archives
+    /// are indexed first, then compared by a single thread. Only the presence, type, size,
+    /// content and symlink targets of entries are compared, so options comparing metadata
+    /// or changing how entries are traversed are not supported.
+    #[arg(long)]
+    tar1: bool,
+    /// Read the second tree from an uncompressed tar archive, rather than from a directory.
+    ///
+    /// See `--tar1`.
+    #[arg(long, conflicts_with = "empty_second")]
+    tar2: bool,
+    /// Read the first tree from a zip archive, rather than from a directory.
+    ///
+    /// Roots that are files ending in `.zip` are read as archives without this flag. Members
+    /// must be stored or deflated, and are read in place, inflated as they are compared. Names
+    /// are split on `\` as well as `/`. Otherwise the same as `--tar1`, and supports the same
+    /// options.
+    #[arg(long, conflicts_with = "tar1")]
+    zip1: bool,
+    /// Read the second tree from a zip archive, rather than from a directory.
+    ///
+    /// See `--zip1`.
+    #[arg(long, conflicts_with_all = ["empty_second", "tar2"])]
+    zip2: bool,
+    /// Compare the first directory with the merged view of an overlay, as the second tree,
+    /// rather than with a second directory. This is its lower directory, see `--upper`.
+    #[arg(long, value_name = "DIR", requires = "upper")]
+    lower: Option<PathBuf>,
+    /// Upper directory of the overlay compared with the first directory, see `--lower`.
+    ///
+    /// The second tree is then the lower directory with the changes of this one applied, as
+    /// overlayfs would mount it: entries of this directory replace those of the lower one, and
+    /// directories present in both are merged. Whiteouts delete entries of the lower directory,
+    /// and are not part of the merged view themselves: files named `.wh.NAME` delete `NAME`,
+    /// character devices 0/0 delete the entry of the same name, and a file named `.wh..wh..opq`
+    /// hides the whole lower directory. The trees are then compared like archives, see
+    /// `--tar1`, and paths of the second tree are shown relative to this directory.
+    #[arg(
+        long,
+        value_name = "DIR",
+        requires = "lower",
+        conflicts_with_all = [
+            "dir2",
+            "pairs_from",
+            "empty_second",
+            "missing_as_empty",
+            "tar2",
+            "zip2",
+        ]
+    )]
+    upper: Option<PathBuf>,
+    /// Compare the first directory with the tree listed by a command, as the second tree, rather
+    /// than with a second directory.
+    ///
+    /// The command is written as for `--compare-cmd`, without placeholders, and must print a
+    /// manifest of the tree on its standard output, in the format of the library's manifests:
+    /// a `#dirdiff-manifest v1` header, then one line per entry with its type (`f`, `l` or
+    /// `d`), its size (`-` but for regular files), the SHA-256 of its content or of its target
+    /// (`-` for directories) and its path, separated by tabs. This can list a remote bucket, a
+    /// database or anything else. Regular files and symlinks are compared by hash, those of the
+    /// first directory being hashed as they are compared. The trees are then compared like
+    /// archives, see `--tar1`, and the command is shown as the second root.
+    #[arg(
+        long,
+        value_name = "COMMAND",
+        conflicts_with_all = [
+            "dir2",
+            "pairs_from",
+            "empty_second",
+            "missing_as_empty",
+            "tar2",
+            "zip2",
+            "upper",
+            "follow_symlink_args",
+            "relative_output_base",
+        ]
+    )]
+    exec_listing: Option<String>,
+    #[arg(short, long, env = "DIRDIFF_JOBS")]
+    /// Number of parallel threads to use.
+    ///
+    /// Use 0 or no option for auto-detection: as many threads as available cores, but no more
+    /// than 8 unless `--threads-cpu-bound` is given.
+    jobs: Option<u16>,
+    /// Assume the comparison is IO-bound when auto-detecting the number of threads (default).
+    ///
+    /// When files have to be read from disk or over the network, threads mostly wait for IO,
+    /// and past a few of them they only compete for the same device and steal each other's
+    /// work, so that more threads can make the comparison slower.
+    #[arg(long, conflicts_with = "threads_cpu_bound")]
+    threads_io_bound: bool,
+    /// Assume the comparison is CPU-bound when auto-detecting the number of threads.
+    ///
+    /// Uses as many threads as available cores, which pays off when files are already in the
+    /// page cache or on very fast storage.
+    #[arg(long)]
+    threads_cpu_bound: bool,
+    /// Number of threads comparing the content of files, apart from the `-j` threads listing
+    /// directories.
+    ///
+    /// By default, each thread compares the files of the directories it lists, so that a large
+    /// file holds up the traversal on its thread. With this option, directory threads queue the
+    /// pairs of files for these threads instead, and only wait for them once a few of them per
+    /// thread are queued. This helps on trees mixing a few huge files with many directories.
+    /// 0, the default, keeps files compared by the directory threads.
+    #[arg(
+        long,
+        value_name = "N",
+        default_value_t = 0,
+        conflicts_with_all = ["two_pass", "non_dir_trees"]
+    )]
+    compare_threads: u16,
+    /// Stop the comparison after this long, like `90`, `30s`, `5m` or `2h`, in seconds without
+    /// a suffix.
+    ///
+    /// The diffs found until then are printed, followed by an error, and the exit code is 3.
+    /// Files whose comparison was under way, including by `--compare-cmd`, whose command is
+    /// killed, or `--comparer`, are printed as `[Not compared]`. With `--pairs-from`, it
+    /// bounds the whole run, and the pairs left are not compared. Threads only stop between
+    /// operations: one blocked listing a directory or reading a file, like on an unresponsive
+    /// network mount, holds the run up until the operation returns.
+    #[arg(long, value_name = "DURATION", value_parser = parse_duration)]
+    timeout: Option<Duration>,
+    /// Stop comparing the content of a pair of regular files after this long, like `30s`, in
+    /// seconds without a suffix, and go on with the other files.
+    ///
+    /// Such files get the tag `[Comparison timed out]`, and the kind `compare_timed_out`. The
+    /// time is only checked between the chunks read, as a read cannot be interrupted: a file
+    /// blocking on a read, like on a stuck network mount, still holds its thread up until the
+    /// read returns, so that this is best-effort for files blocking on their first read. Files
+    /// compared by `--compare-cmd` or `--comparer` are not bounded. Not supported for archives.
+    #[arg(
+        long,
+        value_name = "DURATION",
+        value_parser = parse_duration,
+        conflicts_with_all = ["names_only", "shallow", "count_only", "print_hashes"]
+    )]
+    per_file_timeout: Option<Duration>,
+    /// Maximum number of files and directories the threads open at once.
+    ///
+    /// Threads wait for others to close files rather than exceed it, which avoids running out
+    /// of file descriptors with many threads. Defaults to half of the soft limit of the process
+    /// on open files, `ulimit -n`, on Unix, and to no limit elsewhere. 0 removes the limit.
+    /// The directories kept open by `--secure` are not counted.
+    #[arg(long, value_name = "N")]
+    max_open_files: Option<usize>,
+    /// Number of file descriptors to keep free, waiting before opening files while fewer are.
+    ///
+    /// The descriptors in use are sampled every few milliseconds, so that the threads only
+    /// slow down when the process gets close to its limit on open files, whoever opened them.
+    /// Replaces the default of `--max-open-files`, and can be combined with an explicit one.
+    /// Only supported on Linux, and ignored elsewhere.
+    #[arg(long, value_name = "N")]
+    min_free_fd: Option<u64>,
+    /// Whether to check if the mtime is different.
+    ///
+    /// Only applies to file whose content is otherwise the same,
+    /// and gets its specific output tag: `[Differ by mtime only]`.
+    #[arg(long)]
+    check_mtime: bool,
+    /// Whether to check if the mtime of directories is different.
+    ///
+    /// This is separate from `--check-mtime`, as the mtime of a directory changes whenever
+    /// an entry is added to or removed from it. Directories get the `[Differ by mtime only]`
+    /// tag as well.
+    #[arg(long)]
+    check_dir_mtime: bool,
+    /// Whether to report files whose mtime differs as `[Files differ]`, like files whose
+    /// content differs, rather than as `[Differ by mtime only]`.
+    ///
+    /// Their records are told apart from those of files whose content differs by the note
+    /// `by mtime` after their path, or by `"mtime_only":true` in the JSON output. They are
+    /// failures for the exit code, unless `--fail-on` is given without `different`.
+    /// Directories of `--check-dir-mtime` are still reported by mtime only.
+    #[arg(long, requires = "check_mtime")]
+    mtime_is_diff: bool,
+    /// Resolution mtimes are compared at by `--check-mtime`, `--check-dir-mtime` and
+    /// `--flag-mtime-same-content-differs`.
+    ///
+    /// Both mtimes are rounded down to it first, so that comparing filesystems storing them at
+    /// different resolutions, like ext4 at nanoseconds and FAT at 2 seconds, does not report
+    /// every file. Mtimes are rounded down, not compared within a tolerance: with `s`, 10.9s
+    /// and 10.1s are equal, while 10.9s and 11.1s are not.
+    #[arg(long, value_enum, value_name = "UNIT", default_value_t = Granularity::Ns)]
+    mtime_granularity: Granularity,
+    /// Compare mtimes at the coarser of the resolutions of the filesystems of both dirs.,
+    /// instead of `--mtime-granularity`.
+    ///
+    /// Resolutions are told by the types of the filesystems, on Linux only, and only for those
+    /// whose resolution does not depend on how they are mounted or on a server, see
+    /// `--mtime-granularity` for how mtimes are rounded. A resolution which cannot be told,
+    /// like on NFS or outside of Linux, is taken as 1 second, with a warning. An empty tree
+    /// takes the resolution of the other dir.
+    #[arg(long, conflicts_with_all = ["mtime_granularity", "ignore_mtime"])]
+    auto_mtime_granularity: bool,
+    /// Never look at mtimes, whatever the other options.
+    ///
+    /// The opposite of `--check-mtime`, and the default, but explicit: no diff is ever reported
+    /// because of an mtime, and no directory is ever skipped because of one, like when
+    /// comparing a tree with a snapshot of it. Cannot be combined with `--check-mtime`,
+    /// `--check-dir-mtime`, `--mtime-is-diff` and `--prune-unchanged-dirs`.
+    #[arg(
+        long,
+        conflicts_with_all = [
+            "check_mtime",
+            "check_dir_mtime",
+            "mtime_is_diff",
+            "prune_unchanged_dirs",
+        ]
+    )]
+    ignore_mtime: bool,
+    /// Skip the subdirectories whose mtime is older than the one of this reference file on both
+    /// sides, like a timestamp left by the last backup, without looking at what they contain.
+    ///
+    /// WARNING: this is a heuristic, which silently misses diffs whenever it is wrong. The mtime
+    /// of a directory only changes when entries are added to it, removed from it or renamed in
+    /// it: a file modified in place, or anything changing in a deeper subdirectory, leaves it
+    /// untouched. Mtimes can also be set to any value, like by `touch`, `tar` or `rsync -t`.
+    /// Only use it on trees where every change renames or recreates files in their directory
+    /// and updates the mtime of all the parents. The roots are always compared.
+    #[arg(long, value_name = "REFERENCE")]
+    prune_unchanged_dirs: Option<PathBuf>,
+    /// Whether to check if the permissions of files and directories are different.
+    ///
+    /// Only applies to files whose content is otherwise the same. On Unix, the permission
+    /// bits of the mode are compared, elsewhere only the read-only flag is.
+    #[arg(long)]
+    perms: bool,
+    /// Permission bits compared by `--perms`, as an octal mask like `0755`.
+    ///
+    /// Both modes are masked before being compared, so that only the bits of the mask are
+    /// significant. Defaults to `7777`, all the bits. Ignored outside of Unix.
+    #[arg(long, value_parser = parse_perm_mask, value_name = "OCTAL", requires = "perms")]
+    perm_mask: Option<u32>,
+    /// Whether to check if the owning user and group of files and directories are different.
+    ///
+    /// Only applies to files whose content is otherwise the same. Only supported on Unix.
+    /// The grepable output names the owners, as `user:group`, falling back to the numeric ids
+    /// of those missing from the user and group databases. Each id is looked up once.
+    #[arg(long)]
+    owner: bool,
+    /// Whether to check if regular files have a different number of hard links.
+    ///
+    /// Only applies to files whose content is otherwise the same, and catches files that
+    /// lost a hard link while being copied. Only supported on Unix.
+    #[arg(long)]
+    check_nlink: bool,
+    /// Whether to check if regular files have different inode flags, like immutable or
+    /// append-only.
+    ///
+    /// Only applies to files whose content is otherwise the same. Filesystems without flags
+    /// are skipped, with a warning. Only supported on Linux.
+    #[arg(long)]
+    check_flags: bool,
+    /// Whether to check if regular files have different capabilities, like `cap_net_raw=ep`.
+    ///
+    /// Compares the `security.capability` extended attribute, and gets the tag
+    /// `[Capabilities differ]`, along with the capabilities on each side, as printed by
+    /// `getcap`, or `none`. Only applies to files whose content is otherwise the same. Only
+    /// supported on Linux.
+    #[arg(long)]
+    check_caps: bool,
+    /// Whether to check if regular files and directories have different POSIX ACLs.
+    ///
+    /// Compares the `system.posix_acl_access` extended attribute, along with
+    /// `system.posix_acl_default` for directories, and gets the tag `[ACLs differ]`, along
+    /// with the ACLs on each side, as printed by `getfacl -c` with numeric ids, the entries of
+    /// default ACLs prefixed with `default:`, or `none` for entries with permissions from their
+    /// mode only. Applies to files whose content is otherwise the same. Only supported on
+    /// Linux, and ignored with a warning elsewhere.
+    #[arg(long)]
+    acls: bool,
+    /// Whether to check if regular files have a different number of allocated blocks.
+    ///
+    /// Only applies to files whose content is otherwise the same, like files copied to a
+    /// filesystem with another block size or with compression, or that lost their holes.
+    /// Such differences are often expected, so they do not make the exit code 1 unless
+    /// `blocks` is given to `--fail-on`. Only supported on Unix.
+    #[arg(long)]
+    check_blocks: bool,
+    /// Whether to check if only one of two regular files shares physical extents with other
+    /// files, like a reflink clone of another file against a full copy.
+    ///
+    /// Only applies to files whose content is otherwise the same, and gets the tag
+    /// `[Extent sharing differs]`. Extents are shared by reflink clones, snapshots and
+    /// deduplicated files, which matters for storage planning but not for the content, so this
+    /// does not make the exit code 1 unless `extent_sharing` is given to `--fail-on`. Only
+    /// supported on Linux, on filesystems reporting shared extents, like Btrfs and XFS, and
+    /// skipped with a warning on others.
+    #[arg(long)]
+    check_reflinks: bool,
+    /// Whether to also report regular files whose content differs although they have the same
+    /// size and mtime, which tools preserving mtimes can hide from mtime-based syncs, and which
+    /// can be a sign of corruption or tampering.
+    ///
+    /// Such files get the tag `[Differ despite same mtime]`, and the kind `suspicious_mtime`,
+    /// after their `[Files differ]` record. Mtimes are compared at `--mtime-granularity`. Only
+    /// files whose bytes are compared are reported, not those found different by
+    /// `--compare-cmd` or `--comparer`. Not supported for archives.
+    #[arg(long, conflicts_with_all = ["count_only", "compare_cmd"])]
+    flag_mtime_same_content_differs: bool,
+    /// Whether to follow symlinks when comparing directories' content
+    ///
+    /// Symlinks then have the type of their target, and those whose type differs from the
+    /// entry on the other side are reported as `[Types differ]`, along with where they resolved.
+    #[arg(short = 'L', long)]
+    follow_symlink: bool,
+    /// Whether to follow symlinks for program's arguments.
+    #[arg(short = 'H')]
+    follow_symlink_args: bool,
+    /// Whether to compare symlinks by the location their target resolves to.
+    ///
+    /// By default, symlinks are equal when their targets are written the same. With this
+    /// option, targets are fully resolved, and made relative to their root when inside of it:
+    /// `../a/file` and an absolute path to the same file are equal. A broken link is only
+    /// equal to a broken link with the same target. Has no effect with `-L`, but on the
+    /// symlinks `--no-follow-outside-root` keeps.
+    #[arg(long)]
+    resolve_symlink_targets: bool,
+    /// Whether `-L` only follows the symlinks resolving inside the root they are found under.
+    ///
+    /// The other ones, like those to `/etc` or to the other root, are compared as symlinks, by
+    /// their target. Has no effect without `-L` or `--data-only`. With `-L`, it can be combined
+    /// with `--resolve-symlink-targets` to compare those symlinks by where they resolve to.
+    #[arg(long, conflicts_with = "find_duplicates")]
+    no_follow_outside_root: bool,
+    /// Whether to ignore symlinks when comparing directories' content.
+    ///
+    /// Symlinks are neither compared nor reported as present in one dir. only, as if they
+    /// were not there. Symlinks given as arguments are still traversed. Cannot be combined
+    /// with `-L`, which compares symlinks as their targets instead.
+    #[arg(long, conflicts_with = "follow_symlink")]
+    ignore_symlinks: bool,
+    /// Whether to only compare directories and regular files, ignoring all other types.
+    ///
+    /// Symlinks, devices, FIFOs and sockets are neither compared nor reported, as if they
+    /// were not there. With `-L`, symlinks take the type of their target, so that symlinks
+    /// to directories and regular files are compared as such, while broken ones fail as usual.
+    #[arg(long)]
+    regular_only: bool,
+    /// Whether to ignore empty regular files, like `.gitkeep` placeholders.
+    ///
+    /// Empty files are dropped from each side before entries are paired, so that they are
+    /// neither compared nor reported, as if they were not there. A nonempty file is thus
+    /// reported as present in one dir. only if the other dir. has an empty file with the same
+    /// name. Directories are kept, even empty ones.
+    #[arg(long)]
+    ignore_empty: bool,
+    /// Skip the regular files smaller than this size, like `4K`.
+    ///
+    /// Sizes are in bytes, or in multiples of 1024 with a `K`, `M`, `G` or `T` suffix. A file
+    /// is skipped if it is out of the range of `--min-size` and `--max-size`, bounds included,
+    /// on every side it is present on. A file in range on one side only is still reported as
+    /// `[Files differ]`, since its size differs from the other one.
+    #[arg(long, value_parser = parse_size, value_name = "SIZE")]
+    min_size: Option<u64>,
+    /// Skip the regular files larger than this size, like `10M`, see `--min-size`.
+    #[arg(long, value_parser = parse_size, value_name = "SIZE")]
+    max_size: Option<u64>,
+    /// Whether to report entries of a same directory whose names only differ by case.
+    ///
+    /// Such entries cannot coexist on a case-insensitive filesystem. Each directory of both
+    /// trees is checked on its own, independently of the comparison, and gets the
+    /// `[Case collision]` tag. Collisions present in both trees are reported once,
+    /// and their full paths are joined with the first directory.
+    #[arg(long)]
+    detect_case_collisions: bool,
+    /// Only compare the content of files, ignoring all their metadata.
+    ///
+    /// Regular files are compared byte by byte and directories are recursed into, while
+    /// mtimes, permissions and owners are ignored. Implies `-L`, so that symlinks are compared
+    /// by the content of their target. Cannot be combined with the options comparing metadata
+    /// or changing how content and symlinks are compared: `--check-mtime`, `--check-dir-mtime`,
+    /// `--perms`, `--owner`, `--check-nlink`, `--check-flags`, `--check-caps`, `--acls`,
+    /// `--check-blocks`, `--check-reflinks`, `--flag-mtime-same-content-differs`, `--sparse`,
+    /// `--ignore-content-case`, `--ignore-content-case-unicode`, `--trust-xattr`,
+    /// `--resolve-symlink-targets` and `--ignore-symlinks`.
+    #[arg(
+        long,
+        conflicts_with_all = ["checks", "ignore_symlinks"]
+    )]
+    data_only: bool,
+    /// Only compare the names and types of entries, never their content or metadata.
+    ///
+    /// Directories are recursed into, and only entries present in one dir. only and entries
+    /// whose types differ are reported. Symlinks are equal whatever their target. Cannot be
+    /// combined with the options comparing content or metadata.
+    #[arg(
+        long,
+        conflicts_with_all = ["checks", "data_only", "count_only"]
+    )]
+    names_only: bool,
+    /// Only report which directories have different entries, one level at a time.
+    ///
+    /// For each pair of directories, starting with the roots, the names and types of their
+    /// immediate entries are compared. If they differ, the directory is reported once as
+    /// `[Entries differ]`, along with its number of entries on each side, instead of each
+    /// entry being reported. Subdirectories present on both sides are then compared the same
+    /// way, while the content of files is never read. The roots are reported with an empty path.
+    #[arg(
+        long,
+        conflicts_with_all = [
+            "checks",
+            "compare_cmd",
+            "comparer",
+            "data_only",
+            "names_only",
+            "count_only",
+        ]
+    )]
+    shallow: bool,
+    /// Report directories present on both sides but without any entry in common as a whole.
+    ///
+    /// A directory is entirely different when no name is present in both dir., and it has at
+    /// least one entry on either side. It is then reported once as `[Entries differ]`, along
+    /// with its number of entries on each side, rather than each of its entries as present
+    /// in one dir. only. Directories sharing a single name, even with a different type on each
+    /// side, are reported entry by entry as usual.
+    #[arg(long, conflicts_with_all = ["shallow", "empty_second", "count_only"])]
+    collapse: bool,
+    /// Only report which directories changed, each once as `[Directory changed]`, instead of
+    /// the diffs of their entries.
+    ///
+    /// A directory changed when any of its entries would be reported otherwise: present in one
+    /// dir. only, of different types or contents, or with different compared metadata, like
+    /// under `--perms`. Its files are compared until one differs, and the others are then left
+    /// uncompared, which is much faster than comparing them all when only a verdict per
+    /// directory is needed. Subdirectories present on both sides are still compared, and are
+    /// reported on their own if they changed: a changed file only reports the directory it is
+    /// in, not its parents. The roots are reported with an empty path.
+    #[arg(
+        long,
+        conflicts_with_all = [
+            "shallow",
+            "collapse",
+            "empty_second",
+            "count_only",
+            "two_pass",
+            "compare_threads",
+            "find_duplicates",
+            "detect_case_collisions",
+            "emit_sync_script",
+            "apply",
+            "non_dir_trees",
+        ]
+    )]
+    dir_changed_only: bool,
+    /// Only report what copying the newer entries of the first dir. onto the second one would
+    /// update, as planned by `rsync --update`.
+    ///
+    /// Entries present in the first dir. only are reported as usual, and entries present on
+    /// both sides, but not both directories, as `[Newer in first dir.]` when their mtime is
+    /// strictly newer in the first dir., whatever their content, which is never compared.
+    /// Entries whose mtimes are equal, at `--mtime-granularity`, or newer in the second dir.,
+    /// are left out, as are those present in the second dir. only.
+    #[arg(
+        long,
+        conflicts_with_all = [
+            "perms",
+            "owner",
+            "check_dir_mtime",
+            "detect_case_collisions",
+            "dir_changed_only",
+            "find_duplicates",
+            "count_only",
+            "non_dir_trees",
+        ]
+    )]
+    updates_to_second: bool,
+    /// Only count the work a comparison would do, without comparing files' content.
+    ///
+    /// Directories are traversed as usual, but instead of the diff, prints the number of
+    /// pairs of files that would be compared, of entries present in one dir. only,
+    /// and of bytes that would be read at most.
+    #[arg(long)]
+    count_only: bool,
+    /// Print a fingerprint of the differences of each top-level directory, after the diffs.
+    ///
+    /// For each top-level directory containing differences, prints a line
+    /// `DIR: changed=N fingerprint=HASH`, where `N` is the number of differing paths in it,
+    /// and `HASH` a hash of these paths, sorted. The hash only depends on the set of paths,
+    /// so that it is the same across runs as long as the same files differ. Entries of the
+    /// roots themselves are grouped under `.`. With `--format json`, each line is an object
+    /// with the fields `dir`, `changed` and `fingerprint`.
+    #[arg(long, conflicts_with = "count_only")]
+    dir_fingerprint: bool,
+    /// Print the number of diffs of each top-level directory to stderr, after the diffs, along
+    /// with a bar like `git diff --stat`.
+    ///
+    /// Each line is `DIR | N BAR`, where the bar has a `~` per entry changed, a `+` per entry
+    /// present in the second dir. only, and a `-` per entry present in the first dir. only.
+    /// The bars are scaled down for the longest one to be 40 characters wide at most, keeping
+    /// at least one character for each kind found. Entries of the roots themselves are
+    /// grouped under `.`, and a line with the totals comes last.
+    #[arg(long, conflicts_with = "count_only")]
+    diffstat: bool,
+    /// Print the directories containing differences, anywhere beneath them, instead of the
+    /// diffs.
+    ///
+    /// Once the comparison is over, prints the path of each such directory relative to the
+    /// roots, sorted, the roots themselves being `.`. A directory is listed along with all its
+    /// ancestors, see `--dirs-only-leaf` to only get the deepest ones. The JSON summary is
+    /// left out, and with `--format json`, each line is an object with the fields `dir` and
+    /// `changed`, the number of diffs beneath it.
+    #[arg(
+        long,
+        conflicts_with_all = [
+            "count_only",
+            "dir_changed_only",
+            "group_by",
+            "format_template",
+            "json_pretty",
+            "checkpoint",
+            "emit_sync_script",
+            "apply",
+        ]
+    )]
+    dirs_only: bool,
+    /// Same as `--dirs-only`, but only print the directories containing differences none of
+    /// whose subdirectories do.
+    #[arg(
+        long,
+        conflicts_with_all = [
+            "dirs_only",
+            "count_only",
+            "dir_changed_only",
+            "group_by",
+            "format_template",
+            "json_pretty",
+            "checkpoint",
+            "emit_sync_script",
+            "apply",
+        ]
+    )]
+    dirs_only_leaf: bool,
+    /// Print the number of diffs beneath each directory of `--dirs-only`, as `DIR: changed=N`.
+    #[arg(long)]
+    dir_counts: bool,
+    /// Add an identifier of the path of each diff to its JSON record, as the field `id`.
+    ///
+    /// The identifier is a 16-digit hexadecimal hash of the path relative to the roots, the
+    /// same as the fingerprint of a directory where only this path differs. It is a hash of
+    /// the path, not of the content, so that the same path gets the same identifier across runs
+    /// and machines, whatever its roots, which lets diffs be tracked from one run to the next.
+    /// Only changes the JSON output, the grepable one can show it with the `{id}` placeholder
+    /// of `--format-template`.
+    #[arg(long)]
+    stable_ids: bool,
+    /// Print the commands that would make the second directory match the first one, instead
+    /// of the diffs.
+    ///
+    /// Entries present in the first dir. only are copied with `cp -pPR`, those present in the
+    /// second dir. only are removed with `rm -rf`, and differing entries are removed then
+    /// copied. Entries differing by mtime only are touched with `touch -h`, which sets the mtime
+    /// of symlinks rather than that of their targets, after all the other commands, so that
+    /// copies into a directory do not change its mtime back. Other differences of
+    /// metadata, like permissions, are listed as comments. dirdiff never runs the commands:
+    /// review them before running them, from the same directory, as they remove entries and
+    /// their content from the second directory. A warning is printed to stderr when they do.
+    #[arg(
+        long,
+        conflicts_with_all = [
+            "format",
+            "format_template",
+            "names_only",
+            "shallow",
+            "collapse",
+            "count_only",
+            "dir_fingerprint",
+            "non_dir_trees",
+        ]
+    )]
+    emit_sync_script: bool,
+    /// Make the second directory match the first one, once the comparison is over.
+    ///
+    /// Runs the actions printed by `--emit-sync-script`, in-process, and prints each of them
+    /// once it is done. Entries are copied recursively, symlinks as symlinks, along with the
+    /// permissions of files, those of directories under `--perms`, and mtimes under
+    /// `--check-mtime` and `--check-dir-mtime`. Entries present in the second dir. only are
+    /// only removed with `--delete`. Copying entries changes the mtime of the directories of
+    /// the second dir. they are copied to. Without this option, nothing is ever modified.
+    #[arg(
+        long,
+        conflicts_with_all = [
+            "emit_sync_script",
+            "format",
+            "format_template",
+            "names_only",
+            "shallow",
+            "collapse",
+            "count_only",
+            "dir_fingerprint",
+            "non_dir_trees",
+        ]
+    )]
+    apply: bool,
+    /// Remove the entries present in the second directory only, with their content, under
+    /// `--apply`.
+    #[arg(long, requires = "apply")]
+    delete: bool,
+    /// Format of the actions of `--emit-sync-script` and `--apply`.
+    #[arg(long, value_enum, default_value_t = SyncFormat::Sh)]
+    sync_format: SyncFormat,
+    /// Number of times to retry an IO operation failing with a transient error.
+    ///
+    /// Only interrupted, timed out and would-block operations are retried, with an
+    /// exponential backoff. Useful on flaky network filesystems. The default of
+    /// `--meta-retries` and `--content-retries`.
+    #[arg(long, default_value_t = 0, env = "DIRDIFF_RETRIES")]
+    retries: u32,
+    /// Number of times to retry listing a directory or reading metadata, instead of
+    /// `--retries`.
+    #[arg(long, value_name = "N")]
+    meta_retries: Option<u32>,
+    /// Number of times to retry comparing the content of a pair of files, instead of
+    /// `--retries`.
+    ///
+    /// Both files are opened again and compared from the start on each retry.
+    #[arg(long, value_name = "N")]
+    content_retries: Option<u32>,
+    /// Print paths joined with the directory they are in, instead of relative to it.
+    ///
+    /// Paths of files present in both directories are joined with the first one.
+    #[arg(long)]
+    full_paths: bool,
+    /// Prefix prepended to the printed paths, like `release-42/`.
+    ///
+    /// Applied after `--strip-prefix`.
+    #[arg(long, value_name = "PREFIX", conflicts_with = "full_paths")]
+    path_prefix: Option<PathBuf>,
+    /// Leading components removed from the printed paths, when they start with them.
+    ///
+    /// Paths not starting with the given components are printed unchanged.
+    #[arg(long, value_name = "PREFIX", conflicts_with = "full_paths")]
+    strip_prefix: Option<PathBuf>,
+    /// Print paths relative to the given directory, like `.` for the current one, instead of
+    /// relative to the roots.
+    ///
+    /// The paths are those of the entries joined with the directory they are in, as with
+    /// `--full-paths`, going up with `..` as needed. The roots and the directory are resolved
+    /// first, symlinks included. Fails if a root cannot be reached from the directory, like
+    /// one on another Windows drive.
+    #[arg(
+        long,
+        value_name = "DIR",
+        conflicts_with_all = ["full_paths", "path_prefix", "strip_prefix"]
+    )]
+    relative_output_base: Option<PathBuf>,
+    /// Format of the output records, like `{kind}\t{path}` or `{path}\0`.
+    ///
+    /// Placeholders: `{kind}`, `{path}`, `{fullpath1}`, `{fullpath2}`, `{size1}`, `{size2}` and
+    /// `{id}`, see `--stable-ids`.
+    /// Escape sequences: `\t`, `\n`, `\0` and `\\`, and `{{` and `}}` for literal braces.
+    /// A newline follows each record, unless the template ends with `\0`.
+    #[arg(long, value_name = "TEMPLATE")]
+    format_template: Option<String>,
+    /// Tag of the files whose content differs, instead of `Files differ`.
+    ///
+    /// Like the other `--tag-*` options, only changes the grepable output, where the tag is
+    /// printed in brackets and replaces `{kind}` in `--format-template`. Tags cannot contain
+    /// tabs, newlines or `]`, which would break the lines of the output.
+    #[arg(long, value_name = "TAG", value_parser = parse_tag)]
+    tag_different: Option<String>,
+    /// Tag of the entries present in the first dir. only, instead of
+    /// `Present in first dir. only`.
+    #[arg(long, value_name = "TAG", value_parser = parse_tag)]
+    tag_only_first: Option<String>,
+    /// Tag of the entries present in the second dir. only, instead of
+    /// `Present in second dir. only`.
+    #[arg(long, value_name = "TAG", value_parser = parse_tag)]
+    tag_only_second: Option<String>,
+    /// Tag of the entries differing by mtime only, instead of `Differ by mtime only`.
+    #[arg(long, value_name = "TAG", value_parser = parse_tag)]
+    tag_mtime: Option<String>,
+    /// How the paths of the grepable output which are not valid UTF-8 are written.
+    ///
+    /// By default, they are escaped like the other paths, and replaced lossily in the
+    /// placeholders of `--format-template`. Only the path of each record is concerned: paths
+    /// in its details, like link targets, are always escaped.
+    #[arg(long, value_enum, value_name = "MODE")]
+    non_utf8: Option<NonUtf8>,
+    /// Format of the output.
+    ///
+    /// The kinds of diff of the JSON output are `different`, `only1`, `only2`, `mtime`,
+    /// `sparseness`, `changed`, `perms`, `owner`, `type`, `nlink`, `case_collision`, `entries`,
+    /// `flags`, `vanished`, `blocks`, `not_compared`, `resolved`, `path_too_long`, `moved`,
+    /// `caps`, `hashes`, `dir_changed`, `newer`, `link_target`, `acl`, `raced_type`,
+    /// `possible_rename`, `content_type`, `extent_sharing`, `compare_timed_out` and
+    /// `suspicious_mtime`.
+    #[arg(
+        long,
+        value_enum,
+        default_value_t = Format::Grepable,
+        env = "DIRDIFF_FORMAT",
+        conflicts_with = "format_template"
+    )]
+    format: Format,
+    /// Whether to flush the output after each record of the JSON output.
+    ///
+    /// On by default, so that each record can be consumed as soon as it is found.
+    /// Disabling it with `--flush false` speeds up large outputs. The grepable
+    /// output is always flushed line by line.
+    #[arg(long, action = clap::ArgAction::Set, default_value_t = true, value_name = "BOOL")]
+    flush: bool,
+    /// Whether to leave out the summary record ending the JSON output.
+    ///
+    /// Once the comparison is over, the JSON output ends with a record whose kind is
+    /// `summary`, with a field per kind of diff holding the number of such diffs, across all
+    /// the pairs of `--pairs-from`. It is missing if the comparison failed.
+    #[arg(long)]
+    no_summary: bool,
+    /// Write the JSON output as a single pretty-printed array, with a field per line.
+    ///
+    /// Implies `--format json`. The records are the same as those of the default output,
+    /// including the summary, but each one is written over several lines, one per field. They
+    /// are kept in memory until the comparison is over, and only then written. Nothing is
+    /// streamed, and only the `error` record is written if the comparison fails. Memory grows
+    /// with the number of diffs, by about the size of each record, which makes it best suited
+    /// to small diffs read by humans.
+    #[arg(
+        long,
+        conflicts_with_all = [
+            "format_template",
+            "dir_fingerprint",
+            "count_only",
+            "emit_sync_script",
+            "apply",
+        ]
+    )]
+    json_pretty: bool,
+    /// Write the JSON output as a single array, streamed record by record.
+    ///
+    /// Implies `--format json`. The records are the same as those of the default output,
+    /// including the summary or the `error` record, one per line, but separated by commas
+    /// between `[` and `]`, so that the whole output is a single JSON document. Unlike with
+    /// `--json-pretty`, each record is written as soon as it is found, without being kept in
+    /// memory.
+    #[arg(
+        long,
+        conflicts_with_all = [
+            "json_pretty",
+            "format_template",
+            "dir_fingerprint",
+            "count_only",
+            "dirs_only",
+            "dirs_only_leaf",
+            "emit_sync_script",
+            "apply",
+            "merkle_root",
+        ]
+    )]
+    json_array_stream: bool,
+    /// Insert the diffs in a SQLite database at this path, instead of printing them.
+    ///
+    /// The database is created if missing, along with a `diffs` table, which must not exist
+    /// yet. Each diff is a row with its `kind`, one of those of the JSON output, its `path`, a
+    /// `detail`, and the `size1`, `size2`, `mtime1` and `mtime2` of its entry in each dir. The
+    /// detail holds the values of both sides as `first -> second` for the kinds having some,
+    /// like the types of the `type` kind, or the other path involved for the `case_collision`
+    /// and `moved` kinds. Sizes are only set for regular files and mtimes are in seconds since
+    /// the epoch, both being null on a side the entry is missing from. Those of the second dir.
+    /// are read from the entry compared with the first one, which has another path under
+    /// `--rename-map`. Not supported with archives, `--upper` and `--exec-listing`, as the
+    /// second tree is then not a plain directory. Rows are committed by batches of a thousand,
+    /// and those of the last batch are lost if the comparison fails.
+    #[arg(
+        long,
+        value_name = "FILE",
+        conflicts_with_all = [
+            "pairs_from",
+            "format",
+            "format_template",
+            "json_pretty",
+            "json_array_stream",
+            "count_only",
+            "dirs_only",
+            "dirs_only_leaf",
+            "emit_sync_script",
+            "apply",
+            "merkle_root",
+            "non_dir_trees",
+        ]
+    )]
+    sqlite: Option<PathBuf>,
+    /// Print the diffs sorted by path, once the comparison is over.
+    ///
+    /// Diffs are kept in memory until then. Several diffs about the same entry are printed
+    /// in the order they were found in.
+    #[arg(long)]
+    sorted: bool,
+    /// Order of the paths of the sorted diffs.
+    ///
+    /// `byte`, the default, orders names by their bytes. `locale` orders them as the
+    /// `LC_COLLATE` locale of the environment does, set by `LC_ALL`, `LC_COLLATE` or `LANG`,
+    /// like `ls` or `sort`, so that accented names are next to their unaccented forms rather
+    /// than after `z`. Directories are still paired by the bytes of their names, so that the
+    /// diffs found are the same either way. Only supported on Unix.
+    #[arg(long, value_enum, requires = "sorted")]
+    collation: Option<CollationOrder>,
+    /// Compare the names, types and sizes of all the entries first, then the content of the
+    /// regular files of the same size.
+    ///
+    /// The diffs found by the first pass are printed as soon as they are found, followed by
+    /// a `[Structure compared]` line, or by a record of kind `structure_done` in the JSON
+    /// output, once it is over. The diffs about the content and metadata of files follow.
+    /// Files compared by `--compare-cmd` or `--comparer` are all left to the second pass.
+    /// The pairs of files left to it are kept in memory meanwhile.
+    #[arg(
+        long,
+        conflicts_with_all = ["sorted", "names_only", "shallow", "count_only", "secure"]
+    )]
+    two_pass: bool,
+    /// Order in which the directories are traversed.
+    ///
+    /// Each thread goes on with the last directory it found under `dfs`, and with the first
+    /// one still waiting under `bfs`, which reports the diffs near the roots first but keeps
+    /// whole levels of the trees queued. Idle threads take the oldest directories found by
+    /// the others in both cases, so that the order is only roughly followed with several
+    /// threads. The subdirectories are queued before the files of their parent are compared,
+    /// so that idle threads can take them meanwhile. See `--sorted` for a stable order.
+    #[arg(long, value_enum, default_value_t = TraversalOrder::Dfs)]
+    traversal: TraversalOrder,
+    /// Only print the shallowest diff, the one with the fewest components in its path, and
+    /// stop traversing once no shallower one can be found.
+    ///
+    /// Requires `--traversal bfs`. Directories whose entries are no shallower than a diff
+    /// already found are skipped, so that a diff near the roots ends the comparison early.
+    /// The diff is printed once the traversal is over. Of several diffs at the same depth, any
+    /// one is printed, depending on the threads. The exit code is that of this diff only.
+    #[arg(
+        long,
+        conflicts_with_all = ["sorted", "two_pass", "count_only", "find_duplicates", "checkpoint"]
+    )]
+    shallowest_diff: bool,
+    /// Group the sorted diffs, sorting them by path within each group.
+    #[arg(long, value_enum, requires = "sorted")]
+    group_by: Option<GroupBy>,
+    /// Order of the groups of `--group-by kind`, as a comma-separated list of kinds.
+    ///
+    /// The kinds are those of the JSON output. Kinds left out come after the given ones,
+    /// in the order: `different`, `only1`, `only2`, `mtime`, `sparseness`, `changed`, `perms`,
+    /// `owner`, `type`, `nlink`, `case_collision`, `entries`, `flags`, `vanished`, `blocks`,
+    /// `not_compared`, `resolved`, `path_too_long`, `moved`, `caps`, `hashes`, `dir_changed`,
+    /// `newer`, `link_target`, `acl`, `raced_type`, `possible_rename`, `content_type`,
+    /// `extent_sharing`, `compare_timed_out` and `suspicious_mtime`.
+    #[arg(
+        long,
+        value_delimiter = ',',
+        value_name = "KINDS",
+        requires = "group_by"
+    )]
+    kind_order: Vec<String>,
+    /// Kinds of diff making the exit code 1, as a comma-separated list of kinds.
+    ///
+    /// The kinds are those of the JSON output. Diffs of other kinds are still printed, but
+    /// the exit code is 0 if no diff of the given kinds is found. Defaults to all the kinds
+    /// but `mtime`, `blocks`, `resolved`, `moved`, `hashes` and `extent_sharing`, so that files
+    /// differing by mtime, allocated blocks or extent sharing only are not failures, nor are the
+    /// records of `--show-resolved`, `--find-duplicates` and `--print-hashes`.
+    #[arg(long, value_delimiter = ',', value_name = "KINDS")]
+    fail_on: Option<Vec<String>>,
+    /// Read the paths of the files expected to differ from the given file, one per line,
+    /// relative to the roots.
+    ///
+    /// Files at these paths differing by content or mtime are still printed, but with the tag
+    /// `[Expected diff]`, or with `"expected":true` in the JSON output, and are never
+    /// failures, whatever `--fail-on`. Other diffs about them, like them being present in one
+    /// dir. only, are unaffected.
+    #[arg(long, value_name = "FILE")]
+    expected_diff_from: Option<PathBuf>,
+    /// Leave out the diffs found by a previous run, read from its output, so that only the
+    /// differences new since then are printed.
+    ///
+    /// The output is grepable, with the same `--tag-*` options, or JSON, one record per line
+    /// as by default or with `--json-array-stream`. A diff is left out if its kind and its path
+    /// relative to the roots are exactly those of a diff of the previous run, whatever its
+    /// details, so that the previous run must print paths relative to the roots, without
+    /// `--full-paths`, `--path-prefix`, `--strip-prefix` or `--relative-output-base`. Paths
+    /// which are not valid UTF-8 are only matched from grepable output, where they are escaped.
+    /// Records tagged `[Expected diff]`, whose files differed by content or by mtime only,
+    /// leave out the diffs of both kinds. The lines of `--print-hashes` are skipped. Left out
+    /// diffs are not failures either, nor are they counted in the JSON summary. With
+    /// `--pairs-from`, the pairs of the records are ignored.
+    #[arg(long, value_name = "FILE")]
+    suppress_from: Option<PathBuf>,
+    /// Only print the entries present in the first dir. only, in the second dir. only, or the
+    /// files present in both with a different content.
+    ///
+    /// Other diffs are still found, but left out of the output, including the counts of the
+    /// JSON summary and `--dir-fingerprint`. The exit code still accounts for them, as set by
+    /// `--fail-on`: add `--fail-on only1` to `--only-in first` to fail only on what is printed.
+    #[arg(long, value_enum, value_name = "SIDE", conflicts_with = "count_only")]
+    only_in: Option<OnlyIn>,
+    /// Don't report the entries that could not be read one by one, only their number, as
+    /// `N entries could not be read` on stderr once all the pairs are compared.
+    ///
+    /// These are the directories that vanished during the scan, otherwise reported as
+    /// `[Vanished during scan]`. They are left out of the output like the diffs left out by
+    /// `--only-in`, but still count in the exit code, as set by `--fail-on`.
+    #[arg(long, conflicts_with = "count_only")]
+    quiet_errors: bool,
+    /// Print nothing if the trees are identical, or only `trees differ: N differences` to
+    /// stderr otherwise, `N` being the number of failing diffs.
+    ///
+    /// A gate for scripts: the exit code is 0 or 1 as usual, 2 on errors, and diffs are never
+    /// printed. Failing diffs are those of `--fail-on`, so that files differing by mtime only
+    /// under `--check-mtime` do not count, unless with `--mtime-is-diff` or with `mtime` given
+    /// to `--fail-on`.
+    #[arg(
+        long,
+        conflicts_with_all = [
+            "format",
+            "json_pretty",
+            "json_array_stream",
+            "sqlite",
+            "emit_sync_script",
+            "apply",
+            "merkle_root",
+            "dirs_only",
+            "dirs_only_leaf",
+            "count_only",
+            "group_by",
+            "diffstat",
+            "dir_fingerprint",
+            "explain",
+        ]
+    )]
+    assert_identical: bool,
+    /// Whether to check if sparse files have their holes at the same places.
+    ///
+    /// Only applies to files whose content is otherwise the same,
+    /// and gets its specific output tag: `[Differ by holes only]`.
+    /// Only supported on Linux, and on filesystems reporting holes.
+    #[arg(long)]
+    sparse: bool,
+    /// Whether to ignore the case of letters when comparing files' content.
+    ///
+    /// Only ASCII letters are folded, other bytes, including those of non-ASCII
+    /// UTF-8 characters, must be the same.
+    #[arg(long)]
+    ignore_content_case: bool,
+    /// Whether to ignore the case of all Unicode letters when comparing files' content, like
+    /// `Straße` and `STRASSE`.
+    ///
+    /// Files are decoded as UTF-8 and case-folded, which can change their length, so they are
+    /// read whole, up to `--max-compare-size`, taking memory of the order of twice their size
+    /// for each thread. Files that are larger, or are not valid UTF-8, are compared byte by
+    /// byte instead, with a warning for the latter, or only their number under
+    /// `--quiet-errors`. Files with an extension given to `--comparer` use its strategy instead.
+    #[arg(
+        long,
+        conflicts_with_all = [
+            "ignore_content_case",
+            "sort_lines",
+            "compare_cmd",
+            "compare_prefix",
+            "count_diff_bytes",
+            "secure",
+        ]
+    )]
+    ignore_content_case_unicode: bool,
+    /// Whether to hint the kernel that files are read sequentially and only once.
+    ///
+    /// Read-ahead is increased while comparing files, which are then dropped from the page
+    /// cache. This pays off on trees much larger than memory, whose files would otherwise
+    /// evict more useful pages from the cache, but slows down comparisons run several times
+    /// on the same files. Results are the same either way. Only supported on Linux.
+    #[arg(long)]
+    fadvise: bool,
+    /// Maximum number of bytes read to compare files' content, across all the pairs.
+    ///
+    /// Both files of a pair count. Once the budget runs out, the remaining regular files are
+    /// reported as `[Not compared]` instead of being read, including a pair it ran out in the
+    /// middle of: a partly read pair is never reported as different unless a difference was
+    /// found in the part that was read. Files of different sizes are still reported as
+    /// different, as that needs no read. `--compare-cmd` and `--comparer` are not counted.
+    #[arg(long, value_name = "BYTES", conflicts_with_all = ["names_only", "shallow", "count_only"])]
+    byte_budget: Option<u64>,
+    /// Only compare the first bytes of regular files, up to this size, like `4K`, see
+    /// `--min-size`.
+    ///
+    /// Files of the same size whose first bytes are the same are considered equal, whatever
+    /// follows, which is much faster on large files when checking their headers is enough.
+    /// This is a sampling heuristic, not a comparison: files differing past these bytes are
+    /// not reported. Files of different sizes are still reported as different.
+    #[arg(
+        long,
+        value_parser = parse_size,
+        value_name = "SIZE",
+        conflicts_with_all = [
+            "names_only",
+            "shallow",
+            "compare_cmd",
+            "comparer",
+            "sort_lines",
+            "print_hashes",
+        ]
+    )]
+    compare_prefix: Option<u64>,
+    /// Count the bytes differing between regular files, along with the files that differ.
+    ///
+    /// Files are read to the end even once they differ, comparing each byte position, and
+    /// files of different sizes are read too, the tail of the largest one counting as differing
+    /// whole. This reads much more than stopping at the first difference. The count is the
+    /// `differing_bytes` field of JSON records, and follows the path of grepable ones. Not
+    /// supported for archives.
+    #[arg(
+        long,
+        conflicts_with_all = ["names_only", "shallow", "compare_cmd", "compare_prefix"]
+    )]
+    count_diff_bytes: bool,
+    /// Only compare the content of regular files of this type, sniffed from their first bytes,
+    /// like `image` or `image/png`.
+    ///
+    /// The type is a kind among `app`, `archive`, `audio`, `book`, `doc`, `font`, `image`,
+    /// `text` and `video`, or a MIME type. Types are told by the magic bytes files start with,
+    /// whatever their extension, so most text files have none. Pairs of files of other types
+    /// are skipped, along with their metadata, while files of different types, one of them
+    /// being this one, are reported as `[Content types differ]`. Entries present in one dir.
+    /// only are still reported, whatever their type. Files of different sizes are read too,
+    /// to sniff their types. Not supported for archives.
+    #[arg(
+        long,
+        value_parser = parse_content_type,
+        value_name = "TYPE",
+        conflicts_with_all = [
+            "names_only",
+            "shallow",
+            "compare_cmd",
+            "comparer",
+            "sort_lines",
+            "ignore_content_case_unicode",
+            "print_hashes",
+        ]
+    )]
+    content_type: Option<ContentType>,
+    /// Extended attribute holding a hash of files' content, like `user.checksum`.
+    ///
+    /// Files of the same size having the same value for this attribute are considered
+    /// equal without reading their content. Otherwise, their content is compared as usual.
+    /// Only supported on Linux.
+    #[arg(long, value_name = "NAME")]
+    trust_xattr: Option<String>,
+    /// Whether to consider regular files equal without reading them when they are the same
+    /// file on both sides, with the same device and inode numbers.
+    ///
+    /// This happens when the trees overlap, like through bind mounts or hard links between
+    /// them, but not for reflinked copies, which are distinct files. Only supported on Unix.
+    #[arg(long)]
+    trust_same_inode: bool,
+    /// Command telling whether two regular files are equal, like `cmp -s %1 %2`.
+    ///
+    /// `%1` and `%2` are replaced by the paths of the files, and `%%` by a literal `%`.
+    /// Arguments can be quoted as on a shell command line, but no shell is run. Files are
+    /// equal if the command exits successfully, and differ otherwise, whatever their size.
+    /// A process is spawned for each pair of files, which is much slower than the builtin
+    /// comparison on trees with many small files.
+    #[arg(
+        long,
+        value_name = "COMMAND",
+        conflicts_with_all = ["ignore_content_case", "trust_xattr", "names_only"]
+    )]
+    compare_cmd: Option<String>,
+    /// Strategy comparing the content of regular files with a given extension, as `EXT=STRATEGY`.
+    ///
+    /// `raw` compares bytes, as without this option, `gzip` compares the decompressed content
+    /// of gzipped files, whatever their compression level, and `sha256` compares the SHA-256
+    /// hashes of files, reading them whole one after the other. The strategy of the extension
+    /// of the files is used even if their sizes differ. If a file is malformed for it, like a
+    /// `.gz` file that is not gzipped, both files are compared byte by byte instead. Can be
+    /// repeated, like `--comparer gz=gzip --comparer tgz=gzip`.
+    #[arg(
+        long,
+        value_name = "EXT=STRATEGY",
+        value_parser = parse_comparer,
+        conflicts_with_all = ["names_only", "compare_cmd"]
+    )]
+    comparer: Vec<(String, Arc<dyn ContentComparer>)>,
+    /// Whether to compare regular files as sets of lines, whatever their order.
+    ///
+    /// Both files are read whole, their lines sorted and then compared, which takes memory of
+    /// the order of twice the size of the files for each thread. A missing newline at the end
+    /// of the last line is ignored. Files larger than `--max-compare-size` are compared byte by
+    /// byte instead. Files with an extension given to `--comparer` use its strategy instead.
+    #[arg(
+        long,
+        conflicts_with_all = ["names_only", "data_only", "compare_cmd", "shallow", "secure"]
+    )]
+    sort_lines: bool,
+    /// Size of the largest files read by `--sort-lines` or `--ignore-content-case-unicode`,
+    /// like `16M`, see `--min-size`. Defaults to 16M.
+    #[arg(long, value_parser = parse_size, value_name = "SIZE")]
+    max_compare_size: Option<u64>,
+    /// Whether to open each directory and file relative to its parent, never following symlinks.
+    ///
+    /// Directories are otherwise opened by their path from the roots, so that one replaced with
+    /// a symlink while being compared is followed, possibly outside of the roots. With this
+    /// option, they are opened through the file descriptor of their parent, and fail to open if
+    /// they became symlinks. Only supported on Linux.
+    #[arg(
+        long,
+        conflicts_with_all = [
+            "follow_symlink",
+            "data_only",
+            "resolve_symlink_targets",
+            "empty_second",
+            "sparse",
+            "trust_xattr",
+            "compare_cmd",
+            "comparer",
+            "missing_as_empty",
+        ]
+    )]
+    secure: bool,
+    /// Whether to print why each pair of regular files was deemed equal or different.
+    ///
+    /// A line is printed to stderr for each comparison, naming the rule that decided it: the
+    /// comparison command, the sizes, the trusted extended attribute, or the content, with the
+    /// offset of the first difference. Files not compared, like under `--names-only`, are not
+    /// listed.
+    #[arg(long)]
+    explain: bool,
+    /// Print the N slowest directories to stderr at the end, 10 if N is not given.
+    ///
+    /// The time of a directory is the time spent listing it and comparing its files, without
+    /// its subdirectories, and is printed along with its number of entries. Only the N slowest
+    /// directories are kept in memory. With `--pairs-from`, the directories of all the pairs are
+    /// ranked together.
+    #[arg(
+        long,
+        value_name = "N",
+        num_args = 0..=1,
+        default_missing_value = "10",
+        conflicts_with_all = ["non_dir_trees"]
+    )]
+    per_dir_timing: Option<usize>,
+    /// Print the progress of the comparison every second, and once it is over, as `text` or
+    /// `json` records, one per line.
+    ///
+    /// Each record gives the number of pairs of directories whose entries were all compared,
+    /// of pairs of regular files compared, and the directories being compared by the threads,
+    /// relative to the roots, `.` being the roots themselves. JSON records are objects with
+    /// the fields `dirs_done`, `files_done` and `active`. Records are written to stderr, or to
+    /// `--progress-fd`. With `--pairs-from`, the counts add up across pairs.
+    #[arg(
+        long,
+        value_enum,
+        value_name = "FORMAT",
+        conflicts_with_all = ["non_dir_trees"]
+    )]
+    progress_format: Option<ProgressFormat>,
+    /// File descriptor the progress records are written to, rather than stderr, like `3` with
+    /// `3>progress.log`. It must be open for writing. Only supported on Unix.
+    #[arg(long, value_name = "FD", requires = "progress_format")]
+    progress_fd: Option<i32>,
+    /// Record the top-level directories compared in this file, and skip those it already
+    /// records, to resume an interrupted comparison.
+    ///
+    /// A directory present in both roots is recorded as soon as its whole subtree is compared
+    /// and its diffs printed, so that a run interrupted at any point can be resumed by running
+    /// the same command again: the diffs of both runs then add up to those of a single one.
+    /// The other entries of the roots are compared again, and their diffs printed again, on
+    /// each run. The exit code only accounts for the diffs of the current run. Keep the
+    /// default `--flush true` with JSON, and remove the file to start over. Cannot be combined
+    /// with the options printing diffs once the comparison is over.
+    #[arg(
+        long,
+        value_name = "FILE",
+        conflicts_with_all = [
+            "pairs_from",
+            "non_dir_trees",
+            "sorted",
+            "two_pass",
+            "compare_threads",
+            "find_duplicates",
+            "group_by",
+            "json_pretty",
+            "dir_fingerprint",
+            "count_only",
+            "emit_sync_script",
+            "apply",
+        ]
+    )]
+    checkpoint: Option<PathBuf>,
+    /// Whether to print where the symlinks followed by `-L` resolved to.
+    ///
+    /// Each entry reported under a followed symlink, on either side, is followed by a
+    /// `[Resolved links]` record giving the fully resolved path of the links, on the sides it
+    /// was a link on. Needs `-L` or `--data-only`, other symlinks are never followed.
+    #[arg(long)]
+    show_resolved: bool,
+    /// Once the comparison is over, report the files present in one dir. only whose content is
+    /// present in the other dir. under another path, as moved or renamed.
+    ///
+    /// The regular files present in one dir. only, including those under directories present
+    /// in one dir. only, are grouped by size, hashed, and compared byte by byte when their
+    /// hashes match. Each file of the first dir. is reported once, as `[Moved or renamed]`,
+    /// along with the first of its duplicates in the second dir. Each other duplicate of the
+    /// second dir. is reported along with the first of those of the first dir. Empty files
+    /// are left out. These records come after the other diffs, and their paths are kept in
+    /// memory meanwhile.
+    #[arg(
+        long,
+        conflicts_with_all = ["count_only", "secure", "non_dir_trees"]
+    )]
+    find_duplicates: bool,
+    /// Compare an entry of the first dir. with an entry of another name in the second one, as
+    /// `OLD=NEW`, like `--rename-map v1=v2` to compare `dir1/v1/x` with `dir2/v2/x`.
+    ///
+    /// Both are paths relative to the roots. Only the name of an entry can change, so `NEW` must
+    /// be in the directory the parent of `OLD` is compared with. Can be repeated, and renames
+    /// nest: with `--rename-map v1=v2`, `--rename-map v1/a=v2/b` compares `dir1/v1/a` with
+    /// `dir2/v2/b`. An entry cannot be renamed twice, nor two entries to the same one. Entries
+    /// named `OLD` in the second dir. and `NEW` in the first one are compared with nothing.
+    /// Diffs are printed with the paths of the first dir., even for entries present in the
+    /// second dir. only.
+    #[arg(
+        long,
+        value_name = "OLD=NEW",
+        value_parser = parse_rename,
+        conflicts_with_all = [
+            "emit_sync_script",
+            "apply",
+            "non_dir_trees",
+        ]
+    )]
+    rename_map: Vec<(PathBuf, PathBuf)>,
+    /// Whether to print the SHA-256 hashes of the content of each pair of regular files
+    /// compared, whether they are equal or not.
+    ///
+    /// Each pair gets a line `<hash1> <hash2> <path>`, with the hashes of the files in the
+    /// first and second dir. as hex digits, before the other records of the files. In JSON,
+    /// they are records of the `hashes` kind, with the fields `hash1` and `hash2`. The content
+    /// of the files is then compared by these hashes, so both files are read whole, even when
+    /// their sizes differ. Files not compared, like under `--names-only`, are not listed.
+    #[arg(
+        long,
+        conflicts_with_all = [
+            "compare_cmd",
+            "comparer",
+            "trust_xattr",
+            "trust_same_inode",
+            "ignore_content_case",
+            "byte_budget",
+            "count_only",
+            "dir_fingerprint",
+            "emit_sync_script",
+            "apply",
+            "non_dir_trees",
+            "dir_changed_only",
+        ]
+    )]
+    print_hashes: bool,
+    /// Report entries of a directory present in one dir. only each, of the same type, whose names
+    /// are within this many edits of each other as possibly renamed, like `report-final.pdf`
+    /// and `report_final.pdf`, rather than as present in one dir. only.
+    ///
+    /// Edits are insertions, deletions and substitutions of characters, counted as the
+    /// Levenshtein distance of the names. Each entry is paired at most once: the closest pairs
+    /// are taken first, and among those at the same distance, the entries of the first dir.
+    /// are paired in the order of their names, with the first entry of the second dir. by name.
+    /// Entries paired this way are not recursed into. The entries present in one dir. only of
+    /// each directory are compared with all those of the other side.
+    #[arg(
+        long,
+        value_name = "DISTANCE",
+        conflicts_with_all = [
+            "emit_sync_script",
+            "apply",
+            "collapse",
+            "shallow",
+            "dir_changed_only",
+            "updates_to_second",
+            "non_dir_trees",
+        ]
+    )]
+    fuzzy_names: Option<usize>,
+    /// Only compare the entries whose paths are listed in this file, one per line, like those a
+    /// file watcher found changed.
+    ///
+    /// Paths are relative to the roots. Listed entries are compared as usual, and reported as
+    /// present in one dir. only when missing from the other one, while listed directories are
+    /// recursed into. The directories leading to listed entries are only traversed to reach
+    /// them, and their other entries are left out. When such a directory is missing or not a
+    /// directory on one side, it is reported instead of the listed entries beneath it. Entries
+    /// missing from both sides are not reported.
+    #[arg(
+        long,
+        value_name = "FILE",
+        conflicts_with_all = [
+            "shallow",
+            "collapse",
+            "dir_changed_only",
+            "merkle_root",
+            "non_dir_trees",
+        ]
+    )]
+    paths_from: Option<PathBuf>,
+    /// Do not recurse into the directories listed by `--paths-from`: only their own entries are
+    /// compared, and their subdirectories are not descended into, unless listed too.
+    #[arg(long, requires = "paths_from")]
+    paths_from_no_recurse: bool,
+}
+
+/// Second directory standing for an empty tree.
+const EMPTY_TREE: &str = "/dev/null";
+
+/// Granularity of `--auto-mtime-granularity`, the coarsest one of the filesystems of `roots`,
+/// as told by `detect`, leaving out the empty tree, taken as seconds for those which cannot be
+/// told.
+fn detected_granularity(
+    roots: [&Path; 2],
+    detect: impl Fn(&Path) -> Option<MtimeGranularity>,
+) -> MtimeGranularity {
+    roots
+        .into_iter()
+        .filter(|root| *root != Path::new(EMPTY_TREE))
+        .map(|root| {
+            detect(root).unwrap_or_else(|| {
+                eprintln!(
+                    "Warning: couldn't tell the mtime resolution of {}, taken as 1 second",
+                    root.display()
+                );
+                MtimeGranularity::Seconds
+            })
+        })
+        .max()
+        .unwrap_or_default()
+}
+
+/// Maximum number of threads picked by auto-detection, unless `--threads-cpu-bound` is given.
+const IO_BOUND_MAX_THREADS: u16 = 8;
+
+/// Time between two records of `--progress-format`.
+const PROGRESS_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Number of threads to use for `-j jobs`, auto-detected from the `available` cores if it is
+/// 0 or not given.
+fn thread_count(
+    jobs: Option<u16>,
+    cpu_bound: bool,
+    available: impl FnOnce() -> anyhow::Result<u16>,
+) -> anyhow::Result<u16> {
+    Ok(match jobs {
+        Some(jobs) if jobs > 0 => jobs,
+        _ if cpu_bound => available()?,
+        _ => std::cmp::min(available()?, IO_BOUND_MAX_THREADS),
+    })
+}
+
+/// Size of the largest files read whole by the comparers, unless `--max-compare-size` is given.
+const DEFAULT_MAX_COMPARE_SIZE: u64 = 16 << 20;
+
+/// Parses a `--comparer` mapping, from an extension to the name of a strategy.
+fn parse_comparer(mapping: &str) -> Result<(String, Arc<dyn ContentComparer>), String> {
+    let (extension, strategy) = match mapping.split_once('=') {
+        Some((extension, strategy)) if !extension.is_empty() => (extension, strategy),
+        _ => return Err("expected EXT=STRATEGY".to_owned()),
+    };
+    let comparer: Arc<dyn ContentComparer> = match strategy {
+        "raw" => Arc::new(RawBytes),
+        "gzip" => Arc::new(Gzip),
+        "sha256" => Arc::new(Sha256),
+        _ => {
+            return Err(format!(
+                "unknown strategy `{}`, expected raw, gzip or sha256",
+                strategy
+            ))
+        }
+    };
+    Ok((extension.to_owned(), comparer))
+}
+
+/// Parses a `--rename-map` pair, from a path in the first dir. to one in the second dir.
+fn parse_rename(rename: &str) -> Result<(PathBuf, PathBuf), String> {
+    match rename.split_once('=') {
+        Some((old, new)) if !old.is_empty() && !new.is_empty() => Ok((old.into(), new.into())),
+        _ => Err("expected OLD=NEW".to_owned()),
+    }
+}
+
+/// Parses a tag of the `--tag-*` options, which must fit between brackets on a single field.
+fn parse_tag(tag: &str) -> Result<String, String> {
+    if tag.contains(['\t', '\n', '\r', ']']) {
+        return Err("tags cannot contain tabs, newlines or ]".to_owned());
+    }
+    Ok(tag.to_owned())
+}
+
+/// Parses the octal mask of `--perm-mask`, with or without a `0o` prefix.
+fn parse_perm_mask(mask: &str) -> Result<u32, String> {
+    let digits = mask.strip_prefix("0o").unwrap_or(mask);
+    match u32::from_str_radix(digits, 8) {
+        Ok(mask) if mask <= 0o7777 => Ok(mask),
+        Ok(_) => Err("mask must be at most 7777".to_owned()),
+        Err(e) => Err(format!("invalid octal mask: {}", e)),
+    }
+}
+
+/// Parses a size in bytes, optionally followed by a `K`, `M`, `G` or `T` suffix for powers
+/// of 1024, whatever its case.
+fn parse_size(size: &str) -> Result<u64, String> {
+    let (digits, shift) = match size.char_indices().last() {
+        Some((i, suffix)) if suffix.is_ascii_alphabetic() => {
+            let shift = match suffix.to_ascii_uppercase() {
+                'K' => 10,
+                'M' => 20,
+                'G' => 30,
+                'T' => 40,
+                _ => {
+                    return Err(format!(
+                        "unknown suffix `{}`, expected K, M, G or T",
+                        suffix
+                    ))
+                }
+            };
+            (&size[..i], shift)
+        }
+        _ => (size, 0),
+    };
+    let n: u64 = digits.parse().map_err(|e| format!("invalid size: {}", e))?;
+    n.checked_mul(1 << shift)
+        .ok_or_else(|| "size is too large".to_owned())
+}
+
+/// Parses a content type, as a kind of types or a MIME type, see [`ContentType::parse`].
+fn parse_content_type(name: &str) -> Result<ContentType, String> {
+    ContentType::parse(name).map_err(|e| e.to_string())
+}
+
+/// Parses a duration in seconds, optionally followed by an `s`, `m` or `h` suffix for seconds,
+/// minutes or hours.
+fn parse_duration(duration: &str) -> Result<Duration, String> {
+    let (digits, unit) = match duration.char_indices().last() {
+        Some((i, suffix)) if suffix.is_ascii_alphabetic() => {
+            let unit = match suffix {
+                's' => 1,
+                'm' => 60,
+                'h' => 60 * 60,
+                _ => return Err(format!("unknown suffix `{}`, expected s, m or h", suffix)),
+            };
+            (&duration[..i], unit)
+        }
+        _ => (duration, 1),
+    };
+    let n: u64 = digits
+        .parse()
+        .map_err(|e| format!("invalid duration: {}", e))?;
+    n.checked_mul(unit)
+        .map(Duration::from_secs)
+        .ok_or_else(|| "duration is too long".to_owned())
+}
+
+/// Checks that the kinds given to `option` are kinds of diff.
+fn check_kinds(option: &str, kinds: &[String]) -> anyhow::Result<()> {
+    for kind in kinds {
+        if !DIFF_KINDS.contains(&kind.as_str()) {
+            bail!(
+                "Unknown kind `{}` in {}, expected one of {}",
+                kind,
+                option,
+                DIFF_KINDS.join(", ")
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Checks that the options given together make sense, beyond the conflicts clap checks.
+///
+/// Clap only tells whether an option is given, not its value, so options only meaningful
+/// with some values of another one are checked here, instead of being silently ignored.
+fn check_combinations(cli_args: &CliArgs) -> anyhow::Result<()> {
+    let json = cli_args.format == Format::Json;
+    let format = match cli_args.format {
+        // Which implies the JSON format.
+        _ if cli_args.json_pretty => "--json-pretty",
+        _ if cli_args.json_array_stream => "--json-array-stream",
+        Format::Grepable => "--format grepable",
+        Format::Itemize => "--format itemize",
+        Format::Json => "--format json",
+    };
+    let tags = [
+        ("--tag-different", &cli_args.tag_different),
+        ("--tag-only-first", &cli_args.tag_only_first),
+        ("--tag-only-second", &cli_args.tag_only_second),
+        ("--tag-mtime", &cli_args.tag_mtime),
+    ];
+    for (option, tag) in tags {
+        if tag.is_some() && cli_args.format != Format::Grepable {
+            bail!("`{}` cannot be combined with `{}`", option, format);
+        }
+    }
+    // Options which only change the JSON output.
+    let json_options = [
+        ("--stable-ids", cli_args.stable_ids),
+        ("--no-summary", cli_args.no_summary),
+        ("--flush false", !cli_args.flush),
+    ];
+    for (option, given) in json_options {
+        if given && !json {
+            bail!("`{}` cannot be combined with `{}`", option, format);
+        }
+    }
+    if cli_args.shallowest_diff && cli_args.traversal != TraversalOrder::Bfs {
+        bail!("`--shallowest-diff` requires `--traversal bfs`");
+    }
+    if cli_args.dir_counts && !cli_args.dirs_only && !cli_args.dirs_only_leaf {
+        bail!("`--dir-counts` requires `--dirs-only` or `--dirs-only-leaf`");
+    }
+    if cli_args.sync_format != SyncFormat::Sh && !cli_args.emit_sync_script && !cli_args.apply {
+        bail!("`--sync-format` requires `--emit-sync-script` or `--apply`");
+    }
+    // rsync has no change for a rename.
+    if cli_args.fuzzy_names.is_some() && cli_args.format == Format::Itemize {
+        bail!("`--fuzzy-names` cannot be combined with `{}`", format);
+    }
+    if cli_args.max_compare_size.is_some()
+        && !cli_args.sort_lines
+        && !cli_args.ignore_content_case_unicode
+    {
+        bail!("`--max-compare-size` requires `--sort-lines` or `--ignore-content-case-unicode`");
+    }
+    if cli_args.auto_mtime_granularity && !cli_args.check_mtime && !cli_args.check_dir_mtime {
+        bail!("`--auto-mtime-granularity` requires `--check-mtime` or `--check-dir-mtime`");
+    }
+    if cli_args.mtime_granularity != Granularity::Ns {
+        if cli_args.ignore_mtime {
+            bail!("`--mtime-granularity` cannot be combined with `--ignore-mtime`");
+        }
+        if !cli_args.check_mtime
+            && !cli_args.check_dir_mtime
+            && !cli_args.flag_mtime_same_content_differs
+        {
+            bail!(
+                "`--mtime-granularity` requires `--check-mtime`, `--check-dir-mtime` or \
+                 `--flag-mtime-same-content-differs`"
+            );
+        }
+    }
+    if let (Some(min_size), Some(max_size)) = (cli_args.min_size, cli_args.max_size) {
+        if min_size > max_size {
+            bail!(
+                "`--min-size` ({}) cannot be larger than `--max-size` ({}), which would skip every file",
+                min_size,
+                max_size
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Checks that a root given on the command line is a readable directory.
+///
+/// This is done before spawning the workers, so that a bad argument is
+/// reported clearly instead of failing in the middle of the traversal.
+fn check_root(name: &str, path: &Path) -> anyhow::Result<()> {
+    let metadata = match path.metadata() {
+        Ok(metadata) => metadata,
+        Err(e) if e.kind() == ErrorKind::NotFound => {
+            bail!(
+                "{} ({}) does not exist, see --missing-as-empty to compare it as empty",
+                name,
+                path.display()
+            )
+        }
+        Err(e) => {
+            return Err(e).with_context(|| format!("Couldn't access {} ({})", name, path.display()))
+        }
+    };
+    if !metadata.is_dir() {
+        bail!("{} ({}) is not a directory", name, path.display());
+    }
+    read_dir(path).with_context(|| format!("{} ({}) is not readable", name, path.display()))?;
+    Ok(())
+}
+
+/// Whether a root does not exist, for `--missing-as-empty`.
+///
+/// Other errors are left for [`check_root`] to report.
+fn is_missing(path: &Path) -> bool {
+    matches!(path.metadata(), Err(e) if e.kind() == ErrorKind::NotFound)
+}
+
+/// Tree a root is read from: an archive because of `--tar1`, `--zip1` or their counterparts
+/// for the second root, or of its extension, and a directory otherwise.
+fn root_tree(tar: bool, zip: bool, path: PathBuf) -> Tree {
+    let has_extension = |extension| {
+        matches!(path.extension(), Some(e) if e.eq_ignore_ascii_case(extension)) && path.is_file()
+    };
+    if tar || has_extension("tar") {
+        Tree::Tar(path)
+    } else if zip || has_extension("zip") {
+        Tree::Zip(path)
+    } else {
+        Tree::Dir(path)
+    }
+}
+
+/// Checks that a root read from an archive can be opened, as [`check_root`] does for directories.
+fn check_archive(name: &str, path: &Path) -> anyhow::Result<()> {
+    File::open(path).with_context(|| format!("{} ({}) is not readable", name, path.display()))?;
+    Ok(())
+}
+
+/// Reads the pairs of directories of a `--pairs-from` file.
+///
+/// Pairs are returned along with their line number, which identifies them in the output.
+fn read_pairs(path: &Path) -> anyhow::Result<Vec<(usize, PathBuf, PathBuf)>> {
+    let file = File::open(path).with_context(|| format!("Couldn't open {}", path.display()))?;
+    let mut pairs = Vec::new();
+    for (i, line) in BufReader::new(file).lines().enumerate() {
+        let line = line.with_context(|| format!("Couldn't read {}", path.display()))?;
+        if line.is_empty() {
+            continue;
+        }
+        match line.split_once('\t') {
+            Some((dir1, dir2)) if !dir2.contains('\t') => {
+                pairs.push((i + 1, dir1.into(), dir2.into()))
+            }
+            _ => bail!(
+                "Line {} of {} is not of the form `dir1<TAB>dir2`",
+                i + 1,
+                path.display()
+            ),
+        }
+    }
+    Ok(pairs)
+}
+
+/// Where the records of `--progress-format` are written: the file descriptor `fd` if given,
+/// and stderr otherwise.
+fn progress_output(fd: Option<i32>) -> anyhow::Result<Box<dyn Write + Send>> {
+    let fd = match fd {
+        Some(fd) => fd,
+        None => return Ok(Box::new(io::stderr())),
+    };
+    #[cfg(unix)]
+    {
+        use std::os::unix::io::FromRawFd;
+        // Fails if the descriptor is not open, rather than writing to whatever it is later.
+        // SAFETY: `fcntl` only queries the descriptor.
+        if unsafe { libc::fcntl(fd, libc::F_GETFD) } == -1 {
+            return Err(io::Error::last_os_error())
+                .with_context(|| format!("File descriptor {} of --progress-fd", fd));
+        }
+        // SAFETY: the descriptor is open, and given to dirdiff to write the progress to.
+        Ok(Box::new(unsafe { File::from_raw_fd(fd) }))
+    }
+    #[cfg(not(unix))]
+    bail!("--progress-fd {} is only supported on Unix", fd)
+}
+
+/// Writes a record of the progress of the comparison to `out` every [`PROGRESS_INTERVAL`],
+/// and a last one once the returned sender is dropped.
+///
+/// Errors writing the records are ignored, as they must not stop the comparison.
+fn report_progress(
+    progress: Arc<Progress>,
+    format: ProgressFormat,
+    mut out: Box<dyn Write + Send>,
+) -> (Sender<()>, thread::JoinHandle<()>) {
+    let (done, wait) = channel();
+    let thread = thread::spawn(move || loop {
+        let over = wait.recv_timeout(PROGRESS_INTERVAL) != Err(RecvTimeoutError::Timeout);
+        let snapshot = progress.snapshot();
+        let active = snapshot.active.iter().map(|dir| {
+            // The roots have an empty path.
+            match dir.as_os_str().is_empty() {
+                true => ".".into(),
+                false => dir.to_string_lossy(),
+            }
+        });
+        let record = match format {
+            ProgressFormat::Text => {
+                let active: Vec<_> = active.collect();
+                let mut record = format!(
+                    "{} directories and {} files compared",
+                    snapshot.dirs_done, snapshot.files_done
+                );
+                if !active.is_empty() {
+                    record.push_str(&format!(", in {}", active.join(", ")));
+                }
+                record
+            }
+            ProgressFormat::Json => {
+                let active: Vec<_> = active.map(|dir| json::string(&dir)).collect();
+                format!(
+                    "{{\"dirs_done\":{},\"files_done\":{},\"active\":[{}]}}",
+                    snapshot.dirs_done,
+                    snapshot.files_done,
+                    active.join(",")
+                )
+            }
+        };
+        let _ = writeln!(out, "{}", record).and_then(|()| out.flush());
+        if over {
+            break;
+        }
+    });
+    (done, thread)
+}
+
+/// Runs dirdiff, returning whether any failing difference was found.
+///
+/// The JSON records are written in `array` for `--json-array-stream`, opened and closed by the
+/// caller, so that the `error` record can be written in it too.
+fn run(cli_args: CliArgs, array: Option<Arc<json::ArrayStream>>) -> anyhow::Result<bool> {
+    check_combinations(&cli_args)?;
+    let unwind_path = |path: PathBuf| {
+        resolve_symlinks(&path).context(format!("Couldn't unwind path {}.", path.display()))
+    };
+    // Both hints conflict, IO-bound is the default.
+    let cpu_bound = cli_args.threads_cpu_bound && !cli_args.threads_io_bound;
+    let n_threads = thread_count(cli_args.jobs, cpu_bound, || {
+        Ok(thread::available_parallelism()
+            .context("Could not determine available parallelisme, specify the -j option with a non zero value.")?
+            .get()
+            .try_into()
+            .unwrap_or(u16::MAX))
+    })?;
+    let mut pairs = match &cli_args.pairs_from {
+        Some(path) => read_pairs(path)?,
+        // Both are required by clap without `--pairs-from`, unless the second one is empty.
+        None if cli_args.empty_second || cli_args.merkle_root => {
+            vec![(0, cli_args.dir1.unwrap(), EMPTY_TREE.into())]
+        }
+        // The overlay is read through its upper directory, and its lower one below.
+        None if cli_args.upper.is_some() => {
+            vec![(0, cli_args.dir1.unwrap(), cli_args.upper.clone().unwrap())]
+        }
+        // The listing is read through its command, standing for the second root.
+        None if cli_args.exec_listing.is_some() => {
+            let command = cli_args.exec_listing.clone().unwrap();
+            vec![(0, cli_args.dir1.unwrap(), command.into())]
+        }
+        None => vec![(0, cli_args.dir1.unwrap(), cli_args.dir2.unwrap())],
+    };
+    if cli_args.missing_as_empty {
+        for (_, dir1, dir2) in &mut pairs {
+            for dir in [dir1, dir2] {
+                if is_missing(dir) {
+                    *dir = EMPTY_TREE.into();
+                }
+            }
+        }
+    }
+    for (_, dir1, dir2) in &pairs {
+        let roots = [
+            ("dir1", dir1, cli_args.tar1, cli_args.zip1),
+            ("dir2", dir2, cli_args.tar2, cli_args.zip2),
+        ];
+        for (name, dir, tar, zip) in roots {
+            let listed = name == "dir2" && cli_args.exec_listing.is_some();
+            if dir == Path::new(EMPTY_TREE) || listed {
+                continue;
+            }
+            match root_tree(tar, zip, dir.clone()) {
+                Tree::Dir(_) => check_root(name, dir)?,
+                _ => check_archive(name, dir)?,
+            }
+        }
+    }
+    if let Some(lower) = &cli_args.lower {
+        check_root("lower", lower)?;
+    }
+    if cli_args.secure && !cfg!(target_os = "linux") {
+        bail!("--secure is only supported on Linux");
+    }
+    let prune_unchanged_before = match &cli_args.prune_unchanged_dirs {
+        Some(reference) => Some(
+            reference
+                .metadata()
+                .and_then(|m| m.modified())
+                .with_context(|| format!("Could not read the mtime of {}", reference.display()))?,
+        ),
+        None => None,
+    };
+    let checkpoint = match &cli_args.checkpoint {
+        Some(path) => {
+            let checkpoint = Checkpoint::open(path.clone())
+                .with_context(|| format!("Couldn't open checkpoint {}", path.display()))?;
+            if checkpoint.skipped() > 0 {
+                eprintln!(
+                    "Skipping {} directories already compared, as recorded in {}",
+                    checkpoint.skipped(),
+                    path.display()
+                );
+            }
+            Some(Arc::new(checkpoint))
+        }
+        None => None,
+    };
+    if cli_args.acls && !cfg!(target_os = "linux") {
+        eprintln!("Warning: ACLs are only compared on Linux, --acls is ignored");
+    }
+    if cli_args.collation == Some(CollationOrder::Locale) {
+        // Before any thread is spawned.
+        set_locale_from_env();
+    }
+    let progress = cli_args.progress_format.map(|_| Arc::new(Progress::new()));
+    let progress_reporter = match (&progress, cli_args.progress_format) {
+        (Some(progress), Some(format)) => {
+            let out = progress_output(cli_args.progress_fd)?;
+            Some(report_progress(progress.clone(), format, out))
+        }
+        _ => None,
+    };
+    // Files compared byte by byte as they are not valid UTF-8, for `--quiet-errors`.
+    let not_utf8 = cli_args.quiet_errors.then(|| Arc::new(AtomicU64::new(0)));
+    let options = DiffOptions {
+        check_mtime: cli_args.check_mtime,
+        check_dir_mtime: cli_args.check_dir_mtime,
+        mtime_is_diff: cli_args.mtime_is_diff,
+        mtime_granularity: match cli_args.mtime_granularity {
+            Granularity::Ns => MtimeGranularity::Nanoseconds,
+            Granularity::Us => MtimeGranularity::Microseconds,
+            Granularity::Ms => MtimeGranularity::Milliseconds,
+            Granularity::S => MtimeGranularity::Seconds,
+        },
+        ignore_mtime: cli_args.ignore_mtime,
+        perms: cli_args.perms,
+        perm_mask: cli_args.perm_mask,
+        owner: cli_args.owner,
+        check_nlink: cli_args.check_nlink,
+        check_flags: cli_args.check_flags,
+        check_caps: cli_args.check_caps,
+        check_acls: cli_args.acls,
+        check_blocks: cli_args.check_blocks,
+        check_reflinks: cli_args.check_reflinks,
+        flag_mtime_same_content_differs: cli_args.flag_mtime_same_content_differs,
+        follow_symlink: cli_args.follow_symlink || cli_args.data_only,
+        follow_inside_roots: cli_args.no_follow_outside_root,
+        tally: cli_args.count_only.then(Default::default),
+        progress,
+        dir_timings: cli_args
+            .per_dir_timing
+            .map(|top| Arc::new(DirTimings::new(top))),
+        checkpoint,
+        retry: RetryPolicy::new(cli_args.meta_retries.unwrap_or(cli_args.retries)),
+        content_retry: RetryPolicy::new(cli_args.content_retries.unwrap_or(cli_args.retries)),
+        sparse: cli_args.sparse,
+        ignore_content_case: cli_args.ignore_content_case,
+        fadvise: cli_args.fadvise,
+        byte_budget: cli_args
+            .byte_budget
+            .map(|bytes| Arc::new(AtomicU64::new(bytes))),
+        compare_prefix: cli_args.compare_prefix,
+        count_diff_bytes: cli_args.count_diff_bytes,
+        content_type: cli_args.content_type.clone(),
+        trust_xattr: cli_args.trust_xattr.clone(),
+        trust_same_inode: cli_args.trust_same_inode,
+        open_files: {
+            let open_files = match cli_args.max_open_files {
+                Some(0) => None,
+                Some(max) => Some(OpenFiles::new(max)),
+                // The margin replaces the default limit.
+                None if cli_args.min_free_fd.is_some() => None,
+                None => OpenFiles::from_rlimit(),
+            };
+            match cli_args.min_free_fd {
+                Some(min_free) => Some(
+                    open_files
+                        .unwrap_or_else(|| OpenFiles::new(usize::MAX))
+                        .with_min_free(min_free),
+                ),
+                None => open_files,
+            }
+            .map(Arc::new)
+        },
+        resolve_symlink_targets: cli_args.resolve_symlink_targets,
+        ignore_symlinks: cli_args.ignore_symlinks,
+        regular_only: cli_args.regular_only,
+        ignore_empty: cli_args.ignore_empty,
+        min_size: cli_args.min_size,
+        max_size: cli_args.max_size,
+        detect_case_collisions: cli_args.detect_case_collisions,
+        names_only: cli_args.names_only,
+        shallow: cli_args.shallow,
+        collapse: cli_args.collapse,
+        dir_changed_only: cli_args.dir_changed_only,
+        updates_to_second: cli_args.updates_to_second,
+        // Set for each pair.
+        empty_first: false,
+        empty_second: false,
+        prune_unchanged_before,
+        sorted: cli_args.sorted,
+        collation: match cli_args.collation {
+            Some(CollationOrder::Locale) => Collation::Locale,
+            Some(CollationOrder::Byte) | None => Collation::Byte,
+        },
+        two_pass: cli_args.two_pass,
+        compare_threads: cli_args.compare_threads,
+        timeout: None,
+        per_file_timeout: cli_args.per_file_timeout,
+        selection: match &cli_args.paths_from {
+            Some(path) => Some(
+                PathSelection::new(read_paths(path)?, !cli_args.paths_from_no_recurse)
+                    .with_context(|| format!("Invalid --paths-from {}", path.display()))?,
+            ),
+            None => None,
+        },
+        traversal: match cli_args.traversal {
+            TraversalOrder::Dfs => Traversal::DepthFirst,
+            TraversalOrder::Bfs => Traversal::BreadthFirst,
+        },
+        shallowest_diff: cli_args.shallowest_diff,
+        secure: cli_args.secure,
+        explain: cli_args.explain,
+        show_resolved: cli_args.show_resolved,
+        find_duplicates: cli_args.find_duplicates,
+        print_hashes: cli_args.print_hashes,
+        renames: RenameMap::new(cli_args.rename_map.clone()).context("Invalid --rename-map")?,
+        fuzzy_names: cli_args.fuzzy_names,
+        comparers: {
+            let mut comparers = Comparers::default();
+            for (extension, comparer) in &cli_args.comparer {
+                comparers.insert(extension, comparer.clone());
+            }
+            let max_size = cli_args
+                .max_compare_size
+                .unwrap_or(DEFAULT_MAX_COMPARE_SIZE);
+            if cli_args.sort_lines {
+                comparers.set_fallback(Arc::new(SortedLines { max_size }));
+            }
+            if cli_args.ignore_content_case_unicode {
+                comparers.set_fallback(Arc::new(UnicodeCaseless {
+                    max_size,
+                    not_utf8: not_utf8.clone(),
+                }));
+            }
+            comparers
+        },
+        compare_cmd: match &cli_args.compare_cmd {
+            Some(command) => Some(CompareCommand::parse(command)?),
+            None => None,
+        },
+    };
+    let template = match &cli_args.format_template {
+        Some(template) => Some(Arc::new(Template::parse(template)?)),
+        None => None,
+    };
+    let tags = [
+        ("different", &cli_args.tag_different),
+        ("only1", &cli_args.tag_only_first),
+        ("only2", &cli_args.tag_only_second),
+        ("mtime", &cli_args.tag_mtime),
+    ];
+    let expected = Arc::new(match &cli_args.expected_diff_from {
+        Some(path) => ExpectedDiffs::read(path)?,
+        None => ExpectedDiffs::default(),
+    });
+    let tags: Arc<BTreeMap<_, _>> = Arc::new(
+        tags.into_iter()
+            .filter_map(|(kind, tag)| Some((kind, tag.clone()?)))
+            .collect(),
+    );
+    let baseline = Arc::new(match &cli_args.suppress_from {
+        Some(path) => Baseline::read(path, &tags)?,
+        None => Baseline::default(),
+    });
+    let kind_order = match cli_args.group_by {
+        Some(GroupBy::Kind) => {
+            check_kinds("--kind-order", &cli_args.kind_order)?;
+            let mut kind_order = Vec::new();
+            for kind in &cli_args.kind_order {
+                if !kind_order.contains(kind) {
+                    kind_order.push(kind.clone());
+                }
+            }
+            for kind in DIFF_KINDS {
+                if !kind_order.iter().any(|k| k == kind) {
+                    kind_order.push(kind.to_string());
+                }
+            }
+            Some(kind_order)
+        }
+        None => None,
+    };
+    let fail_on = match &cli_args.fail_on {
+        // Every entry is reported, and none is a difference.
+        _ if cli_args.merkle_root => Vec::new(),
+        Some(fail_on) => {
+            check_kinds("--fail-on", fail_on)?;
+            fail_on.clone()
+        }
+        None => DIFF_KINDS
+            .iter()
+            .filter(|&&kind| {
+                ![
+                    "mtime",
+                    "blocks",
+                    "resolved",
+                    "moved",
+                    "hashes",
+                    "extent_sharing",
+                ]
+                .contains(&kind)
+            })
+            .map(|kind| kind.to_string())
+            .collect(),
+    };
+    let paths = PathStyle {
+        full: cli_args.full_paths,
+        strip_prefix: cli_args.strip_prefix.clone(),
+        prefix: cli_args.path_prefix.clone(),
+        relative_base: match &cli_args.relative_output_base {
+            Some(base) => Some(
+                base.canonicalize()
+                    .with_context(|| format!("Couldn't resolve {}", base.display()))?,
+            ),
+            None => None,
+        },
+        rebased_roots: Vec::new(),
+    };
+    if cli_args.emit_sync_script || cli_args.apply {
+        let option = if cli_args.apply {
+            "--apply"
+        } else {
+            "--emit-sync-script"
+        };
+        for (_, dir1, dir2) in &pairs {
+            if dir2 == Path::new(EMPTY_TREE) {
+                bail!("{} cannot copy to an empty tree", option);
+            }
+            for (name, dir) in [("dir1", dir1), ("dir2", dir2)] {
+                if dir != Path::new(EMPTY_TREE)
+                    && !matches!(root_tree(false, false, dir.clone()), Tree::Dir(_))
+                {
+                    bail!("{} cannot sync {}, an archive", option, name);
+                }
+            }
+        }
+        if cli_args.emit_sync_script && cli_args.sync_format == SyncFormat::Sh {
+            print!("{}", sync::SH_HEADER);
+        }
+    }
+    let pending_actions = cli_args.apply.then(Default::default);
+    let apply_opts = ApplyOpts {
+        delete: cli_args.delete,
+        perms: cli_args.perms,
+        mtime: cli_args.check_mtime,
+        dir_mtime: cli_args.check_dir_mtime,
+    };
+    let pending_records = cli_args.json_pretty.then(Default::default);
+    let database = match &cli_args.sqlite {
+        Some(path) => Some(Arc::new(Mutex::new(
+            sqlite::Database::create(path)
+                .with_context(|| format!("Couldn't create the database {}", path.display()))?,
+        ))),
+        None => None,
+    };
+    let framing = match (&pending_records, &array) {
+        (Some(pending), _) => JsonFraming::Pending(Arc::clone(pending)),
+        (None, Some(array)) => JsonFraming::Array(array.clone()),
+        (None, None) => JsonFraming::Lines,
+    };
+    let counts = Arc::new(KindCounts::new());
+    let mut failures = 0;
+    // Entries that could not be read, for `--quiet-errors`.
+    let mut unreadable = 0;
+    let deadline = cli_args.timeout.map(|timeout| Instant::now() + timeout);
+    for (line, dir1, dir2) in pairs {
+        let options = DiffOptions {
+            timeout: deadline.map(|deadline| deadline.saturating_duration_since(Instant::now())),
+            empty_first: dir1 == Path::new(EMPTY_TREE),
+            empty_second: dir2 == Path::new(EMPTY_TREE),
+            mtime_granularity: match cli_args.auto_mtime_granularity {
+                true => detected_granularity([&dir1, &dir2], MtimeGranularity::detect),
+                false => options.mtime_granularity,
+            },
+            ..options.clone()
+        };
+        let (dir1, dir2) = if cli_args.follow_symlink_args {
+            // The empty tree is not a real path.
+            let unwind = |dir: PathBuf, empty| if empty { Ok(dir) } else { unwind_path(dir) };
+            (
+                unwind(dir1, options.empty_first)?,
+                unwind(dir2, options.empty_second)?,
+            )
+        } else {
+            (dir1, dir2)
+        };
+        let pair = cli_args.pairs_from.is_some().then_some(line);
+        let paths = paths.for_roots(&dir1, &dir2)?;
+        let output: Box<dyn DiffHandler + Send + Sync> = if cli_args.assert_identical {
+            Box::new(SilentHandler)
+        } else if let Some(database) = &database {
+            Box::new(SqliteHandler::new(
+                paths.clone(),
+                options.renames.clone(),
+                database.clone(),
+            ))
+        } else {
+            match cli_args.format {
+                _ if cli_args.emit_sync_script || cli_args.apply => Box::new(SyncHandler::new(
+                    cli_args.sync_format,
+                    pending_actions.clone(),
+                )),
+                _ if cli_args.merkle_root => Box::new(MerkleHandler::new(
+                    dir1.clone(),
+                    options.follow_symlink,
+                    n_threads.into(),
+                    cli_args.quiet_errors,
+                )),
+                _ if cli_args.dirs_only || cli_args.dirs_only_leaf => {
+                    Box::new(DirsOnlyHandler::new(
+                        pair,
+                        cli_args.format == Format::Json,
+                        cli_args.dirs_only_leaf,
+                        cli_args.dir_counts,
+                    ))
+                }
+                Format::Grepable => Box::new(GrepableHandler::new(
+                    pair.map(|line| line.to_string()),
+                    paths.clone(),
+                    template.clone(),
+                    tags.clone(),
+                    expected.clone(),
+                    cli_args.non_utf8,
+                )),
+                Format::Itemize => Box::new(ItemizeHandler::new(
+                    pair.map(|line| line.to_string()),
+                    paths.clone(),
+                )),
+                Format::Json => Box::new(JsonHandler::new(
+                    pair,
+                    paths.clone(),
+                    cli_args.flush,
+                    cli_args.stable_ids,
+                    expected.clone(),
+                    counts.clone(),
+                    framing.clone(),
+                )),
+            }
+        };
+        let output: Box<dyn DiffHandler + Send + Sync> = match &kind_order {
+            Some(kind_order) => Box::new(GroupedHandler::new(
+                output,
+                dir1.clone(),
+                dir2.clone(),
+                kind_order.clone(),
+            )),
+            None => output,
+        };
+        let output: Box<dyn DiffHandler + Send + Sync> = if cli_args.diffstat {
+            Box::new(DiffstatHandler::new(output, pair))
+        } else {
+            output
+        };
+        let output: Box<dyn DiffHandler + Send + Sync> = if cli_args.dir_fingerprint {
+            Box::new(FingerprintHandler::new(
+                output,
+                pair,
+                cli_args.format == Format::Json,
+            ))
+        } else {
+            output
+        };
+        let h = Arc::new(StatusHandler::new(
+            output,
+            fail_on.clone(),
+            cli_args.only_in.map(OnlyIn::kind),
+            // `--merkle-root` counts the entries it could not walk itself.
+            cli_args.quiet_errors && !cli_args.merkle_root,
+            expected.clone(),
+            baseline.clone(),
+        ));
+        // The empty tree is not a real path.
+        let tree1 = match options.empty_first {
+            false => root_tree(cli_args.tar1, cli_args.zip1, dir1.clone()),
+            true => Tree::Dir(dir1.clone()),
+        };
+        let tree2 = match (
+            options.empty_second,
+            &cli_args.lower,
+            &cli_args.exec_listing,
+        ) {
+            (false, None, None) => root_tree(cli_args.tar2, cli_args.zip2, dir2.clone()),
+            (false, Some(lower), _) => Tree::Overlay {
+                lower: lower.clone(),
+                upper: dir2.clone(),
+            },
+            (false, _, Some(command)) => Tree::Listing(command.clone()),
+            (true, _, _) => Tree::Dir(dir2.clone()),
+        };
+        let result = if !matches!((&tree1, &tree2), (Tree::Dir(_), Tree::Dir(_))) {
+            diff_trees(tree1, tree2, h.clone(), &options)
+        } else {
+            diff_dirs(dir1, dir2, h.clone(), n_threads, &options)
+                .map(|stats| unreadable += stats.errors)
+        };
+        // The diffs found before the timeout are still written whole.
+        if matches!(&result, Err(e) if e.is::<TimedOut>()) {
+            h.finish().context("Couldn't write the output")?;
+        }
+        result?;
+        h.finish().context("Couldn't write the output")?;
+        failures += h.failures();
+        if let Some(pending) = &pending_actions {
+            let actions = std::mem::take(&mut *pending.lock().unwrap());
+            apply_actions(actions, cli_args.sync_format, &apply_opts)?;
+        }
+    }
+    if let Some((done, thread)) = progress_reporter {
+        // Joined, so that the last record comes before the totals.
+        drop(done);
+        let _ = thread.join();
+    }
+    if cli_args.quiet_errors && unreadable > 0 {
+        eprintln!("{} entries could not be read", unreadable);
+    }
+    let not_utf8 = not_utf8.map_or(0, |count| count.load(Ordering::Relaxed));
+    if not_utf8 > 0 {
+        eprintln!(
+            "{} files were not valid UTF-8, compared byte by byte",
+            not_utf8
+        );
+    }
+    if let Some(timings) = &options.dir_timings {
+        eprintln!("{}", timings);
+    }
+    if let Some(tally) = &options.tally {
+        println!("{}", tally);
+    } else if let Some(pending) = &pending_records {
+        let mut records = std::mem::take(&mut *pending.lock().unwrap());
+        if !cli_args.no_summary {
+            records.push(counts.summary());
+        }
+        println!("{}", json::pretty_array(&records));
+    } else if cli_args.format == Format::Json
+        && !cli_args.no_summary
+        && !cli_args.dirs_only
+        && !cli_args.dirs_only_leaf
+    {
+        // Only written once all the pairs were compared successfully.
+        match &array {
+            Some(array) => print!("{}{}", array.separator(), counts.summary()),
+            None => println!("{}", counts.summary()),
+        }
+    }
+    if cli_args.assert_identical && failures > 0 {
+        eprintln!("trees differ: {} differences", failures);
+    }
+    Ok(failures > 0)
+}
+
+/// Exits with 0 if no failing difference was found (see `--fail-on`), 1 if some were, 2 on errors
+/// and 3 on timeouts (see `--timeout`).
+fn main() -> ExitCode {
+    let mut cli_args: CliArgs = CliArgs::parse();
+    if cli_args.json_pretty || cli_args.json_array_stream {
+        cli_args.format = Format::Json;
+    }
+    let (json, json_pretty) = (cli_args.format == Format::Json, cli_args.json_pretty);
+    let array = cli_args
+        .json_array_stream
+        .then(|| Arc::new(json::ArrayStream::new()));
+    if array.is_some() {
+        print!("{}", json::ArrayStream::START);
+    }
+    let result = run(cli_args, array.clone());
+    // Written after all the records of the handlers, dropped by now.
+    if let Err(e) = &result {
+        if json_pretty {
+            println!("{}", json::pretty_array(&[error_record(e)]));
+        } else if let Some(array) = &array {
+            print!("{}{}", array.separator(), error_record(e));
+        } else if json {
+            println!("{}", error_record(e));
+        }
+    }
+    if let Some(array) = &array {
+        println!("{}", array.end());
+    }
+    match result {
+        Ok(false) => ExitCode::SUCCESS,
+        Ok(true) => ExitCode::from(1),
+        Err(e) => {
+            eprintln!("Error: {:?}", e);
+            if e.is::<TimedOut>() {
+                ExitCode::from(3)
+            } else {
+                ExitCode::from(2)
+            }
+        }
+    }
+}
+
+/// The record ending the JSON output when the comparison fails, with the `error` kind, the
+/// message of `e` and its causes, and the error number of the first cause coming from the OS.
+fn error_record(e: &anyhow::Error) -> String {
+    let mut record = format!(
+        "{{\"kind\":\"error\",\"message\":{}",
+        json::string(&format!("{:#}", e))
+    );
+    let errno = e
+        .chain()
+        .find_map(|cause| cause.downcast_ref::<io::Error>()?.raw_os_error());
+    if let Some(errno) = errno {
+        record.push_str(&format!(",\"errno\":{}", errno));
+    }
+    record.push('}');
+    record
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::CommandFactory;
+
+    /// Catches the mistakes clap only panics on when parsing, like conflicts with arguments
+    /// that do not exist.
+    #[test]
+    fn cli_args() {
+        CliArgs::command().debug_assert();
+    }
+
+    #[test]
+    fn duration() {
+        assert_eq!(parse_duration("90"), Ok(Duration::from_secs(90)));
+        assert_eq!(parse_duration("30s"), Ok(Duration::from_secs(30)));
+        assert_eq!(parse_duration("5m"), Ok(Duration::from_secs(300)));
+        assert_eq!(parse_duration("2h"), Ok(Duration::from_secs(7200)));
+        assert!(parse_duration("2d").is_err());
+        assert!(parse_duration("m").is_err());
+        assert!(parse_duration("-1").is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn relative_paths() {
+        let relative = |path, base| relative_to(Path::new(path), Path::new(base));
+        assert_eq!(relative("/x/a", "/x"), Some(PathBuf::from("a")));
+        assert_eq!(relative("/x/a", "/x/b/c"), Some(PathBuf::from("../../a")));
+        assert_eq!(relative("/x", "/x"), Some(PathBuf::new()));
+        assert_eq!(relative("/x", "/y"), Some(PathBuf::from("../x")));
+    }
+
+    #[test]
+    fn merkle_walk_errors() {
+        let vanished = || Diff::Vanished("d".into(), "f".into(), Side::First);
+        let handler = MerkleHandler::new(PathBuf::from("a"), false, 1, true);
+        handler.process(Path::new("a"), Path::new(EMPTY_TREE), vanished());
+        handler.process(Path::new("a"), Path::new(EMPTY_TREE), vanished());
+        assert_eq!(
+            handler.finish().unwrap_err().to_string(),
+            "2 entries could not be read, no hash of the tree is printed"
+        );
+        let handler = MerkleHandler::new(PathBuf::from("a"), false, 1, false);
+        handler.process(Path::new("a"), Path::new(EMPTY_TREE), vanished());
+        assert_eq!(
+            handler.finish().unwrap_err().to_string(),
+            "Some entries couldn't be walked, no hash of the tree is printed"
+        );
+    }
+
+    #[test]
+    fn detected_mtime_granularity() {
+        use MtimeGranularity::*;
+        let detect = |root: &Path| match root.to_str() {
+            Some("ext4") => Some(Nanoseconds),
+            Some("ntfs") => Some(Microseconds),
+            Some("fat") => Some(Seconds),
+            _ => None,
+        };
+        let granularity = |dir1: &str, dir2: &str| {
+            detected_granularity([Path::new(dir1), Path::new(dir2)], detect)
+        };
+        assert_eq!(granularity("ext4", "ext4"), Nanoseconds);
+        assert_eq!(granularity("ext4", "ntfs"), Microseconds);
+        assert_eq!(granularity("fat", "ntfs"), Seconds);
+        assert_eq!(granularity("ext4", "nfs"), Seconds);
+        assert_eq!(granularity("ntfs", EMPTY_TREE), Microseconds);
+        assert_eq!(granularity(EMPTY_TREE, EMPTY_TREE), Nanoseconds);
+        // Differences finer than the coarser resolution are ignored.
+        let at = |nanos| std::time::UNIX_EPOCH + Duration::from_nanos(nanos);
+        let (ext4, fat) = (at(10_000_000_100), at(10_900_000_000));
+        let granularity = granularity("ext4", "fat");
+        assert_eq!(granularity.truncate(ext4), granularity.truncate(fat));
+        assert_ne!(Nanoseconds.truncate(ext4), Nanoseconds.truncate(fat));
+    }
+
+    #[test]
+    fn diffstat_bars() {
+        assert_eq!(bar_length(0, 100), 0);
+        assert_eq!(bar_length(7, 40), 7);
+        assert_eq!(bar_length(100, 100), DIFFSTAT_WIDTH);
+        assert_eq!(bar_length(50, 100), DIFFSTAT_WIDTH / 2);
+        assert_eq!(bar_length(1, 1000), 1);
+    }
+
+    #[test]
+    fn perm_mask() {
+        assert_eq!(parse_perm_mask("0o7777"), Ok(0o7777));
+        assert_eq!(parse_perm_mask("644"), Ok(0o644));
+        assert!(parse_perm_mask("10000").is_err());
+        assert!(parse_perm_mask("8").is_err());
+    }
+
+    #[test]
+    fn baseline_records() {
+        let tags = BTreeMap::from([("only1", "Gone".to_owned())]);
+        let parse = |line| Baseline::parse(line, &tags).unwrap();
+        let diff = |kind: &str, path: &str| (kind.to_owned(), PathBuf::from(path));
+        assert_eq!(parse("[Gone]\t\"d/f\""), [diff("only1", "d/f")]);
+        assert_eq!(
+            parse("[Expected diff]\t\"f\""),
+            [diff("different", "f"), diff("mtime", "f")]
+        );
+        assert_eq!(
+            parse("3\t[Files differ]\t\"a\\tb\"\tin 2 bytes"),
+            [diff("different", "a\tb")]
+        );
+        assert_eq!(
+            parse(r#"{"kind":"only2","path":"g","id":"x"},"#),
+            [diff("only2", "g")]
+        );
+        let hashes = format!("{0} {0} f", "0".repeat(64));
+        assert!(parse(&hashes).is_empty());
+        assert!(parse(r#"{"kind":"summary","different":1}"#).is_empty());
+        assert!(Baseline::parse("[Present in first dir. only]\tf", &tags).is_err());
+        assert!(Baseline::parse("[Unknown]\t\"f\"", &tags).is_err());
+    }
+
+    #[test]
+    fn size() {
+        assert_eq!(parse_size("0"), Ok(0));
+        assert_eq!(parse_size("4K"), Ok(4096));
+        assert_eq!(parse_size("10m"), Ok(10 << 20));
+        assert_eq!(parse_size("1T"), Ok(1 << 40));
+        assert!(parse_size("1X").is_err());
+        assert!(parse_size("K").is_err());
+        assert!(parse_size("-1").is_err());
+        assert!(parse_size("16777216T").is_err());
+    }
+
+    #[test]
+    fn defaults_from_environment() {
+        std::env::set_var("DIRDIFF_JOBS", "3");
+        std::env::set_var("DIRDIFF_RETRIES", "2");
+        std::env::set_var("DIRDIFF_FORMAT", "json");
+        let args = CliArgs::try_parse_from(["dirdiff", "a", "b"]).unwrap();
+        assert_eq!(args.jobs, Some(3));
+        assert_eq!(args.retries, 2);
+        assert_eq!(args.format, Format::Json);
+        // Options given on the command line win.
+        let args = CliArgs::try_parse_from([
+            "dirdiff",
+            "-j",
+            "5",
+            "--retries",
+            "0",
+            "--format",
+            "grepable",
+            "a",
+            "b",
+        ])
+        .unwrap();
+        assert_eq!(args.jobs, Some(5));
+        assert_eq!(args.retries, 0);
+        assert_eq!(args.format, Format::Grepable);
+        for var in ["DIRDIFF_JOBS", "DIRDIFF_RETRIES", "DIRDIFF_FORMAT"] {
+            std::env::remove_var(var);
+        }
+    }
+
+    #[test]
+    fn auto_detected_threads() {
+        let available = || Ok(128);
+        assert_eq!(thread_count(Some(0), false, available).unwrap(), 8);
+        assert_eq!(thread_count(None, false, available).unwrap(), 8);
+        assert_eq!(thread_count(Some(0), true, available).unwrap(), 128);
+        assert_eq!(thread_count(None, false, || Ok(4)).unwrap(), 4);
+    }
+
+    #[test]
+    fn explicit_threads() {
+        let available = || panic!("auto-detection with an explicit -j");
+        assert_eq!(thread_count(Some(200), false, available).unwrap(), 200);
+        assert_eq!(thread_count(Some(200), true, available).unwrap(), 200);
     }
-    Ok(())
 }
@@ -0,0 +1,313 @@
+//! Manifests, listing the entries of a tree with their hashes.
+//!
+//! A manifest is UTF-8 text. Its first line is the header `#dirdiff-manifest v1`, naming the
+//! major version of the format, optionally followed by a minor version as in `v1.2`. Readers
+//! reject other major versions, whose lines they could misparse, and accept any minor version
+//! of a major version they know: minor versions only add columns.
+//!
+//! Each following line is an entry, as tab-separated columns. Version 1 defines four of them:
+//!
+//! 1. the type: `f` for a regular file, `l` for a symlink, and `d` for a directory;
+//! 2. the size of a regular file, in bytes, or `-` for other types;
+//! 3. the SHA-256 of the content of a regular file or of the target of a symlink, as 64
+//!    lowercase hex digits, or `-` for a directory;
+//! 4. the path, relative to the root of the tree, with `/` as separator. Paths cannot contain
+//!    tabs or newlines.
+//!
+//! Columns past these are ignored, as are empty lines. Entries are written sorted by path.
+
+use crate::FileType;
+use anyhow::{bail, Context};
+use sha2::{Digest, Sha256};
+use std::{
+    fs::{self, File},
+    io::{self, BufRead, Read, Write},
+    path::{Component, Path, PathBuf},
+};
+
+/// Major version of the manifests written, and the only one read.
+const VERSION: u32 = 1;
+/// Start of the header line, followed by the version.
+const HEADER_PREFIX: &str = "#dirdiff-manifest v";
+
+/// SHA-256 hash, of [`ManifestEntry::hash`].
+pub type Hash = [u8; 32];
+
+/// Entry of a manifest.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ManifestEntry {
+    /// Path relative to the root of the tree.
+    pub path: PathBuf,
+    /// One of [`FileType::Regular`], [`FileType::Symlink`] and [`FileType::Directory`].
+    pub file_type: FileType,
+    /// Size of a regular file.
+    pub size: Option<u64>,
+    /// Hash of the content of a regular file, or of the target of a symlink.
+    pub hash: Option<Hash>,
+}
+
+/// Lists the entries below `root`, sorted by path, hashing regular files and symlink targets.
+///
+/// Symlinks are not followed, and other file types are an error.
+pub fn manifest_of(root: &Path) -> anyhow::Result<Vec<ManifestEntry>> {
+    let mut entries = Vec::new();
+    list(root, Path::new(""), &mut entries)?;
+    Ok(entries)
+}
+
+fn list(root: &Path, dir: &Path, entries: &mut Vec<ManifestEntry>) -> anyhow::Result<()> {
+    let full_dir = root.join(dir);
+    let mut names = fs::read_dir(&full_dir)
+        .with_context(|| format!("Couldn't read {}", full_dir.display()))?
+        .map(|entry| entry.map(|entry| entry.file_name()))
+        .collect::<io::Result<Vec<_>>>()?;
+    names.sort();
+    for name in names {
+        let path = dir.join(name);
+        let full_path = root.join(&path);
+        let file_type: FileType = fs::symlink_metadata(&full_path)?.file_type().into();
+        let (size, hash) = match file_type {
+            FileType::Regular => {
+                let (size, hash) = hash_content(File::open(&full_path)?)?;
+                (Some(size), Some(hash))
+            }
+            FileType::Symlink => {
+                let target = fs::read_link(&full_path)?;
+                let hash = Sha256::digest(target.to_string_lossy().as_bytes());
+                (None, Some(hash.into()))
+            }
+            FileType::Directory => (None, None),
+            #[allow(unreachable_patterns)]
+            _ => bail!(
+                "{} is a {}, which manifests cannot list",
+                full_path.display(),
+                file_type
+            ),
+        };
+        entries.push(ManifestEntry {
+            path: path.clone(),
+            file_type,
+            size,
+            hash,
+        });
+        if file_type == FileType::Directory {
+            list(root, &path, entries)?;
+        }
+    }
+    Ok(())
+}
+
+/// Hashes everything read from `content`, returning its size along with its hash.
+pub(crate) fn hash_content(mut content: impl Read) -> io::Result<(u64, Hash)> {
+    let mut hasher = Sha256::new();
+    let size = io::copy(&mut content, &mut hasher)?;
+    Ok((size, hasher.finalize().into()))
+}
+
+/// Writes a manifest of `entries`, with its header.
+pub fn write_manifest(entries: &[ManifestEntry], mut out: impl Write) -> anyhow::Result<()> {
+    writeln!(out, "{}{}", HEADER_PREFIX, VERSION)?;
+    for entry in entries {
+        let file_type = match entry.file_type {
+            FileType::Regular => "f",
+            FileType::Symlink => "l",
+            FileType::Directory => "d",
+            #[allow(unreachable_patterns)]
+            _ => bail!("Manifests cannot list {}", entry.file_type),
+        };
+        let size = entry.size.map_or("-".to_string(), |size| size.to_string());
+        let hash = entry.hash.map_or("-".to_string(), |hash| hex(&hash));
+        let mut path = String::new();
+        for component in entry.path.components() {
+            let name = match component {
+                Component::Normal(name) => name.to_str(),
+                _ => None,
+            };
+            match name {
+                Some(name) if !name.contains(['\t', '\n', '\r']) => {
+                    if !path.is_empty() {
+                        path.push('/');
+                    }
+                    path.push_str(name);
+                }
+                _ => bail!("Manifests cannot list {}", entry.path.display()),
+            }
+        }
+        writeln!(out, "{}\t{}\t{}\t{}", file_type, size, hash, path)?;
+    }
+    Ok(())
+}
+
+/// Reads a manifest, checking its header.
+pub fn read_manifest(input: impl BufRead) -> anyhow::Result<Vec<ManifestEntry>> {
+    let mut lines = input.lines();
+    let header = match lines.next() {
+        Some(header) => header.context("Couldn't read the manifest")?,
+        None => bail!(
+            "The manifest is empty, expected a `{}{}` header",
+            HEADER_PREFIX,
+            VERSION
+        ),
+    };
+    check_header(&header)?;
+    let mut entries = Vec::new();
+    for (i, line) in lines.enumerate() {
+        let line = line.context("Couldn't read the manifest")?;
+        if line.is_empty() {
+            continue;
+        }
+        // The header is line 1.
+        let entry = parse_entry(&line)
+            .with_context(|| format!("Invalid line {} of the manifest", i + 2))?;
+        entries.push(entry);
+    }
+    Ok(entries)
+}
+
+/// Checks that the header of a manifest names a major version this reader knows.
+fn check_header(header: &str) -> anyhow::Result<()> {
+    let version = match header.strip_prefix(HEADER_PREFIX) {
+        Some(version) => version,
+        None => bail!(
+            "Not a manifest, expected a `{}{}` header",
+            HEADER_PREFIX,
+            VERSION
+        ),
+    };
+    let major = version.split_once('.').map_or(version, |(major, _)| major);
+    match major.parse::<u32>() {
+        Ok(VERSION) => Ok(()),
+        Ok(_) => bail!(
+            "Unsupported manifest version {}, only version {} is supported",
+            version,
+            VERSION
+        ),
+        Err(_) => bail!("Invalid manifest version `{}`", version),
+    }
+}
+
+fn parse_entry(line: &str) -> anyhow::Result<ManifestEntry> {
+    let fields: Vec<&str> = line.split('\t').collect();
+    let (file_type, size, hash, path) = match fields[..] {
+        [file_type, size, hash, path, ..] if !path.is_empty() => (file_type, size, hash, path),
+        _ => bail!("Expected a type, a size, a hash and a path"),
+    };
+    let file_type = match file_type {
+        "f" => FileType::Regular,
+        "l" => FileType::Symlink,
+        "d" => FileType::Directory,
+        _ => bail!("Unknown type `{}`, expected f, l or d", file_type),
+    };
+    let size = match (file_type, size) {
+        (FileType::Regular, size) => Some(
+            size.parse::<u64>()
+                .with_context(|| format!("Invalid size `{}`", size))?,
+        ),
+        (_, "-") => None,
+        (_, _) => bail!("Only regular files have a size, expected `-`"),
+    };
+    let hash = match (file_type, hash) {
+        (FileType::Directory, "-") => None,
+        (FileType::Directory, _) => bail!("Directories have no hash, expected `-`"),
+        (_, hash) => match parse_hash(hash) {
+            Some(hash) => Some(hash),
+            None => bail!("Invalid hash `{}`, expected 64 hex digits", hash),
+        },
+    };
+    let mut entry_path = PathBuf::new();
+    for name in path.split('/') {
+        if name.is_empty() || name == "." || name == ".." {
+            bail!("Invalid path `{}`, expected a relative path", path);
+        }
+        entry_path.push(name);
+    }
+    Ok(ManifestEntry {
+        path: entry_path,
+        file_type,
+        size,
+        hash,
+    })
+}
+
+/// Hash written as lowercase hex digits.
+pub fn hex(hash: &Hash) -> String {
+    hash.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// Hash written as hex digits.
+fn parse_hash(hex: &str) -> Option<Hash> {
+    if hex.len() != 64 || !hex.is_ascii() {
+        return None;
+    }
+    let mut hash = [0; 32];
+    for (byte, digits) in hash.iter_mut().zip(hex.as_bytes().chunks(2)) {
+        *byte = u8::from_str_radix(std::str::from_utf8(digits).ok()?, 16).ok()?;
+    }
+    Some(hash)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entries() -> Vec<ManifestEntry> {
+        vec![
+            ManifestEntry {
+                path: PathBuf::from("a b"),
+                file_type: FileType::Regular,
+                size: Some(3),
+                hash: Some(Sha256::digest(b"abc").into()),
+            },
+            ManifestEntry {
+                path: PathBuf::from("sub"),
+                file_type: FileType::Directory,
+                size: None,
+                hash: None,
+            },
+            ManifestEntry {
+                path: Path::new("sub").join("link"),
+                file_type: FileType::Symlink,
+                size: None,
+                hash: Some(Sha256::digest(b"../a b").into()),
+            },
+        ]
+    }
+
+    #[test]
+    fn round_trip() {
+        let mut manifest = Vec::new();
+        write_manifest(&entries(), &mut manifest).unwrap();
+        let text = String::from_utf8(manifest.clone()).unwrap();
+        assert!(text.starts_with("#dirdiff-manifest v1\n"));
+        assert!(text.contains(
+            "f\t3\tba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad\ta b\n"
+        ));
+        assert!(text.contains("d\t-\t-\tsub\n"));
+        assert_eq!(read_manifest(&manifest[..]).unwrap(), entries());
+    }
+
+    #[test]
+    fn unknown_version() {
+        let error = read_manifest(&b"#dirdiff-manifest v2\nd\t-\t-\tsub\n"[..]).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "Unsupported manifest version 2, only version 1 is supported"
+        );
+        assert!(read_manifest(&b"d\t-\t-\tsub\n"[..]).is_err());
+    }
+
+    #[test]
+    fn extra_columns() {
+        let manifest = b"#dirdiff-manifest v1.3\nd\t-\t-\tsub\t1700000000\tnew column\n";
+        let entries = read_manifest(&manifest[..]).unwrap();
+        assert_eq!(
+            entries,
+            [ManifestEntry {
+                path: PathBuf::from("sub"),
+                file_type: FileType::Directory,
+                size: None,
+                hash: None,
+            }]
+        );
+    }
+}
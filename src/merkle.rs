@@ -0,0 +1,120 @@
+//! Hash of a whole tree, for `--merkle-root`.
+//!
+//! The tree is walked by the workers as if it were compared with an empty tree, each entry
+//! being reported once, in whatever order the workers find them. The hash is only computed
+//! once the walk is over, in two phases:
+//!
+//! 1. The entries are split between threads, which hash the content of the regular files and
+//!    the targets of the symlinks.
+//! 2. The entries are then joined bottom-up, the deepest first, so that all the entries of a
+//!    directory are hashed before it is: its hash is the one of its entries sorted by name,
+//!    each as its name, its type and its own hash.
+//!
+//! The root hash is the one of the tree as a directory, and only depends on the names, types
+//! and content of the entries, whatever the order they were found in. Hashes are SHA-256
+//! hashes, like those of the manifests.
+
+use crate::fingerprint::bytes;
+use dirdiff::manifest::Hash;
+use sha2::{Digest, Sha256};
+use std::{
+    collections::BTreeMap,
+    ffi::OsString,
+    fs::{self, File},
+    io::{self, Read},
+    path::{Path, PathBuf},
+    thread,
+};
+
+/// Hash of the tree at `root`, whose entries are at `paths` relative to it, computed by
+/// `n_threads` threads. Symlinks are hashed as their target, unless `follow_symlink` is set.
+pub fn root_hash(
+    root: &Path,
+    paths: Vec<PathBuf>,
+    follow_symlink: bool,
+    n_threads: usize,
+) -> io::Result<Hash> {
+    // Type and hash of each entry, left for later for directories.
+    let chunk_size = paths.len().div_ceil(n_threads.max(1)).max(1);
+    let mut leaves: Vec<(PathBuf, u8, Hash)> = thread::scope(|s| {
+        let threads: Vec<_> = paths
+            .chunks(chunk_size)
+            .map(|chunk| {
+                s.spawn(move || {
+                    chunk
+                        .iter()
+                        .map(|path| {
+                            let (kind, hash) = leaf(&root.join(path), follow_symlink)?;
+                            Ok((path.clone(), kind, hash))
+                        })
+                        .collect::<io::Result<Vec<_>>>()
+                })
+            })
+            .collect();
+        threads
+            .into_iter()
+            .map(|thread| thread.join().expect("hashing threads do not panic"))
+            .collect::<io::Result<Vec<_>>>()
+            .map(|chunks| chunks.into_iter().flatten().collect())
+    })?;
+    // Deepest first, so that directories come after all their entries.
+    leaves.sort_by_key(|(path, _, _)| std::cmp::Reverse(path.components().count()));
+    let mut entries: BTreeMap<PathBuf, Vec<(OsString, u8, Hash)>> = BTreeMap::new();
+    for (path, kind, hash) in leaves {
+        let hash = match kind {
+            b'd' => directory_hash(entries.remove(&path).unwrap_or_default()),
+            _ => hash,
+        };
+        let (parent, name) = match (path.parent(), path.file_name()) {
+            (Some(parent), Some(name)) => (parent.to_owned(), name.to_owned()),
+            _ => continue,
+        };
+        entries.entry(parent).or_default().push((name, kind, hash));
+    }
+    Ok(directory_hash(
+        entries.remove(Path::new("")).unwrap_or_default(),
+    ))
+}
+
+/// Type of the entry at `path`, as a letter, and the hash of its content, left to zeros for
+/// directories.
+fn leaf(path: &Path, follow_symlink: bool) -> io::Result<(u8, Hash)> {
+    let metadata = match follow_symlink {
+        true => fs::metadata(path)?,
+        false => fs::symlink_metadata(path)?,
+    };
+    let file_type = metadata.file_type();
+    let mut hash = Sha256::new();
+    if file_type.is_dir() {
+        return Ok((b'd', Hash::default()));
+    } else if file_type.is_symlink() {
+        hash.update(bytes(fs::read_link(path)?.as_os_str()));
+        return Ok((b'l', hash.finalize().into()));
+    } else if !file_type.is_file() {
+        // Devices, FIFOs and sockets have no content to hash.
+        return Ok((b'o', hash.finalize().into()));
+    }
+    let mut file = File::open(path)?;
+    let mut buffer = vec![0; 64 * 1024];
+    loop {
+        match file.read(&mut buffer) {
+            Ok(0) => break,
+            Ok(n) => hash.update(&buffer[..n]),
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => (),
+            Err(e) => return Err(e),
+        }
+    }
+    Ok((b'f', hash.finalize().into()))
+}
+
+/// Hash of a directory with the given entries, as names, types and hashes.
+fn directory_hash(mut entries: Vec<(OsString, u8, Hash)>) -> Hash {
+    entries.sort();
+    let mut hash = Sha256::new();
+    for (name, kind, entry_hash) in entries {
+        hash.update(bytes(&name));
+        hash.update([0, kind]);
+        hash.update(entry_hash);
+    }
+    hash.finalize().into()
+}
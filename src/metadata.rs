@@ -0,0 +1,131 @@
+//! Comparison of files' metadata, other than their content.
+
+use crate::{FileMetadata, MtimeGranularity};
+use std::path::Path;
+
+/// All the permission bits of a mode, including the setuid, setgid and sticky bits.
+pub const ALL_PERMS: u32 = 0o7777;
+
+/// Whether two files have the same permissions, which only those of the real filesystem have.
+///
+/// On Unix, this compares the permission bits of the mode kept by `mask`.
+/// Elsewhere, only the read-only flag is compared, and `mask` is ignored.
+#[cfg(unix)]
+pub fn same_perms(m1: &FileMetadata, m2: &FileMetadata, mask: u32) -> bool {
+    use std::os::unix::fs::PermissionsExt;
+    let mask = mask & ALL_PERMS;
+    let mode = |m: &FileMetadata| m.real().map(|m| m.permissions().mode() & mask);
+    mode(m1) == mode(m2)
+}
+
+#[cfg(not(unix))]
+pub fn same_perms(m1: &FileMetadata, m2: &FileMetadata, _mask: u32) -> bool {
+    let readonly = |m: &FileMetadata| m.real().map(|m| m.permissions().readonly());
+    readonly(m1) == readonly(m2)
+}
+
+/// User and group ids owning a file, or `None` where they are not supported or for files not
+/// on the real filesystem.
+#[cfg(unix)]
+pub fn owner(m: &FileMetadata) -> Option<(u32, u32)> {
+    use std::os::unix::fs::MetadataExt;
+    let m = m.real()?;
+    Some((m.uid(), m.gid()))
+}
+
+#[cfg(not(unix))]
+pub fn owner(_m: &FileMetadata) -> Option<(u32, u32)> {
+    None
+}
+
+/// Number of hard links to a file, or `None` where it is not supported or for files not on
+/// the real filesystem.
+#[cfg(unix)]
+pub fn nlink(m: &FileMetadata) -> Option<u64> {
+    use std::os::unix::fs::MetadataExt;
+    let m = m.real()?;
+    Some(m.nlink())
+}
+
+#[cfg(not(unix))]
+pub fn nlink(_m: &FileMetadata) -> Option<u64> {
+    None
+}
+
+/// Number of 512-byte blocks allocated to a file, or `None` as for [`nlink`].
+#[cfg(unix)]
+pub fn blocks(m: &FileMetadata) -> Option<u64> {
+    use std::os::unix::fs::MetadataExt;
+    let m = m.real()?;
+    Some(m.blocks())
+}
+
+#[cfg(not(unix))]
+pub fn blocks(_m: &FileMetadata) -> Option<u64> {
+    None
+}
+
+/// Device and inode numbers of a file, which identify it, or `None` as for [`nlink`].
+#[cfg(unix)]
+pub fn inode(m: &FileMetadata) -> Option<(u64, u64)> {
+    use std::os::unix::fs::MetadataExt;
+    let m = m.real()?;
+    Some((m.dev(), m.ino()))
+}
+
+#[cfg(not(unix))]
+pub fn inode(_m: &FileMetadata) -> Option<(u64, u64)> {
+    None
+}
+
+/// Filesystems whose mtime resolution is known, by the magic number `statfs` gives as their
+/// type, along with the finest granularity at least as coarse as their resolution.
+#[cfg(target_os = "linux")]
+const MTIME_GRANULARITIES: &[(u32, MtimeGranularity)] = &[
+    // ext2, ext3 and ext4, with the 256-byte inodes of their defaults.
+    (0xef53, MtimeGranularity::Nanoseconds),
+    // XFS, Btrfs, tmpfs, F2FS and ZFS.
+    (0x5846_5342, MtimeGranularity::Nanoseconds),
+    (0x9123_683e, MtimeGranularity::Nanoseconds),
+    (0x0102_1994, MtimeGranularity::Nanoseconds),
+    (0xf2f5_2010, MtimeGranularity::Nanoseconds),
+    (0x2fc1_2fc1, MtimeGranularity::Nanoseconds),
+    // NTFS, at 100 nanoseconds, and UDF, at microseconds.
+    (0x7366_746e, MtimeGranularity::Microseconds),
+    (0x1501_3346, MtimeGranularity::Microseconds),
+    // exFAT, at 10 milliseconds.
+    (0x2011_bab0, MtimeGranularity::Seconds),
+    // FAT, at 2 seconds, rounded to seconds like `--mtime-granularity s` does.
+    (0x4d44, MtimeGranularity::Seconds),
+    // HFS+, ISO 9660 and SquashFS, at seconds.
+    (0x482b, MtimeGranularity::Seconds),
+    (0x9660, MtimeGranularity::Seconds),
+    (0x7371_7368, MtimeGranularity::Seconds),
+];
+
+/// Granularity of the mtimes of the filesystem `path` is on, or `None` if it is not known.
+///
+/// Filesystems are told apart by their type, so that those whose resolution depends on how
+/// they are mounted or on a server, like NFS, SMB or FUSE ones, are not known.
+#[cfg(target_os = "linux")]
+pub fn mtime_granularity(path: &Path) -> Option<MtimeGranularity> {
+    use std::{ffi::CString, mem::MaybeUninit, os::unix::ffi::OsStrExt};
+    let path = CString::new(path.as_os_str().as_bytes()).ok()?;
+    let mut stats = MaybeUninit::<libc::statfs>::uninit();
+    // SAFETY: `path` is a valid NUL-terminated string, and `stats` is valid for writes.
+    if unsafe { libc::statfs(path.as_ptr(), stats.as_mut_ptr()) } < 0 {
+        return None;
+    }
+    // SAFETY: `statfs` succeeded, so it filled `stats`. Magic numbers fit in 32 bits, whatever
+    // the size of the field.
+    let magic = unsafe { stats.assume_init() }.f_type as u32;
+    MTIME_GRANULARITIES
+        .iter()
+        .find(|(m, _)| *m == magic)
+        .map(|(_, granularity)| *granularity)
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn mtime_granularity(_path: &Path) -> Option<MtimeGranularity> {
+    None
+}
@@ -0,0 +1,171 @@
+//! Limit on the number of files the workers keep open at once, for
+//! [`DiffOptions::open_files`](crate::DiffOptions::open_files).
+
+use std::{
+    sync::{Condvar, Mutex},
+    time::{Duration, Instant},
+};
+
+/// How long the number of free file descriptors sampled for a margin is trusted.
+const SAMPLE_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Counting semaphore bounding the number of files and directories opened at once.
+///
+/// Workers wait before opening files while the limit is reached, rather than failing with
+/// `EMFILE` once the process runs out of file descriptors. They can also wait while few
+/// descriptors are free, see [`OpenFiles::with_min_free`].
+#[derive(Debug)]
+pub struct OpenFiles {
+    max: usize,
+    /// Number of file descriptors to keep free.
+    min_free: Option<u64>,
+    /// Number of files currently open.
+    open: Mutex<usize>,
+    closed: Condvar,
+    /// Number of free file descriptors, along with when it was sampled.
+    sampled: Mutex<Option<(Instant, u64)>>,
+}
+
+impl OpenFiles {
+    /// Allows `max` files to be open at once, and at least 2, so that a pair of files can
+    /// always be compared.
+    pub fn new(max: usize) -> Self {
+        Self {
+            max: max.max(2),
+            min_free: None,
+            open: Mutex::new(0),
+            closed: Condvar::new(),
+            sampled: Mutex::new(None),
+        }
+    }
+
+    /// Also waits before opening files while fewer than `min_free` file descriptors are free,
+    /// whoever opened the others, until some are closed.
+    ///
+    /// The descriptors in use are counted from `/proc/self/fd` every few milliseconds, against
+    /// the soft limit of the process, so that this only works on Linux, and does nothing
+    /// elsewhere. Files are always allowed to be opened when none is counted as open, so that
+    /// the workers go on, one at a time, even if the margin is never met.
+    pub fn with_min_free(self, min_free: u64) -> Self {
+        Self {
+            min_free: Some(min_free),
+            ..self
+        }
+    }
+
+    /// Allows half of the soft limit of the process on its number of open files, leaving the
+    /// other half to the files it opens otherwise.
+    ///
+    /// Returns `None` if there is no such limit, or where it cannot be queried.
+    pub fn from_rlimit() -> Option<Self> {
+        soft_limit().map(|limit| Self::new(usize::try_from(limit / 2).unwrap_or(usize::MAX)))
+    }
+
+    /// Number of files allowed to be open at once.
+    pub fn max(&self) -> usize {
+        self.max
+    }
+
+    /// Waits until `n` more files can be opened, and counts them as open until the returned
+    /// permit is dropped.
+    pub(crate) fn acquire(&self, n: usize) -> Permit<'_> {
+        // Never more than the limit, or this would wait forever.
+        let n = n.min(self.max);
+        let mut open = self.open.lock().unwrap();
+        while *open + n > self.max || (*open > 0 && self.low_on_fds(n)) {
+            // Descriptors closed outside of the permits are only found by sampling again.
+            open = self.closed.wait_timeout(open, SAMPLE_INTERVAL).unwrap().0;
+        }
+        *open += n;
+        Permit { files: self, n }
+    }
+
+    /// Whether opening `n` more files would leave fewer descriptors free than the margin.
+    fn low_on_fds(&self, n: usize) -> bool {
+        let min_free = match self.min_free {
+            Some(min_free) => min_free,
+            None => return false,
+        };
+        let mut sampled = self.sampled.lock().unwrap();
+        let free = match *sampled {
+            Some((at, free)) if at.elapsed() < SAMPLE_INTERVAL => free,
+            _ => match (soft_limit(), open_fds()) {
+                (Some(limit), Some(used)) => {
+                    let free = limit.saturating_sub(used);
+                    *sampled = Some((Instant::now(), free));
+                    free
+                }
+                _ => return false,
+            },
+        };
+        free < min_free + n as u64
+    }
+}
+
+/// Files counted as open by [`OpenFiles::acquire`], until dropped.
+pub(crate) struct Permit<'a> {
+    files: &'a OpenFiles,
+    n: usize,
+}
+
+impl Drop for Permit<'_> {
+    fn drop(&mut self) {
+        *self.files.open.lock().unwrap() -= self.n;
+        self.files.closed.notify_all();
+    }
+}
+
+#[cfg(unix)]
+fn soft_limit() -> Option<u64> {
+    let mut limit = libc::rlimit {
+        rlim_cur: 0,
+        rlim_max: 0,
+    };
+    // SAFETY: `limit` is a valid `rlimit` for the call to write to.
+    if unsafe { libc::getrlimit(libc::RLIMIT_NOFILE, &mut limit) } != 0 {
+        return None;
+    }
+    if limit.rlim_cur == libc::RLIM_INFINITY {
+        return None;
+    }
+    #[allow(clippy::useless_conversion)]
+    u64::try_from(limit.rlim_cur).ok()
+}
+
+#[cfg(not(unix))]
+fn soft_limit() -> Option<u64> {
+    None
+}
+
+/// Number of file descriptors the process has open.
+#[cfg(target_os = "linux")]
+fn open_fds() -> Option<u64> {
+    let entries = std::fs::read_dir("/proc/self/fd").ok()?;
+    // Not counting the one listing them.
+    Some((entries.count() as u64).saturating_sub(1))
+}
+
+#[cfg(not(target_os = "linux"))]
+fn open_fds() -> Option<u64> {
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn min_free() {
+        let files = OpenFiles::new(usize::MAX).with_min_free(1);
+        assert!(!files.low_on_fds(1));
+        // Some descriptors are always in use, like the standard streams.
+        let unreachable = OpenFiles::new(usize::MAX).with_min_free(soft_limit().unwrap());
+        assert!(unreachable.low_on_fds(1));
+        // Still allowed, as no file is counted as open.
+        let permit = unreachable.acquire(2);
+        assert_eq!(*unreachable.open.lock().unwrap(), 2);
+        drop(permit);
+        assert_eq!(*unreachable.open.lock().unwrap(), 0);
+    }
+}
@@ -0,0 +1,155 @@
+//! Names of the owners of files, for the `owner` diffs of the grepable output.
+//!
+//! User and group ids are looked up in the user and group databases, like `/etc/passwd` and
+//! `/etc/group` or the directory services they are configured with, which can be slow. The
+//! names found, and the ids found missing, are cached, so that each id is looked up once
+//! however many files it owns.
+
+use std::{collections::BTreeMap, sync::Mutex};
+
+/// Names of users by their ids.
+static USERS: Names = Names::new(user_name);
+/// Names of groups by their ids.
+static GROUPS: Names = Names::new(group_name);
+
+/// Cache of the names of ids of one kind.
+struct Names {
+    /// Names of the ids looked up, or `None` for those missing from the database.
+    cache: Mutex<BTreeMap<u32, Option<String>>>,
+    lookup: fn(u32) -> Option<String>,
+}
+
+impl Names {
+    const fn new(lookup: fn(u32) -> Option<String>) -> Self {
+        Self {
+            cache: Mutex::new(BTreeMap::new()),
+            lookup,
+        }
+    }
+
+    /// Name of `id`, or `id` itself if it has none.
+    fn get(&self, id: u32) -> String {
+        // The lock is held during the lookup, so that threads meeting a new id at the same
+        // time do not both look it up.
+        let mut cache = self.cache.lock().unwrap();
+        let name = cache.entry(id).or_insert_with(|| (self.lookup)(id));
+        name.clone().unwrap_or_else(|| id.to_string())
+    }
+}
+
+/// The owner `uid:gid`, with the names of the user and group in place of their ids when they
+/// have one.
+pub fn owner(uid: u32, gid: u32) -> String {
+    format!("{}:{}", USERS.get(uid), GROUPS.get(gid))
+}
+
+/// Size of the buffer given to the first lookup of an id, doubled until the entry fits.
+#[cfg(unix)]
+const BUFFER_SIZE: usize = 1024;
+
+#[cfg(unix)]
+fn user_name(uid: u32) -> Option<String> {
+    use std::{ffi::CStr, ptr};
+
+    let mut buffer = vec![0; BUFFER_SIZE];
+    loop {
+        // SAFETY: an all-zero `passwd` is valid, its pointers being null.
+        let mut passwd: libc::passwd = unsafe { std::mem::zeroed() };
+        let mut result = ptr::null_mut();
+        // SAFETY: `buffer` is as long as the length given, and `getpwuid_r`, unlike
+        // `getpwuid`, is safe to call from several threads.
+        let e = unsafe {
+            libc::getpwuid_r(
+                uid,
+                &mut passwd,
+                buffer.as_mut_ptr(),
+                buffer.len(),
+                &mut result,
+            )
+        };
+        match e {
+            libc::ERANGE => buffer.resize(2 * buffer.len(), 0),
+            0 if !result.is_null() => {
+                // SAFETY: the name of an entry found is a C string in `buffer`.
+                let name = unsafe { CStr::from_ptr(passwd.pw_name) };
+                return Some(name.to_string_lossy().into_owned());
+            }
+            // The id has no entry, or the database cannot be read.
+            _ => return None,
+        }
+    }
+}
+
+#[cfg(unix)]
+fn group_name(gid: u32) -> Option<String> {
+    use std::{ffi::CStr, ptr};
+
+    let mut buffer = vec![0; BUFFER_SIZE];
+    loop {
+        // SAFETY: an all-zero `group` is valid, its pointers being null.
+        let mut group: libc::group = unsafe { std::mem::zeroed() };
+        let mut result = ptr::null_mut();
+        // SAFETY: as for `getpwuid_r` in `user_name`.
+        let e = unsafe {
+            libc::getgrgid_r(
+                gid,
+                &mut group,
+                buffer.as_mut_ptr(),
+                buffer.len(),
+                &mut result,
+            )
+        };
+        match e {
+            libc::ERANGE => buffer.resize(2 * buffer.len(), 0),
+            0 if !result.is_null() => {
+                // SAFETY: the name of an entry found is a C string in `buffer`.
+                let name = unsafe { CStr::from_ptr(group.gr_name) };
+                return Some(name.to_string_lossy().into_owned());
+            }
+            _ => return None,
+        }
+    }
+}
+
+/// Owners are only compared on Unix.
+#[cfg(not(unix))]
+fn user_name(_uid: u32) -> Option<String> {
+    None
+}
+
+#[cfg(not(unix))]
+fn group_name(_gid: u32) -> Option<String> {
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    #[test]
+    fn cached() {
+        static LOOKUPS: AtomicUsize = AtomicUsize::new(0);
+        fn lookup(id: u32) -> Option<String> {
+            LOOKUPS.fetch_add(1, Ordering::SeqCst);
+            (id == 0).then(|| "root".to_owned())
+        }
+        let names = Names::new(lookup);
+        for _ in 0..3 {
+            assert_eq!(names.get(0), "root");
+            // Ids without a name are shown as numbers, and are not looked up again either.
+            assert_eq!(names.get(4242), "4242");
+        }
+        assert_eq!(LOOKUPS.load(Ordering::SeqCst), 2);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn lookup() {
+        assert_eq!(user_name(0).as_deref(), Some("root"));
+        assert_eq!(
+            owner(0, u32::MAX - 1).split_once(':'),
+            Some(("root", "4294967294"))
+        );
+    }
+}
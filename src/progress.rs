@@ -0,0 +1,62 @@
+//! Progress of a comparison as it runs, for
+//! [`DiffOptions::progress`](crate::DiffOptions::progress).
+
+use std::{
+    collections::BTreeSet,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+};
+
+/// Counts of the work done so far, along with the directories being processed, updated by
+/// the workers and read from any other thread.
+#[derive(Debug, Default)]
+pub struct Progress {
+    dirs_done: AtomicU64,
+    files_done: AtomicU64,
+    /// Directories being listed and having their entries compared, by any worker.
+    active: Mutex<BTreeSet<PathBuf>>,
+}
+
+/// State of a [`Progress`] at some point.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ProgressSnapshot {
+    /// Pairs of directories whose entries were all compared, the roots included, but not the
+    /// content of their subdirectories.
+    pub dirs_done: u64,
+    /// Pairs of regular files whose content was compared.
+    pub files_done: u64,
+    /// Directories being processed, relative to the roots and sorted. The roots have an empty
+    /// path.
+    pub active: Vec<PathBuf>,
+}
+
+impl Progress {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Current counts and active directories.
+    pub fn snapshot(&self) -> ProgressSnapshot {
+        ProgressSnapshot {
+            dirs_done: self.dirs_done.load(Ordering::Relaxed),
+            files_done: self.files_done.load(Ordering::Relaxed),
+            active: self.active.lock().unwrap().iter().cloned().collect(),
+        }
+    }
+
+    pub(crate) fn dir_started(&self, dir: &Path) {
+        self.active.lock().unwrap().insert(dir.to_owned());
+    }
+
+    pub(crate) fn dir_done(&self, dir: &Path) {
+        self.active.lock().unwrap().remove(dir);
+        self.dirs_done.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn file_done(&self) {
+        self.files_done.fetch_add(1, Ordering::Relaxed);
+    }
+}
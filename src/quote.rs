@@ -0,0 +1,180 @@
+//! Quoting of paths in the grepable output.
+//!
+//! Paths are written between double quotes, with the characters that would break the line,
+//! like tabs and newlines, escaped as in Rust strings. Valid Unicode is written as is, whatever
+//! the script, while bytes or UTF-16 units that are not part of valid Unicode are escaped, so
+//! that the path can be told back from the escapes, see [`escaped`].
+
+use std::ffi::{OsStr, OsString};
+
+/// Quotes `name`, a path or a file name, as [`escaped`] between double quotes.
+pub fn quoted(name: &OsStr) -> String {
+    format!("\"{}\"", escaped(name))
+}
+
+/// Escapes `name`, a path or a file name, so that its bytes can be told back from the result:
+///
+/// - `"` and `\` are written `\"` and `\\`,
+/// - NUL, tab, newline and carriage return are written `\0`, `\t`, `\n` and `\r`,
+/// - other control characters, U+0001 to U+001F and U+007F to U+009F, are written `\u{X}`,
+///   `X` being their code point in lowercase hexadecimal, without leading zeros, like `\u{1b}`,
+/// - bytes which are not part of valid UTF-8 are written `\xHH`, `HH` being their value as
+///   two uppercase hexadecimal digits, like `\xFF`,
+/// - other characters are written as is, in UTF-8.
+#[cfg(not(windows))]
+pub fn escaped(name: &OsStr) -> String {
+    let mut escaped = String::new();
+    for chunk in crate::fingerprint::bytes(name).utf8_chunks() {
+        for c in chunk.valid().chars() {
+            match c {
+                '"' => escaped.push_str("\\\""),
+                '\\' => escaped.push_str("\\\\"),
+                '\0' => escaped.push_str("\\0"),
+                '\t' => escaped.push_str("\\t"),
+                '\n' => escaped.push_str("\\n"),
+                '\r' => escaped.push_str("\\r"),
+                c if c.is_control() => escaped.push_str(&format!("\\u{{{:x}}}", c as u32)),
+                c => escaped.push(c),
+            }
+        }
+        for b in chunk.invalid() {
+            escaped.push_str(&format!("\\x{:02X}", b));
+        }
+    }
+    escaped
+}
+
+/// Escapes `name`, a path or a file name.
+///
+/// Characters are escaped as by [`char::escape_debug`], but for single quotes, and unpaired
+/// surrogates, which are not valid UTF-16, as `\u{d800}`. Backslashes are escaped too,
+/// although they separate the components of every path, so that a separator is not mistaken
+/// for the start of an escape, like in `a\tb`.
+#[cfg(windows)]
+pub fn escaped(name: &OsStr) -> String {
+    use std::os::windows::ffi::OsStrExt;
+    let mut escaped = String::new();
+    for c in char::decode_utf16(name.encode_wide()) {
+        match c {
+            Ok('\'') => escaped.push('\''),
+            Ok(c) => escaped.extend(c.escape_debug()),
+            Err(e) => escaped.push_str(&format!("\\u{{{:x}}}", e.unpaired_surrogate())),
+        }
+    }
+    escaped
+}
+
+/// Reads back a name quoted by [`quoted`] at the start of `s`, returning it along with what
+/// follows the closing quote, or `None` if `s` does not start with a quoted name.
+///
+/// Unpaired surrogates, which only Windows names have, are read back as U+FFFD.
+pub fn unquoted(s: &str) -> Option<(OsString, &str)> {
+    let mut chars = s.strip_prefix('"')?.char_indices();
+    let mut bytes = Vec::new();
+    let end = loop {
+        let c = match chars.next()? {
+            (i, '"') => break i,
+            (_, '\\') => match chars.next()?.1 {
+                c @ ('"' | '\\' | '\'') => c,
+                '0' => '\0',
+                't' => '\t',
+                'n' => '\n',
+                'r' => '\r',
+                'x' => {
+                    let hex: String = chars.by_ref().take(2).map(|(_, c)| c).collect();
+                    bytes.push(u8::from_str_radix(&hex, 16).ok()?);
+                    continue;
+                }
+                'u' => {
+                    let rest = chars.as_str().strip_prefix('{')?;
+                    let (hex, _) = rest.split_once('}')?;
+                    let code = u32::from_str_radix(hex, 16).ok()?;
+                    chars.by_ref().take(hex.len() + 2).for_each(drop);
+                    char::from_u32(code).unwrap_or(char::REPLACEMENT_CHARACTER)
+                }
+                _ => return None,
+            },
+            (_, c) => c,
+        };
+        bytes.extend_from_slice(c.encode_utf8(&mut [0; 4]).as_bytes());
+    };
+    // The opening quote is one byte long.
+    Some((os_string(bytes), &s[end + 2..]))
+}
+
+#[cfg(unix)]
+fn os_string(bytes: Vec<u8>) -> OsString {
+    use std::os::unix::ffi::OsStringExt;
+    OsString::from_vec(bytes)
+}
+
+#[cfg(not(unix))]
+fn os_string(bytes: Vec<u8>) -> OsString {
+    String::from_utf8_lossy(&bytes).into_owned().into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn non_latin() {
+        assert_eq!(
+            quoted(OsStr::new("données/日本語.txt")),
+            r#""données/日本語.txt""#
+        );
+        assert_eq!(quoted(OsStr::new("tab\there")), r#""tab\there""#);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn escapes() {
+        use std::os::unix::ffi::OsStrExt;
+        assert_eq!(
+            escaped(OsStr::from_bytes(b"caf\xe9 \"q\" a\\b")),
+            r#"caf\xE9 \"q\" a\\b"#
+        );
+        assert_eq!(
+            escaped(OsStr::from_bytes(b"\0\x1b\r\x7f\xff\xfe")),
+            r"\0\u{1b}\r\u{7f}\xFF\xFE"
+        );
+        // Printable characters that `{:?}` escaped, like combining marks, are written as is.
+        assert_eq!(escaped(OsStr::new("e\u{301}")), "e\u{301}");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn round_trip() {
+        use std::os::unix::ffi::OsStrExt;
+        for name in [
+            &b"plain"[..],
+            b"caf\xe9 \"q\" a\\b",
+            b"\0\x1b\r\n\t\x7f\xff",
+            "données/日本語".as_bytes(),
+        ] {
+            let name = OsStr::from_bytes(name);
+            let quoted = format!("{}\trest", quoted(name));
+            assert_eq!(
+                unquoted(&quoted),
+                Some((name.to_owned(), "\trest")),
+                "{quoted}"
+            );
+        }
+        assert_eq!(unquoted("plain"), None);
+        assert_eq!(unquoted(r#""unterminated"#), None);
+        assert_eq!(unquoted(r#""\q""#), None);
+    }
+
+    #[cfg(windows)]
+    #[test]
+    fn windows() {
+        use std::{ffi::OsString, os::windows::ffi::OsStringExt};
+        assert_eq!(
+            quoted(OsStr::new(r"données\日本語.txt")),
+            r#""données\\日本語.txt""#
+        );
+        assert_eq!(quoted(OsStr::new("it's")), r#""it's""#);
+        let unpaired: Vec<u16> = "a".encode_utf16().chain([0xd800]).collect();
+        assert_eq!(quoted(&OsString::from_wide(&unpaired)), r#""a\u{d800}""#);
+    }
+}
@@ -0,0 +1,130 @@
+//! Reading of whether files share physical extents with other files, like reflink clones, for
+//! `--check-reflinks`.
+//!
+//! Extents are read on Linux only, through the `FS_IOC_FIEMAP` ioctl, whose `SHARED` flag is
+//! set on the extents of filesystems like Btrfs and XFS which are shared with another file or
+//! snapshot. Elsewhere, and on filesystems without it, sharing cannot be told.
+
+use std::{io, path::Path};
+
+/// `_IOWR('f', 11, struct fiemap)`, missing from the `libc` crate.
+///
+/// Its encoding differs on a few architectures, like MIPS, PowerPC and SPARC.
+#[cfg(all(
+    target_os = "linux",
+    not(any(
+        target_arch = "mips",
+        target_arch = "mips64",
+        target_arch = "powerpc",
+        target_arch = "powerpc64",
+        target_arch = "sparc64"
+    ))
+))]
+const FS_IOC_FIEMAP: u32 =
+    (3 << 30) | ((std::mem::size_of::<Fiemap>() as u32) << 16) | ((b'f' as u32) << 8) | 11;
+
+#[cfg(all(
+    target_os = "linux",
+    any(
+        target_arch = "mips",
+        target_arch = "mips64",
+        target_arch = "powerpc",
+        target_arch = "powerpc64",
+        target_arch = "sparc64"
+    )
+))]
+const FS_IOC_FIEMAP: u32 =
+    (6 << 29) | ((std::mem::size_of::<Fiemap>() as u32) << 16) | ((b'f' as u32) << 8) | 11;
+
+/// Flag of the last extent of a file.
+#[cfg(target_os = "linux")]
+const FIEMAP_EXTENT_LAST: u32 = 0x1;
+/// Flag of the extents shared with other files.
+#[cfg(target_os = "linux")]
+const FIEMAP_EXTENT_SHARED: u32 = 0x2000;
+/// Number of extents read by each ioctl.
+#[cfg(target_os = "linux")]
+const EXTENTS_PER_CALL: usize = 64;
+
+/// `struct fiemap`, without its trailing array of extents.
+#[cfg(target_os = "linux")]
+#[repr(C)]
+#[derive(Default)]
+struct Fiemap {
+    start: u64,
+    length: u64,
+    flags: u32,
+    mapped_extents: u32,
+    extent_count: u32,
+    reserved: u32,
+}
+
+/// `struct fiemap_extent`.
+#[cfg(target_os = "linux")]
+#[repr(C)]
+#[derive(Default, Clone, Copy)]
+struct FiemapExtent {
+    logical: u64,
+    physical: u64,
+    length: u64,
+    reserved64: [u64; 2],
+    flags: u32,
+    reserved: [u32; 3],
+}
+
+/// A `struct fiemap` followed by room for its extents.
+#[cfg(target_os = "linux")]
+#[repr(C)]
+struct FiemapRequest {
+    header: Fiemap,
+    extents: [FiemapExtent; EXTENTS_PER_CALL],
+}
+
+/// Whether a regular file has extents shared with other files, as reflink clones, snapshots
+/// or deduplicated files do.
+///
+/// Returns `None` if its filesystem cannot tell.
+#[cfg(target_os = "linux")]
+pub fn has_shared_extents(path: &Path) -> io::Result<Option<bool>> {
+    use std::{fs::File, os::unix::io::AsRawFd};
+
+    let file = File::open(path)?;
+    let mut start = 0;
+    loop {
+        let mut request = FiemapRequest {
+            header: Fiemap {
+                start,
+                length: u64::MAX - start,
+                extent_count: EXTENTS_PER_CALL as u32,
+                ..Default::default()
+            },
+            extents: [FiemapExtent::default(); EXTENTS_PER_CALL],
+        };
+        // SAFETY: the file descriptor is valid, and `request` has room for the number of
+        // extents it asks for.
+        let result = unsafe { libc::ioctl(file.as_raw_fd(), FS_IOC_FIEMAP as _, &mut request) };
+        if result < 0 {
+            let e = io::Error::last_os_error();
+            return match e.raw_os_error() {
+                Some(libc::ENOTTY) | Some(libc::EOPNOTSUPP) | Some(libc::EINVAL) => Ok(None),
+                _ => Err(e),
+            };
+        }
+        let mapped = &request.extents[..request.header.mapped_extents as usize];
+        if mapped.iter().any(|e| e.flags & FIEMAP_EXTENT_SHARED != 0) {
+            return Ok(Some(true));
+        }
+        match mapped.last() {
+            Some(last) if last.flags & FIEMAP_EXTENT_LAST == 0 => {
+                start = last.logical + last.length;
+            }
+            // No extent is left, or the file has none, like empty ones.
+            _ => return Ok(Some(false)),
+        }
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn has_shared_extents(_path: &Path) -> io::Result<Option<bool>> {
+    Ok(None)
+}
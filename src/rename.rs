@@ -0,0 +1,102 @@
+//! Entries compared under another name in the second dir., for
+//! [`DiffOptions::renames`](crate::DiffOptions::renames).
+
+use anyhow::bail;
+use std::{
+    collections::HashMap,
+    ffi::{OsStr, OsString},
+    path::{Component, Path, PathBuf},
+};
+
+/// Entries of the first dir. to compare with an entry of another name in the second dir.
+///
+/// Each rename is given as a pair of paths relative to the roots, like `v1` and `v2`, so that
+/// `dir1/v1/x` is compared with `dir2/v2/x`. A rename only changes the name of an entry, so the
+/// second path must be in the directory the parent of the first one is compared with. Renames
+/// nest: with `v1=v2`, `v1/old=v2/new` compares `dir1/v1/old` with `dir2/v2/new`.
+#[derive(Debug, Clone, Default)]
+pub struct RenameMap {
+    /// Renames of the entries of each directory, by its path in the first dir., as pairs of
+    /// names in the first and the second dir.
+    by_dir: HashMap<PathBuf, Vec<(OsString, OsString)>>,
+}
+
+impl RenameMap {
+    /// Compares each path of the first dir. with its pair in the second dir.
+    ///
+    /// Fails if a path is not relative, if an entry is renamed twice, if two entries are
+    /// renamed to the same one, or if a pair changes more than the name of an entry.
+    pub fn new(renames: Vec<(PathBuf, PathBuf)>) -> anyhow::Result<Self> {
+        let mut map = Self::default();
+        let mut parents = Vec::new();
+        for (old, new) in renames {
+            for path in [&old, &new] {
+                let normal = path.components().all(|c| matches!(c, Component::Normal(_)));
+                if path.as_os_str().is_empty() || !normal {
+                    bail!(
+                        "Cannot rename {}, only paths relative to the roots without .. are",
+                        path.display()
+                    );
+                }
+            }
+            let (old_name, new_name) = match (old.file_name(), new.file_name()) {
+                (Some(old_name), Some(new_name)) => (old_name.to_owned(), new_name.to_owned()),
+                _ => unreachable!("paths are made of normal components"),
+            };
+            let parent = old.parent().unwrap_or(Path::new("")).to_owned();
+            let renames = map.by_dir.entry(parent.clone()).or_default();
+            if renames.iter().any(|(o, _)| *o == old_name) {
+                bail!("{} is renamed twice", old.display());
+            }
+            if renames.iter().any(|(_, n)| *n == new_name) {
+                bail!("Two entries are renamed to {}", new.display());
+            }
+            renames.push((old_name, new_name));
+            parents.push((parent, new));
+        }
+        // Only once all the renames are known, as they apply to the parents of the others.
+        for (parent, new) in parents {
+            let new_parent = new.parent().unwrap_or(Path::new(""));
+            let compared = map.second_path(&parent);
+            if compared != new_parent {
+                bail!(
+                    "Cannot rename to {}, as {} is compared with {}",
+                    new.display(),
+                    parent.display(),
+                    compared.display()
+                );
+            }
+        }
+        Ok(map)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.by_dir.is_empty()
+    }
+
+    /// Path in the second dir. of the entry at `path` in the first dir.
+    pub fn second_path(&self, path: &Path) -> PathBuf {
+        let mut first = PathBuf::new();
+        let mut second = PathBuf::new();
+        for name in path.iter() {
+            second.push(self.renamed(&first, name).unwrap_or(name));
+            first.push(name);
+        }
+        second
+    }
+
+    /// Name in the second dir. of the entry `name` of `dir` in the first dir., if renamed.
+    fn renamed(&self, dir: &Path, name: &OsStr) -> Option<&OsStr> {
+        let renames = self.by_dir.get(dir)?;
+        renames
+            .iter()
+            .find(|(old, _)| old == name)
+            .map(|(_, new)| new.as_os_str())
+    }
+
+    /// Renames of the entries of `dir` in the first dir., as pairs of names in the first and
+    /// the second dir.
+    pub(crate) fn renames_in(&self, dir: &Path) -> &[(OsString, OsString)] {
+        self.by_dir.get(dir).map(Vec::as_slice).unwrap_or_default()
+    }
+}
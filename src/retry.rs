@@ -0,0 +1,102 @@
+//! Retrying of IO operations that failed with transient errors.
+//!
+//! On network filesystems, operations may occasionally be interrupted or time out,
+//! and succeed when issued again. Other errors, like a missing file or a permission denied,
+//! are returned right away.
+
+use std::{io, thread, time::Duration};
+
+/// Delay before the first retry, doubled after each failed attempt.
+const INITIAL_DELAY: Duration = Duration::from_millis(10);
+/// Upper bound on the delay between two attempts.
+const MAX_DELAY: Duration = Duration::from_secs(2);
+
+/// How many times an IO operation is retried, for `--retries`.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct RetryPolicy {
+    retries: u32,
+}
+
+impl RetryPolicy {
+    pub fn new(retries: u32) -> Self {
+        Self { retries }
+    }
+
+    /// Runs `op`, running it again with an exponential backoff as long as it fails with
+    /// a transient error and retries are left.
+    pub fn retry<T>(&self, mut op: impl FnMut() -> io::Result<T>) -> io::Result<T> {
+        let mut delay = INITIAL_DELAY;
+        let mut attempt = 0;
+        loop {
+            match op() {
+                Err(e) if is_transient(&e) => {
+                    if attempt == self.retries {
+                        return Err(if attempt == 0 {
+                            e
+                        } else {
+                            io::Error::new(
+                                e.kind(),
+                                format!("{} (gave up after {} retries)", e, attempt),
+                            )
+                        });
+                    }
+                    attempt += 1;
+                    thread::sleep(delay);
+                    delay = std::cmp::min(2 * delay, MAX_DELAY);
+                }
+                res => return res,
+            }
+        }
+    }
+}
+
+/// Whether an error is worth retrying.
+fn is_transient(e: &io::Error) -> bool {
+    matches!(
+        e.kind(),
+        io::ErrorKind::Interrupted | io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// An operation failing with `kind` its first `failures` times, counting its attempts.
+    fn failing(failures: u32, kind: io::ErrorKind, attempts: &mut u32) -> io::Result<u32> {
+        *attempts += 1;
+        if *attempts <= failures {
+            Err(io::Error::from(kind))
+        } else {
+            Ok(*attempts)
+        }
+    }
+
+    #[test]
+    fn transient_errors_are_retried() {
+        let mut attempts = 0;
+        let res =
+            RetryPolicy::new(3).retry(|| failing(2, io::ErrorKind::Interrupted, &mut attempts));
+        assert_eq!(res.unwrap(), 3);
+    }
+
+    #[test]
+    fn retries_are_bounded() {
+        let mut attempts = 0;
+        let res = RetryPolicy::new(2).retry(|| failing(5, io::ErrorKind::TimedOut, &mut attempts));
+        let e = res.unwrap_err();
+        assert_eq!(attempts, 3);
+        assert_eq!(e.kind(), io::ErrorKind::TimedOut);
+        assert!(e.to_string().contains("gave up after 2 retries"));
+    }
+
+    #[test]
+    fn other_errors_are_not_retried() {
+        for kind in [io::ErrorKind::NotFound, io::ErrorKind::PermissionDenied] {
+            let mut attempts = 0;
+            let res = RetryPolicy::new(3).retry(|| failing(1, kind, &mut attempts));
+            assert_eq!(res.unwrap_err().kind(), kind);
+            assert_eq!(attempts, 1);
+        }
+    }
+}
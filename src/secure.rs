@@ -0,0 +1,124 @@
+//! Traversal of the directories through file descriptors, for `--secure`.
+//!
+//! Each directory is opened relative to the file descriptor of its parent, and regular files
+//! relative to the one of their directory, without following symlinks. Paths are never resolved
+//! again from the roots, so that replacing a directory with a symlink during the comparison
+//! cannot redirect it outside of the roots. Only supported on Linux.
+
+use std::{ffi::OsStr, fs::File, io, path::Path, path::PathBuf};
+
+/// An open directory.
+#[cfg(target_os = "linux")]
+#[derive(Debug)]
+pub struct DirFd(std::os::unix::io::OwnedFd);
+
+/// An open directory, which cannot exist outside of Linux.
+#[cfg(not(target_os = "linux"))]
+#[derive(Debug)]
+pub enum DirFd {}
+
+#[cfg(target_os = "linux")]
+impl DirFd {
+    const DIR_FLAGS: libc::c_int = libc::O_RDONLY | libc::O_DIRECTORY | libc::O_CLOEXEC;
+
+    /// Opens a root, following symlinks as any path given as argument.
+    pub fn open(path: &Path) -> io::Result<Self> {
+        use std::{ffi::CString, os::unix::ffi::OsStrExt};
+        let path = CString::new(path.as_os_str().as_bytes())?;
+        // SAFETY: `path` is a valid NUL-terminated string.
+        let fd = unsafe { libc::open(path.as_ptr(), Self::DIR_FLAGS) };
+        Self::from_raw(fd)
+    }
+
+    /// Opens the directory `name` inside of this one, failing if it is a symlink.
+    pub fn open_dir(&self, name: &OsStr) -> io::Result<Self> {
+        Self::from_raw(self.open_at(name, Self::DIR_FLAGS | libc::O_NOFOLLOW)?)
+    }
+
+    /// Opens the regular file `name` inside of this directory, failing if it is a symlink.
+    pub fn open_file(&self, name: &OsStr) -> io::Result<File> {
+        use std::os::unix::io::FromRawFd;
+        let fd = self.open_at(name, libc::O_RDONLY | libc::O_NOFOLLOW | libc::O_CLOEXEC)?;
+        // SAFETY: `fd` was just opened, and is owned by nobody else.
+        Ok(unsafe { File::from_raw_fd(fd) })
+    }
+
+    /// Path to this directory through `/proc`, which does not depend on where it is anymore.
+    pub fn path(&self) -> PathBuf {
+        use std::os::unix::io::AsRawFd;
+        format!("/proc/self/fd/{}", self.0.as_raw_fd()).into()
+    }
+
+    fn open_at(&self, name: &OsStr, flags: libc::c_int) -> io::Result<libc::c_int> {
+        use std::{ffi::CString, os::unix::ffi::OsStrExt, os::unix::io::AsRawFd};
+        let name = CString::new(name.as_bytes())?;
+        // SAFETY: the file descriptor is valid, and `name` is a valid NUL-terminated string.
+        let fd = unsafe { libc::openat(self.0.as_raw_fd(), name.as_ptr(), flags) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(fd)
+    }
+
+    fn from_raw(fd: libc::c_int) -> io::Result<Self> {
+        use std::os::unix::io::{FromRawFd, OwnedFd};
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // SAFETY: `fd` was just opened, and is owned by nobody else.
+        Ok(Self(unsafe { OwnedFd::from_raw_fd(fd) }))
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+impl DirFd {
+    pub fn open(_path: &Path) -> io::Result<Self> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "opening directories by file descriptor is only supported on Linux",
+        ))
+    }
+
+    pub fn open_dir(&self, _name: &OsStr) -> io::Result<Self> {
+        match *self {}
+    }
+
+    pub fn open_file(&self, _name: &OsStr) -> io::Result<File> {
+        match *self {}
+    }
+
+    pub fn path(&self) -> PathBuf {
+        match *self {}
+    }
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use super::*;
+    use std::{fs, os::unix::fs::symlink};
+
+    #[test]
+    fn swapped_for_symlink() {
+        let dir = std::env::temp_dir().join(format!("dirdiff-secure-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(dir.join("root/sub")).unwrap();
+        fs::create_dir_all(dir.join("outside")).unwrap();
+        fs::write(dir.join("root/sub/f"), "inside").unwrap();
+        fs::write(dir.join("outside/f"), "outside").unwrap();
+        let root = DirFd::open(&dir.join("root")).unwrap();
+        let sub = root.open_dir(OsStr::new("sub")).unwrap();
+        // The directory being compared is replaced with a symlink leading outside of the root.
+        fs::rename(dir.join("root/sub"), dir.join("moved")).unwrap();
+        symlink(dir.join("outside"), dir.join("root/sub")).unwrap();
+        // What was opened is still read.
+        let f = fs::read_to_string(sub.path().join("f")).unwrap();
+        assert_eq!(f, "inside");
+        let mut f = String::new();
+        io::Read::read_to_string(&mut sub.open_file(OsStr::new("f")).unwrap(), &mut f).unwrap();
+        assert_eq!(f, "inside");
+        // And the symlink is not followed when opening the directory again.
+        assert!(root.open_dir(OsStr::new("sub")).is_err());
+        assert!(root.open_file(OsStr::new("sub")).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,101 @@
+//! Paths the comparison is restricted to, for
+//! [`DiffOptions::selection`](crate::DiffOptions::selection).
+
+use anyhow::bail;
+use std::{
+    collections::HashSet,
+    path::{Component, Path, PathBuf},
+};
+
+/// Entries to compare, given as paths relative to the roots, like those a file watcher found
+/// changed.
+///
+/// Listed entries are compared as usual, and reported as present in one dir. only when missing
+/// from the other one. Listed directories are recursed into, or only have their own entries
+/// compared without recursion. The directories leading to listed entries are traversed to
+/// reach them, without being compared themselves, and their other entries are left out. A
+/// directory leading to a listed entry which is missing or not a directory on one side is
+/// compared as usual instead, so that the listed entry is reported through it.
+#[derive(Debug, Clone)]
+pub struct PathSelection {
+    listed: HashSet<PathBuf>,
+    /// Directories leading to listed entries, the roots included.
+    leading: HashSet<PathBuf>,
+    /// Whether listed directories are recursed into.
+    recurse: bool,
+}
+
+impl PathSelection {
+    /// Only compares the entries at `paths`, recursing into the directories if `recurse` is
+    /// set.
+    ///
+    /// Fails if a path is not relative to the roots, or is the roots themselves.
+    pub fn new(paths: Vec<PathBuf>, recurse: bool) -> anyhow::Result<Self> {
+        let mut listed = HashSet::new();
+        let mut leading = HashSet::new();
+        for path in paths {
+            let normal = path.components().all(|c| matches!(c, Component::Normal(_)));
+            if path.as_os_str().is_empty() || !normal {
+                bail!(
+                    "Cannot select {}, only paths relative to the roots without .. are",
+                    path.display()
+                );
+            }
+            leading.extend(path.ancestors().skip(1).map(Path::to_owned));
+            listed.insert(path);
+        }
+        Ok(Self {
+            listed,
+            leading,
+            recurse,
+        })
+    }
+
+    /// Whether all the entries of `dir` are compared, as it is listed or recursed into.
+    pub(crate) fn covers(&self, dir: &Path) -> bool {
+        match self.recurse {
+            true => dir.ancestors().any(|dir| self.listed.contains(dir)),
+            false => self.listed.contains(dir),
+        }
+    }
+
+    pub(crate) fn is_listed(&self, path: &Path) -> bool {
+        self.listed.contains(path)
+    }
+
+    /// Whether `path` is a directory leading to listed entries.
+    pub(crate) fn leads_to_listed(&self, path: &Path) -> bool {
+        self.leading.contains(path)
+    }
+
+    /// Whether the subdirectory at `path` of a directory whose entries are compared is
+    /// descended into.
+    pub(crate) fn descends_into(&self, path: &Path) -> bool {
+        self.recurse || self.is_listed(path) || self.leads_to_listed(path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn selected() {
+        let paths = vec![PathBuf::from("d/e"), PathBuf::from("f")];
+        let selection = PathSelection::new(paths.clone(), true).unwrap();
+        assert!(selection.leads_to_listed(Path::new("")));
+        assert!(selection.leads_to_listed(Path::new("d")));
+        assert!(!selection.leads_to_listed(Path::new("d/e")));
+        assert!(selection.covers(Path::new("d/e/g/h")));
+        assert!(!selection.covers(Path::new("d")));
+        assert!(selection.descends_into(Path::new("x")));
+        let selection = PathSelection::new(paths, false).unwrap();
+        assert!(selection.covers(Path::new("d/e")));
+        assert!(!selection.covers(Path::new("d/e/g")));
+        assert!(selection.descends_into(Path::new("d")));
+        assert!(!selection.descends_into(Path::new("d/e/g")));
+        for path in ["", "/d", "d/../e", "./d"] {
+            assert!(PathSelection::new(vec![PathBuf::from(path)], true).is_err());
+        }
+    }
+}
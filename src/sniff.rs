@@ -0,0 +1,67 @@
+//! Content types of files, sniffed from their first bytes, for
+//! [`DiffOptions::content_type`](crate::DiffOptions::content_type).
+//!
+//! Types are told by the magic bytes at the start of the files, whatever their extension, as
+//! recognized by the `infer` crate. Text files have no magic bytes, so most of them have no
+//! known type.
+
+use anyhow::bail;
+use infer::{MatcherType, Type};
+
+/// Names of the kinds of content types, as accepted by [`ContentType::parse`].
+const KINDS: &[(&str, MatcherType)] = &[
+    ("app", MatcherType::App),
+    ("archive", MatcherType::Archive),
+    ("audio", MatcherType::Audio),
+    ("book", MatcherType::Book),
+    ("doc", MatcherType::Doc),
+    ("font", MatcherType::Font),
+    ("image", MatcherType::Image),
+    ("text", MatcherType::Text),
+    ("video", MatcherType::Video),
+];
+
+/// Content type of the files to compare.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ContentType {
+    /// All the types of a kind, like images.
+    Kind(MatcherType),
+    /// A single MIME type, like `image/png`.
+    Mime(String),
+}
+
+impl ContentType {
+    /// Parses the name of a kind of content types, like `image`, or a MIME type, like
+    /// `image/png`, failing on unknown kinds and on MIME types that cannot be sniffed.
+    pub fn parse(name: &str) -> anyhow::Result<Self> {
+        if let Some((_, kind)) = KINDS.iter().find(|(kind, _)| *kind == name) {
+            return Ok(ContentType::Kind(*kind));
+        }
+        if !name.contains('/') {
+            let kinds: Vec<_> = KINDS.iter().map(|(kind, _)| *kind).collect();
+            bail!(
+                "Unknown content type `{}`, expected a MIME type or one of {}",
+                name,
+                kinds.join(", ")
+            );
+        }
+        if !infer::is_mime_supported(name) {
+            bail!("MIME type `{}` cannot be sniffed", name);
+        }
+        Ok(ContentType::Mime(name.to_owned()))
+    }
+
+    /// Whether content of the type `sniffed`, as returned by [`sniff`], has this type.
+    pub(crate) fn matches(&self, sniffed: Option<Type>) -> bool {
+        match (self, sniffed) {
+            (_, None) => false,
+            (ContentType::Kind(kind), Some(sniffed)) => sniffed.matcher_type() == *kind,
+            (ContentType::Mime(mime), Some(sniffed)) => sniffed.mime_type() == mime,
+        }
+    }
+}
+
+/// Type of content starting with `head`, if it is known.
+pub(crate) fn sniff(head: &[u8]) -> Option<Type> {
+    infer::get(head)
+}
@@ -0,0 +1,55 @@
+//! Comparison of the hole layout of sparse files, for `--sparse`.
+//!
+//! The layout is read using `SEEK_DATA` and `SEEK_HOLE`, which are only used on Linux.
+//! Elsewhere, or when the filesystem does not support them, layouts are considered the same.
+
+use std::{io, path::Path};
+
+/// Whether two files have the same data regions, or `None` if it cannot be told.
+pub fn same_layout(path1: &Path, path2: &Path) -> io::Result<Option<bool>> {
+    let regions1 = match data_regions(path1)? {
+        Some(regions) => regions,
+        None => return Ok(None),
+    };
+    let regions2 = match data_regions(path2)? {
+        Some(regions) => regions,
+        None => return Ok(None),
+    };
+    Ok(Some(regions1 == regions2))
+}
+
+/// Returns the `(start, end)` offsets of the regions of a file holding data, as opposed to holes.
+#[cfg(target_os = "linux")]
+fn data_regions(path: &Path) -> io::Result<Option<Vec<(u64, u64)>>> {
+    use std::{fs::File, os::unix::io::AsRawFd};
+
+    let file = File::open(path)?;
+    let fd = file.as_raw_fd();
+    let mut regions = Vec::new();
+    let mut offset = 0;
+    loop {
+        // SAFETY: `fd` is a valid file descriptor, owned by `file`.
+        let start = unsafe { libc::lseek(fd, offset, libc::SEEK_DATA) };
+        if start < 0 {
+            let e = io::Error::last_os_error();
+            return match e.raw_os_error() {
+                // There is no data past `offset`.
+                Some(libc::ENXIO) => Ok(Some(regions)),
+                Some(libc::EINVAL) => Ok(None),
+                _ => Err(e),
+            };
+        }
+        // SAFETY: as above.
+        let end = unsafe { libc::lseek(fd, start, libc::SEEK_HOLE) };
+        if end < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        regions.push((start as u64, end as u64));
+        offset = end;
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn data_regions(_path: &Path) -> io::Result<Option<Vec<(u64, u64)>>> {
+    Ok(None)
+}
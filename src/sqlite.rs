@@ -0,0 +1,151 @@
+//! Diffs written to a SQLite database, for `--sqlite`.
+//!
+//! Each diff is a row of the `diffs` table, along with the size and mtime of its entry in
+//! each dir., so that the results of large comparisons can be queried, like
+//! `SELECT path FROM diffs WHERE kind = 'different' ORDER BY size1 DESC`. Rows are inserted in
+//! transactions of [`BATCH_SIZE`] rows, as committing each of them on its own is orders of
+//! magnitude slower.
+
+use dirdiff::Diff;
+use rusqlite::{params, Connection};
+use std::{fs, path::Path, time::UNIX_EPOCH};
+
+/// Number of rows inserted per transaction.
+const BATCH_SIZE: usize = 1000;
+
+const CREATE_TABLE: &str = "CREATE TABLE diffs (
+    kind TEXT NOT NULL,
+    path TEXT NOT NULL,
+    detail TEXT,
+    size1 INTEGER,
+    size2 INTEGER,
+    mtime1 INTEGER,
+    mtime2 INTEGER
+)";
+
+const INSERT: &str = "INSERT INTO diffs (kind, path, detail, size1, size2, mtime1, mtime2) \
+    VALUES (?, ?, ?, ?, ?, ?, ?)";
+
+/// A row of the `diffs` table.
+pub struct Row {
+    pub kind: &'static str,
+    pub path: String,
+    pub detail: Option<String>,
+    /// Size and mtime of the entry in the first dir., see [`entry_stats`].
+    pub stats1: (Option<i64>, Option<i64>),
+    /// Size and mtime of the entry in the second dir.
+    pub stats2: (Option<i64>, Option<i64>),
+}
+
+/// A database the diffs are inserted in.
+pub struct Database {
+    connection: Connection,
+    /// Rows inserted in the open transaction, if any.
+    pending: usize,
+}
+
+impl Database {
+    /// Opens the database at `path`, created if missing, and creates the `diffs` table in it.
+    ///
+    /// Fails if the table exists already, so that the rows of several runs are never mixed.
+    pub fn create(path: &Path) -> rusqlite::Result<Self> {
+        let connection = Connection::open(path)?;
+        connection.execute_batch(CREATE_TABLE)?;
+        Ok(Self {
+            connection,
+            pending: 0,
+        })
+    }
+
+    /// Inserts `row`, committing the transaction once it holds [`BATCH_SIZE`] rows.
+    pub fn insert(&mut self, row: &Row) -> rusqlite::Result<()> {
+        if self.pending == 0 {
+            self.connection.execute_batch("BEGIN")?;
+        }
+        self.connection.prepare_cached(INSERT)?.execute(params![
+            row.kind,
+            row.path,
+            row.detail,
+            row.stats1.0,
+            row.stats2.0,
+            row.stats1.1,
+            row.stats2.1,
+        ])?;
+        self.pending += 1;
+        if self.pending >= BATCH_SIZE {
+            self.commit()?;
+        }
+        Ok(())
+    }
+
+    /// Commits the rows inserted since the last commit.
+    pub fn commit(&mut self) -> rusqlite::Result<()> {
+        if self.pending > 0 {
+            self.connection.execute_batch("COMMIT")?;
+            self.pending = 0;
+        }
+        Ok(())
+    }
+}
+
+/// Size and mtime of the entry at `path`, or `None` for those it does not have, like a size
+/// for anything but regular files, or both if it is missing. Mtimes are in seconds since the
+/// epoch.
+pub fn entry_stats(path: &Path) -> (Option<i64>, Option<i64>) {
+    let metadata = match fs::symlink_metadata(path) {
+        Ok(metadata) => metadata,
+        Err(_) => return (None, None),
+    };
+    let size = match metadata.is_file() {
+        true => i64::try_from(metadata.len()).ok(),
+        false => None,
+    };
+    let mtime = metadata
+        .modified()
+        .ok()
+        .and_then(|mtime| match mtime.duration_since(UNIX_EPOCH) {
+            Ok(since) => i64::try_from(since.as_secs()).ok(),
+            Err(e) => i64::try_from(e.duration().as_secs()).ok().map(|secs| -secs),
+        });
+    (size, mtime)
+}
+
+/// The values of both sides of `diff`, as `first -> second`, for the kinds of diff having
+/// some, the other path involved for the `case_collision`, `moved` and `possible_rename` kinds,
+/// or the number of differing bytes of `different` diffs, if counted.
+pub fn detail(diff: &Diff) -> Option<String> {
+    let sides = |first: String, second: String| Some(format!("{} -> {}", first, second));
+    let resolved = |resolved: &Option<std::path::PathBuf>| {
+        resolved
+            .as_ref()
+            .map_or(String::new(), |p| p.to_string_lossy().into_owned())
+    };
+    match diff {
+        Diff::Different(_, _, Some(differing)) => Some(differing.to_string()),
+        Diff::TypeMismatch(_, _, type1, type2) => sides(type1.to_string(), type2.to_string()),
+        Diff::OwnerDiffer(_, _, (uid1, gid1), (uid2, gid2)) => {
+            sides(format!("{}:{}", uid1, gid1), format!("{}:{}", uid2, gid2))
+        }
+        Diff::NlinkDiffer(_, _, n1, n2) => sides(n1.to_string(), n2.to_string()),
+        Diff::EntriesDiffer(_, _, n1, n2) => sides(n1.to_string(), n2.to_string()),
+        Diff::FlagsDiffer(_, _, f1, f2) => sides(format!("{:#x}", f1), format!("{:#x}", f2)),
+        Diff::BlocksDiffer(_, _, b1, b2) => sides(b1.to_string(), b2.to_string()),
+        Diff::ExtentSharingDiffer(_, _, s1, s2) => sides(s1.to_string(), s2.to_string()),
+        Diff::CapsDiffer(_, _, caps1, caps2) => sides(caps1.clone(), caps2.clone()),
+        Diff::AclDiffer(_, _, acl1, acl2) => sides(acl1.clone(), acl2.clone()),
+        Diff::ContentTypesDiffer(_, _, type1, type2) => sides(type1.clone(), type2.clone()),
+        Diff::LinkTargetsDiffer(_, _, target1, target2) => sides(
+            target1.to_string_lossy().into_owned(),
+            target2.to_string_lossy().into_owned(),
+        ),
+        Diff::ResolvedLinks(_, _, resolved1, resolved2) => {
+            sides(resolved(resolved1), resolved(resolved2))
+        }
+        Diff::CaseCollision(_, _, other) => Some(other.to_string_lossy().into_owned()),
+        Diff::ContentMovedOrRenamed(_, _, other) => Some(other.to_string_lossy().into_owned()),
+        Diff::PossibleRename(dir, _, name2, _) => {
+            Some(dir.join(name2).to_string_lossy().into_owned())
+        }
+        _ => None,
+    }
+}
@@ -0,0 +1,124 @@
+//! Pulling diffs through an iterator, rather than having them pushed to a [`DiffHandler`].
+
+use crate::{diff_dirs_until, Diff, DiffHandler, DiffOptions, RealFs};
+use std::{
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc::{sync_channel, Receiver, SyncSender},
+        Arc,
+    },
+    thread::{self, JoinHandle},
+};
+
+/// Number of diffs the workers can get ahead of the consumer before they block.
+const CHANNEL_CAPACITY: usize = 1024;
+
+/// Sends the diffs to the channel of a [`DiffStream`].
+struct StreamHandler {
+    sender: SyncSender<anyhow::Result<Diff>>,
+    abort: Arc<AtomicBool>,
+}
+
+impl DiffHandler for StreamHandler {
+    fn process(&self, _root1: &Path, _root2: &Path, diff: Diff) {
+        if self.sender.send(Ok(diff)).is_err() {
+            // The stream was dropped, nobody is listening anymore.
+            self.abort.store(true, Ordering::SeqCst);
+        }
+    }
+}
+
+/// Iterator over the diffs of two directories.
+///
+/// The directories are compared by workers running in the background, which block when the
+/// consumer falls too far behind. Dropping the stream stops the workers, and waits for them.
+/// If the comparison fails, the error is the last item.
+///
+/// # Example
+///
+/// ```
+/// use dirdiff::{Diff, DiffOptions, DiffStream};
+/// use std::fs;
+///
+/// # let root = std::env::temp_dir().join(format!("dirdiff-stream-{}", std::process::id()));
+/// let (dir1, dir2) = (root.join("dir1"), root.join("dir2"));
+/// for dir in [&dir1, &dir2] {
+///     fs::create_dir_all(dir)?;
+/// }
+/// for i in 0..100 {
+///     fs::write(dir1.join(i.to_string()), "")?;
+/// }
+///
+/// let stream = DiffStream::new(dir1, dir2, 4, DiffOptions::default());
+/// // The stream is dropped after the first few diffs, which stops the comparison.
+/// for diff in stream.take(3) {
+///     assert!(matches!(diff?, Diff::InDir1Only(..)));
+/// }
+/// # fs::remove_dir_all(root)?;
+/// # Ok::<(), anyhow::Error>(())
+/// ```
+pub struct DiffStream {
+    /// Only `None` while being dropped.
+    receiver: Option<Receiver<anyhow::Result<Diff>>>,
+    abort: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl DiffStream {
+    /// Starts diffing `dir1` and `dir2` using `n_threads` workers.
+    pub fn new(dir1: PathBuf, dir2: PathBuf, n_threads: u16, options: DiffOptions) -> Self {
+        let (sender, receiver) = sync_channel(CHANNEL_CAPACITY);
+        let abort = Arc::new(AtomicBool::new(false));
+        let handler = Arc::new(StreamHandler {
+            sender: sender.clone(),
+            abort: abort.clone(),
+        });
+        let worker_abort = abort.clone();
+        let thread = thread::spawn(move || {
+            let fs = Arc::new(RealFs);
+            if let Err(e) =
+                diff_dirs_until(fs, dir1, dir2, handler, n_threads, &options, worker_abort)
+            {
+                // Fails only if the stream was dropped.
+                let _ = sender.send(Err(e));
+            }
+        });
+        Self {
+            receiver: Some(receiver),
+            abort,
+            thread: Some(thread),
+        }
+    }
+}
+
+impl Iterator for DiffStream {
+    type Item = anyhow::Result<Diff>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self.receiver.as_ref()?.recv() {
+            Ok(item) => Some(item),
+            Err(_) => {
+                // All the senders are gone, so the comparison is over.
+                if let Some(thread) = self.thread.take() {
+                    if let Err(panic) = thread.join() {
+                        std::panic::resume_unwind(panic);
+                    }
+                }
+                None
+            }
+        }
+    }
+}
+
+impl Drop for DiffStream {
+    fn drop(&mut self) {
+        self.abort.store(true, Ordering::SeqCst);
+        // Unblocks the workers waiting for room in the channel.
+        self.receiver = None;
+        if let Some(thread) = self.thread.take() {
+            // Panics of the workers are only propagated while iterating.
+            let _ = thread.join();
+        }
+    }
+}
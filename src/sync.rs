@@ -0,0 +1,337 @@
+//! Actions making the second directory match the first one, for `--emit-sync-script` and
+//! `--apply`.
+//!
+//! Each diff is mapped to at most one action, on the full paths of the entry in both roots.
+//! Actions are only written out, unless they are applied once the comparison is over.
+
+use crate::json;
+use dirdiff::Diff;
+use std::{
+    fs::{self, File},
+    io,
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/// Start of the shell scripts, before the actions of all the pairs.
+pub const SH_HEADER: &str = "#!/bin/sh
+# Makes the second directory match the first one, as generated by dirdiff.
+# Review it before running it: it removes the entries present in the second directory only.
+set -e
+";
+
+/// What to do with an entry of the second directory.
+#[derive(Debug)]
+pub enum Action {
+    /// Copy an entry of the first directory, missing from the second one.
+    Copy { from: PathBuf, to: PathBuf },
+    /// Remove an entry of the second directory, then copy the one of the first directory.
+    Replace { from: PathBuf, to: PathBuf },
+    /// Remove an entry missing from the first directory, recursively.
+    Remove(PathBuf),
+    /// Set the mtime of the entry of the second directory to the one of the first directory.
+    Touch { from: PathBuf, to: PathBuf },
+    /// A diff no action fixes, like different owners.
+    Skip { kind: &'static str, path: PathBuf },
+}
+
+impl Action {
+    /// Action fixing `diff`, if it needs any.
+    pub fn new(root1: &Path, root2: &Path, diff: &Diff) -> Option<Self> {
+        let path = diff.path();
+        let (from, to) = (root1.join(&path), root2.join(&path));
+        Some(match diff {
+            Diff::InDir1Only(..) => Action::Copy { from, to },
+            // Replaced rather than overwritten, so that a symlink to a directory is not
+            // copied into.
+            Diff::Different(..)
+            | Diff::DifferentMTime(..)
+            | Diff::TypeMismatch(..)
+            | Diff::NotCompared(..)
+            | Diff::CompareTimedOut(..)
+            | Diff::NewerInFirst(..)
+            | Diff::LinkTargetsDiffer(..)
+            | Diff::ContentTypesDiffer(..) => Action::Replace { from, to },
+            Diff::InDir2Only(..) => Action::Remove(to),
+            Diff::SameButDifferentMTime(..) => Action::Touch { from, to },
+            Diff::ResolvedLinks(..) => return None,
+            // The files are already replaced as different.
+            Diff::SuspiciousMtimeMatch(..) => return None,
+            // Both files are already copied or removed as present in one dir. only.
+            Diff::ContentMovedOrRenamed(..) => return None,
+            _ => Action::Skip {
+                kind: diff.kind(),
+                path: to,
+            },
+        })
+    }
+
+    /// Whether the action removes an entry without replacing it.
+    pub fn is_removal(&self) -> bool {
+        matches!(self, Action::Remove(_))
+    }
+
+    /// Path of the entry of the second directory the action applies to.
+    pub fn target(&self) -> &Path {
+        match self {
+            Action::Copy { to, .. }
+            | Action::Replace { to, .. }
+            | Action::Touch { to, .. }
+            | Action::Remove(to)
+            | Action::Skip { path: to, .. } => to,
+        }
+    }
+
+    /// Runs the action, unless it is a removal without `delete`, or a skip.
+    ///
+    /// Returns whether the action was run.
+    pub fn apply(&self, opts: &ApplyOpts) -> io::Result<bool> {
+        match self {
+            Action::Copy { from, to } => copy(from, to, opts)?,
+            Action::Replace { from, to } => {
+                remove(to)?;
+                copy(from, to, opts)?;
+            }
+            Action::Remove(path) if opts.delete => remove(path)?,
+            Action::Touch { from, to } => copy_mtime(&fs::symlink_metadata(from)?, to)?,
+            Action::Remove(_) | Action::Skip { .. } => return Ok(false),
+        }
+        Ok(true)
+    }
+
+    /// The action as a line of shell script.
+    pub fn sh(&self) -> Vec<u8> {
+        let mut line = Vec::new();
+        let mut push = |parts: &[&[u8]]| {
+            for (i, part) in parts.iter().enumerate() {
+                if i > 0 {
+                    line.push(b' ');
+                }
+                line.extend_from_slice(part);
+            }
+        };
+        match self {
+            Action::Copy { from, to } => push(&[b"cp -pPR --", &quote(from), &quote(to)]),
+            Action::Replace { from, to } => push(&[
+                b"rm -rf --",
+                &quote(to),
+                b"&& cp -pPR --",
+                &quote(from),
+                &quote(to),
+            ]),
+            Action::Remove(path) => push(&[b"rm -rf --", &quote(path)]),
+            // Without following symlinks, only touched under `-L`, rather than changing the mtime
+            // of what they point to, which may be out of the tree.
+            Action::Touch { from, to } => push(&[b"touch -h -r", &quote(from), b"--", &quote(to)]),
+            Action::Skip { kind, path } => {
+                // Escaped rather than quoted, as a newline in the path would end the comment.
+                let path = format!("{:?}", path);
+                push(&[b"# Not synced,", kind.as_bytes(), path.as_bytes()])
+            }
+        }
+        line.push(b'\n');
+        line
+    }
+
+    /// The action as a JSON object, with the field `action` and the paths it applies to.
+    pub fn json(&self) -> String {
+        let path = |p: &Path| json::string(&p.to_string_lossy());
+        let fields = match self {
+            Action::Copy { from, to } => {
+                format!("\"copy\",\"from\":{},\"to\":{}", path(from), path(to))
+            }
+            Action::Replace { from, to } => {
+                format!("\"replace\",\"from\":{},\"to\":{}", path(from), path(to))
+            }
+            Action::Remove(p) => format!("\"remove\",\"path\":{}", path(p)),
+            Action::Touch { from, to } => {
+                format!("\"touch\",\"from\":{},\"to\":{}", path(from), path(to))
+            }
+            Action::Skip { kind, path: p } => {
+                format!(
+                    "\"skip\",\"kind\":{},\"path\":{}",
+                    json::string(kind),
+                    path(p)
+                )
+            }
+        };
+        format!("{{\"action\":{}}}\n", fields)
+    }
+}
+
+/// Options of [`Action::apply`].
+#[derive(Debug, Clone, Copy)]
+pub struct ApplyOpts {
+    /// Remove the entries present in the second directory only.
+    pub delete: bool,
+    /// Copy the permissions of directories, those of files are always copied.
+    pub perms: bool,
+    /// Copy the mtime of files.
+    pub mtime: bool,
+    /// Copy the mtime of directories.
+    pub dir_mtime: bool,
+}
+
+/// Copies `from` to `to`, which must not exist, recursively for directories.
+///
+/// Symlinks are copied as symlinks, and the metadata are copied as told by `opts`.
+fn copy(from: &Path, to: &Path, opts: &ApplyOpts) -> io::Result<()> {
+    let meta = fs::symlink_metadata(from)?;
+    let file_type = meta.file_type();
+    if file_type.is_symlink() {
+        copy_symlink(from, to)?;
+        if opts.mtime {
+            copy_mtime(&meta, to)?;
+        }
+        return Ok(());
+    }
+    if file_type.is_dir() {
+        fs::create_dir(to)?;
+        for entry in fs::read_dir(from)? {
+            let name = entry?.file_name();
+            copy(&from.join(&name), &to.join(&name), opts)?;
+        }
+        // Only once the entries are copied, which would change the mtime, and might not be
+        // writable anymore.
+        if opts.perms {
+            fs::set_permissions(to, meta.permissions())?;
+        }
+        if opts.dir_mtime {
+            copy_mtime(&meta, to)?;
+        }
+        return Ok(());
+    }
+    if !file_type.is_file() {
+        return Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            format!(
+                "{} is neither a file, a directory nor a symlink",
+                from.display()
+            ),
+        ));
+    }
+    fs::copy(from, to)?;
+    if opts.mtime {
+        copy_mtime(&meta, to)?;
+    }
+    Ok(())
+}
+
+/// Sets the mtime of `to` to the one of `meta`, the one of the symlink `to` itself if `meta`
+/// is the metadata of a symlink.
+fn copy_mtime(meta: &fs::Metadata, to: &Path) -> io::Result<()> {
+    if meta.file_type().is_symlink() {
+        // Opening it would follow the symlink.
+        return set_symlink_mtime(to, meta.modified()?);
+    }
+    File::open(to)?.set_modified(meta.modified()?)
+}
+
+/// Sets the mtime of the symlink `path`, without following it.
+#[cfg(unix)]
+fn set_symlink_mtime(path: &Path, mtime: SystemTime) -> io::Result<()> {
+    use std::{ffi::CString, os::unix::ffi::OsStrExt};
+
+    let (sec, nsec) = match mtime.duration_since(UNIX_EPOCH) {
+        Ok(since) => (since.as_secs() as i64, since.subsec_nanos()),
+        // Before the epoch, the nanoseconds still count forward from the seconds.
+        Err(e) => match e.duration() {
+            before if before.subsec_nanos() == 0 => (-(before.as_secs() as i64), 0),
+            before => (
+                -(before.as_secs() as i64) - 1,
+                1_000_000_000 - before.subsec_nanos(),
+            ),
+        },
+    };
+    let times = [
+        libc::timespec {
+            tv_sec: 0,
+            tv_nsec: libc::UTIME_OMIT,
+        },
+        libc::timespec {
+            tv_sec: sec as libc::time_t,
+            tv_nsec: nsec as _,
+        },
+    ];
+    let path = CString::new(path.as_os_str().as_bytes())?;
+    // SAFETY: `path` is a C string and `times` holds the two times `utimensat` reads.
+    let result = unsafe {
+        libc::utimensat(
+            libc::AT_FDCWD,
+            path.as_ptr(),
+            times.as_ptr(),
+            libc::AT_SYMLINK_NOFOLLOW,
+        )
+    };
+    if result < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn set_symlink_mtime(path: &Path, _mtime: SystemTime) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        format!(
+            "Cannot set the mtime of {}, that of symlinks is only set on Unix",
+            path.display()
+        ),
+    ))
+}
+
+#[cfg(unix)]
+fn copy_symlink(from: &Path, to: &Path) -> io::Result<()> {
+    std::os::unix::fs::symlink(fs::read_link(from)?, to)
+}
+
+#[cfg(not(unix))]
+fn copy_symlink(from: &Path, _to: &Path) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        format!(
+            "Cannot copy {}, symlinks are only copied on Unix",
+            from.display()
+        ),
+    ))
+}
+
+/// Removes `path`, recursively for directories, without following symlinks.
+fn remove(path: &Path) -> io::Result<()> {
+    if fs::symlink_metadata(path)?.is_dir() {
+        fs::remove_dir_all(path)
+    } else {
+        fs::remove_file(path)
+    }
+}
+
+/// Quotes `path` for a POSIX shell, between single quotes.
+///
+/// A path starting with `-` is not taken for an option, as the commands end theirs with `--`,
+/// and `touch -r` takes the next argument whatever it is.
+fn quote(path: &Path) -> Vec<u8> {
+    let mut quoted = vec![b'\''];
+    for &b in bytes(path).iter() {
+        if b == b'\'' {
+            quoted.extend_from_slice(b"'\\''");
+        } else {
+            quoted.push(b);
+        }
+    }
+    quoted.push(b'\'');
+    quoted
+}
+
+#[cfg(unix)]
+fn bytes(path: &Path) -> std::borrow::Cow<'_, [u8]> {
+    use std::os::unix::ffi::OsStrExt;
+    path.as_os_str().as_bytes().into()
+}
+
+#[cfg(not(unix))]
+fn bytes(path: &Path) -> std::borrow::Cow<'_, [u8]> {
+    match path.to_string_lossy() {
+        std::borrow::Cow::Borrowed(s) => s.as_bytes().into(),
+        std::borrow::Cow::Owned(s) => s.into_bytes().into(),
+    }
+}
@@ -0,0 +1,65 @@
+//! Forwarding diffs to several handlers at once.
+
+use crate::{Diff, DiffHandler};
+use std::{io, path::Path, sync::Arc};
+
+/// Passes each diff to all the inner handlers, in order.
+///
+/// The diff is cloned for all of them but the last one, which gets it as is. Each handler is
+/// called in turn, from the thread that found the diff, so that a slow handler holds up the
+/// others. The calls of [`DiffHandler::finish`] go on even once one fails, and the first error
+/// is returned.
+///
+/// # Example
+///
+/// ```no_run
+/// use dirdiff::{diff_dirs, ChannelHandler, DiffOptions, TeeHandler};
+/// use std::sync::{mpsc::channel, Arc};
+///
+/// let (sender1, receiver1) = channel();
+/// let (sender2, receiver2) = channel();
+/// let handler = TeeHandler::new(vec![
+///     Arc::new(ChannelHandler::new(sender1)),
+///     Arc::new(ChannelHandler::new(sender2)),
+/// ]);
+/// diff_dirs("dir1".into(), "dir2".into(), Arc::new(handler), 4, &DiffOptions::default())?;
+/// assert_eq!(receiver1.iter().count(), receiver2.iter().count());
+/// # Ok::<(), anyhow::Error>(())
+/// ```
+pub struct TeeHandler {
+    handlers: Vec<Arc<dyn DiffHandler + Send + Sync>>,
+}
+
+impl TeeHandler {
+    pub fn new(handlers: Vec<Arc<dyn DiffHandler + Send + Sync>>) -> Self {
+        Self { handlers }
+    }
+}
+
+impl DiffHandler for TeeHandler {
+    fn process(&self, root1: &Path, root2: &Path, diff: Diff) {
+        if let Some((last, others)) = self.handlers.split_last() {
+            for handler in others {
+                handler.process(root1, root2, diff.clone());
+            }
+            last.process(root1, root2, diff);
+        }
+    }
+
+    fn structure_done(&self) {
+        for handler in &self.handlers {
+            handler.structure_done();
+        }
+    }
+
+    fn finish(&self) -> io::Result<()> {
+        let mut result = Ok(());
+        for handler in &self.handlers {
+            let finished = handler.finish();
+            if result.is_ok() {
+                result = finished;
+            }
+        }
+        result
+    }
+}
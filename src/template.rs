@@ -0,0 +1,133 @@
+//! Templates describing the output records, for `--format-template`.
+//!
+//! A template is a string in which the following placeholders are replaced, for each diff:
+//!
+//! - `{kind}`: the kind of diff, like `Files differ`,
+//! - `{path}`: the path of the entry, as it would be printed without a template,
+//! - `{fullpath1}` and `{fullpath2}`: the path of the entry joined with the first and second root,
+//! - `{size1}` and `{size2}`: the size of the entry in the first and second directory,
+//!   or nothing if it is not there,
+//! - `{id}`: a hash of the path of the entry relative to the roots, the same in every run, as
+//!   with `--stable-ids`.
+//!
+//! The escape sequences `\t`, `\n`, `\0` and `\\` stand for a tab, a newline, a NUL byte and a backslash,
+//! and `{{` and `}}` for literal braces. Each record is followed by a newline, unless the template ends
+//! with `\0`. Paths which are not valid UTF-8 are written as set by `--non-utf8`.
+
+use crate::fingerprint::path_id;
+use anyhow::bail;
+use std::{fs, path::Path};
+
+#[derive(Debug)]
+enum Piece {
+    Literal(String),
+    Kind,
+    Path,
+    FullPath1,
+    FullPath2,
+    Size1,
+    Size2,
+    Id,
+}
+
+#[derive(Debug)]
+pub struct Template {
+    pieces: Vec<Piece>,
+}
+
+impl Template {
+    /// Parses a template, failing on unknown placeholders and escape sequences.
+    pub fn parse(template: &str) -> anyhow::Result<Self> {
+        let mut pieces = Vec::new();
+        let mut literal = String::new();
+        let mut chars = template.chars();
+        while let Some(c) = chars.next() {
+            match c {
+                '\\' => literal.push(match chars.next() {
+                    Some('t') => '\t',
+                    Some('n') => '\n',
+                    Some('0') => '\0',
+                    Some('\\') => '\\',
+                    Some(c) => bail!("Unknown escape sequence `\\{}` in template", c),
+                    None => bail!("Template ends with an unfinished escape sequence"),
+                }),
+                '{' if chars.as_str().starts_with('{') => {
+                    chars.next();
+                    literal.push('{');
+                }
+                '{' => {
+                    let rest = chars.as_str();
+                    let end = match rest.find('}') {
+                        Some(end) => end,
+                        None => bail!("Unclosed placeholder in template"),
+                    };
+                    let piece = match &rest[..end] {
+                        "kind" => Piece::Kind,
+                        "path" => Piece::Path,
+                        "fullpath1" => Piece::FullPath1,
+                        "fullpath2" => Piece::FullPath2,
+                        "size1" => Piece::Size1,
+                        "size2" => Piece::Size2,
+                        "id" => Piece::Id,
+                        name => bail!(
+                            "Unknown placeholder `{{{}}}` in template, expected one of \
+                            {{kind}}, {{path}}, {{fullpath1}}, {{fullpath2}}, {{size1}}, {{size2}}, \
+                            {{id}}",
+                            name
+                        ),
+                    };
+                    if !literal.is_empty() {
+                        pieces.push(Piece::Literal(std::mem::take(&mut literal)));
+                    }
+                    pieces.push(piece);
+                    chars = rest[end + 1..].chars();
+                }
+                '}' if chars.as_str().starts_with('}') => {
+                    chars.next();
+                    literal.push('}');
+                }
+                '}' => bail!("Unmatched `}}` in template, use `}}}}` for a literal brace"),
+                c => literal.push(c),
+            }
+        }
+        if !literal.ends_with('\0') {
+            literal.push('\n');
+        }
+        pieces.push(Piece::Literal(literal));
+        Ok(Self { pieces })
+    }
+
+    /// Renders the record of a diff of kind `kind` about `path`, which is relative to the roots,
+    /// writing the paths as returned by `text`.
+    ///
+    /// `printed_path` is the path as it would be printed without a template.
+    pub fn render(
+        &self,
+        kind: &str,
+        path: &Path,
+        printed_path: &Path,
+        root1: &Path,
+        root2: &Path,
+        text: impl Fn(&Path) -> Vec<u8>,
+    ) -> Vec<u8> {
+        let size = |root: &Path| {
+            fs::symlink_metadata(root.join(path))
+                .map(|m| m.len().to_string())
+                .unwrap_or_default()
+        };
+        let mut record = Vec::new();
+        for piece in &self.pieces {
+            match piece {
+                Piece::Literal(s) => record.extend_from_slice(s.as_bytes()),
+                Piece::Kind => record.extend_from_slice(kind.as_bytes()),
+                Piece::Path => record.extend(text(printed_path)),
+                Piece::FullPath1 => record.extend(text(&root1.join(path))),
+                Piece::FullPath2 => record.extend(text(&root2.join(path))),
+                Piece::Size1 => record.extend_from_slice(size(root1).as_bytes()),
+                Piece::Size2 => record.extend_from_slice(size(root2).as_bytes()),
+                Piece::Id => record.extend_from_slice(path_id(path).as_bytes()),
+            }
+        }
+        record
+    }
+}
@@ -0,0 +1,58 @@
+//! Reading of extended attributes.
+//!
+//! Extended attributes are only read on Linux. Elsewhere, files are considered not to have any.
+
+use std::{io, path::Path};
+
+/// Returns the value of the extended attribute `name` of a file, following symlinks.
+///
+/// Returns `None` if the file has no such attribute, or if its filesystem does not support them.
+#[cfg(target_os = "linux")]
+pub fn get(path: &Path, name: &str) -> io::Result<Option<Vec<u8>>> {
+    use std::{ffi::CString, os::unix::ffi::OsStrExt};
+
+    let path = CString::new(path.as_os_str().as_bytes())?;
+    let name = CString::new(name)?;
+    loop {
+        // SAFETY: both strings are valid and NUL-terminated, and a null buffer of size 0
+        // only queries the size of the value.
+        let size = unsafe { libc::getxattr(path.as_ptr(), name.as_ptr(), std::ptr::null_mut(), 0) };
+        if size < 0 {
+            return missing(io::Error::last_os_error());
+        }
+        let mut value = vec![0u8; size as usize];
+        // SAFETY: `value` is valid for writes of `value.len()` bytes.
+        let size = unsafe {
+            libc::getxattr(
+                path.as_ptr(),
+                name.as_ptr(),
+                value.as_mut_ptr() as *mut libc::c_void,
+                value.len(),
+            )
+        };
+        if size < 0 {
+            let e = io::Error::last_os_error();
+            if e.raw_os_error() == Some(libc::ERANGE) {
+                // The value grew since its size was queried.
+                continue;
+            }
+            return missing(e);
+        }
+        value.truncate(size as usize);
+        return Ok(Some(value));
+    }
+}
+
+/// Turns the errors meaning that there is no value into `None`.
+#[cfg(target_os = "linux")]
+fn missing(e: io::Error) -> io::Result<Option<Vec<u8>>> {
+    match e.raw_os_error() {
+        Some(libc::ENODATA) | Some(libc::ENOTSUP) => Ok(None),
+        _ => Err(e),
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn get(_path: &Path, _name: &str) -> io::Result<Option<Vec<u8>>> {
+    Ok(None)
+}
@@ -0,0 +1,76 @@
+//! Diffs sent to channels by [`ChannelHandler`].
+
+use crate::fixture::TempDir;
+use dirdiff::{diff_dirs, ChannelHandler, Diff, DiffOptions};
+use std::{
+    path::PathBuf,
+    sync::{
+        mpsc::{channel, sync_channel},
+        Arc,
+    },
+    thread,
+};
+
+fn trees(name: &str) -> (TempDir, TempDir) {
+    let (tree1, tree2) = (
+        TempDir::new(&format!("{name}1")),
+        TempDir::new(&format!("{name}2")),
+    );
+    tree1.write("f", "1");
+    tree2.write("f", "2");
+    tree1.write("sub/only1", "");
+    tree2.write("sub/only2", "");
+    (tree1, tree2)
+}
+
+/// Kinds and paths of the diffs, sorted as the workers send them in any order.
+fn sorted(diffs: Vec<Diff>) -> Vec<(&'static str, PathBuf)> {
+    let mut diffs: Vec<_> = diffs
+        .iter()
+        .map(|diff| (diff.kind(), diff.path()))
+        .collect();
+    diffs.sort();
+    diffs
+}
+
+fn expected() -> Vec<(&'static str, PathBuf)> {
+    vec![
+        ("different", "f".into()),
+        ("only1", ["sub", "only1"].iter().collect()),
+        ("only2", ["sub", "only2"].iter().collect()),
+    ]
+}
+
+#[test]
+fn unbounded() {
+    let (tree1, tree2) = trees("unbounded");
+    let (sender, receiver) = channel();
+    let handler = Arc::new(ChannelHandler::new(sender));
+    diff_dirs(
+        tree1.path().into(),
+        tree2.path().into(),
+        handler,
+        4,
+        &DiffOptions::default(),
+    )
+    .unwrap();
+    assert_eq!(sorted(receiver.iter().collect()), expected());
+}
+
+/// A channel smaller than the number of diffs, so that the workers wait for the receiver.
+#[test]
+fn bounded() {
+    let (tree1, tree2) = trees("bounded");
+    let (sender, receiver) = sync_channel(1);
+    let consumer = thread::spawn(move || receiver.iter().collect::<Vec<_>>());
+    let handler = Arc::new(ChannelHandler::bounded(sender));
+    diff_dirs(
+        tree1.path().into(),
+        tree2.path().into(),
+        handler,
+        4,
+        &DiffOptions::default(),
+    )
+    .unwrap();
+    assert_eq!(sorted(consumer.join().unwrap()), expected());
+}
@@ -0,0 +1,120 @@
+//! Tests of the options of the `dirdiff` binary, run over trees in a temporary directory.
+
+use crate::fixture::TempDir;
+use std::{
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+mod content;
+mod metadata;
+mod output;
+mod sources;
+mod sync;
+mod traversal;
+
+/// Two trees to compare, `a` and `b`, in a temporary directory the binary is run from, so that
+/// the paths it prints are relative to it.
+pub struct Trees(TempDir);
+
+impl Trees {
+    pub fn new(name: &str) -> Self {
+        let dir = TempDir::new(name);
+        for root in ["a", "b"] {
+            std::fs::create_dir(dir.path().join(root)).unwrap();
+        }
+        Self(dir)
+    }
+
+    pub fn path(&self) -> &Path {
+        self.0.path()
+    }
+
+    /// Writes `contents` to the file at `path`, relative to the temporary directory, like
+    /// `a/file`.
+    pub fn write(&self, path: &str, contents: impl AsRef<[u8]>) -> PathBuf {
+        self.0.write(path, contents)
+    }
+
+    /// Runs `dirdiff` with `args`, then the trees.
+    pub fn diff(&self, args: &[&str]) -> Run {
+        let mut all = args.to_vec();
+        all.extend(["a", "b"]);
+        self.run(&all)
+    }
+
+    /// Runs `dirdiff` with `args` only.
+    pub fn run(&self, args: &[&str]) -> Run {
+        self.run_with_env(&[], args)
+    }
+
+    /// Runs `dirdiff` with `args` only, and the variables of `env` set.
+    pub fn run_with_env(&self, env: &[(&str, &str)], args: &[&str]) -> Run {
+        let output = Command::new(env!("CARGO_BIN_EXE_dirdiff"))
+            .args(args)
+            .envs(env.iter().copied())
+            .current_dir(self.path())
+            // Set by the environment the tests run in, they would change the defaults.
+            .env_remove("DIRDIFF_JOBS")
+            .env_remove("DIRDIFF_FORMAT")
+            .env_remove("DIRDIFF_RETRIES")
+            .output()
+            .unwrap();
+        Run {
+            code: output.status.code(),
+            stdout: String::from_utf8_lossy(&output.stdout).into_owned(),
+            stderr: String::from_utf8_lossy(&output.stderr).into_owned(),
+        }
+    }
+}
+
+/// Outcome of a run of `dirdiff`.
+#[derive(Debug)]
+pub struct Run {
+    pub code: Option<i32>,
+    pub stdout: String,
+    pub stderr: String,
+}
+
+impl Run {
+    /// Lines of the output, sorted, as the diffs are printed in the order they are found.
+    pub fn lines(&self) -> Vec<&str> {
+        let mut lines: Vec<_> = self.stdout.lines().collect();
+        lines.sort_unstable();
+        lines
+    }
+
+    /// Fails unless the run exited with `code`.
+    #[track_caller]
+    pub fn assert_code(&self, code: i32) -> &Self {
+        assert_eq!(self.code, Some(code), "{self:#?}");
+        self
+    }
+
+    /// Fails unless the sorted lines of the output are `expected`, in any order.
+    #[track_caller]
+    pub fn assert_lines(&self, expected: &[&str]) -> &Self {
+        let mut expected = expected.to_vec();
+        expected.sort_unstable();
+        assert_eq!(self.lines(), expected, "{self:#?}");
+        self
+    }
+
+    /// Fails unless the output is made of the `expected` lines, in this order.
+    #[track_caller]
+    pub fn assert_stdout(&self, expected: &[&str]) -> &Self {
+        assert_eq!(
+            self.stdout.lines().collect::<Vec<_>>(),
+            expected,
+            "{self:#?}"
+        );
+        self
+    }
+
+    /// Fails unless the error output contains `message`.
+    #[track_caller]
+    pub fn assert_stderr(&self, message: &str) -> &Self {
+        assert!(self.stderr.contains(message), "{self:#?}");
+        self
+    }
+}
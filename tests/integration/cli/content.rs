@@ -0,0 +1,721 @@
+//! Options choosing how the content of regular files is compared.
+
+use super::Trees;
+
+#[test]
+fn count_only() {
+    let trees = Trees::new("count_only");
+    trees.write("a/f", "1");
+    trees.write("b/f", "2");
+    trees.write("a/sub/g", "xyz");
+    trees.write("b/sub/g", "xyw");
+    // Sizes differ, which needs no read.
+    trees.write("a/grown", "a");
+    trees.write("b/grown", "bb");
+    trees.write("a/only1", "");
+    trees.write("b/only2/h", "abc");
+    trees.diff(&["--count-only"]).assert_code(0).assert_lines(&[
+        "Bytes to read:\t8",
+        "Pairs of files to compare:\t3",
+        "Present in one dir. only:\t2",
+    ]);
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn sparse() {
+    use std::{
+        fs::File,
+        io::{Seek, SeekFrom, Write},
+    };
+    let trees = Trees::new("sparse");
+    let len = 1 << 20;
+    let mut full = vec![0; len];
+    full.extend(b"end");
+    trees.write("a/f", &full);
+    // The same content, with a hole instead of the zeros.
+    let mut sparse = File::create(trees.path().join("b/f")).unwrap();
+    sparse.set_len(len as u64).unwrap();
+    sparse.seek(SeekFrom::End(0)).unwrap();
+    sparse.write_all(b"end").unwrap();
+    trees.diff(&[]).assert_code(0);
+    trees
+        .diff(&["--sparse"])
+        .assert_code(1)
+        .assert_lines(&["[Differ by holes only]\t\"f\""]);
+}
+
+/// Empty files are equal without being opened, so even unreadable ones are.
+#[cfg(unix)]
+#[test]
+fn empty_files() {
+    use std::{fs, os::unix::fs::PermissionsExt};
+    let trees = Trees::new("empty_files");
+    for path in ["a/empty", "b/empty"] {
+        let path = trees.write(path, "");
+        fs::set_permissions(path, fs::Permissions::from_mode(0o000)).unwrap();
+    }
+    trees.diff(&[]).assert_code(0).assert_lines(&[]);
+}
+
+#[test]
+fn ignore_content_case() {
+    let trees = Trees::new("content_case");
+    trees.write("a/ascii", "Hello");
+    trees.write("b/ascii", "hELLO");
+    trees.write("a/unicode", "Été");
+    trees.write("b/unicode", "éTÉ");
+    // Bytes differing by the bit of case of ASCII letters, but which are not letters.
+    trees.write("a/binary", b"\x00@\xff");
+    trees.write("b/binary", b"\x00`\xff");
+    trees.diff(&[]).assert_lines(&[
+        "[Files differ]\t\"ascii\"",
+        "[Files differ]\t\"binary\"",
+        "[Files differ]\t\"unicode\"",
+    ]);
+    // Only ASCII letters are folded.
+    trees
+        .diff(&["--ignore-content-case"])
+        .assert_lines(&["[Files differ]\t\"binary\"", "[Files differ]\t\"unicode\""]);
+}
+
+#[cfg(unix)]
+#[test]
+fn data_only() {
+    use std::{fs, os::unix::fs::PermissionsExt, time::SystemTime};
+    let trees = Trees::new("data_only");
+    trees.write("a/f", "x");
+    let f2 = trees.write("b/f", "x");
+    let mtime = SystemTime::UNIX_EPOCH;
+    fs::File::options()
+        .write(true)
+        .open(&f2)
+        .unwrap()
+        .set_modified(mtime)
+        .unwrap();
+    fs::set_permissions(&f2, fs::Permissions::from_mode(0o600)).unwrap();
+    // The symlink is followed, and its target has the same content.
+    trees.write("a/linked", "x");
+    std::os::unix::fs::symlink("f", trees.path().join("b/linked")).unwrap();
+    trees.diff(&["--check-mtime", "--perms"]).assert_code(1);
+    trees
+        .diff(&["--data-only"])
+        .assert_code(0)
+        .assert_lines(&[]);
+    trees.diff(&["--data-only", "--check-mtime"]).assert_code(2);
+}
+
+#[test]
+fn names_only() {
+    let trees = Trees::new("names_only");
+    trees.write("a/f", "1");
+    trees.write("b/f", "22");
+    trees.write("a/sub/g", "x");
+    trees.write("b/sub/g", "y");
+    trees.write("a/only1", "");
+    trees
+        .diff(&["--names-only"])
+        .assert_code(1)
+        .assert_lines(&["[Present in first dir. only]\t\"only1\""]);
+    trees
+        .diff(&["--names-only", "--ignore-content-case"])
+        .assert_code(2);
+}
+
+#[cfg(unix)]
+#[test]
+fn compare_cmd() {
+    let trees = Trees::new("compare_cmd");
+    trees.write("a/differ", "1");
+    trees.write("b/differ", "2");
+    trees.write("a/same", "same");
+    trees.write("b/same", "same");
+    // Whatever their size.
+    trees.write("a/grown", "a");
+    trees.write("b/grown", "bb");
+    trees
+        .diff(&["--compare-cmd", "/bin/true"])
+        .assert_code(0)
+        .assert_lines(&[]);
+    trees
+        .diff(&["--compare-cmd", "/bin/false"])
+        .assert_code(1)
+        .assert_lines(&[
+            "[Files differ]\t\"differ\"",
+            "[Files differ]\t\"grown\"",
+            "[Files differ]\t\"same\"",
+        ]);
+    trees
+        .diff(&["--compare-cmd", "cmp -s %1 %2"])
+        .assert_lines(&["[Files differ]\t\"differ\"", "[Files differ]\t\"grown\""]);
+    trees.diff(&["--compare-cmd", "cmp %3"]).assert_code(2);
+}
+
+#[test]
+fn explain() {
+    let trees = Trees::new("explain");
+    trees.write("a/same", "abc");
+    trees.write("b/same", "abc");
+    trees.write("a/differ", "abcdef");
+    trees.write("b/differ", "abcxef");
+    trees.write("a/grown", "abc");
+    trees.write("b/grown", "abcd");
+    trees.write("a/empty", "");
+    trees.write("b/empty", "");
+    let run = trees.diff(&["--explain"]);
+    run.assert_code(1)
+        .assert_stderr("same: equal by full byte comparison\n")
+        .assert_stderr("differ: different by byte comparison, first at offset 3\n")
+        .assert_stderr("grown: different by size, 3 bytes against 4\n")
+        .assert_stderr("empty: equal by size, both empty\n");
+    // Nothing is explained unless asked to.
+    assert_eq!(trees.diff(&[]).stderr, "");
+}
+
+/// Gzips `content` at `level`, from 0 to 9.
+fn gzip(content: &[u8], level: u32) -> Vec<u8> {
+    use flate2::{write::GzEncoder, Compression};
+    use std::io::Write;
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::new(level));
+    encoder.write_all(content).unwrap();
+    encoder.finish().unwrap()
+}
+
+#[test]
+fn gzip_comparer() {
+    let trees = Trees::new("gzip_comparer");
+    let content = "some text, some text, some text\n".repeat(100);
+    let (fast, best) = (gzip(content.as_bytes(), 1), gzip(content.as_bytes(), 9));
+    assert_ne!(fast, best);
+    std::fs::write(trees.path().join("a/f.gz"), &fast).unwrap();
+    std::fs::write(trees.path().join("b/f.gz"), &best).unwrap();
+    std::fs::write(trees.path().join("a/other.GZ"), &fast).unwrap();
+    std::fs::write(trees.path().join("b/other.GZ"), gzip(b"other", 9)).unwrap();
+    // Malformed files are compared byte by byte.
+    trees.write("a/plain.gz", "not gzipped");
+    trees.write("b/plain.gz", "not gzipped");
+    trees
+        .diff(&[])
+        .assert_code(1)
+        .assert_lines(&["[Files differ]\t\"f.gz\"", "[Files differ]\t\"other.GZ\""]);
+    trees
+        .diff(&["--comparer", "gz=gzip"])
+        .assert_code(1)
+        .assert_lines(&["[Files differ]\t\"other.GZ\""]);
+    trees
+        .diff(&["--comparer", "gz=gzip", "--comparer", "gz=raw"])
+        .assert_code(1)
+        .assert_lines(&["[Files differ]\t\"f.gz\"", "[Files differ]\t\"other.GZ\""]);
+    trees
+        .diff(&["--comparer", "gz=zstd"])
+        .assert_code(2)
+        .assert_stderr("unknown strategy `zstd`");
+}
+
+#[test]
+fn sha256_comparer() {
+    let trees = Trees::new("sha256_comparer");
+    trees.write("a/same.bin", "same content");
+    trees.write("b/same.bin", "same content");
+    trees.write("a/modified.bin", "some content");
+    trees.write("b/modified.bin", "some contenT");
+    trees.write("a/grown.bin", "content");
+    trees.write("b/grown.bin", "content, and more");
+    trees
+        .diff(&["--comparer", "bin=sha256"])
+        .assert_code(1)
+        .assert_lines(&[
+            "[Files differ]\t\"grown.bin\"",
+            "[Files differ]\t\"modified.bin\"",
+        ]);
+    trees
+        .diff(&["--comparer", "bin=sha256", "--ignore-content-case"])
+        .assert_code(1)
+        .assert_lines(&["[Files differ]\t\"grown.bin\""]);
+}
+
+#[test]
+fn byte_budget() {
+    let trees = Trees::new("byte_budget");
+    for name in ["f1", "f2", "f3"] {
+        trees.write(&format!("a/{name}"), "abcd");
+        trees.write(&format!("b/{name}"), "abcd");
+    }
+    // Sizes differ, which needs no read.
+    trees.write("a/grown", "a");
+    trees.write("b/grown", "bb");
+    trees
+        .diff(&["--byte-budget", "24"])
+        .assert_code(1)
+        .assert_lines(&["[Files differ]\t\"grown\""]);
+    // Both files of a pair count: the budget covers two pairs of the three.
+    let run = trees.diff(&["--byte-budget", "16"]);
+    run.assert_code(1);
+    let not_compared: Vec<_> = run
+        .lines()
+        .into_iter()
+        .filter(|line| line.starts_with("[Not compared]\t"))
+        .collect();
+    assert_eq!(not_compared.len(), 1, "{run:#?}");
+    assert_eq!(run.lines().len(), 2, "{run:#?}");
+    trees
+        .diff(&["--byte-budget", "0"])
+        .assert_code(1)
+        .assert_lines(&[
+            "[Files differ]\t\"grown\"",
+            "[Not compared]\t\"f1\"",
+            "[Not compared]\t\"f2\"",
+            "[Not compared]\t\"f3\"",
+        ]);
+}
+
+/// A pair the budget runs out in the middle of is not compared, rather than different.
+#[test]
+fn byte_budget_midway() {
+    let trees = Trees::new("byte_budget_midway");
+    // Read in two chunks, the second one holding the difference.
+    let size = 10 * 1024;
+    trees.write("a/f", "x".repeat(size));
+    trees.write("b/f", "x".repeat(size - 1) + "y");
+    trees
+        .diff(&["--byte-budget", "20000"])
+        .assert_code(1)
+        .assert_lines(&["[Not compared]\t\"f\""]);
+    trees
+        .diff(&["--byte-budget", &(2 * size).to_string()])
+        .assert_code(1)
+        .assert_lines(&["[Files differ]\t\"f\""]);
+}
+
+#[test]
+fn two_pass() {
+    let trees = Trees::new("two_pass");
+    trees.write("a/f", "1");
+    trees.write("b/f", "2");
+    trees.write("a/sub/g", "x");
+    trees.write("b/sub/g", "y");
+    trees.write("a/only1", "");
+    // Sizes differ, so this is found by the first pass.
+    trees.write("a/grown", "a");
+    trees.write("b/grown", "bb");
+    let run = trees.diff(&["--two-pass", "-j", "1"]);
+    run.assert_code(1);
+    let lines: Vec<_> = run.stdout.lines().collect();
+    let structure = lines
+        .iter()
+        .position(|&line| line == "[Structure compared]")
+        .unwrap();
+    let mut first = lines[..structure].to_vec();
+    first.sort_unstable();
+    assert_eq!(
+        first,
+        [
+            "[Files differ]\t\"grown\"",
+            "[Present in first dir. only]\t\"only1\""
+        ]
+    );
+    let mut second = lines[structure + 1..].to_vec();
+    second.sort_unstable();
+    assert_eq!(
+        second,
+        ["[Files differ]\t\"f\"", "[Files differ]\t\"sub/g\""]
+    );
+    let run = trees.diff(&["--two-pass", "--format", "json", "--no-summary"]);
+    assert_eq!(
+        run.stdout
+            .lines()
+            .position(|line| line == r#"{"kind":"structure_done"}"#),
+        Some(2),
+        "{run:#?}"
+    );
+}
+
+#[test]
+fn sort_lines() {
+    let trees = Trees::new("sort_lines");
+    trees.write("a/reordered", "b\na\nc\n");
+    trees.write("b/reordered", "c\nb\na\n");
+    // The last line is the same with or without a newline.
+    trees.write("a/last", "a\nb\n");
+    trees.write("b/last", "b\na");
+    trees.write("a/changed", "a\nb\n");
+    trees.write("b/changed", "b\nc\n");
+    // Lines are not deduplicated.
+    trees.write("a/repeated", "a\na\nb\n");
+    trees.write("b/repeated", "a\nb\nb\n");
+    trees.diff(&[]).assert_code(1).assert_lines(&[
+        "[Files differ]\t\"changed\"",
+        "[Files differ]\t\"last\"",
+        "[Files differ]\t\"reordered\"",
+        "[Files differ]\t\"repeated\"",
+    ]);
+    trees.diff(&["--sort-lines"]).assert_code(1).assert_lines(&[
+        "[Files differ]\t\"changed\"",
+        "[Files differ]\t\"repeated\"",
+    ]);
+    // Larger files are compared byte by byte.
+    trees
+        .diff(&["--sort-lines", "--max-compare-size", "3"])
+        .assert_code(1)
+        .assert_lines(&[
+            "[Files differ]\t\"changed\"",
+            "[Files differ]\t\"last\"",
+            "[Files differ]\t\"reordered\"",
+            "[Files differ]\t\"repeated\"",
+        ]);
+}
+
+#[test]
+fn ignore_content_case_unicode() {
+    let trees = Trees::new("content_case_unicode");
+    trees.write("a/ascii", "Hello");
+    trees.write("b/ascii", "hELLO");
+    trees.write("a/unicode", "Été, Straße");
+    trees.write("b/unicode", "éTÉ, STRASSE");
+    trees.write("a/changed", "Été");
+    trees.write("b/changed", "Éta");
+    // Not UTF-8, compared byte by byte.
+    trees.write("a/latin1", b"\xe9t\xe9");
+    trees.write("b/latin1", b"\xe9T\xe9");
+    trees.write("a/same_latin1", b"\xe9");
+    trees.write("b/same_latin1", b"\xe9");
+    trees
+        .diff(&["--ignore-content-case-unicode"])
+        .assert_code(1)
+        .assert_lines(&["[Files differ]\t\"changed\"", "[Files differ]\t\"latin1\""])
+        .assert_stderr("latin1 is not valid UTF-8, compared byte by byte");
+    // Only counted.
+    let run = trees.diff(&["--ignore-content-case-unicode", "--quiet-errors"]);
+    run.assert_code(1)
+        .assert_stderr("2 files were not valid UTF-8, compared byte by byte");
+    assert!(!run.stderr.contains("latin1"), "{run:#?}");
+    // Larger files are compared byte by byte.
+    trees
+        .diff(&["--ignore-content-case-unicode", "--max-compare-size", "4"])
+        .assert_lines(&[
+            "[Files differ]\t\"ascii\"",
+            "[Files differ]\t\"changed\"",
+            "[Files differ]\t\"latin1\"",
+            "[Files differ]\t\"unicode\"",
+        ]);
+}
+
+#[test]
+fn ignore_empty() {
+    let trees = Trees::new("ignore_empty");
+    trees.write("a/.keep", "");
+    trees.write("b/.keep", "");
+    trees.write("a/placeholder", "");
+    trees.write("a/n", "");
+    trees.write("b/n", "x");
+    std::fs::create_dir(trees.path().join("a/empty")).unwrap();
+    trees.diff(&[]).assert_code(1).assert_lines(&[
+        "[Files differ]\t\"n\"",
+        "[Present in first dir. only]\t\"empty\"",
+        "[Present in first dir. only]\t\"placeholder\"",
+    ]);
+    // Empty directories are kept.
+    trees
+        .diff(&["--ignore-empty"])
+        .assert_code(1)
+        .assert_lines(&[
+            "[Present in first dir. only]\t\"empty\"",
+            "[Present in second dir. only]\t\"n\"",
+        ]);
+}
+
+#[cfg(unix)]
+#[test]
+fn trust_same_inode() {
+    let trees = Trees::new("trust_same_inode");
+    let file = trees.write("a/x", "abc");
+    std::fs::hard_link(file, trees.path().join("b/x")).unwrap();
+    trees.write("a/y", "abc");
+    trees.write("b/y", "abc");
+    let run = trees.diff(&["--trust-same-inode", "--explain", "--sorted"]);
+    run.assert_code(0);
+    assert_eq!(
+        run.stderr,
+        "x: equal by inode, the same file on both sides\ny: equal by full byte comparison\n"
+    );
+    // Without the option, the shared file is read.
+    let run = trees.diff(&["--explain", "--sorted"]);
+    assert_eq!(
+        run.stderr,
+        "x: equal by full byte comparison\ny: equal by full byte comparison\n"
+    );
+}
+
+#[test]
+fn print_hashes() {
+    const ABC: &str = "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad";
+    const ABD: &str = "a52d159f262b2c6ddb724a61840befc36eb30c88877a4030b65cbe86298449c9";
+    const ABCD: &str = "88d4266fd4e6338d13b845fcf289579d209c897823b9217da3e161936f031589";
+    let trees = Trees::new("print_hashes");
+    trees.write("a/same", "abc");
+    trees.write("b/same", "abc");
+    trees.write("a/sub/differ", "abc");
+    trees.write("b/sub/differ", "abd");
+    trees.write("a/grown", "abc");
+    trees.write("b/grown", "abcd");
+    trees.write("a/only1", "abc");
+    let same = format!("{ABC} {ABC} same");
+    let differ = format!("{ABC} {ABD} sub/differ");
+    let grown = format!("{ABC} {ABCD} grown");
+    // Before the other records of the files.
+    trees
+        .diff(&["--print-hashes", "--sorted"])
+        .assert_code(1)
+        .assert_stdout(&[
+            &grown,
+            "[Files differ]\t\"grown\"",
+            "[Present in first dir. only]\t\"only1\"",
+            &same,
+            &differ,
+            "[Files differ]\t\"sub/differ\"",
+        ]);
+    let run = trees.diff(&["--print-hashes", "--explain", "--sorted"]);
+    assert_eq!(
+        run.stderr.lines().collect::<Vec<_>>(),
+        [
+            "grown: different by SHA-256 hash",
+            "same: equal by SHA-256 hash",
+            "sub/differ: different by SHA-256 hash",
+        ]
+    );
+    // Not failures on their own.
+    let trees = Trees::new("print_hashes_same");
+    trees.write("a/same", "abc");
+    trees.write("b/same", "abc");
+    trees
+        .diff(&["--print-hashes"])
+        .assert_code(0)
+        .assert_lines(&[&same]);
+    let run = trees.diff(&["--print-hashes", "--format", "json"]);
+    run.assert_code(0);
+    let lines: Vec<_> = run.stdout.lines().collect();
+    assert_eq!(
+        lines[0],
+        format!(r#"{{"kind":"hashes","path":"same","hash1":"{ABC}","hash2":"{ABC}"}}"#)
+    );
+    assert!(lines[1].contains(r#""different":0,"#) && lines[1].contains(r#""hashes":1"#));
+    trees
+        .diff(&["--print-hashes", "--compare-cmd", "cmp -s %1 %2"])
+        .assert_code(2);
+}
+
+#[test]
+fn compare_threads() {
+    let trees = Trees::new("compare_threads");
+    // A few large files, among many directories of small ones.
+    let large = vec![b'x'; 4 << 20];
+    trees.write("a/large/same", &large);
+    trees.write("b/large/same", &large);
+    trees.write("a/large/differ", &large);
+    let mut changed = large.clone();
+    *changed.last_mut().unwrap() = b'y';
+    trees.write("b/large/differ", &changed);
+    for dir in 0..30 {
+        trees.write(&format!("a/{dir}/same"), "1");
+        trees.write(&format!("b/{dir}/same"), "1");
+        trees.write(&format!("a/{dir}/differ"), "1");
+        trees.write(&format!("b/{dir}/differ"), "2");
+    }
+    let expected = trees.diff(&["-j", "1"]);
+    expected.assert_code(1);
+    assert_eq!(expected.lines().len(), 31, "{expected:#?}");
+    for args in [
+        &["--compare-threads", "1", "-j", "1"][..],
+        &["--compare-threads", "4", "-j", "2"],
+        &["--compare-threads", "2", "--sorted"],
+    ] {
+        trees
+            .diff(args)
+            .assert_code(1)
+            .assert_lines(&expected.lines());
+    }
+}
+
+#[test]
+fn compare_prefix() {
+    let trees = Trees::new("compare_prefix");
+    let header = "#".repeat(4096);
+    trees.write("a/same_header", format!("{header}1"));
+    trees.write("b/same_header", format!("{header}2"));
+    trees.write("a/other_header", format!("1{header}"));
+    trees.write("b/other_header", format!("2{header}"));
+    trees.write("a/longer", header.clone());
+    trees.write("b/longer", format!("{header}1"));
+    trees.diff(&[]).assert_code(1).assert_lines(&[
+        "[Files differ]\t\"longer\"",
+        "[Files differ]\t\"other_header\"",
+        "[Files differ]\t\"same_header\"",
+    ]);
+    trees
+        .diff(&["--compare-prefix", "4K"])
+        .assert_code(1)
+        .assert_lines(&[
+            "[Files differ]\t\"longer\"",
+            "[Files differ]\t\"other_header\"",
+        ]);
+    trees
+        .diff(&["--compare-prefix", "4K", "--print-hashes"])
+        .assert_code(2);
+}
+
+#[test]
+fn split_retries() {
+    let trees = Trees::new("split_retries");
+    trees.write("a/f", "1");
+    trees.write("b/f", "2");
+    for args in [
+        &["--meta-retries", "1", "--content-retries", "4"][..],
+        &["--retries", "2", "--content-retries", "0"],
+    ] {
+        trees
+            .diff(args)
+            .assert_code(1)
+            .assert_lines(&["[Files differ]\t\"f\""]);
+    }
+    trees
+        .diff(&["--content-retries", "many"])
+        .assert_code(2)
+        .assert_stderr("--content-retries");
+}
+
+#[test]
+fn merkle_root() {
+    let trees = Trees::new("merkle_root");
+    // Written in different orders, so that their entries are not listed in the same order.
+    for i in 0..20 {
+        trees.write(&format!("a/d{}/f{i}", i % 3), format!("{i}"));
+    }
+    for i in (0..20).rev() {
+        trees.write(&format!("b/d{}/f{i}", i % 3), format!("{i}"));
+    }
+    let root = |dir: &str, jobs: &str| {
+        let run = trees.run(&["--merkle-root", "-j", jobs, dir]);
+        run.assert_code(0);
+        let root = run.stdout.trim_end().to_owned();
+        assert_eq!(root.len(), 64, "{run:#?}");
+        root
+    };
+    let root_a = root("a", "1");
+    assert_eq!(root("a", "4"), root_a);
+    assert_eq!(root("b", "3"), root_a);
+    // One byte changed.
+    trees.write("b/d1/f7", "8");
+    assert_ne!(root("b", "1"), root_a);
+    trees.write("b/d1/f7", "7");
+    assert_eq!(root("b", "1"), root_a);
+    // Names count, as do empty directories.
+    std::fs::rename(trees.path().join("b/d1/f7"), trees.path().join("b/d1/g7")).unwrap();
+    assert_ne!(root("b", "1"), root_a);
+    std::fs::rename(trees.path().join("b/d1/g7"), trees.path().join("b/d1/f7")).unwrap();
+    std::fs::create_dir(trees.path().join("b/empty")).unwrap();
+    assert_ne!(root("b", "1"), root_a);
+}
+
+/// The options comparing less than the default reject those checking more.
+#[test]
+fn less_than_checks() {
+    let trees = Trees::new("less_than_checks");
+    for less in ["--data-only", "--names-only", "--shallow"] {
+        for check in [
+            "--check-mtime",
+            "--perms",
+            "--acls",
+            "--check-caps",
+            "--sparse",
+        ] {
+            trees
+                .diff(&[less, check])
+                .assert_code(2)
+                .assert_stderr("cannot be used with");
+        }
+    }
+    trees.diff(&["--names-only", "--data-only"]).assert_code(2);
+    trees
+        .diff(&["--shallow", "--comparer", "gz=gzip"])
+        .assert_code(2);
+    trees
+        .diff(&["--data-only", "--ignore-symlinks"])
+        .assert_code(2);
+}
+
+#[test]
+fn count_diff_bytes() {
+    let trees = Trees::new("count_diff_bytes");
+    trees.write("a/one", "abcdef");
+    trees.write("b/one", "abcxef");
+    trees.write("a/many", "a".repeat(100_000));
+    trees.write("b/many", "a".repeat(50_000) + &"b".repeat(50_000));
+    trees.write("a/len", "abc");
+    trees.write("b/len", "abxdef");
+    trees.write("a/same", "same");
+    trees.write("b/same", "same");
+    trees
+        .diff(&["--count-diff-bytes"])
+        .assert_code(1)
+        .assert_lines(&[
+            "[Files differ]\t\"len\"\tin 4 bytes",
+            "[Files differ]\t\"many\"\tin 50000 bytes",
+            "[Files differ]\t\"one\"\tin 1 bytes",
+        ]);
+    let run = trees.diff(&["--count-diff-bytes", "--format", "json", "--no-summary"]);
+    assert!(run.stdout.contains("\"differing_bytes\":1}"), "{run:#?}");
+    trees
+        .diff(&["--count-diff-bytes", "--shallow"])
+        .assert_code(2)
+        .assert_stderr("cannot be used with");
+}
+
+#[test]
+fn content_type() {
+    let trees = Trees::new("content_type");
+    let png = b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR";
+    let mut other_png = png.to_vec();
+    other_png.push(1);
+    trees.write("a/same.png", png);
+    trees.write("b/same.png", png);
+    trees.write("a/changed.png", png);
+    trees.write("b/changed.png", &other_png);
+    // Whatever their extension.
+    trees.write("a/image.dat", png);
+    trees.write("b/image.dat", &other_png);
+    trees.write("a/notes.txt", "abc");
+    trees.write("b/notes.txt", "abd");
+    trees.write("a/replaced", png);
+    trees.write("b/replaced", "text");
+    trees.diff(&["--content-type", "image"]).assert_code(1).assert_lines(&[
+        "[Content types differ]\t\"replaced\"\tis image/png in first dir. while unknown in second dir.",
+        "[Files differ]\t\"changed.png\"",
+        "[Files differ]\t\"image.dat\"",
+    ]);
+    trees
+        .diff(&["--content-type", "image/jpeg"])
+        .assert_code(0)
+        .assert_lines(&[]);
+    trees
+        .diff(&["--content-type", "picture"])
+        .assert_code(2)
+        .assert_stderr("Unknown content type `picture`");
+}
+
+/// Files compared in time are reported as usual, see the unit tests for those timing out.
+#[test]
+fn per_file_timeout() {
+    let trees = Trees::new("per_file_timeout");
+    trees.write("a/f", "abc");
+    trees.write("b/f", "abd");
+    trees.write("a/same", "same");
+    trees.write("b/same", "same");
+    trees
+        .diff(&["--per-file-timeout", "60"])
+        .assert_code(1)
+        .assert_lines(&["[Files differ]\t\"f\""]);
+    trees
+        .diff(&["--per-file-timeout", "1m", "--print-hashes"])
+        .assert_code(2)
+        .assert_stderr("cannot be used with");
+}
@@ -0,0 +1,722 @@
+//! Options comparing the metadata of entries.
+
+use super::Trees;
+#[cfg(unix)]
+use std::{
+    fs::File,
+    time::{Duration, SystemTime},
+};
+
+/// Sets the extended attribute `name` of the file at `path` in the trees.
+#[cfg(target_os = "linux")]
+fn set_xattr(trees: &Trees, path: &str, name: &str, value: &[u8]) {
+    try_set_xattr(trees, path, name, value).unwrap();
+}
+
+/// Sets the extended attribute `name` of the file at `path` in the trees, which may take
+/// privileges.
+#[cfg(target_os = "linux")]
+fn try_set_xattr(trees: &Trees, path: &str, name: &str, value: &[u8]) -> std::io::Result<()> {
+    use std::{ffi::CString, os::unix::ffi::OsStrExt};
+    let path = CString::new(trees.path().join(path).as_os_str().as_bytes()).unwrap();
+    let name = CString::new(name).unwrap();
+    // SAFETY: both strings are NUL-terminated, and `value` is valid for `value.len()` bytes.
+    let res = unsafe {
+        libc::setxattr(
+            path.as_ptr(),
+            name.as_ptr(),
+            value.as_ptr() as *const libc::c_void,
+            value.len(),
+            0,
+        )
+    };
+    if res == 0 {
+        Ok(())
+    } else {
+        Err(std::io::Error::last_os_error())
+    }
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn trust_xattr() {
+    let trees = Trees::new("trust_xattr");
+    // The content differs, but the trusted hashes are the same.
+    trees.write("a/trusted", "1");
+    trees.write("b/trusted", "2");
+    set_xattr(&trees, "a/trusted", "user.checksum", b"h");
+    set_xattr(&trees, "b/trusted", "user.checksum", b"h");
+    // The hashes differ, so the content is compared.
+    trees.write("a/differ", "1");
+    trees.write("b/differ", "2");
+    set_xattr(&trees, "a/differ", "user.checksum", b"h1");
+    set_xattr(&trees, "b/differ", "user.checksum", b"h2");
+    trees.write("a/same", "1");
+    trees.write("b/same", "1");
+    set_xattr(&trees, "a/same", "user.checksum", b"h1");
+    set_xattr(&trees, "b/same", "user.checksum", b"h2");
+    trees
+        .diff(&[])
+        .assert_lines(&["[Files differ]\t\"differ\"", "[Files differ]\t\"trusted\""]);
+    trees
+        .diff(&["--trust-xattr", "user.checksum"])
+        .assert_code(1)
+        .assert_lines(&["[Files differ]\t\"differ\""]);
+    trees
+        .diff(&["--trust-xattr", "user.checksum", "--explain"])
+        .assert_stderr("trusted: equal by trusted extended attribute\n")
+        .assert_stderr("same: equal by full byte comparison\n");
+}
+
+/// Toggles the append-only flag of the file at `path` in the trees, returning whether it was
+/// permitted.
+///
+/// Setting it requires root, and filesystems like tmpfs may not support it.
+#[cfg(all(
+    target_os = "linux",
+    any(target_arch = "x86_64", target_arch = "aarch64")
+))]
+fn toggle_append_only(trees: &Trees, path: &str) -> bool {
+    use std::os::unix::io::AsRawFd;
+    // `_IOR('f', 1, long)` and `_IOW('f', 2, long)`.
+    const FS_IOC_GETFLAGS: u64 = (2 << 30) | (8 << 16) | ((b'f' as u64) << 8) | 1;
+    const FS_IOC_SETFLAGS: u64 = (1 << 30) | (8 << 16) | ((b'f' as u64) << 8) | 2;
+    const FS_APPEND_FL: libc::c_int = 0x20;
+    let file = File::open(trees.path().join(path)).unwrap();
+    let mut flags: libc::c_int = 0;
+    unsafe {
+        libc::ioctl(file.as_raw_fd(), FS_IOC_GETFLAGS as _, &mut flags) == 0 && {
+            flags ^= FS_APPEND_FL;
+            libc::ioctl(file.as_raw_fd(), FS_IOC_SETFLAGS as _, &flags) == 0
+        }
+    }
+}
+
+#[cfg(all(
+    target_os = "linux",
+    any(target_arch = "x86_64", target_arch = "aarch64")
+))]
+#[test]
+fn check_flags() {
+    let trees = Trees::new("flags");
+    trees.write("a/f", "x");
+    trees.write("b/f", "x");
+    if !toggle_append_only(&trees, "a/f") {
+        eprintln!("Skipped: the append-only flag cannot be set");
+        return;
+    }
+    let run = trees.diff(&["--check-flags", "--format", "json", "--no-summary"]);
+    // Cleared before checking, so that the trees can be removed.
+    assert!(toggle_append_only(&trees, "a/f"));
+    run.assert_code(1);
+    let flags: Vec<u32> = run
+        .stdout
+        .trim_end()
+        .strip_suffix('}')
+        .unwrap()
+        .split(',')
+        .filter_map(|field| field.split_once(':'))
+        .filter(|(name, _)| name.starts_with("\"flags"))
+        .map(|(_, value)| value.parse().unwrap())
+        .collect();
+    assert_eq!(flags.len(), 2, "{run:#?}");
+    assert_eq!(flags[0] ^ flags[1], 0x20, "{run:#?}");
+    trees.diff(&["--check-flags"]).assert_code(0);
+}
+
+#[cfg(unix)]
+#[test]
+fn owner_names() {
+    use std::os::unix::fs::{chown, MetadataExt};
+    let trees = Trees::new("owner_names");
+    trees.write("a/f", "x");
+    trees.write("b/f", "x");
+    // An id missing from the databases, which only root can give.
+    let unknown = 4_000_000;
+    if chown(trees.path().join("b/f"), Some(unknown), Some(unknown)).is_err() {
+        eprintln!("Skipped: files cannot be given to another user");
+        return;
+    }
+    let meta = std::fs::metadata(trees.path().join("a/f")).unwrap();
+    let (uid, gid) = (meta.uid(), meta.gid());
+    let run = trees.diff(&["--owner"]);
+    run.assert_code(1);
+    let lines = run.lines();
+    assert_eq!(lines.len(), 1, "{run:#?}");
+    assert!(
+        lines[0].starts_with("[Owner differs]\t\"f\"\tis owned by "),
+        "{run:#?}"
+    );
+    assert!(
+        lines[0].ends_with(" in first dir. while by 4000000:4000000 in second dir."),
+        "{run:#?}"
+    );
+    if uid == 0 {
+        assert!(lines[0].contains("by root:"), "{run:#?}");
+    }
+    // The numeric ids are kept for the JSON output.
+    let run = trees.diff(&["--owner", "--format", "json", "--no-summary"]);
+    assert!(
+        run.stdout.contains(&format!(
+            "\"uid1\":{uid},\"gid1\":{gid},\"uid2\":4000000,\"gid2\":4000000"
+        )),
+        "{run:#?}"
+    );
+}
+
+/// Sets the mtime of the file or directory at `path` in the trees, in seconds since the epoch.
+#[cfg(unix)]
+fn set_mtime(trees: &Trees, path: &str, secs: u64) {
+    set_precise_mtime(trees, path, Duration::from_secs(secs));
+}
+
+/// Sets the mtime of the file or directory at `path` in the trees, since the epoch.
+#[cfg(unix)]
+fn set_precise_mtime(trees: &Trees, path: &str, since_epoch: Duration) {
+    let mtime = SystemTime::UNIX_EPOCH + since_epoch;
+    let file = File::open(trees.path().join(path)).unwrap();
+    file.set_modified(mtime).unwrap();
+}
+
+#[cfg(unix)]
+#[test]
+fn perms() {
+    use std::{fs, os::unix::fs::PermissionsExt};
+    let trees = Trees::new("perms");
+    trees.write("a/d/f", "");
+    trees.write("b/d/f", "");
+    trees.write("a/g", "g");
+    trees.write("b/g", "g");
+    let chmod = |path: &str, mode| {
+        let path = trees.path().join(path);
+        fs::set_permissions(path, fs::Permissions::from_mode(mode)).unwrap();
+    };
+    chmod("a/d", 0o755);
+    chmod("b/d", 0o700);
+    chmod("a/g", 0o644);
+    chmod("b/g", 0o600);
+    trees.diff(&[]).assert_code(0);
+    trees
+        .diff(&["--perms"])
+        .assert_code(1)
+        .assert_lines(&["[Permissions differ]\t\"d\"", "[Permissions differ]\t\"g\""]);
+    // Only the bits of the owner are significant.
+    trees
+        .diff(&["--perms", "--perm-mask", "700"])
+        .assert_code(0);
+    // Only the execute bits of the group and others differ between the directories.
+    trees
+        .diff(&["--perms", "--perm-mask", "0o711"])
+        .assert_code(1)
+        .assert_lines(&["[Permissions differ]\t\"d\""]);
+}
+
+#[cfg(unix)]
+#[test]
+fn check_dir_mtime() {
+    let trees = Trees::new("dir_mtime");
+    trees.write("a/d/f", "");
+    trees.write("b/d/f", "");
+    trees.write("a/e/f", "");
+    trees.write("b/e/f", "");
+    // The files are equal, but were written at slightly different times.
+    for path in ["a/d/f", "b/d/f", "a/e/f", "b/e/f"] {
+        set_mtime(&trees, path, 1 << 30);
+    }
+    set_mtime(&trees, "a/d", 1 << 30);
+    set_mtime(&trees, "b/d", 1 << 29);
+    set_mtime(&trees, "a/e", 1 << 30);
+    set_mtime(&trees, "b/e", 1 << 30);
+    trees.diff(&["--check-mtime"]).assert_code(0);
+    trees
+        .diff(&["--check-dir-mtime"])
+        .assert_code(0)
+        .assert_lines(&["[Differ by mtime only]\t\"d\""]);
+}
+
+#[cfg(unix)]
+#[test]
+fn mtime_is_diff() {
+    let trees = Trees::new("mtime_is_diff");
+    trees.write("a/touched", "same");
+    trees.write("b/touched", "same");
+    trees.write("a/f", "1");
+    trees.write("b/f", "2");
+    trees.write("a/d/g", "");
+    trees.write("b/d/g", "");
+    for path in ["a/touched", "a/f", "b/f", "a/d/g", "b/d/g"] {
+        set_mtime(&trees, path, 1 << 30);
+    }
+    set_mtime(&trees, "b/touched", 1 << 29);
+    set_mtime(&trees, "a/d", 1 << 30);
+    set_mtime(&trees, "b/d", 1 << 29);
+    trees
+        .diff(&["--check-mtime", "--check-dir-mtime"])
+        .assert_code(1)
+        .assert_lines(&[
+            "[Differ by mtime only]\t\"d\"",
+            "[Differ by mtime only]\t\"touched\"",
+            "[Files differ]\t\"f\"",
+        ]);
+    // Differences of mtime only are not failures by default.
+    trees.write("b/f", "1");
+    set_mtime(&trees, "b/f", 1 << 30);
+    trees.diff(&["--check-mtime"]).assert_code(0);
+    // Still told apart from files whose content differs.
+    trees.write("b/f", "2");
+    set_mtime(&trees, "b/f", 1 << 30);
+    trees
+        .diff(&["--check-mtime", "--check-dir-mtime", "--mtime-is-diff"])
+        .assert_code(1)
+        .assert_lines(&[
+            "[Differ by mtime only]\t\"d\"",
+            "[Files differ]\t\"f\"",
+            "[Files differ]\t\"touched\"\tby mtime",
+        ]);
+    trees
+        .diff(&[
+            "--check-mtime",
+            "--mtime-is-diff",
+            "--format",
+            "json",
+            "--no-summary",
+        ])
+        .assert_lines(&[
+            r#"{"kind":"different","path":"f"}"#,
+            r#"{"kind":"different","path":"touched","mtime_only":true}"#,
+        ]);
+    trees
+        .diff(&["--mtime-is-diff"])
+        .assert_code(2)
+        .assert_stderr("--check-mtime");
+}
+
+#[cfg(unix)]
+#[test]
+fn fail_on() {
+    let trees = Trees::new("fail_on");
+    trees.write("a/m", "1");
+    trees.write("b/m", "1");
+    set_mtime(&trees, "a/m", 1 << 30);
+    set_mtime(&trees, "b/m", 1 << 29);
+    let mtime = "[Differ by mtime only]\t\"m\"";
+    // Differences of mtime only are not failures by default.
+    trees
+        .diff(&["--check-mtime"])
+        .assert_code(0)
+        .assert_lines(&[mtime]);
+    trees
+        .diff(&["--check-mtime", "--fail-on", "different"])
+        .assert_code(0)
+        .assert_lines(&[mtime]);
+    trees
+        .diff(&["--check-mtime", "--fail-on", "mtime"])
+        .assert_code(1)
+        .assert_lines(&[mtime]);
+    // The diffs of the kinds left out are still printed.
+    trees.write("a/c", "1");
+    trees.write("b/c", "2");
+    trees
+        .diff(&["--check-mtime", "--fail-on", "mtime,only1"])
+        .assert_code(1);
+    trees
+        .diff(&["--fail-on", "only1,only2"])
+        .assert_code(0)
+        .assert_lines(&["[Files differ]\t\"c\""]);
+    trees
+        .diff(&["--fail-on", "different,typo"])
+        .assert_code(2)
+        .assert_stderr("Unknown kind `typo` in --fail-on");
+}
+
+#[cfg(unix)]
+#[test]
+fn check_nlink() {
+    let trees = Trees::new("nlink");
+    trees.write("a/f", "x");
+    let file = trees.write("b/f", "x");
+    // A second hard link on one side only.
+    std::fs::hard_link(file, trees.path().join("b/link")).unwrap();
+    trees.write("a/link", "x");
+    trees.diff(&[]).assert_code(0);
+    trees
+        .diff(&["--check-nlink"])
+        .assert_code(1)
+        .assert_lines(&[
+            "[Link counts differ]\t\"f\"\thas 1 links in first dir. while 2 in second dir.",
+            "[Link counts differ]\t\"link\"\thas 1 links in first dir. while 2 in second dir.",
+        ]);
+    trees
+        .diff(&["--check-nlink", "--format", "json", "--no-summary"])
+        .assert_lines(&[
+            r#"{"kind":"nlink","path":"f","nlink1":1,"nlink2":2}"#,
+            r#"{"kind":"nlink","path":"link","nlink1":1,"nlink2":2}"#,
+        ]);
+}
+
+#[cfg(unix)]
+#[test]
+fn check_blocks() {
+    use std::os::unix::fs::MetadataExt;
+    let trees = Trees::new("blocks");
+    let len = 1 << 20;
+    trees.write("a/f", vec![0; len]);
+    // The same content, as a hole.
+    let sparse = File::create(trees.path().join("b/f")).unwrap();
+    sparse.set_len(len as u64).unwrap();
+    let blocks = |path| std::fs::metadata(trees.path().join(path)).unwrap().blocks();
+    let (blocks1, blocks2) = (blocks("a/f"), blocks("b/f"));
+    if blocks1 == blocks2 {
+        eprintln!("Skipped: the filesystem does not support holes");
+        return;
+    }
+    trees.diff(&[]).assert_code(0).assert_lines(&[]);
+    let blocks = format!(
+        "[Blocks differ]\t\"f\"\thas {blocks1} blocks in first dir. while {blocks2} in second dir."
+    );
+    // Reported, but not a failure unless asked to.
+    trees
+        .diff(&["--check-blocks"])
+        .assert_code(0)
+        .assert_lines(&[&blocks]);
+    trees
+        .diff(&["--check-blocks", "--fail-on", "blocks"])
+        .assert_code(1);
+}
+
+/// Makes the file at `to` in the trees a reflink clone of the one at `from`, returning whether
+/// the filesystem supports them.
+#[cfg(target_os = "linux")]
+fn reflink(trees: &Trees, from: &str, to: &str) -> bool {
+    use std::os::unix::io::AsRawFd;
+    // `_IOW(0x94, 9, int)`.
+    const FICLONE: u64 = (1 << 30) | (4 << 16) | (0x94 << 8) | 9;
+    let src = File::open(trees.path().join(from)).unwrap();
+    let dst = File::create(trees.path().join(to)).unwrap();
+    unsafe { libc::ioctl(dst.as_raw_fd(), FICLONE as _, src.as_raw_fd()) == 0 }
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn check_reflinks() {
+    let trees = Trees::new("check_reflinks");
+    let content = vec![1; 1 << 16];
+    trees.write("a/orig", &content);
+    trees.write("b/orig", &content);
+    trees.write("b/f", &content);
+    if !reflink(&trees, "a/orig", "a/f") {
+        eprintln!("Skipped: the filesystem does not support reflinks");
+        return;
+    }
+    trees.diff(&[]).assert_code(0).assert_lines(&[]);
+    // Reported, but not a failure unless asked to.
+    trees
+        .diff(&["--check-reflinks"])
+        .assert_code(0)
+        .assert_lines(&[
+        "[Extent sharing differs]\t\"f\"\tshares extents in first dir. while not in second dir.",
+        "[Extent sharing differs]\t\"orig\"\tshares extents in first dir. while not in second dir.",
+    ]);
+    trees
+        .diff(&["--check-reflinks", "--fail-on", "extent_sharing"])
+        .assert_code(1);
+}
+
+/// Without reflinks, nothing is reported, even where sharing cannot be told.
+#[cfg(target_os = "linux")]
+#[test]
+fn check_reflinks_copies() {
+    let trees = Trees::new("check_reflinks_copies");
+    trees.write("a/f", "same");
+    trees.write("b/f", "same");
+    trees
+        .diff(&["--check-reflinks"])
+        .assert_code(0)
+        .assert_lines(&[]);
+    trees
+        .diff(&["--check-reflinks", "--data-only"])
+        .assert_code(2)
+        .assert_stderr("cannot be used with");
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn check_caps() {
+    let trees = Trees::new("check_caps");
+    trees.write("a/f", "same");
+    trees.write("b/f", "same");
+    trees.diff(&["--check-caps"]).assert_code(0);
+    // `cap_net_raw=ep`, as a revision 2 `vfs_cap_data`: the revision and effective flag, then
+    // the permitted and inheritable sets of the low and high words.
+    let caps: Vec<u8> = [0x0200_0001u32, 1 << 13, 0, 0, 0]
+        .iter()
+        .flat_map(|word| word.to_le_bytes())
+        .collect();
+    if let Err(e) = try_set_xattr(&trees, "a/f", "security.capability", &caps) {
+        eprintln!("Skipped: cannot set capabilities: {}", e);
+        return;
+    }
+    trees.diff(&[]).assert_code(0);
+    trees.diff(&["--check-caps"]).assert_code(1).assert_lines(&[
+        "[Capabilities differ]\t\"f\"\tare cap_net_raw=ep in first dir. while none in second dir.",
+    ]);
+    trees
+        .diff(&["--check-caps", "--format", "json", "--no-summary"])
+        .assert_lines(&[r#"{"kind":"caps","path":"f","caps1":"cap_net_raw=ep","caps2":"none"}"#]);
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn acls() {
+    let trees = Trees::new("acls");
+    trees.write("a/f", "same");
+    trees.write("b/f", "same");
+    trees.write("a/sub/g", "");
+    trees.write("b/sub/g", "");
+    trees.diff(&["--acls"]).assert_code(0);
+    // `user::rw-,user:1000:r--,group::r--,mask::r--,other::r--`: the version, then the tag,
+    // permissions and id of each entry.
+    let entries: [(u16, u16, u32); 5] = [
+        (1, 6, 0),
+        (2, 4, 1000),
+        (4, 4, 0),
+        (0x10, 4, 0),
+        (0x20, 4, 0),
+    ];
+    let mut acl = 2u32.to_le_bytes().to_vec();
+    for (tag, perm, id) in entries {
+        acl.extend(tag.to_le_bytes());
+        acl.extend(perm.to_le_bytes());
+        acl.extend(id.to_le_bytes());
+    }
+    if let Err(e) = try_set_xattr(&trees, "a/f", "system.posix_acl_access", &acl) {
+        eprintln!("Skipped: the filesystem does not support ACLs: {}", e);
+        return;
+    }
+    set_xattr(&trees, "b/sub", "system.posix_acl_default", &acl);
+    trees.diff(&[]).assert_code(0);
+    let described = "user::rw-,user:1000:r--,group::r--,mask::r--,other::r--";
+    trees.diff(&["--acls"]).assert_code(1).assert_lines(&[
+        &format!("[ACLs differ]\t\"f\"\tare {described} in first dir. while none in second dir."),
+        &format!(
+            "[ACLs differ]\t\"sub\"\tare none in first dir. while {} in second dir.",
+            described
+                .replace("user::", "default:user::")
+                .replace(",", ",default:")
+        ),
+    ]);
+}
+
+#[cfg(unix)]
+#[test]
+fn ignore_mtime() {
+    let trees = Trees::new("ignore_mtime");
+    trees.write("a/d/same", "1");
+    trees.write("b/d/same", "1");
+    trees.write("a/differ", "1");
+    trees.write("b/differ", "2");
+    set_mtime(&trees, "a/d/same", 1_000_000);
+    set_mtime(&trees, "b/d/same", 2_000_000);
+    set_mtime(&trees, "a/d", 1_000_000);
+    set_mtime(&trees, "b/d", 2_000_000);
+    trees
+        .diff(&["--check-mtime", "--check-dir-mtime"])
+        .assert_lines(&[
+            "[Differ by mtime only]\t\"d\"",
+            "[Differ by mtime only]\t\"d/same\"",
+            "[Files differ]\t\"differ\"",
+        ]);
+    for mode in [
+        &[][..],
+        &["--perms"],
+        &["--owner"],
+        &["--check-nlink"],
+        &["--check-blocks"],
+        &["--perms", "--owner", "--check-nlink", "--check-blocks"],
+    ] {
+        let mut args = vec!["--ignore-mtime"];
+        args.extend(mode);
+        trees
+            .diff(&args)
+            .assert_code(1)
+            .assert_lines(&["[Files differ]\t\"differ\""]);
+    }
+    for conflicting in [
+        "--check-mtime",
+        "--check-dir-mtime",
+        "--prune-unchanged-dirs=a/differ",
+    ] {
+        trees.diff(&["--ignore-mtime", conflicting]).assert_code(2);
+    }
+}
+
+#[cfg(unix)]
+#[test]
+fn mtime_granularity() {
+    let trees = Trees::new("mtime_granularity");
+    let at = |secs, millis| Duration::from_secs(secs) + Duration::from_millis(millis);
+    // 0.4s apart, within the same second.
+    trees.write("a/close", "");
+    trees.write("b/close", "");
+    set_precise_mtime(&trees, "a/close", at(1_000_000, 100));
+    set_precise_mtime(&trees, "b/close", at(1_000_000, 500));
+    // 0.2s apart, but across seconds.
+    trees.write("a/across", "");
+    trees.write("b/across", "");
+    set_precise_mtime(&trees, "a/across", at(1_000_000, 900));
+    set_precise_mtime(&trees, "b/across", at(1_000_001, 100));
+    // 1µs apart.
+    trees.write("a/micro", "");
+    trees.write("b/micro", "");
+    set_precise_mtime(&trees, "a/micro", at(1_000_000, 0));
+    set_precise_mtime(
+        &trees,
+        "b/micro",
+        at(1_000_000, 0) + Duration::from_micros(1),
+    );
+    for granularity in ["ns", "us"] {
+        trees
+            .diff(&["--check-mtime", "--mtime-granularity", granularity])
+            .assert_lines(&[
+                "[Differ by mtime only]\t\"across\"",
+                "[Differ by mtime only]\t\"close\"",
+                "[Differ by mtime only]\t\"micro\"",
+            ]);
+    }
+    trees
+        .diff(&["--check-mtime", "--mtime-granularity", "ms"])
+        .assert_lines(&[
+            "[Differ by mtime only]\t\"across\"",
+            "[Differ by mtime only]\t\"close\"",
+        ]);
+    // Rounded down, rather than compared within a tolerance.
+    trees
+        .diff(&["--check-mtime", "--mtime-granularity", "s"])
+        .assert_lines(&["[Differ by mtime only]\t\"across\""]);
+}
+
+#[cfg(unix)]
+#[test]
+fn updates_to_second() {
+    let trees = Trees::new("updates_to_second");
+    for (path, secs) in [
+        ("a/newer1", 1 << 30),
+        ("b/newer1", 1 << 29),
+        ("a/newer2", 1 << 29),
+        ("b/newer2", 1 << 30),
+        ("a/same", 1 << 30),
+        ("b/same", 1 << 30),
+    ] {
+        // Even the same content is reported, and different content left out.
+        trees.write(path, if path.ends_with('2') { path } else { "" });
+        set_mtime(&trees, path, secs);
+    }
+    trees.write("a/only1", "");
+    trees.write("b/only2", "");
+    trees
+        .diff(&["--updates-to-second"])
+        .assert_code(1)
+        .assert_lines(&[
+            "[Newer in first dir.]\t\"newer1\"",
+            "[Present in first dir. only]\t\"only1\"",
+        ]);
+    trees
+        .diff(&["--updates-to-second", "--format", "itemize"])
+        .assert_lines(&[">f+++++++++ \"only1\"", ">f..t...... \"newer1\""]);
+}
+
+#[cfg(unix)]
+#[test]
+fn assert_identical() {
+    let trees = Trees::new("assert_identical");
+    trees.write("a/f", "x");
+    trees.write("b/f", "x");
+    trees.write("a/d/g", "y");
+    trees.write("b/d/g", "y");
+    let run = trees.diff(&["--assert-identical"]);
+    run.assert_code(0).assert_stdout(&[]);
+    assert_eq!(run.stderr, "", "{run:#?}");
+    // Differing by mtime only.
+    set_mtime(&trees, "a/f", 1 << 30);
+    set_mtime(&trees, "b/f", 1 << 31);
+    let run = trees.diff(&["--assert-identical", "--check-mtime"]);
+    run.assert_code(0).assert_stdout(&[]);
+    assert_eq!(run.stderr, "", "{run:#?}");
+    trees
+        .diff(&["--assert-identical", "--check-mtime", "--mtime-is-diff"])
+        .assert_code(1)
+        .assert_stdout(&[])
+        .assert_stderr("trees differ: 1 differences");
+    trees.write("b/d/g", "z");
+    trees.write("a/only1", "");
+    let run = trees.diff(&["--assert-identical"]);
+    run.assert_code(1).assert_stdout(&[]);
+    assert_eq!(run.stderr, "trees differ: 2 differences\n", "{run:#?}");
+    trees
+        .diff(&["--assert-identical", "--format", "json"])
+        .assert_code(2)
+        .assert_stderr("cannot be used with");
+}
+
+#[cfg(unix)]
+#[test]
+fn flag_mtime_same_content_differs() {
+    let trees = Trees::new("flag_mtime_same_content_differs");
+    trees.write("a/tampered", "abc");
+    trees.write("b/tampered", "abd");
+    trees.write("a/touched", "abc");
+    trees.write("b/touched", "abd");
+    trees.write("a/longer", "abc");
+    trees.write("b/longer", "abcd");
+    for path in ["tampered", "longer"] {
+        set_mtime(&trees, &format!("a/{path}"), 1 << 30);
+        set_mtime(&trees, &format!("b/{path}"), 1 << 30);
+    }
+    set_mtime(&trees, "a/touched", 1 << 30);
+    set_mtime(&trees, "b/touched", 1 << 31);
+    let run = trees.diff(&["--flag-mtime-same-content-differs", "--sorted"]);
+    run.assert_code(1).assert_stdout(&[
+        "[Files differ]\t\"longer\"",
+        "[Files differ]\t\"tampered\"",
+        "[Differ despite same mtime]\t\"tampered\"",
+        "[Files differ]\t\"touched\"",
+    ]);
+    trees
+        .diff(&[
+            "--flag-mtime-same-content-differs",
+            "--format",
+            "json",
+            "--no-summary",
+        ])
+        .assert_code(1)
+        .assert_stdout(&[
+            r#"{"kind":"different","path":"longer"}"#,
+            r#"{"kind":"different","path":"tampered"}"#,
+            r#"{"kind":"suspicious_mtime","path":"tampered"}"#,
+            r#"{"kind":"different","path":"touched"}"#,
+        ]);
+    // Within the same second, at the granularity of seconds.
+    set_precise_mtime(
+        &trees,
+        "b/touched",
+        Duration::from_millis((1 << 30) * 1000 + 400),
+    );
+    trees
+        .diff(&[
+            "--flag-mtime-same-content-differs",
+            "--mtime-granularity",
+            "s",
+        ])
+        .assert_code(1)
+        .assert_lines(&[
+            "[Differ despite same mtime]\t\"tampered\"",
+            "[Differ despite same mtime]\t\"touched\"",
+            "[Files differ]\t\"longer\"",
+            "[Files differ]\t\"tampered\"",
+            "[Files differ]\t\"touched\"",
+        ]);
+    trees
+        .diff(&["--flag-mtime-same-content-differs", "--data-only"])
+        .assert_code(2)
+        .assert_stderr("cannot be used with");
+}
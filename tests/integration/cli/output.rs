@@ -0,0 +1,1044 @@
+//! Options choosing how the diffs are written.
+
+use super::Trees;
+use std::path::Path;
+
+#[test]
+fn full_paths() {
+    let trees = Trees::new("full_paths");
+    trees.write("a/sub/f", "1");
+    trees.write("b/sub/f", "2");
+    trees.write("a/only1", "");
+    trees.write("b/sub/only2", "");
+    trees.diff(&[]).assert_lines(&[
+        "[Files differ]\t\"sub/f\"",
+        "[Present in first dir. only]\t\"only1\"",
+        "[Present in second dir. only]\t\"sub/only2\"",
+    ]);
+    trees.diff(&["--full-paths"]).assert_lines(&[
+        "[Files differ]\t\"a/sub/f\"",
+        "[Present in first dir. only]\t\"a/only1\"",
+        "[Present in second dir. only]\t\"b/sub/only2\"",
+    ]);
+}
+
+#[test]
+fn format_template() {
+    let trees = Trees::new("format_template");
+    trees.write("a/sub/f", "1");
+    trees.write("b/sub/f", "22");
+    let render = |template| {
+        let run = trees.diff(&["--format-template", template]);
+        run.assert_code(1);
+        run.stdout.clone()
+    };
+    assert_eq!(render("{kind}"), "Files differ\n");
+    assert_eq!(render("{path}"), "sub/f\n");
+    assert_eq!(render("{fullpath1}\\t{fullpath2}"), "a/sub/f\tb/sub/f\n");
+    assert_eq!(render("{size1} {size2}"), "1 2\n");
+    // No newline is added after a NUL.
+    assert_eq!(render("{{{kind}}}\\0"), "{Files differ}\0");
+    trees
+        .diff(&["--format-template", "{unknown}"])
+        .assert_code(2)
+        .assert_stderr("Unknown placeholder `{unknown}`");
+}
+
+#[test]
+fn json() {
+    let trees = Trees::new("json");
+    trees.write("a/f", "1");
+    trees.write("b/f", "2");
+    trees.write("a/only1", "");
+    trees.write("a/d/g", "");
+    trees.write("b/d", "\"quoted\"\n");
+    trees.write("b/tab\tname", "");
+    trees
+        .diff(&["--format", "json", "--no-summary"])
+        .assert_code(1)
+        .assert_lines(&[
+            r#"{"kind":"different","path":"f"}"#,
+            r#"{"kind":"only1","path":"only1"}"#,
+            r#"{"kind":"only2","path":"tab\tname"}"#,
+            r#"{"kind":"type","path":"d","type1":"directory","type2":"regular file"}"#,
+        ]);
+    let run = trees.diff(&["--format", "json"]);
+    run.assert_code(1);
+    let summary = run.stdout.lines().last().unwrap();
+    assert!(summary.starts_with(r#"{"kind":"summary","#), "{run:#?}");
+    let counted: Vec<&str> = summary[1..summary.len() - 1]
+        .split(',')
+        .skip(1)
+        .filter(|field| !field.ends_with(":0"))
+        .collect();
+    assert_eq!(
+        counted,
+        [
+            r#""different":1"#,
+            r#""only1":1"#,
+            r#""only2":1"#,
+            r#""type":1"#
+        ],
+        "{run:#?}"
+    );
+}
+
+/// Records are flushed whole, so that the output of a killed run is made of complete records.
+#[test]
+fn json_of_killed_run() {
+    use std::io::{BufRead, BufReader, Read};
+    let trees = Trees::new("json_killed");
+    for i in 0..2000 {
+        trees.write(&format!("a/d{}/f{i}", i % 10), "1");
+        trees.write(&format!("b/d{}/f{i}", i % 10), "2");
+    }
+    let mut child = std::process::Command::new(env!("CARGO_BIN_EXE_dirdiff"))
+        .args(["--format", "json", "--no-summary", "a", "b"])
+        .current_dir(trees.path())
+        .stdout(std::process::Stdio::piped())
+        .spawn()
+        .unwrap();
+    let mut stdout = BufReader::new(child.stdout.take().unwrap());
+    let mut output = String::new();
+    stdout.read_line(&mut output).unwrap();
+    child.kill().unwrap();
+    stdout.read_to_string(&mut output).unwrap();
+    child.wait().unwrap();
+    assert!(output.ends_with('\n'));
+    for line in output.lines() {
+        assert!(
+            line.starts_with(r#"{"kind":"different","path":"d"#) && line.ends_with("\"}"),
+            "{line}"
+        );
+    }
+}
+
+#[test]
+fn group_by_kind() {
+    let trees = Trees::new("group_by_kind");
+    trees.write("a/z", "1");
+    trees.write("b/z", "2");
+    trees.write("a/d", "1");
+    trees.write("b/d", "2");
+    trees.write("a/o1", "");
+    trees.write("b/o2", "");
+    trees.write("a/t", "");
+    trees.write("b/t/f", "");
+    trees.write("a/a", "");
+    trees.write("b/a/f", "");
+    let differ = ["[Files differ]\t\"d\"", "[Files differ]\t\"z\""];
+    let types = [
+        "[Types differ]\t\"a\"\tis a regular empty file in first dir. while a directory in second dir.",
+        "[Types differ]\t\"t\"\tis a regular empty file in first dir. while a directory in second dir.",
+    ];
+    let only1 = "[Present in first dir. only]\t\"o1\"";
+    let only2 = "[Present in second dir. only]\t\"o2\"";
+    trees
+        .diff(&["--sorted", "--group-by", "kind"])
+        .assert_code(1)
+        .assert_stdout(&[differ[0], differ[1], only1, only2, types[0], types[1]]);
+    trees
+        .diff(&[
+            "--sorted",
+            "--group-by",
+            "kind",
+            "--kind-order",
+            "type,only2",
+        ])
+        .assert_code(1)
+        .assert_stdout(&[types[0], types[1], only2, differ[0], differ[1], only1]);
+    trees
+        .diff(&["--sorted", "--group-by", "kind", "--kind-order", "typo"])
+        .assert_code(2)
+        .assert_stderr("Unknown kind `typo` in --kind-order");
+}
+
+#[test]
+fn sorted() {
+    let trees = Trees::new("sorted");
+    let mut expected = Vec::new();
+    for i in 0..200 {
+        trees.write(&format!("a/d{}/f{i:03}", i % 7), "1");
+        trees.write(&format!("b/d{}/f{i:03}", i % 7), "2");
+        expected.push(format!("[Files differ]\t\"d{}/f{i:03}\"", i % 7));
+    }
+    expected.sort();
+    let expected: Vec<_> = expected.iter().map(String::as_str).collect();
+    // The diffs found by each worker are merged in the same order however many there are.
+    for jobs in ["1", "4", "16"] {
+        trees
+            .diff(&["--sorted", "-j", jobs])
+            .assert_code(1)
+            .assert_stdout(&expected);
+    }
+}
+
+#[test]
+fn path_prefix() {
+    let trees = Trees::new("path_prefix");
+    trees.write("a/sub/f", "1");
+    trees.write("b/sub/f", "2");
+    trees.write("a/subway", "");
+    trees.diff(&["--path-prefix", "release-42"]).assert_lines(&[
+        "[Files differ]\t\"release-42/sub/f\"",
+        "[Present in first dir. only]\t\"release-42/subway\"",
+    ]);
+    // Only whole leading components are stripped.
+    trees.diff(&["--strip-prefix", "sub"]).assert_lines(&[
+        "[Files differ]\t\"f\"",
+        "[Present in first dir. only]\t\"subway\"",
+    ]);
+    trees
+        .diff(&[
+            "--strip-prefix",
+            "sub",
+            "--path-prefix",
+            "release-42",
+            "--format",
+            "json",
+            "--no-summary",
+        ])
+        .assert_lines(&[
+            r#"{"kind":"different","path":"release-42/f"}"#,
+            r#"{"kind":"only1","path":"release-42/subway"}"#,
+        ]);
+}
+
+#[test]
+fn dir_fingerprint() {
+    let trees = Trees::new("dir_fingerprint");
+    trees.write("a/sub/f", "1");
+    trees.write("b/sub/f", "2");
+    trees.write("a/sub/g", "");
+    trees.write("a/top", "");
+    let diffs = [
+        "[Files differ]\t\"sub/f\"",
+        "[Present in first dir. only]\t\"sub/g\"",
+        "[Present in first dir. only]\t\"top\"",
+    ];
+    let summary = [
+        ".: changed=1 fingerprint=3002daef38bcddf4",
+        "sub: changed=2 fingerprint=e6afa44fe8a118e8",
+    ];
+    for _ in 0..2 {
+        let run = trees.diff(&["--dir-fingerprint", "-j", "4"]);
+        run.assert_code(1);
+        let lines: Vec<_> = run.stdout.lines().collect();
+        // The summary comes after the diffs.
+        let mut printed = lines[..3].to_vec();
+        printed.sort_unstable();
+        assert_eq!(printed, diffs);
+        assert_eq!(lines[3..], summary);
+    }
+    trees.write("b/sub/g", "");
+    trees.diff(&["--dir-fingerprint"]).assert_lines(&[
+        diffs[0],
+        diffs[2],
+        summary[0],
+        "sub: changed=1 fingerprint=06486c8b066af148",
+    ]);
+}
+
+#[test]
+fn per_dir_timing() {
+    let trees = Trees::new("per_dir_timing");
+    let content = "x".repeat(1 << 20);
+    for i in 0..20 {
+        trees.write(&format!("a/slow/f{i}"), &content);
+        trees.write(&format!("b/slow/f{i}"), &content);
+    }
+    for dir in ["d1", "d2", "d3"] {
+        trees.write(&format!("a/{dir}/f"), "");
+        trees.write(&format!("b/{dir}/f"), "");
+    }
+    let run = trees.diff(&["--per-dir-timing", "2"]);
+    run.assert_code(0).assert_lines(&[]);
+    let lines: Vec<_> = run.stderr.lines().collect();
+    assert_eq!(lines.len(), 3, "{run:#?}");
+    assert_eq!(lines[0], "Slowest directories:");
+    assert!(lines[1].ends_with("s\t20 entries\tslow"), "{run:#?}");
+}
+
+#[test]
+fn json_pretty() {
+    let trees = Trees::new("json_pretty");
+    trees.write("a/f", "1");
+    trees.write("b/f", "2");
+    trees.write("a/only1", "");
+    trees.write("b/only2", "");
+    trees
+        .diff(&["--json-pretty", "--no-summary", "--sorted"])
+        .assert_code(1)
+        .assert_stdout(&[
+            "[",
+            "  {",
+            r#"    "kind": "different","#,
+            r#"    "path": "f""#,
+            "  },",
+            "  {",
+            r#"    "kind": "only1","#,
+            r#"    "path": "only1""#,
+            "  },",
+            "  {",
+            r#"    "kind": "only2","#,
+            r#"    "path": "only2""#,
+            "  }",
+            "]",
+        ]);
+    // The summary is the last element.
+    let run = trees.diff(&["--json-pretty"]);
+    run.assert_code(1);
+    let elements = run.stdout.lines().filter(|&line| line == "  {").count();
+    assert_eq!(elements, 4, "{run:#?}");
+    let summary = run.stdout.rsplit_once("  {\n").unwrap().1;
+    assert!(
+        summary.starts_with("    \"kind\": \"summary\",\n"),
+        "{run:#?}"
+    );
+    assert!(summary.ends_with("\n  }\n]\n"), "{run:#?}");
+    let trees = Trees::new("json_pretty_empty");
+    trees
+        .diff(&["--json-pretty", "--no-summary"])
+        .assert_code(0)
+        .assert_stdout(&["[]"]);
+}
+
+#[test]
+fn json_error_record() {
+    let trees = Trees::new("json_error_record");
+    trees
+        .run(&["--format", "json", "--pairs-from", "missing"])
+        .assert_code(2)
+        .assert_stdout(&[
+            r#"{"kind":"error","message":"Couldn't open missing: No such file or directory (os error 2)","errno":2}"#,
+        ])
+        .assert_stderr("Error: Couldn't open missing");
+    trees
+        .run(&["--json-pretty", "a", "missing"])
+        .assert_code(2)
+        .assert_stdout(&[
+            "[",
+            "  {",
+            r#"    "kind": "error","#,
+            r#"    "message": "dir2 (missing) does not exist, see --missing-as-empty to compare it as empty""#,
+            "  }",
+            "]",
+        ]);
+    // The error ends the records written before it, in place of the summary.
+    #[cfg(unix)]
+    {
+        trees.write("a/f", "1");
+        trees.write("b/f", "2");
+        std::os::unix::fs::symlink("nowhere", trees.path().join("a/link")).unwrap();
+        let run = trees.diff(&["--format", "json", "-L", "-j", "1"]);
+        run.assert_code(2);
+        let last = run.stdout.lines().last().unwrap();
+        assert!(
+            last.starts_with(r#"{"kind":"error","message":"Error while following link "#),
+            "{run:#?}"
+        );
+        assert!(!run.stdout.contains("summary"), "{run:#?}");
+    }
+}
+
+#[test]
+fn non_latin_names() {
+    let trees = Trees::new("non_latin_names");
+    trees.write("a/日本語/données.txt", "1");
+    trees.write("b/日本語/données.txt", "2");
+    trees.write("a/Ελληνικά", "");
+    let separator = std::path::MAIN_SEPARATOR.escape_debug().to_string();
+    trees.diff(&[]).assert_code(1).assert_lines(&[
+        &format!("[Files differ]\t\"日本語{separator}données.txt\""),
+        "[Present in first dir. only]\t\"Ελληνικά\"",
+    ]);
+}
+
+#[test]
+fn only_in() {
+    let trees = Trees::new("only_in");
+    trees.write("a/f", "1");
+    trees.write("b/f", "2");
+    trees.write("a/only1", "");
+    trees.write("b/only2", "");
+    trees.write("a/dir/g", "1");
+    std::fs::create_dir(trees.path().join("b/dir")).unwrap();
+    trees.write("b/dir/g/h", "");
+    trees.diff(&[]).assert_code(1).assert_lines(&[
+        "[Files differ]\t\"f\"",
+        "[Present in first dir. only]\t\"only1\"",
+        "[Present in second dir. only]\t\"only2\"",
+        "[Types differ]\t\"dir/g\"\tis a regular file in first dir. while a directory in second dir.",
+    ]);
+    trees
+        .diff(&["--only-in", "first"])
+        .assert_code(1)
+        .assert_lines(&["[Present in first dir. only]\t\"only1\""]);
+    trees
+        .diff(&["--only-in", "second"])
+        .assert_code(1)
+        .assert_lines(&["[Present in second dir. only]\t\"only2\""]);
+    trees
+        .diff(&["--only-in", "both"])
+        .assert_code(1)
+        .assert_lines(&["[Files differ]\t\"f\""]);
+    // The exit code still accounts for the diffs left out, unless told otherwise.
+    std::fs::remove_file(trees.path().join("a/only1")).unwrap();
+    trees
+        .diff(&["--only-in", "first"])
+        .assert_code(1)
+        .assert_lines(&[]);
+    trees
+        .diff(&["--only-in", "first", "--fail-on", "only1"])
+        .assert_code(0);
+    // Left out of the summary too.
+    let run = trees.diff(&["--only-in", "second", "--format", "json"]);
+    assert!(
+        run.stdout.contains(r#""different":0,"only1":0,"only2":1,"#),
+        "{run:#?}"
+    );
+}
+
+#[test]
+fn tags() {
+    let trees = Trees::new("tags");
+    trees.write("a/f", "1");
+    trees.write("b/f", "2");
+    trees.write("a/only1", "");
+    trees.write("b/only2", "");
+    trees.write("a/m", "");
+    trees.write("b/m", "");
+    let file = std::fs::File::options()
+        .write(true)
+        .open(trees.path().join("a/m"))
+        .unwrap();
+    file.set_modified(std::time::SystemTime::UNIX_EPOCH)
+        .unwrap();
+    let args = [
+        "--tag-different",
+        "DIFF",
+        "--tag-only-first",
+        "ONE",
+        "--tag-only-second",
+        "TWO",
+        "--tag-mtime",
+        "TIME",
+        "--check-mtime",
+    ];
+    trees.diff(&args).assert_code(1).assert_lines(&[
+        "[DIFF]\t\"f\"",
+        "[ONE]\t\"only1\"",
+        "[TIME]\t\"m\"",
+        "[TWO]\t\"only2\"",
+    ]);
+    // The other tags are kept.
+    trees
+        .diff(&["--tag-only-second", "TWO"])
+        .assert_code(1)
+        .assert_lines(&[
+            "[Files differ]\t\"f\"",
+            "[Present in first dir. only]\t\"only1\"",
+            "[TWO]\t\"only2\"",
+        ]);
+    let run = trees.diff(&[
+        "--tag-different",
+        "DIFF",
+        "--format-template",
+        "{kind} {path}",
+    ]);
+    assert!(run.lines().contains(&"DIFF f"), "{run:#?}");
+    trees
+        .diff(&["--tag-different", "a]b"])
+        .assert_code(2)
+        .assert_stderr("tags cannot contain tabs, newlines or ]");
+}
+
+#[cfg(unix)]
+#[test]
+fn quiet_errors() {
+    let trees = Trees::new("quiet_errors");
+    let subdirs: Vec<_> = (0..20).map(|i| format!("d{i}")).collect();
+    for dir in &subdirs {
+        trees.write(&format!("a/{dir}/f"), "1");
+        trees.write(&format!("b/{dir}/f"), "1");
+    }
+    trees.write("a/f", "1");
+    trees.write("b/f", "2");
+    // Run on the files of the root, compared before the subdirectories are listed with a
+    // single thread, so that these vanish from the second dir.
+    trees.write("vanish.sh", "rm -rf b/d*; cmp -s \"$1\" \"$2\"");
+    let args = ["-j", "1", "--compare-cmd", "sh vanish.sh %1 %2"];
+    let run = trees.diff(&args);
+    run.assert_code(1);
+    assert_eq!(run.lines().len(), 21, "{run:#?}");
+    for dir in &subdirs {
+        trees.write(&format!("b/{dir}/f"), "1");
+    }
+    let run = trees.diff(&[&args[..], &["--quiet-errors"]].concat());
+    run.assert_code(1).assert_lines(&["[Files differ]\t\"f\""]);
+    assert_eq!(run.stderr, "20 entries could not be read\n");
+    // Still failures, as set by `--fail-on`.
+    for dir in &subdirs {
+        trees.write(&format!("b/{dir}/f"), "1");
+    }
+    trees.write("b/f", "1");
+    trees
+        .diff(&[&args[..], &["--quiet-errors"]].concat())
+        .assert_code(1)
+        .assert_lines(&[]);
+}
+
+#[test]
+fn stable_ids() {
+    let runs: Vec<_> = ["stable_ids1", "stable_ids2"]
+        .into_iter()
+        .map(|name| {
+            let trees = Trees::new(name);
+            trees.write("a/sub/f", "1");
+            trees.write("b/sub/f", "2");
+            trees.write("a/sub/g", "1");
+            let run = trees.diff(&["--stable-ids", "--format", "json", "--no-summary"]);
+            run.assert_code(1);
+            run.lines()
+                .iter()
+                .map(|line| line.to_string())
+                .collect::<Vec<_>>()
+        })
+        .collect();
+    // The same in other roots, and different for other paths.
+    assert_eq!(runs[0], runs[1]);
+    assert_eq!(
+        runs[0],
+        [
+            r#"{"kind":"different","path":"sub/f","id":"06486c8b066af148"}"#,
+            r#"{"kind":"only1","path":"sub/g","id":"064bd28b066dd471"}"#,
+        ]
+    );
+    let trees = Trees::new("stable_ids_template");
+    trees.write("a/sub/f", "1");
+    trees.write("b/sub/f", "2");
+    trees
+        .diff(&["--format-template", "{id} {path}"])
+        .assert_lines(&["06486c8b066af148 sub/f"]);
+}
+
+#[test]
+fn collation() {
+    let trees = Trees::new("collation");
+    for name in ["e", "f", "é", "sub/e", "sub/f", "sub/é"] {
+        trees.write(&format!("a/{name}"), "");
+    }
+    trees.write("b/sub/g", "");
+    let args = ["--sorted", "--collation", "locale", "a", "b"];
+    let by_byte = [
+        "[Present in first dir. only]\t\"e\"",
+        "[Present in first dir. only]\t\"f\"",
+        "[Present in first dir. only]\t\"sub/e\"",
+        "[Present in first dir. only]\t\"sub/f\"",
+        "[Present in second dir. only]\t\"sub/g\"",
+        "[Present in first dir. only]\t\"sub/é\"",
+        "[Present in first dir. only]\t\"é\"",
+    ];
+    trees.diff(&["--sorted"]).assert_stdout(&by_byte);
+    trees
+        .run_with_env(&[("LC_ALL", "C")], &args)
+        .assert_stdout(&by_byte);
+    let run = trees.run_with_env(&[("LC_ALL", "fr_FR.UTF-8")], &args);
+    run.assert_code(1);
+    // The same diffs, whatever the locale.
+    assert_eq!(run.lines(), trees.diff(&[]).lines());
+    if run.stdout.lines().eq(by_byte) {
+        // The locale is not installed.
+        return;
+    }
+    run.assert_stdout(&[
+        "[Present in first dir. only]\t\"e\"",
+        "[Present in first dir. only]\t\"é\"",
+        "[Present in first dir. only]\t\"f\"",
+        "[Present in first dir. only]\t\"sub/e\"",
+        "[Present in first dir. only]\t\"sub/é\"",
+        "[Present in first dir. only]\t\"sub/f\"",
+        "[Present in second dir. only]\t\"sub/g\"",
+    ]);
+}
+
+#[cfg(unix)]
+#[test]
+fn progress_format() {
+    let trees = Trees::new("progress_format");
+    for dir in ["d0", "d1", "d2"] {
+        trees.write(&format!("a/{dir}/f"), "1");
+        trees.write(&format!("b/{dir}/f"), "1");
+    }
+    // Slow enough for a record to be printed while the directories are compared.
+    trees.write("slow.sh", "sleep 0.6");
+    let run = trees.diff(&[
+        "-j",
+        "1",
+        "--compare-cmd",
+        "sh slow.sh",
+        "--progress-format",
+        "json",
+    ]);
+    run.assert_code(0).assert_lines(&[]);
+    let records: Vec<_> = run.stderr.lines().collect();
+    assert!(records.len() >= 2, "{run:#?}");
+    let active = records[..records.len() - 1].iter().any(|record| {
+        record.starts_with(r#"{"dirs_done":"#)
+            && record.contains(r#","files_done":"#)
+            && [
+                r#""active":["d0"]}"#,
+                r#""active":["d1"]}"#,
+                r#""active":["d2"]}"#,
+            ]
+            .iter()
+            .any(|active| record.ends_with(active))
+    });
+    assert!(active, "{run:#?}");
+    // The last one, once everything was compared.
+    assert_eq!(
+        records.last().unwrap(),
+        &r#"{"dirs_done":4,"files_done":3,"active":[]}"#
+    );
+    trees
+        .diff(&["--progress-format", "text"])
+        .assert_stderr("4 directories and 3 files compared");
+}
+
+#[test]
+fn dirs_only() {
+    let trees = Trees::new("dirs_only");
+    trees.write("a/same/f", "1");
+    trees.write("b/same/f", "1");
+    trees.write("a/x/f", "1");
+    trees.write("b/x/f", "2");
+    trees.write("a/x/y/z/g", "1");
+    trees.write("b/x/y/z/g", "2");
+    trees.write("a/x/y/z/h", "1");
+    trees.write("b/x/y/z/h", "2");
+    trees.write("a/w/only1", "");
+    trees.write("b/w/f", "");
+    trees
+        .diff(&["--dirs-only"])
+        .assert_code(1)
+        .assert_lines(&[".", "w", "x", "x/y", "x/y/z"]);
+    // Only the deepest ones, x having a diff of its own but also a changed subdirectory.
+    trees
+        .diff(&["--dirs-only-leaf"])
+        .assert_code(1)
+        .assert_lines(&["w", "x/y/z"]);
+    trees
+        .diff(&["--dirs-only", "--dir-counts"])
+        .assert_code(1)
+        .assert_lines(&[
+            ".: changed=5",
+            "w: changed=2",
+            "x/y/z: changed=2",
+            "x/y: changed=2",
+            "x: changed=3",
+        ]);
+    let run = trees.diff(&["--dirs-only-leaf", "--format", "json"]);
+    run.assert_code(1).assert_lines(&[
+        r#"{"dir":"w","changed":2}"#,
+        r#"{"dir":"x/y/z","changed":2}"#,
+    ]);
+}
+
+#[test]
+fn itemize() {
+    let trees = Trees::new("itemize");
+    trees.write("a/f", "1");
+    trees.write("b/f", "2");
+    trees.write("a/new/g", "");
+    trees.write("b/old", "");
+    trees.write("a/dir_file/h", "");
+    trees.write("b/dir_file", "");
+    trees
+        .diff(&["--format", "itemize"])
+        .assert_code(1)
+        .assert_lines(&[
+            "*deleting   \"old\"",
+            ">fc........ \"f\"",
+            "cd+++++++++ \"dir_file\"",
+            "cd+++++++++ \"new\"",
+        ]);
+}
+
+#[test]
+fn relative_output_base() {
+    let trees = Trees::new("relative_output_base");
+    trees.write("a/sub/f", "1");
+    trees.write("b/sub/f", "2");
+    trees.write("b/sub/only2", "");
+    trees.write("base/deeper/.keep", "");
+    trees
+        .diff(&["--relative-output-base", "."])
+        .assert_code(1)
+        .assert_lines(&[
+            "[Files differ]\t\"a/sub/f\"",
+            "[Present in second dir. only]\t\"b/sub/only2\"",
+        ]);
+    trees
+        .diff(&["--relative-output-base", "base/deeper"])
+        .assert_lines(&[
+            "[Files differ]\t\"../../a/sub/f\"",
+            "[Present in second dir. only]\t\"../../b/sub/only2\"",
+        ]);
+    trees
+        .diff(&["--relative-output-base", "missing"])
+        .assert_code(2)
+        .assert_stderr("Couldn't resolve missing");
+}
+
+#[test]
+fn diffstat() {
+    let trees = Trees::new("diffstat");
+    for i in 0..60 {
+        trees.write(&format!("a/busy/f{i}"), "1");
+        trees.write(&format!("b/busy/f{i}"), if i < 50 { "2" } else { "1" });
+    }
+    for i in 0..10 {
+        trees.write(&format!("b/busy/new{i}"), "");
+    }
+    trees.write("a/quiet/deep/removed", "");
+    trees.write("b/quiet/deep/.keep", "");
+    trees.write("b/quiet/added", "");
+    trees.write("a/top", "1");
+    trees.write("b/top", "2");
+    let run = trees.diff(&["--diffstat"]);
+    run.assert_code(1);
+    assert_eq!(run.stdout.lines().count(), 64, "{run:#?}");
+    // The bars are scaled to the 60 diffs of busy, keeping at least one character per kind.
+    assert_eq!(
+        run.stderr,
+        format!(
+            " .     |  1 ~\n busy  | 60 {}{}\n quiet |  3 +-\n \
+             3 directories, 51 changed (~), 12 added (+), 1 removed (-)\n",
+            "~".repeat(33),
+            "+".repeat(6)
+        )
+    );
+}
+
+#[test]
+fn expected_diff_from() {
+    let trees = Trees::new("expected_diff_from");
+    trees.write("a/build/stamp", "1");
+    trees.write("b/build/stamp", "2");
+    trees.write("a/build/gone", "");
+    trees.write("a/same", "x");
+    trees.write("b/same", "x");
+    trees.write("expected", "build/stamp\n\nbuild/gone\n");
+    // Only the listed file differs: no failure, and the listed path present on one side
+    // still fails.
+    trees
+        .diff(&["--expected-diff-from", "expected"])
+        .assert_code(1)
+        .assert_lines(&[
+            "[Expected diff]\t\"build/stamp\"",
+            "[Present in first dir. only]\t\"build/gone\"",
+        ]);
+    trees.write("b/build/gone", "");
+    trees
+        .diff(&["--expected-diff-from", "expected"])
+        .assert_code(0)
+        .assert_lines(&["[Expected diff]\t\"build/stamp\""]);
+    trees
+        .diff(&[
+            "--expected-diff-from",
+            "expected",
+            "--format",
+            "json",
+            "--no-summary",
+        ])
+        .assert_code(0)
+        .assert_lines(&[r#"{"kind":"different","path":"build/stamp","expected":true}"#]);
+    // A file not in the list still fails.
+    trees.write("b/same", "y");
+    trees
+        .diff(&["--expected-diff-from", "expected"])
+        .assert_code(1)
+        .assert_lines(&[
+            "[Expected diff]\t\"build/stamp\"",
+            "[Files differ]\t\"same\"",
+        ]);
+    trees
+        .diff(&["--expected-diff-from", "missing"])
+        .assert_code(2)
+        .assert_stderr("Couldn't open missing");
+}
+
+#[test]
+fn option_combinations() {
+    let trees = Trees::new("option_combinations");
+    let rejected = [
+        (
+            &["--tag-different", "D", "--format", "json"][..],
+            "`--tag-different` cannot be combined with `--format json`",
+        ),
+        (
+            &["--tag-mtime", "M", "--json-pretty"],
+            "`--tag-mtime` cannot be combined with `--json-pretty`",
+        ),
+        (
+            &["--no-summary"],
+            "`--no-summary` cannot be combined with `--format grepable`",
+        ),
+        (
+            &["--flush", "false", "--format", "itemize"],
+            "`--flush false` cannot be combined with `--format itemize`",
+        ),
+        (
+            &["--dir-counts"],
+            "`--dir-counts` requires `--dirs-only` or `--dirs-only-leaf`",
+        ),
+        (
+            &["--sync-format", "json"],
+            "`--sync-format` requires `--emit-sync-script` or `--apply`",
+        ),
+        (
+            &["--mtime-granularity", "s"],
+            "`--mtime-granularity` requires `--check-mtime`, `--check-dir-mtime` or \
+             `--flag-mtime-same-content-differs`",
+        ),
+        (
+            &["--min-size", "10", "--max-size", "5"],
+            "`--min-size` (10) cannot be larger than `--max-size` (5)",
+        ),
+    ];
+    for (args, error) in rejected {
+        trees.diff(args).assert_code(2).assert_stderr(error);
+    }
+    let accepted = [
+        &["--tag-different", "D"][..],
+        &["--no-summary", "--stable-ids", "--format", "json"],
+        &["--dir-counts", "--dirs-only-leaf"],
+        &["--mtime-granularity", "s", "--check-mtime"],
+        &["--min-size", "5", "--max-size", "5"],
+    ];
+    for args in accepted {
+        trees.diff(args).assert_code(0);
+    }
+}
+
+#[test]
+fn json_array_stream() {
+    let trees = Trees::new("json_array_stream");
+    for i in 0..40 {
+        trees.write(&format!("a/d{}/f{i}", i % 5), "1");
+        trees.write(&format!("b/d{}/f{i}", i % 5), "2");
+    }
+    let run = trees.diff(&["--json-array-stream", "-j", "8"]);
+    run.assert_code(1);
+    // A single array: brackets around records separated by commas, the summary last.
+    let records = run
+        .stdout
+        .strip_prefix("[\n")
+        .and_then(|stdout| stdout.strip_suffix("\n]\n"))
+        .unwrap_or_else(|| panic!("{run:#?}"));
+    let records: Vec<&str> = records.split(",\n").collect();
+    assert_eq!(records.len(), 41, "{run:#?}");
+    for record in &records[..40] {
+        assert!(
+            record.starts_with(r#"{"kind":"different","path":"d"#),
+            "{run:#?}"
+        );
+        assert!(record.ends_with("\"}"), "{run:#?}");
+    }
+    assert!(records[40].starts_with(r#"{"kind":"summary","#), "{run:#?}");
+    let trees = Trees::new("json_array_stream_empty");
+    trees
+        .diff(&["--json-array-stream", "--no-summary"])
+        .assert_code(0)
+        .assert_stdout(&["[", "]"]);
+    trees
+        .run(&["--json-array-stream", "a", "missing"])
+        .assert_code(2)
+        .assert_stdout(&[
+            "[",
+            r#"{"kind":"error","message":"dir2 (missing) does not exist, see --missing-as-empty to compare it as empty"}"#,
+            "]",
+        ]);
+}
+
+/// Kind, path, detail and sizes of a row of the `diffs` table.
+type SqliteRow = (String, String, Option<String>, Option<i64>, Option<i64>);
+
+/// Rows of the `diffs` table of the database at `path`, sorted.
+fn sqlite_rows(path: &Path) -> Vec<SqliteRow> {
+    let connection = rusqlite::Connection::open(path).unwrap();
+    let mut statement = connection
+        .prepare("SELECT kind, path, detail, size1, size2 FROM diffs ORDER BY kind, path")
+        .unwrap();
+    let rows = statement
+        .query_map([], |row| {
+            Ok((
+                row.get(0)?,
+                row.get(1)?,
+                row.get(2)?,
+                row.get(3)?,
+                row.get(4)?,
+            ))
+        })
+        .unwrap();
+    rows.collect::<Result<_, _>>().unwrap()
+}
+
+#[test]
+fn sqlite() {
+    let trees = Trees::new("sqlite");
+    trees.write("a/f", "1");
+    trees.write("b/f", "22");
+    trees.write("a/only1", "abc");
+    trees.write("b/only2/g", "");
+    trees.write("a/t", "");
+    trees.write("b/t/g", "");
+    trees
+        .diff(&["--sqlite", "diffs.db"])
+        .assert_code(1)
+        .assert_stdout(&[]);
+    let row = |kind: &str, path: &str, detail: Option<&str>, size1, size2| {
+        (
+            kind.to_owned(),
+            path.to_owned(),
+            detail.map(str::to_owned),
+            size1,
+            size2,
+        )
+    };
+    assert_eq!(
+        sqlite_rows(&trees.path().join("diffs.db")),
+        [
+            row("different", "f", None, Some(1), Some(2)),
+            row("only1", "only1", None, Some(3), None),
+            row("only2", "only2", None, None, None),
+            row(
+                "type",
+                "t",
+                Some("regular empty file -> directory"),
+                Some(0),
+                None
+            ),
+        ]
+    );
+    let connection = rusqlite::Connection::open(trees.path().join("diffs.db")).unwrap();
+    let mtimes: (Option<i64>, Option<i64>) = connection
+        .query_row(
+            "SELECT mtime1, mtime2 FROM diffs WHERE kind = 'different'",
+            [],
+            |row| Ok((row.get(0)?, row.get(1)?)),
+        )
+        .unwrap();
+    assert!(matches!(mtimes, (Some(1..), Some(1..))), "{mtimes:?}");
+    // The rows of several runs are never mixed.
+    trees
+        .diff(&["--sqlite", "diffs.db"])
+        .assert_code(2)
+        .assert_stderr("table diffs already exists");
+}
+
+#[test]
+fn sqlite_rename_map() {
+    let trees = Trees::new("sqlite_rename_map");
+    trees.write("a/v1/x", "1");
+    trees.write("b/v2/x", "22");
+    trees.write("b/v2/y", "333");
+    trees
+        .diff(&["--sqlite", "diffs.db", "--rename-map", "v1=v2"])
+        .assert_code(1);
+    // The sizes of the second side are those of the entries in b/v2.
+    assert_eq!(
+        sqlite_rows(&trees.path().join("diffs.db")),
+        [
+            (
+                "different".to_owned(),
+                "v1/x".to_owned(),
+                None,
+                Some(1),
+                Some(2)
+            ),
+            ("only2".to_owned(), "v1/y".to_owned(), None, None, Some(3)),
+        ]
+    );
+}
+
+#[cfg(unix)]
+#[test]
+fn non_utf8() {
+    use std::{ffi::OsStr, os::unix::ffi::OsStrExt, process::Command};
+    let trees = Trees::new("non_utf8");
+    let name = OsStr::from_bytes(b"caf\xe9 \\");
+    std::fs::write(trees.path().join("a").join(name), "").unwrap();
+    trees.write("a/valid", "");
+    let only1 = |path: &str| format!("[Present in first dir. only]\t{}", path);
+    // Escaped by default.
+    trees
+        .diff(&[])
+        .assert_lines(&[&only1(r#""caf\xE9 \\""#), &only1(r#""valid""#)]);
+    for (mode, path) in [
+        ("escape", r#""caf\xE9 \\""#),
+        ("lossy", "\"caf\u{fffd} \\\\\""),
+    ] {
+        trees
+            .diff(&["--non-utf8", mode])
+            .assert_lines(&[&only1(path), &only1(r#""valid""#)]);
+    }
+    let run = trees.diff(&["--non-utf8", "skip"]);
+    run.assert_code(1)
+        .assert_lines(&[&only1(r#""valid""#)])
+        .assert_stderr(r#"skipped a diff about "caf\xE9 \\""#);
+    // Raw bytes, even without quotes.
+    let output = Command::new(env!("CARGO_BIN_EXE_dirdiff"))
+        .args([
+            "--non-utf8",
+            "bytes",
+            "--format-template",
+            "{path}\\0",
+            "a",
+            "b",
+        ])
+        .current_dir(trees.path())
+        .output()
+        .unwrap();
+    let mut paths: Vec<_> = output.stdout.split(|&b| b == 0).collect();
+    paths.sort_unstable();
+    assert_eq!(paths, [&b""[..], b"caf\xe9 \\", b"valid"]);
+}
+
+#[test]
+fn suppress_from() {
+    let trees = Trees::new("suppress_from");
+    trees.write("a/known", "1");
+    trees.write("b/known", "2");
+    trees.write("a/same", "x");
+    trees.write("b/same", "x");
+    let grepable = trees.diff(&[]).stdout.clone();
+    let json = trees.diff(&["--format", "json"]).stdout.clone();
+    trees.write("grepable.txt", grepable);
+    trees.write("baseline.json", json);
+    // A new difference, and the known one of another kind.
+    trees.write("b/same", "y");
+    std::fs::remove_file(trees.path().join("b/known")).unwrap();
+    trees.write("b/known/f", "");
+    for baseline in ["grepable.txt", "baseline.json"] {
+        trees
+            .diff(&["--suppress-from", baseline])
+            .assert_code(1)
+            .assert_lines(&[
+                "[Files differ]\t\"same\"",
+                "[Types differ]\t\"known\"\tis a regular file in first dir. while a directory in second dir.",
+            ]);
+    }
+    std::fs::remove_dir_all(trees.path().join("b/known")).unwrap();
+    trees.write("b/known", "2");
+    trees.write("b/same", "x");
+    trees
+        .diff(&["--suppress-from", "grepable.txt"])
+        .assert_code(0)
+        .assert_lines(&[]);
+    trees.write("bad.txt", "not a diff\n");
+    trees
+        .diff(&["--suppress-from", "bad.txt"])
+        .assert_code(2)
+        .assert_stderr("Couldn't parse line 1 of bad.txt as a diff");
+}
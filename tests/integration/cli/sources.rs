@@ -0,0 +1,295 @@
+//! Where the trees are read from.
+
+use super::Trees;
+use dirdiff::manifest;
+
+#[test]
+fn pairs_from() {
+    let trees = Trees::new("pairs");
+    trees.write("a/f", "1");
+    trees.write("b/f", "2");
+    trees.write("a/sub/g", "x");
+    trees.write("b/sub/g", "y");
+    trees.write("c/same", "same");
+    trees.write("pairs", "a\tb\na/sub\tb/sub\nc\tc\n");
+    trees
+        .run(&["--pairs-from", "pairs"])
+        .assert_code(1)
+        .assert_lines(&[
+            "1\t[Files differ]\t\"f\"",
+            "1\t[Files differ]\t\"sub/g\"",
+            "2\t[Files differ]\t\"g\"",
+        ]);
+    trees.write("pairs", "c\tc\n");
+    trees
+        .run(&["--pairs-from", "pairs"])
+        .assert_code(0)
+        .assert_lines(&[]);
+}
+
+#[test]
+fn missing_roots() {
+    let trees = Trees::new("missing");
+    trees.write("a/f", "");
+    let run = trees.run(&["a", "missing"]);
+    run.assert_code(2)
+        .assert_stderr("dir2 (missing) does not exist");
+    assert!(run.stdout.is_empty());
+    trees
+        .run(&["a/f", "b"])
+        .assert_code(2)
+        .assert_stderr("dir1 (a/f) is not a directory");
+    trees
+        .run(&["--missing-as-empty", "a", "missing"])
+        .assert_code(1)
+        .assert_lines(&["[Present in first dir. only]\t\"f\""]);
+    trees
+        .run(&["--missing-as-empty", "missing", "a"])
+        .assert_code(1)
+        .assert_lines(&["[Present in second dir. only]\t\"f\""]);
+    // Only missing roots are compared as empty.
+    trees
+        .run(&["--missing-as-empty", "a/f", "missing"])
+        .assert_code(2)
+        .assert_stderr("dir1 (a/f) is not a directory");
+}
+
+#[cfg(unix)]
+#[test]
+fn unreadable_root() {
+    use std::{fs, os::unix::fs::PermissionsExt};
+    let trees = Trees::new("unreadable");
+    let root = trees.path().join("b");
+    fs::set_permissions(&root, fs::Permissions::from_mode(0o000)).unwrap();
+    // Unless run by root, who can read it anyway.
+    if fs::read_dir(&root).is_err() {
+        trees
+            .run(&["a", "b"])
+            .assert_code(2)
+            .assert_stderr("dir2 (b) is not readable");
+        trees
+            .run(&["--missing-as-empty", "a", "b"])
+            .assert_code(2)
+            .assert_stderr("dir2 (b) is not readable");
+    }
+    fs::set_permissions(&root, fs::Permissions::from_mode(0o755)).unwrap();
+}
+
+#[test]
+fn empty_second() {
+    let trees = Trees::new("empty_second");
+    trees.write("a/f", "");
+    trees.write("a/sub/g", "");
+    trees.write("a/sub/deeper/h", "");
+    let inventory = [
+        "[Present in first dir. only]\t\"f\"",
+        "[Present in first dir. only]\t\"sub\"",
+        "[Present in first dir. only]\t\"sub/deeper\"",
+        "[Present in first dir. only]\t\"sub/deeper/h\"",
+        "[Present in first dir. only]\t\"sub/g\"",
+    ];
+    trees
+        .run(&["--empty-second", "a"])
+        .assert_code(1)
+        .assert_lines(&inventory);
+    trees
+        .run(&["a", "/dev/null"])
+        .assert_code(1)
+        .assert_lines(&inventory);
+}
+
+/// Writes a tar archive at `path` in the trees, of the `(path, content)` files.
+fn write_tar(trees: &Trees, path: &str, files: &[(&str, &str)]) {
+    let mut builder = tar::Builder::new(std::fs::File::create(trees.path().join(path)).unwrap());
+    for (path, content) in files {
+        let mut header = tar::Header::new_gnu();
+        header.set_size(content.len() as u64);
+        header.set_mode(0o644);
+        builder
+            .append_data(&mut header, path, content.as_bytes())
+            .unwrap();
+    }
+    builder.finish().unwrap();
+}
+
+#[test]
+fn tar() {
+    let trees = Trees::new("tar");
+    trees.write("a/f", "1");
+    trees.write("a/sub/g", "content");
+    trees.write("a/sub/deeper/h", "");
+    write_tar(
+        &trees,
+        "same.tar",
+        &[("f", "1"), ("sub/g", "content"), ("sub/deeper/h", "")],
+    );
+    trees
+        .run(&["a", "same.tar"])
+        .assert_code(0)
+        .assert_lines(&[]);
+    trees.run(&["same.tar", "a"]).assert_code(0);
+    write_tar(
+        &trees,
+        "tampered",
+        &[("f", "2"), ("sub/g", "contenT"), ("sub/other", "")],
+    );
+    trees
+        .run(&["--tar2", "a", "tampered"])
+        .assert_code(1)
+        .assert_lines(&[
+            "[Files differ]\t\"f\"",
+            "[Files differ]\t\"sub/g\"",
+            "[Present in first dir. only]\t\"sub/deeper\"",
+            "[Present in second dir. only]\t\"sub/other\"",
+        ]);
+}
+
+/// Writes a zip archive at `path` in the trees, of the `(name, content)` files, deflating
+/// those whose name ends in `.txt`.
+fn write_zip(trees: &Trees, path: &str, files: &[(&str, &str)]) {
+    let mut writer = zip::ZipWriter::new(std::fs::File::create(trees.path().join(path)).unwrap());
+    for (name, content) in files {
+        let method = match name.ends_with(".txt") {
+            true => zip::CompressionMethod::Deflated,
+            false => zip::CompressionMethod::Stored,
+        };
+        let options = zip::write::FileOptions::default().compression_method(method);
+        writer.start_file(*name, options).unwrap();
+        std::io::Write::write_all(&mut writer, content.as_bytes()).unwrap();
+    }
+    writer.finish().unwrap();
+}
+
+#[test]
+fn zip() {
+    let trees = Trees::new("zip");
+    trees.write("a/f", "1");
+    trees.write("a/sub/g.txt", "content ".repeat(100));
+    trees.write("a/sub/deeper/h", "");
+    write_zip(
+        &trees,
+        "same.zip",
+        &[
+            ("f", "1"),
+            ("sub/g.txt", &"content ".repeat(100)),
+            ("sub\\deeper\\h", ""),
+        ],
+    );
+    trees
+        .run(&["a", "same.zip"])
+        .assert_code(0)
+        .assert_lines(&[]);
+    trees.run(&["same.zip", "a"]).assert_code(0);
+    write_zip(
+        &trees,
+        "tampered",
+        &[
+            ("f", "1"),
+            ("sub/g.txt", &"contenT ".repeat(100)),
+            ("sub/deeper/h", ""),
+        ],
+    );
+    trees
+        .run(&["--zip2", "a", "tampered"])
+        .assert_code(1)
+        .assert_lines(&["[Files differ]\t\"sub/g.txt\""]);
+}
+
+#[test]
+fn overlay() {
+    let trees = Trees::new("overlay");
+    // The merged view, as the first directory.
+    trees.write("a/kept", "1");
+    trees.write("a/changed", "new");
+    trees.write("a/added", "");
+    trees.write("a/sub/kept", "1");
+    trees.write("a/opaque/new", "");
+    trees.write("lower/kept", "1");
+    trees.write("lower/changed", "old");
+    trees.write("lower/removed", "");
+    trees.write("lower/sub/kept", "1");
+    trees.write("lower/sub/removed", "");
+    trees.write("lower/opaque/old", "");
+    trees.write("upper/changed", "new");
+    trees.write("upper/added", "");
+    trees.write("upper/.wh.removed", "");
+    trees.write("upper/sub/.wh.removed", "");
+    trees.write("upper/opaque/.wh..wh..opq", "");
+    trees.write("upper/opaque/new", "");
+    let args = ["--lower", "lower", "--upper", "upper", "a"];
+    trees.run(&args).assert_code(0).assert_lines(&[]);
+    // Without the whiteouts, the lower entries are back.
+    for whiteout in ["upper/.wh.removed", "upper/opaque/.wh..wh..opq"] {
+        std::fs::remove_file(trees.path().join(whiteout)).unwrap();
+    }
+    trees.run(&args).assert_code(1).assert_lines(&[
+        "[Present in second dir. only]\t\"opaque/old\"",
+        "[Present in second dir. only]\t\"removed\"",
+    ]);
+}
+
+#[test]
+fn exec_listing() {
+    let trees = Trees::new("exec_listing");
+    for root in ["a", "b"] {
+        trees.write(&format!("{root}/f"), "1");
+        trees.write(&format!("{root}/sub/g"), "content");
+        trees.write(&format!("{root}/sub/deeper/h"), "");
+    }
+    #[cfg(unix)]
+    for root in ["a", "b"] {
+        std::os::unix::fs::symlink("f", trees.path().join(root).join("link")).unwrap();
+    }
+    // A canned manifest of b, printed by the command.
+    let manifest = || {
+        let mut manifest = Vec::new();
+        let entries = manifest::manifest_of(&trees.path().join("b")).unwrap();
+        manifest::write_manifest(&entries, &mut manifest).unwrap();
+        std::fs::write(trees.path().join("listing"), manifest).unwrap();
+    };
+    manifest();
+    trees
+        .run(&["--exec-listing", "cat listing", "a"])
+        .assert_code(0)
+        .assert_lines(&[]);
+    trees.write("b/f", "2");
+    trees.write("b/sub/g", "contenT");
+    trees.write("b/sub/other", "");
+    std::fs::remove_dir_all(trees.path().join("b/sub/deeper")).unwrap();
+    manifest();
+    trees
+        .run(&["--exec-listing", "cat listing", "a"])
+        .assert_code(1)
+        .assert_lines(&[
+            "[Files differ]\t\"f\"",
+            "[Files differ]\t\"sub/g\"",
+            "[Present in first dir. only]\t\"sub/deeper\"",
+            "[Present in second dir. only]\t\"sub/other\"",
+        ]);
+    trees.write("listing", "f\t1\t-\tf\n");
+    trees
+        .run(&["--exec-listing", "cat listing", "a"])
+        .assert_code(2)
+        .assert_stderr("Not a manifest, expected a `#dirdiff-manifest v1` header");
+    trees
+        .run(&["--exec-listing", "false", "a"])
+        .assert_code(2)
+        .assert_stderr("Listing command false failed");
+}
+
+/// Options needing plain directories reject the other kinds of trees.
+#[test]
+fn non_dir_trees() {
+    let trees = Trees::new("non_dir_trees");
+    for args in [
+        &["--sqlite", "db", "--exec-listing", "cat listing", "a"][..],
+        &["--print-hashes", "--zip2", "a", "b"],
+        &["--emit-sync-script", "--upper", "a", "b"],
+        &["--find-duplicates", "--tar1", "a", "b"],
+    ] {
+        trees
+            .run(args)
+            .assert_code(2)
+            .assert_stderr("cannot be used with");
+    }
+}
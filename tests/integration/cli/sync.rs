@@ -0,0 +1,191 @@
+//! Options making the second tree match the first one.
+
+use super::Trees;
+use std::{
+    fs::File,
+    time::{Duration, SystemTime},
+};
+
+/// Trees with an entry of each kind of action, and with `sub` differing by mtime while entries
+/// are copied into it.
+fn trees(name: &str) -> Trees {
+    let trees = Trees::new(name);
+    trees.write("a/f", "1");
+    trees.write("b/f", "2");
+    trees.write("a/sub/g", "x");
+    trees.write("b/sub/g", "y");
+    trees.write("a/sub/only1", "");
+    trees.write("b/only2", "");
+    trees.write("a/same", "same");
+    trees.write("b/same", "same");
+    trees.write("a/touched", "same");
+    trees.write("b/touched", "same");
+    let set_mtime = |path: &str, secs| {
+        let mtime = SystemTime::UNIX_EPOCH + Duration::from_secs(secs);
+        let file = File::open(trees.path().join(path)).unwrap();
+        file.set_modified(mtime).unwrap();
+    };
+    // The identical files were written at slightly different times.
+    set_mtime("a/same", 1 << 29);
+    set_mtime("b/same", 1 << 29);
+    set_mtime("a/touched", 1 << 30);
+    set_mtime("a/sub", 1 << 30);
+    trees
+}
+
+#[test]
+fn emit_sync_script() {
+    let run =
+        trees("sync_script").diff(&["--emit-sync-script", "--check-mtime", "--check-dir-mtime"]);
+    run.assert_code(1);
+    let lines: Vec<_> = run.stdout.lines().collect();
+    assert_eq!(lines[0], "#!/bin/sh");
+    let commands: Vec<_> = lines
+        .iter()
+        .filter(|line| !line.starts_with('#') && **line != "set -e")
+        .copied()
+        .collect();
+    // The mtimes are set last, once nothing is copied into the directories anymore.
+    let (others, touches) = commands.split_at(commands.len() - 2);
+    let mut touches = touches.to_vec();
+    touches.sort_unstable();
+    assert_eq!(
+        touches,
+        [
+            "touch -h -r 'a/sub' -- 'b/sub'",
+            "touch -h -r 'a/touched' -- 'b/touched'",
+        ]
+    );
+    let mut others = others.to_vec();
+    others.sort_unstable();
+    assert_eq!(
+        others,
+        [
+            "cp -pPR -- 'a/sub/only1' 'b/sub/only1'",
+            "rm -rf -- 'b/f' && cp -pPR -- 'a/f' 'b/f'",
+            "rm -rf -- 'b/only2'",
+            "rm -rf -- 'b/sub/g' && cp -pPR -- 'a/sub/g' 'b/sub/g'",
+        ]
+    );
+    run.assert_stderr("remove 1 entries present in the second directory only");
+}
+
+#[test]
+fn sync_format_json() {
+    trees("sync_json")
+        .diff(&["--emit-sync-script", "--sync-format", "json"])
+        .assert_code(1)
+        .assert_lines(&[
+            r#"{"action":"copy","from":"a/sub/only1","to":"b/sub/only1"}"#,
+            r#"{"action":"remove","path":"b/only2"}"#,
+            r#"{"action":"replace","from":"a/f","to":"b/f"}"#,
+            r#"{"action":"replace","from":"a/sub/g","to":"b/sub/g"}"#,
+        ]);
+}
+
+#[cfg(unix)]
+#[test]
+fn run_sync_script() {
+    let trees = trees("run_script");
+    let args = ["--check-mtime", "--check-dir-mtime"];
+    let script = trees
+        .diff(&[&["--emit-sync-script"], &args[..]].concat())
+        .stdout;
+    trees.write("sync.sh", script);
+    let status = std::process::Command::new("sh")
+        .arg("sync.sh")
+        .current_dir(trees.path())
+        .status()
+        .unwrap();
+    assert!(status.success());
+    trees.diff(&args).assert_code(0).assert_lines(&[]);
+}
+
+/// Symlinks followed by `-L` are touched themselves, rather than their targets, which may be
+/// out of the trees.
+#[cfg(unix)]
+#[test]
+fn run_sync_script_on_symlinks() {
+    let trees = Trees::new("run_script_symlinks");
+    trees.write("a/f", "same");
+    let outside = trees.write("outside", "same");
+    let set_mtime = |path: &std::path::Path, secs| {
+        let mtime = SystemTime::UNIX_EPOCH + Duration::from_secs(secs);
+        File::open(path).unwrap().set_modified(mtime).unwrap();
+    };
+    set_mtime(&trees.path().join("a/f"), 1 << 30);
+    set_mtime(&outside, 1 << 29);
+    std::os::unix::fs::symlink("../outside", trees.path().join("b/f")).unwrap();
+    let script = trees
+        .diff(&["--emit-sync-script", "--check-mtime", "-L"])
+        .stdout;
+    assert!(script.ends_with("touch -h -r 'a/f' -- 'b/f'\n"), "{script}");
+    trees.write("sync.sh", script);
+    let status = std::process::Command::new("sh")
+        .arg("sync.sh")
+        .current_dir(trees.path())
+        .status()
+        .unwrap();
+    assert!(status.success());
+    let mtime = outside.metadata().unwrap().modified().unwrap();
+    assert_eq!(mtime, SystemTime::UNIX_EPOCH + Duration::from_secs(1 << 29));
+}
+
+#[test]
+fn apply() {
+    let trees = trees("apply");
+    let args = ["--check-mtime", "--check-dir-mtime"];
+    // Nothing is modified without `--apply`.
+    let before = trees.diff(&args);
+    before.assert_code(1);
+    trees
+        .diff(&[&["--emit-sync-script"], &args[..]].concat())
+        .assert_code(1);
+    assert_eq!(trees.diff(&args).stdout, before.stdout);
+    let run = trees.diff(&[&["--apply"], &args[..]].concat());
+    run.assert_code(1)
+        .assert_stderr("Kept 1 entries present in the second directory only");
+    trees
+        .diff(&args)
+        .assert_code(1)
+        .assert_lines(&["[Present in second dir. only]\t\"only2\""]);
+    trees
+        .diff(&["--apply", "--delete"])
+        .assert_code(1)
+        .assert_lines(&["rm -rf -- 'b/only2'"]);
+    trees.diff(&args).assert_code(0).assert_lines(&[]);
+}
+
+/// Symlinks are copied as symlinks, with their own mtime.
+#[cfg(unix)]
+#[test]
+fn apply_symlinks() {
+    use std::os::unix::fs::symlink;
+    let trees = Trees::new("apply_symlinks");
+    trees.write("a/sub/f", "");
+    symlink("f", trees.path().join("a/sub/link")).unwrap();
+    symlink("sub", trees.path().join("a/dir_link")).unwrap();
+    let status = std::process::Command::new("touch")
+        .args(["-h", "-d", "@1000000000", "a/sub/link", "a/dir_link"])
+        .current_dir(trees.path())
+        .status()
+        .unwrap();
+    assert!(status.success());
+    trees
+        .diff(&["--apply", "--check-mtime"])
+        .assert_code(1)
+        .assert_lines(&[
+            "cp -pPR -- 'a/dir_link' 'b/dir_link'",
+            "cp -pPR -- 'a/sub' 'b/sub'",
+        ]);
+    trees.diff(&["--check-mtime"]).assert_code(0);
+    for link in ["b/sub/link", "b/dir_link"] {
+        let meta = trees.path().join(link).symlink_metadata().unwrap();
+        assert!(meta.file_type().is_symlink());
+        let mtime = meta.modified().unwrap();
+        assert_eq!(
+            mtime,
+            SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000_000)
+        );
+    }
+}
@@ -0,0 +1,1000 @@
+//! Options choosing which entries are traversed and compared, and how symlinks are handled.
+
+use super::Trees;
+
+#[cfg(unix)]
+fn symlink(trees: &Trees, target: &str, path: &str) {
+    std::os::unix::fs::symlink(target, trees.path().join(path)).unwrap();
+}
+
+#[cfg(unix)]
+#[test]
+fn follow_root_symlinks() {
+    let trees = Trees::new("follow_roots");
+    trees.write("a/f", "1");
+    trees.write("b/f", "2");
+    symlink(&trees, "a", "la");
+    symlink(&trees, "la", "lla");
+    symlink(&trees, "b", "lb");
+    for args in [&["-H", "lla", "lb"][..], &["lla", "lb"]] {
+        trees
+            .run(args)
+            .assert_code(1)
+            .assert_lines(&["[Files differ]\t\"f\""]);
+    }
+}
+
+/// Runs `mklink` with `args` from the trees' directory, returning whether it succeeded.
+#[cfg(windows)]
+fn mklink(trees: &Trees, args: &[&str]) -> bool {
+    std::process::Command::new("cmd")
+        .args(["/C", "mklink"])
+        .args(args)
+        .current_dir(trees.path())
+        .status()
+        .is_ok_and(|status| status.success())
+}
+
+#[cfg(windows)]
+#[test]
+fn junctions() {
+    let trees = Trees::new("junctions");
+    trees.write("a/t/f", "1");
+    trees.write("b/t/f", "1");
+    let (target1, target2) = (trees.path().join("a\\t"), trees.path().join("b\\t"));
+    assert!(mklink(&trees, &["/J", "a\\j", target1.to_str().unwrap()]));
+    assert!(mklink(&trees, &["/J", "b\\j", target2.to_str().unwrap()]));
+    // Junctions are compared by their targets, unless followed.
+    trees
+        .diff(&[])
+        .assert_code(1)
+        .assert_lines(&["[Files differ]\t\"j\""]);
+    trees.diff(&["-L"]).assert_code(0);
+    // A symlink to the same absolute path as a junction is equal to it. Creating symlinks needs
+    // the developer mode or an elevated prompt.
+    std::fs::remove_dir(trees.path().join("b\\j")).unwrap();
+    if mklink(&trees, &["/D", "b\\j", target1.to_str().unwrap()]) {
+        trees.diff(&[]).assert_code(0).assert_lines(&[]);
+    }
+}
+
+#[test]
+fn file_and_directory() {
+    let trees = Trees::new("file_and_dir");
+    trees.write("a/empty/f", "");
+    trees.write("b/empty", "");
+    trees.write("a/nonempty", "x");
+    trees.write("b/nonempty/f", "");
+    trees.diff(&[]).assert_code(1).assert_lines(&[
+        "[Types differ]\t\"empty\"\tis a directory in first dir. while a regular empty file in second dir.",
+        "[Types differ]\t\"nonempty\"\tis a regular file in first dir. while a directory in second dir.",
+    ]);
+}
+
+#[cfg(unix)]
+#[test]
+fn resolve_symlink_targets() {
+    let trees = Trees::new("resolve_targets");
+    for root in ["a", "b"] {
+        trees.write(&format!("{root}/q"), "q");
+        trees.write(&format!("{root}/r"), "r");
+    }
+    // A relative and an absolute link to the same file in their trees.
+    symlink(&trees, "q", "a/same");
+    let absolute = trees.path().join("b/q");
+    symlink(&trees, absolute.to_str().unwrap(), "b/same");
+    symlink(&trees, "q", "a/other");
+    symlink(&trees, "r", "b/other");
+    let run = trees.diff(&[]);
+    let same = format!("[Link targets differ]\t\"same\"\t\"q\" -> {:?}", absolute);
+    run.assert_lines(&["[Link targets differ]\t\"other\"\t\"q\" -> \"r\"", &same]);
+    trees
+        .diff(&["--resolve-symlink-targets"])
+        .assert_code(1)
+        .assert_lines(&["[Link targets differ]\t\"other\"\t\"q\" -> \"r\""]);
+}
+
+#[cfg(unix)]
+#[test]
+fn link_targets() {
+    let trees = Trees::new("link_targets");
+    symlink(&trees, "old/target", "a/link");
+    symlink(&trees, "new/target", "b/link");
+    symlink(&trees, "same", "a/same");
+    symlink(&trees, "same", "b/same");
+    trees
+        .diff(&[])
+        .assert_code(1)
+        .assert_lines(&["[Link targets differ]\t\"link\"\t\"old/target\" -> \"new/target\""]);
+    trees
+        .diff(&["--format", "json", "--no-summary"])
+        .assert_lines(&[
+            r#"{"kind":"link_target","path":"link","target1":"old/target","target2":"new/target"}"#,
+        ]);
+}
+
+#[cfg(unix)]
+#[test]
+fn ignore_symlinks() {
+    let trees = Trees::new("ignore_symlinks");
+    trees.write("a/f", "1");
+    trees.write("b/f", "2");
+    symlink(&trees, "f", "a/same");
+    symlink(&trees, "f", "b/same");
+    symlink(&trees, "f", "a/differ");
+    symlink(&trees, "g", "b/differ");
+    symlink(&trees, "f", "a/only1");
+    // A link in one tree and a file in the other one.
+    symlink(&trees, "f", "a/file");
+    trees.write("b/file", "");
+    trees.diff(&[]).assert_lines(&[
+        "[Files differ]\t\"f\"",
+        "[Link targets differ]\t\"differ\"\t\"f\" -> \"g\"",
+        "[Present in first dir. only]\t\"only1\"",
+        "[Types differ]\t\"file\"\tis a symbolic link in first dir. while a regular empty file in second dir.",
+    ]);
+    trees
+        .diff(&["--ignore-symlinks"])
+        .assert_code(1)
+        .assert_lines(&[
+            "[Files differ]\t\"f\"",
+            "[Present in second dir. only]\t\"file\"",
+        ]);
+}
+
+#[test]
+fn detect_case_collisions() {
+    let trees = Trees::new("case_collisions");
+    trees.write("a/Foo", "");
+    trees.write("a/foo", "");
+    trees.write("b/foo", "");
+    trees
+        .diff(&[])
+        .assert_lines(&["[Present in first dir. only]\t\"Foo\""]);
+    trees
+        .diff(&["--detect-case-collisions"])
+        .assert_code(1)
+        .assert_lines(&[
+            "[Case collision]\t\"Foo\"\tcollides with \"foo\"",
+            "[Present in first dir. only]\t\"Foo\"",
+        ]);
+}
+
+#[test]
+fn shallow() {
+    let trees = Trees::new("shallow");
+    trees.write("a/same/x", "1");
+    trees.write("b/same/x", "2");
+    trees.write("a/sub/f", "");
+    trees.write("b/sub/g", "");
+    trees.write("a/top", "");
+    trees.write("b/top", "");
+    trees.diff(&[]).assert_code(1).assert_lines(&[
+        "[Files differ]\t\"same/x\"",
+        "[Present in first dir. only]\t\"sub/f\"",
+        "[Present in second dir. only]\t\"sub/g\"",
+    ]);
+    // The content of files is not read, and directories are reported once.
+    trees.diff(&["--shallow"]).assert_code(1).assert_lines(&[
+        "[Entries differ]\t\"sub\"\thas 1 entries in first dir. while 1 in second dir.",
+    ]);
+    trees.write("a/extra", "");
+    trees.diff(&["--shallow"]).assert_code(1).assert_lines(&[
+        "[Entries differ]\t\"\"\thas 4 entries in first dir. while 3 in second dir.",
+        "[Entries differ]\t\"sub\"\thas 1 entries in first dir. while 1 in second dir.",
+    ]);
+}
+
+#[cfg(unix)]
+#[test]
+fn regular_only() {
+    use std::{ffi::CString, os::unix::net::UnixListener};
+    let trees = Trees::new("regular_only");
+    trees.write("a/sub/f", "1");
+    trees.write("b/sub/f", "2");
+    for side in ["a", "b"] {
+        let dir = trees.path().join(side).join("sub");
+        let _socket = UnixListener::bind(dir.join("socket")).unwrap();
+        let fifo = CString::new(dir.join("fifo").into_os_string().into_encoded_bytes()).unwrap();
+        assert_eq!(unsafe { libc::mkfifo(fifo.as_ptr(), 0o644) }, 0);
+    }
+    symlink(&trees, "sub/f", "a/link");
+    trees.diff(&[]).assert_code(1).assert_lines(&[
+        "[Files differ]\t\"sub/f\"",
+        "[Present in first dir. only]\t\"link\"",
+    ]);
+    trees
+        .diff(&["--regular-only"])
+        .assert_code(1)
+        .assert_lines(&["[Files differ]\t\"sub/f\""]);
+}
+
+#[cfg(unix)]
+#[test]
+fn fifos_and_sockets() {
+    use std::{ffi::CString, os::unix::net::UnixListener};
+    let trees = Trees::new("fifos_and_sockets");
+    let mkfifo = |path: &str| {
+        let fifo = trees
+            .path()
+            .join(path)
+            .into_os_string()
+            .into_encoded_bytes();
+        let fifo = CString::new(fifo).unwrap();
+        assert_eq!(unsafe { libc::mkfifo(fifo.as_ptr(), 0o644) }, 0);
+    };
+    trees.write("a/sub/f", "");
+    trees.write("b/sub/f", "");
+    // Nothing ever writes to these, so that opening them would block.
+    mkfifo("a/sub/fifo");
+    mkfifo("b/sub/fifo");
+    let _sockets = ["a", "b"]
+        .map(|side| UnixListener::bind(trees.path().join(side).join("sub/socket")).unwrap());
+    trees.diff(&[]).assert_code(0).assert_lines(&[]);
+    mkfifo("a/fifo_file");
+    trees.write("b/fifo_file", "");
+    trees.diff(&[]).assert_code(1).assert_lines(&[
+        "[Types differ]\t\"fifo_file\"\tis a FIFO in first dir. while a regular empty file in second dir.",
+    ]);
+}
+
+#[cfg(unix)]
+#[test]
+fn type_mismatches() {
+    use std::{ffi::CString, os::unix::net::UnixListener};
+    let trees = Trees::new("type_mismatches");
+    trees.write("a/dir_file/f", "");
+    trees.write("b/dir_file", "1");
+    trees.write("a/dir_link/f", "");
+    symlink(&trees, "dir_file", "b/dir_link");
+    trees.write("a/file_link", "1");
+    symlink(&trees, "dir_file", "b/file_link");
+    trees.write("a/file_fifo", "");
+    let fifo = CString::new(
+        trees
+            .path()
+            .join("b/file_fifo")
+            .into_os_string()
+            .into_encoded_bytes(),
+    );
+    assert_eq!(unsafe { libc::mkfifo(fifo.unwrap().as_ptr(), 0o644) }, 0);
+    symlink(&trees, "file_link", "a/link_socket");
+    let _socket = UnixListener::bind(trees.path().join("b/link_socket")).unwrap();
+    trees.diff(&[]).assert_code(1).assert_lines(&[
+        "[Types differ]\t\"dir_file\"\tis a directory in first dir. while a regular file in second dir.",
+        "[Types differ]\t\"dir_link\"\tis a directory in first dir. while a symbolic link in second dir.",
+        "[Types differ]\t\"file_fifo\"\tis a regular empty file in first dir. while a FIFO in second dir.",
+        "[Types differ]\t\"file_link\"\tis a regular file in first dir. while a symbolic link in second dir.",
+        "[Types differ]\t\"link_socket\"\tis a symbolic link in first dir. while a socket in second dir.",
+    ]);
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn secure() {
+    let trees = Trees::new("secure");
+    trees.write("a/sub/deeper/f", "1");
+    trees.write("b/sub/deeper/f", "2");
+    trees.write("a/sub/g", "");
+    trees.write("b/sub/g", "");
+    trees.write("a/only1", "");
+    trees.diff(&["--secure"]).assert_code(1).assert_lines(&[
+        "[Files differ]\t\"sub/deeper/f\"",
+        "[Present in first dir. only]\t\"only1\"",
+    ]);
+}
+
+#[test]
+fn collapse() {
+    let trees = Trees::new("collapse");
+    trees.write("a/disjoint/f", "");
+    trees.write("a/disjoint/g", "");
+    trees.write("b/disjoint/h", "");
+    trees.write("a/shared/f", "");
+    trees.write("a/shared/same", "");
+    trees.write("b/shared/same", "");
+    trees.write("b/shared/h", "");
+    trees.diff(&[]).assert_code(1).assert_lines(&[
+        "[Present in first dir. only]\t\"disjoint/f\"",
+        "[Present in first dir. only]\t\"disjoint/g\"",
+        "[Present in first dir. only]\t\"shared/f\"",
+        "[Present in second dir. only]\t\"disjoint/h\"",
+        "[Present in second dir. only]\t\"shared/h\"",
+    ]);
+    // Directories sharing a name are still reported entry by entry.
+    trees.diff(&["--collapse"]).assert_code(1).assert_lines(&[
+        "[Entries differ]\t\"disjoint\"\thas 2 entries in first dir. while 1 in second dir.",
+        "[Present in first dir. only]\t\"shared/f\"",
+        "[Present in second dir. only]\t\"shared/h\"",
+    ]);
+}
+
+#[cfg(unix)]
+#[test]
+fn prune_unchanged_dirs() {
+    use std::{
+        fs::File,
+        time::{Duration, SystemTime},
+    };
+    let trees = Trees::new("prune_unchanged_dirs");
+    trees.write("a/old/f", "1");
+    trees.write("b/old/f", "2");
+    trees.write("a/recent/f", "1");
+    trees.write("b/recent/f", "2");
+    trees.write("a/f", "1");
+    trees.write("b/f", "2");
+    let reference = trees.write("backup", "");
+    let day = Duration::from_secs(24 * 60 * 60);
+    File::open(&reference)
+        .unwrap()
+        .set_modified(SystemTime::now() - day)
+        .unwrap();
+    for dir in ["a/old", "b/old"] {
+        File::open(trees.path().join(dir))
+            .unwrap()
+            .set_modified(SystemTime::now() - 2 * day)
+            .unwrap();
+    }
+    // Missed by design, as the files were modified in place.
+    trees
+        .diff(&["--prune-unchanged-dirs", "backup"])
+        .assert_code(1)
+        .assert_lines(&["[Files differ]\t\"f\"", "[Files differ]\t\"recent/f\""]);
+    // Only pruned if older on both sides.
+    File::open(trees.path().join("b/old"))
+        .unwrap()
+        .set_modified(SystemTime::now())
+        .unwrap();
+    trees
+        .diff(&["--prune-unchanged-dirs", "backup"])
+        .assert_code(1)
+        .assert_lines(&[
+            "[Files differ]\t\"f\"",
+            "[Files differ]\t\"old/f\"",
+            "[Files differ]\t\"recent/f\"",
+        ]);
+}
+
+#[cfg(unix)]
+#[test]
+fn show_resolved() {
+    let trees = Trees::new("show_resolved");
+    trees.write("a/t", "x");
+    trees.write("b/t", "x");
+    symlink(&trees, "t", "a/link");
+    trees.write("b/link", "y");
+    let resolved = trees.path().canonicalize().unwrap().join("a/t");
+    trees
+        .diff(&["-L", "--show-resolved"])
+        .assert_code(1)
+        .assert_stdout(&[
+            "[Files differ]\t\"link\"",
+            &format!(
+                "[Resolved links]\t\"link\"\tresolves to {} in first dir.",
+                resolved.display()
+            ),
+        ]);
+    // Equal entries are not reported, wherever they resolved to.
+    trees.write("b/link", "x");
+    trees
+        .diff(&["-L", "--show-resolved"])
+        .assert_code(0)
+        .assert_lines(&[]);
+}
+
+#[test]
+fn size_range() {
+    let trees = Trees::new("size_range");
+    for (name, size) in [("small", 9), ("min", 10), ("max", 20), ("large", 21)] {
+        trees.write(&format!("a/{name}"), "1".repeat(size));
+        trees.write(&format!("b/{name}"), "2".repeat(size));
+    }
+    // In range on one side only, so reported as the sizes differ.
+    trees.write("a/grown", "1".repeat(15));
+    trees.write("b/grown", "1".repeat(25));
+    trees.write("a/only1", "1".repeat(5));
+    // Bounds are included.
+    trees
+        .diff(&["--min-size", "10", "--max-size", "20"])
+        .assert_code(1)
+        .assert_lines(&[
+            "[Files differ]\t\"grown\"",
+            "[Files differ]\t\"max\"",
+            "[Files differ]\t\"min\"",
+        ]);
+    trees
+        .diff(&["--min-size", "21"])
+        .assert_code(1)
+        .assert_lines(&["[Files differ]\t\"grown\"", "[Files differ]\t\"large\""]);
+    trees
+        .diff(&["--max-size", "9"])
+        .assert_code(1)
+        .assert_lines(&[
+            "[Files differ]\t\"small\"",
+            "[Present in first dir. only]\t\"only1\"",
+        ]);
+    trees
+        .diff(&["--max-size", "1K", "--min-size", "1k"])
+        .assert_code(0)
+        .assert_lines(&[]);
+}
+
+/// Entries whose paths are over `PATH_MAX` are reported, and the rest is still compared.
+#[cfg(target_os = "linux")]
+#[test]
+fn path_too_long() {
+    use std::fs;
+    let trees = Trees::new("path_too_long");
+    trees.write("a/f", "1");
+    trees.write("b/f", "2");
+    // Nested from the bottom up, by moving the chain of directories into a new parent, as a
+    // whole the path is too long to create.
+    let name = "d".repeat(200);
+    for (side, content) in [("a", "1"), ("b", "2")] {
+        let side = trees.path().join(side);
+        let (chain, parent) = (side.join("chain"), side.join("parent"));
+        fs::create_dir(&chain).unwrap();
+        fs::write(chain.join("g"), content).unwrap();
+        for _ in 0..25 {
+            fs::create_dir(&parent).unwrap();
+            fs::rename(&chain, parent.join(&name)).unwrap();
+            fs::rename(&parent, &chain).unwrap();
+        }
+        fs::rename(&chain, side.join(&name)).unwrap();
+    }
+    let run = trees.diff(&[]);
+    run.assert_code(1);
+    let lines = run.lines();
+    assert_eq!(lines.len(), 2, "{run:#?}");
+    assert_eq!(lines[0], "[Files differ]\t\"f\"");
+    assert!(
+        lines[1].starts_with(&format!("[Path too long]\t\"{name}/")),
+        "{run:#?}"
+    );
+}
+
+#[test]
+fn traversal() {
+    let trees = Trees::new("traversal");
+    for dir in ["", "d/", "d/d/", "d/d/d/", "e/", "e/e/", "e/e/e/"] {
+        trees.write(&format!("a/{dir}f"), "1");
+        trees.write(&format!("b/{dir}f"), "2");
+    }
+    let dfs = trees.diff(&["--traversal", "dfs", "-j", "1"]);
+    let bfs = trees.diff(&["--traversal", "bfs", "-j", "1"]);
+    dfs.assert_code(1);
+    bfs.assert_code(1);
+    assert_eq!(dfs.lines(), bfs.lines());
+    assert_eq!(bfs.lines().len(), 7);
+    // With a single thread, breadth-first reports each level of the trees after the previous.
+    let depths = |stdout: &str| -> Vec<usize> {
+        stdout
+            .lines()
+            .map(|line| line.matches('/').count())
+            .collect()
+    };
+    assert_eq!(depths(&bfs.stdout), [0, 1, 1, 2, 2, 3, 3]);
+    assert_ne!(depths(&dfs.stdout), [0, 1, 1, 2, 2, 3, 3]);
+}
+
+#[test]
+fn shallowest_diff() {
+    let trees = Trees::new("shallowest_diff");
+    for dir in ["d/", "d/d/", "d/d/d/", "e/e/"] {
+        trees.write(&format!("a/{dir}f"), "1");
+        trees.write(&format!("b/{dir}f"), "2");
+    }
+    trees.write("a/same", "same");
+    trees.write("b/same", "same");
+    let args = ["--traversal", "bfs", "--shallowest-diff"];
+    let run = trees.diff(&args);
+    run.assert_code(1);
+    run.assert_stdout(&["[Files differ]\t\"d/f\""]);
+    // A top-level diff is reported even though deeper ones exist, whatever the threads.
+    trees.write("b/top", "top");
+    for threads in ["1", "4"] {
+        let run = trees.diff(&[&args[..], &["-j", threads]].concat());
+        run.assert_code(1);
+        run.assert_stdout(&["[Present in second dir. only]\t\"top\""]);
+    }
+    // Any diff of the shallowest depth is reported.
+    trees.write("a/top", "top");
+    trees.write("b/e/f", "f");
+    let run = trees.diff(&args);
+    run.assert_code(1);
+    let lines = run.lines();
+    assert_eq!(lines.len(), 1, "{run:#?}");
+    assert!(
+        [
+            "[Files differ]\t\"d/f\"",
+            "[Present in second dir. only]\t\"e/f\""
+        ]
+        .contains(&lines[0]),
+        "{run:#?}"
+    );
+    let run = trees.diff(&["--shallowest-diff"]);
+    run.assert_code(2);
+    run.assert_stderr("`--shallowest-diff` requires `--traversal bfs`");
+}
+
+#[test]
+fn find_duplicates() {
+    let trees = Trees::new("find_duplicates");
+    trees.write("a/old/x", "moved");
+    trees.write("b/new/y", "moved");
+    // Each file is reported once, along with one of its duplicates.
+    trees.write("a/dup1", "copied");
+    trees.write("a/dup2", "copied");
+    trees.write("b/copy1", "copied");
+    trees.write("b/copy2", "copied");
+    trees.write("b/copy3", "copied");
+    // Same size, but a different content.
+    trees.write("a/other", "abcdef");
+    let run = trees.diff(&["--find-duplicates", "--sorted"]);
+    run.assert_code(1);
+    let moved: Vec<_> = run
+        .stdout
+        .lines()
+        .filter(|line| line.starts_with("[Moved or renamed]"))
+        .collect();
+    assert_eq!(
+        moved,
+        [
+            "[Moved or renamed]\t\"dup1\"\thas the same content as \"copy1\"",
+            "[Moved or renamed]\t\"dup1\"\thas the same content as \"copy2\"",
+            "[Moved or renamed]\t\"dup1\"\thas the same content as \"copy3\"",
+            "[Moved or renamed]\t\"dup2\"\thas the same content as \"copy1\"",
+            "[Moved or renamed]\t\"old/x\"\thas the same content as \"new/y\"",
+        ]
+    );
+    // Only reported after the other diffs.
+    assert!(
+        run.stdout.ends_with(&format!("{}\n", moved.join("\n"))),
+        "{run:#?}"
+    );
+    trees
+        .diff(&[
+            "--find-duplicates",
+            "--fail-on",
+            "moved",
+            "--format",
+            "json",
+            "--no-summary",
+        ])
+        .assert_code(1)
+        .assert_lines(&[
+            r#"{"kind":"moved","path":"dup1","path2":"copy1"}"#,
+            r#"{"kind":"moved","path":"dup1","path2":"copy2"}"#,
+            r#"{"kind":"moved","path":"dup1","path2":"copy3"}"#,
+            r#"{"kind":"moved","path":"dup2","path2":"copy1"}"#,
+            r#"{"kind":"moved","path":"old/x","path2":"new/y"}"#,
+            r#"{"kind":"only1","path":"dup1"}"#,
+            r#"{"kind":"only1","path":"dup2"}"#,
+            r#"{"kind":"only1","path":"old"}"#,
+            r#"{"kind":"only1","path":"other"}"#,
+            r#"{"kind":"only2","path":"copy1"}"#,
+            r#"{"kind":"only2","path":"copy2"}"#,
+            r#"{"kind":"only2","path":"copy3"}"#,
+            r#"{"kind":"only2","path":"new"}"#,
+        ]);
+}
+
+#[test]
+fn max_open_files() {
+    let trees = Trees::new("max_open_files");
+    for dir in 0..10 {
+        for file in 0..10 {
+            let path = format!("{}/{}", dir, file);
+            trees.write(&format!("a/{}", path), &path);
+            if (dir + file) % 7 != 0 {
+                trees.write(&format!("b/{}", path), &path);
+            } else {
+                trees.write(&format!("b/{}", path), "changed");
+            }
+        }
+    }
+    let expected = trees.diff(&[]);
+    expected.assert_code(1);
+    assert_eq!(expected.lines().len(), 14, "{expected:#?}");
+    // The limit is raised to 2, so that a pair of files can be compared.
+    for args in [
+        &["--max-open-files", "1", "-j", "4"][..],
+        &["--max-open-files", "0", "-j", "4"],
+    ] {
+        trees
+            .diff(args)
+            .assert_code(1)
+            .assert_lines(&expected.lines());
+    }
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn min_free_fd() {
+    let trees = Trees::new("min_free_fd");
+    for dir in 0..10 {
+        for file in 0..10 {
+            let path = format!("{}/{}", dir, file);
+            trees.write(&format!("a/{}", path), &path);
+            trees.write(
+                &format!("b/{}", path),
+                if file == dir { "changed" } else { &path },
+            );
+        }
+    }
+    let expected = trees.diff(&[]);
+    assert_eq!(expected.lines().len(), 10, "{expected:#?}");
+    // A margin that can never be met only leaves one pair of files open at a time.
+    for margin in ["1", "1000000000"] {
+        trees
+            .diff(&["--min-free-fd", margin, "-j", "4"])
+            .assert_code(1)
+            .assert_lines(&expected.lines());
+    }
+    trees
+        .diff(&[
+            "--min-free-fd",
+            "1000000000",
+            "--max-open-files",
+            "4",
+            "-j",
+            "4",
+        ])
+        .assert_code(1)
+        .assert_lines(&expected.lines());
+}
+
+#[test]
+fn rename_map() {
+    let trees = Trees::new("rename_map");
+    trees.write("a/v1/same", "1");
+    trees.write("b/v2/same", "1");
+    trees.write("a/v1/x", "1");
+    trees.write("b/v2/x", "2");
+    trees.write("b/v2/y", "");
+    trees.write("a/v1/old/z", "");
+    trees.write("b/v2/new/z", "");
+    trees.diff(&[]).assert_code(1).assert_lines(&[
+        "[Present in first dir. only]\t\"v1\"",
+        "[Present in second dir. only]\t\"v2\"",
+    ]);
+    // Diffs keep the paths of the first dir.
+    trees
+        .diff(&["--rename-map", "v1=v2"])
+        .assert_code(1)
+        .assert_lines(&[
+            "[Files differ]\t\"v1/x\"",
+            "[Present in first dir. only]\t\"v1/old\"",
+            "[Present in second dir. only]\t\"v1/new\"",
+            "[Present in second dir. only]\t\"v1/y\"",
+        ]);
+    trees
+        .diff(&["--rename-map", "v1=v2", "--rename-map", "v1/old=v2/new"])
+        .assert_code(1)
+        .assert_lines(&[
+            "[Files differ]\t\"v1/x\"",
+            "[Present in second dir. only]\t\"v1/y\"",
+        ]);
+    trees
+        .diff(&["--rename-map", "v1/old=v2/new"])
+        .assert_code(2)
+        .assert_stderr("Cannot rename to v2/new, as v1 is compared with v1");
+    trees
+        .diff(&["--rename-map", "v1=v2", "--rename-map", "v1=v3"])
+        .assert_code(2)
+        .assert_stderr("v1 is renamed twice");
+}
+
+#[cfg(unix)]
+#[test]
+fn checkpoint() {
+    let trees = Trees::new("checkpoint");
+    let subtrees = ["d1", "d2", "d3", "d4", "d5", "d6"];
+    for dir in subtrees {
+        trees.write(&format!("a/{dir}/sub/f"), "1");
+        trees.write(&format!("b/{dir}/sub/f"), "2");
+        trees.write(&format!("a/{dir}/only1"), "");
+    }
+    trees.write("a/top", "1");
+    trees.write("b/top", "2");
+    let expected = trees.diff(&["-L"]);
+    expected.assert_code(1);
+    // Interrupted by an error in one of the subtrees, after some others were compared.
+    let link = trees.path().join("a/d4/loop");
+    std::os::unix::fs::symlink("loop", &link).unwrap();
+    let first = trees.diff(&["-L", "-j", "1", "--checkpoint", "checkpoint"]);
+    first.assert_code(2);
+    let recorded = std::fs::read_to_string(trees.path().join("checkpoint")).unwrap();
+    let done: Vec<_> = recorded.lines().collect();
+    assert!(!done.contains(&"d4"), "{recorded}");
+    std::fs::remove_file(link).unwrap();
+    let second = trees.diff(&["-L", "-j", "1", "--checkpoint", "checkpoint"]);
+    second.assert_code(1);
+    if !done.is_empty() {
+        second.assert_stderr(&format!(
+            "Skipping {} directories already compared, as recorded in checkpoint",
+            done.len()
+        ));
+    }
+    for line in second.lines() {
+        assert!(
+            !done.iter().any(|dir| line.contains(&format!("\"{dir}/"))),
+            "{line} is in a subtree already compared"
+        );
+    }
+    // Both runs add up to a single one.
+    let mut both = first.lines();
+    both.extend(second.lines());
+    both.sort_unstable();
+    both.dedup();
+    assert_eq!(both, expected.lines());
+    // Every subtree is recorded now, only the entries of the roots are compared again.
+    let recorded = std::fs::read_to_string(trees.path().join("checkpoint")).unwrap();
+    let mut done: Vec<_> = recorded.lines().collect();
+    done.sort_unstable();
+    assert_eq!(done, subtrees);
+    trees
+        .diff(&["-L", "--checkpoint", "checkpoint"])
+        .assert_code(1)
+        .assert_lines(&["[Files differ]\t\"top\""]);
+}
+
+#[test]
+fn checkpoint_resumed() {
+    let trees = Trees::new("checkpoint_resumed");
+    for dir in ["done", "todo"] {
+        trees.write(&format!("a/{dir}/f"), "1");
+        trees.write(&format!("b/{dir}/f"), "2");
+    }
+    // As left by a run interrupted once `done` was compared.
+    trees.write("checkpoint", "done\n");
+    trees
+        .diff(&["--checkpoint", "checkpoint"])
+        .assert_code(1)
+        .assert_lines(&["[Files differ]\t\"todo/f\""])
+        .assert_stderr("Skipping 1 directories already compared, as recorded in checkpoint");
+    assert_eq!(
+        std::fs::read_to_string(trees.path().join("checkpoint")).unwrap(),
+        "done\ntodo\n"
+    );
+}
+
+#[test]
+fn dir_changed_only() {
+    let trees = Trees::new("dir_changed_only");
+    trees.write("a/same/f", "1");
+    trees.write("b/same/f", "1");
+    trees.write("a/sub/f", "1");
+    trees.write("b/sub/f", "2");
+    trees.write("a/sub/g", "1");
+    trees.write("b/sub/g", "2");
+    trees.write("a/sub/deep/f", "1");
+    trees.write("b/sub/deep/f", "2");
+    trees.write("a/only", "1");
+    trees
+        .diff(&["--dir-changed-only"])
+        .assert_code(1)
+        .assert_lines(&[
+            "[Directory changed]\t\"\"",
+            "[Directory changed]\t\"sub\"",
+            "[Directory changed]\t\"sub/deep\"",
+        ]);
+    trees
+        .diff(&["--dir-changed-only", "--two-pass"])
+        .assert_code(2);
+}
+
+#[cfg(unix)]
+#[test]
+fn followed_type_mismatches() {
+    let trees = Trees::new("followed_type_mismatches");
+    trees.write("a/dir/f", "1");
+    trees.write("b/dir", "1");
+    trees.write("a/file", "1");
+    trees.write("b/file/f", "1");
+    symlink(&trees, "dir", "a/to_dir");
+    trees.write("b/to_dir", "1");
+    symlink(&trees, "file", "a/to_file");
+    trees.write("b/to_file/f", "1");
+    let root = trees.path().join("a");
+    trees.diff(&["-L"]).assert_code(1).assert_lines(&[
+        "[Types differ]\t\"dir\"\tis a directory in first dir. while a regular file in second dir.",
+        "[Types differ]\t\"file\"\tis a regular file in first dir. while a directory in second dir.",
+        &format!(
+            "[Types differ]\t\"to_dir\"\tis a symbolic link to a directory ({:?}) in first dir. \
+             while a regular file in second dir.",
+            root.join("dir")
+        ),
+        &format!(
+            "[Types differ]\t\"to_file\"\tis a symbolic link to a regular file ({:?}) in first \
+             dir. while a directory in second dir.",
+            root.join("file")
+        ),
+    ]);
+    let run = trees.diff(&["-L", "--format", "json"]);
+    let resolved = format!(r#","resolved1":{:?}}}"#, root.join("dir"));
+    assert!(
+        run.stdout.lines().any(
+            |line| line.starts_with(r#"{"kind":"type","path":"to_dir","#)
+                && line.ends_with(&resolved)
+        ),
+        "{run:#?}"
+    );
+    // Not followed, symlinks are their own type.
+    trees.diff(&[]).assert_code(1).assert_lines(&[
+        "[Types differ]\t\"dir\"\tis a directory in first dir. while a regular file in second dir.",
+        "[Types differ]\t\"file\"\tis a regular file in first dir. while a directory in second dir.",
+        "[Types differ]\t\"to_dir\"\tis a symbolic link in first dir. while a regular file in second dir.",
+        "[Types differ]\t\"to_file\"\tis a symbolic link in first dir. while a directory in second dir.",
+    ]);
+}
+
+#[cfg(unix)]
+#[test]
+fn no_follow_outside_root() {
+    let trees = Trees::new("no_follow_outside_root");
+    trees.write("outside", "1");
+    trees.write("a/target", "1");
+    trees.write("b/target", "1");
+    trees.write("b/inside", "1");
+    trees.write("b/escaping", "1");
+    symlink(&trees, "target", "a/inside");
+    symlink(&trees, "../outside", "a/escaping");
+    // Both are followed to regular files of the same content.
+    trees.diff(&["-L"]).assert_code(0).assert_lines(&[]);
+    trees
+        .diff(&["-L", "--no-follow-outside-root"])
+        .assert_code(1)
+        .assert_lines(&[
+            "[Types differ]\t\"escaping\"\tis a symbolic link in first dir. while a regular file in second dir.",
+        ]);
+    // Kept symlinks are compared by their target.
+    let outside = trees.path().join("outside");
+    for side in ["a", "b"] {
+        let link = trees.path().join(side).join("escaping");
+        std::fs::remove_file(&link).unwrap();
+        std::os::unix::fs::symlink(&outside, link).unwrap();
+    }
+    trees
+        .diff(&["-L", "--no-follow-outside-root"])
+        .assert_code(0)
+        .assert_lines(&[]);
+}
+
+#[cfg(unix)]
+#[test]
+fn timeout() {
+    let trees = Trees::new("timeout");
+    for i in 0..20 {
+        trees.write(&format!("a/d{i}/f"), "1");
+        trees.write(&format!("b/d{i}/f"), "2");
+    }
+    // Slow to compare, as on an unresponsive mount.
+    trees.write("slow.sh", "sleep 0.2; exit 1");
+    let run = trees.diff(&[
+        "-j",
+        "1",
+        "--compare-cmd",
+        "sh slow.sh %1 %2",
+        "--timeout",
+        "1",
+    ]);
+    run.assert_code(3).assert_stderr("timed out");
+    // Only those found in time, written whole.
+    let found = run.lines();
+    assert!(!found.is_empty() && found.len() < 20, "{run:#?}");
+    for line in found {
+        assert!(
+            line.starts_with("[Files differ]\t\"d") || line.starts_with("[Not compared]\t\"d"),
+            "{run:#?}"
+        );
+    }
+}
+
+#[cfg(unix)]
+#[test]
+fn timeout_cancels_comparisons() {
+    let trees = Trees::new("timeout_cancels_comparisons");
+    trees.write("a/f", "1");
+    trees.write("b/f", "1");
+    // Replaced by `sleep`, so that no child of the killed command holds the output open.
+    trees.write("stuck.sh", "exec sleep 60");
+    let start = std::time::Instant::now();
+    let run = trees.diff(&["--compare-cmd", "sh stuck.sh", "--timeout", "1"]);
+    // The command is killed, rather than waited for.
+    assert!(start.elapsed().as_secs() < 30, "{run:#?}");
+    run.assert_code(3).assert_lines(&["[Not compared]\t\"f\""]);
+}
+
+#[test]
+fn paths_from() {
+    let trees = Trees::new("paths_from");
+    for root in ["a", "b"] {
+        trees.write(&format!("{root}/same"), "");
+        trees.write(&format!("{root}/skipped/f"), root);
+        trees.write(&format!("{root}/dir/f"), root);
+        trees.write(&format!("{root}/dir/sub/g"), root);
+        trees.write(&format!("{root}/deep/other"), root);
+        trees.write(&format!("{root}/deep/er/f"), root);
+    }
+    trees.write("a/only1", "");
+    trees.write("b/dir/only2", "");
+    trees.write(
+        "paths",
+        "dir\ndeep/er/f\nsame\nonly1\nmissing\n\ndeep/none\n",
+    );
+    // Listed files, or those in listed directories; skipped/f and deep/other are left out.
+    trees
+        .diff(&["--paths-from", "paths"])
+        .assert_code(1)
+        .assert_lines(&[
+            "[Files differ]\t\"deep/er/f\"",
+            "[Files differ]\t\"dir/f\"",
+            "[Files differ]\t\"dir/sub/g\"",
+            "[Present in first dir. only]\t\"only1\"",
+            "[Present in second dir. only]\t\"dir/only2\"",
+        ]);
+    // Only the entries of dir itself.
+    trees
+        .diff(&["--paths-from", "paths", "--paths-from-no-recurse"])
+        .assert_code(1)
+        .assert_lines(&[
+            "[Files differ]\t\"deep/er/f\"",
+            "[Files differ]\t\"dir/f\"",
+            "[Present in first dir. only]\t\"only1\"",
+            "[Present in second dir. only]\t\"dir/only2\"",
+        ]);
+    // Listing dir/sub too recurses into it.
+    trees.write("paths", "dir\ndir/sub\n");
+    trees
+        .diff(&["--paths-from", "paths", "--paths-from-no-recurse"])
+        .assert_code(1)
+        .assert_lines(&[
+            "[Files differ]\t\"dir/f\"",
+            "[Files differ]\t\"dir/sub/g\"",
+            "[Present in second dir. only]\t\"dir/only2\"",
+        ]);
+    // A directory leading to a listed entry is reported when missing from a side.
+    std::fs::remove_dir_all(trees.path().join("b/deep")).unwrap();
+    trees.write("paths", "deep/er/f\n");
+    trees
+        .diff(&["--paths-from", "paths"])
+        .assert_code(1)
+        .assert_lines(&["[Present in first dir. only]\t\"deep\""]);
+    trees.write("paths", "../a\n");
+    trees
+        .diff(&["--paths-from", "paths"])
+        .assert_code(2)
+        .assert_stderr("Invalid --paths-from paths");
+}
+
+#[test]
+fn fuzzy_names() {
+    let trees = Trees::new("fuzzy_names");
+    trees.write("a/report-final.pdf", "1");
+    trees.write("b/report_final.pdf", "2");
+    trees.write("a/notes.txt", "");
+    trees.write("b/budget.ods", "");
+    // Directories are not paired with files.
+    trees.write("a/data/f", "");
+    trees.write("b/date", "");
+    trees
+        .diff(&["--fuzzy-names", "2"])
+        .assert_code(1)
+        .assert_lines(&[
+            "[Possibly renamed]\t\"report-final.pdf\"\tas \"report_final.pdf\", 1 edits away",
+            "[Present in first dir. only]\t\"data\"",
+            "[Present in first dir. only]\t\"notes.txt\"",
+            "[Present in second dir. only]\t\"budget.ods\"",
+            "[Present in second dir. only]\t\"date\"",
+        ]);
+    let run = trees.diff(&["--fuzzy-names", "1", "--format", "json", "--no-summary"]);
+    assert!(
+        run.stdout
+            .contains("\"path2\":\"report_final.pdf\",\"distance\":1}"),
+        "{run:#?}"
+    );
+    trees
+        .diff(&["--fuzzy-names", "1", "--format", "itemize"])
+        .assert_code(2);
+}
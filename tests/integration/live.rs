@@ -0,0 +1,96 @@
+use crate::fixture::TempDir;
+use dirdiff::{diff_dirs, Diff, DiffHandler, DiffOptions, Side, Stats};
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+/// Collects the diffs, running `hook` on the first one.
+struct Hooked<F> {
+    hook: Mutex<Option<F>>,
+    diffs: Mutex<Vec<Diff>>,
+}
+
+impl<F: FnOnce() + Send> DiffHandler for Hooked<F> {
+    fn process(&self, _root1: &Path, _root2: &Path, diff: Diff) {
+        if let Some(hook) = self.hook.lock().unwrap().take() {
+            hook();
+        }
+        self.diffs.lock().unwrap().push(diff);
+    }
+}
+
+/// Compares `tree1` and `tree2` with a single worker, running `hook` once the first diff is found,
+/// and returns the diffs with the totals.
+///
+/// The files of a directory are compared before its subdirectories are listed, so a diff about
+/// a file of the root is found in between.
+fn diff_with_hook(
+    tree1: &TempDir,
+    tree2: &TempDir,
+    hook: impl FnOnce() + Send + 'static,
+) -> anyhow::Result<(Vec<Diff>, Stats)> {
+    let handler = Arc::new(Hooked {
+        hook: Mutex::new(Some(hook)),
+        diffs: Mutex::new(Vec::new()),
+    });
+    let (dir1, dir2) = (tree1.path().into(), tree2.path().into());
+    let stats = diff_dirs(dir1, dir2, handler.clone(), 1, &DiffOptions::default())?;
+    let diffs = std::mem::take(&mut *handler.diffs.lock().unwrap());
+    Ok((diffs, stats))
+}
+
+#[test]
+fn vanished_directory() {
+    let (tree1, tree2) = (TempDir::new("vanished1"), TempDir::new("vanished2"));
+    for (tree, content) in [(&tree1, "1"), (&tree2, "2")] {
+        tree.write("f", content);
+        tree.write("sub/g", "");
+        tree.write("other/h", content);
+    }
+    let sub2 = tree2.path().join("sub");
+    let (diffs, stats) =
+        diff_with_hook(&tree1, &tree2, move || fs::remove_dir_all(sub2).unwrap()).unwrap();
+    let mut paths: Vec<_> = diffs
+        .iter()
+        .map(|diff| (diff.kind(), diff.path()))
+        .collect();
+    paths.sort();
+    // The comparison goes on with the other directories.
+    assert_eq!(
+        paths,
+        [
+            ("different", PathBuf::from("f")),
+            ("different", Path::new("other").join("h")),
+            ("vanished", PathBuf::from("sub")),
+        ]
+    );
+    assert!(diffs
+        .iter()
+        .any(|diff| matches!(diff, Diff::Vanished(_, _, Side::Second))));
+    assert_eq!(stats.errors, 1);
+}
+
+/// Directories that cannot be read are still errors.
+#[cfg(unix)]
+#[test]
+fn unreadable_directory() {
+    use std::os::unix::fs::PermissionsExt;
+    let (tree1, tree2) = (TempDir::new("unreadable1"), TempDir::new("unreadable2"));
+    for (tree, content) in [(&tree1, "1"), (&tree2, "2")] {
+        tree.write("f", content);
+        tree.write("sub/g", "");
+    }
+    let sub2 = tree2.path().join("sub");
+    let chmod = |mode| fs::set_permissions(&sub2, fs::Permissions::from_mode(mode)).unwrap();
+    chmod(0o000);
+    if fs::read_dir(&sub2).is_ok() {
+        // Running as root.
+        chmod(0o755);
+        return;
+    }
+    let result = diff_with_hook(&tree1, &tree2, || ());
+    chmod(0o755);
+    assert!(result.is_err());
+}
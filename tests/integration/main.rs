@@ -0,0 +1,11 @@
+//! Tests of the library and of the `dirdiff` binary over trees built in temporary directories.
+
+mod channel;
+mod cli;
+mod fixture;
+mod live;
+mod memory;
+mod stats;
+mod stream;
+mod stress;
+mod tee;
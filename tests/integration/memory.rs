@@ -0,0 +1,239 @@
+use dirdiff::{
+    diff_dirs_in, ChannelHandler, Diff, DiffHandler, DiffOptions, FileSystem, MemoryFs, RealFs,
+    RetryPolicy, Side, TeeHandler, TypeChanged,
+};
+use std::{
+    io,
+    path::{Path, PathBuf},
+    sync::{mpsc::channel, Arc},
+    time::{Duration, UNIX_EPOCH},
+};
+
+/// Kinds and paths of the diffs between `a` and `b` in `fs`, sorted by path.
+fn diffs(
+    fs: &Arc<MemoryFs>,
+    options: &DiffOptions,
+) -> anyhow::Result<Vec<(&'static str, PathBuf)>> {
+    let (sender, receiver) = channel();
+    let handler = Arc::new(ChannelHandler::new(sender));
+    diff_dirs_in(fs.clone(), "a".into(), "b".into(), handler, 4, options)?;
+    let mut diffs: Vec<_> = receiver.iter().map(|d| (d.kind(), d.path())).collect();
+    diffs.sort_by(|d1, d2| d1.1.cmp(&d2.1));
+    Ok(diffs)
+}
+
+#[test]
+fn kinds_of_diffs() {
+    let fs = MemoryFs::new();
+    for root in ["a", "b"] {
+        fs.add_file(format!("{root}/same"), "content");
+        fs.add_file(format!("{root}/sub/same"), "content");
+        fs.add_symlink(format!("{root}/same_link"), "target");
+    }
+    fs.add_file("a/changed", "abc");
+    fs.add_file("b/changed", "abd");
+    fs.add_file("a/resized", "abc");
+    fs.add_file("b/resized", "abcd");
+    fs.add_symlink("a/link", "here");
+    fs.add_symlink("b/link", "there");
+    fs.add_file("a/sub/only1", "");
+    fs.add_dir("b/sub/only2");
+    fs.add_file("a/type", "");
+    fs.add_dir("b/type");
+
+    let diffs = diffs(&Arc::new(fs), &DiffOptions::default()).unwrap();
+    let expected = [
+        ("different", "changed"),
+        ("link_target", "link"),
+        ("different", "resized"),
+        ("only1", "sub/only1"),
+        ("only2", "sub/only2"),
+        ("type", "type"),
+    ];
+    let expected: Vec<_> = expected
+        .map(|(kind, path)| (kind, PathBuf::from(path)))
+        .into();
+    assert_eq!(diffs, expected);
+}
+
+#[test]
+fn mtimes() {
+    let fs = MemoryFs::new();
+    fs.add_file("a/file", "content");
+    fs.add_file("b/file", "content");
+    fs.set_modified("b/file", UNIX_EPOCH + Duration::from_secs(1));
+    let fs = Arc::new(fs);
+
+    assert_eq!(diffs(&fs, &DiffOptions::default()).unwrap(), []);
+    let options = DiffOptions {
+        check_mtime: true,
+        ..Default::default()
+    };
+    assert_eq!(
+        diffs(&fs, &options).unwrap(),
+        [("mtime", PathBuf::from("file"))]
+    );
+}
+
+/// Transient errors are retried as long as the policy allows, other ones end the comparison.
+#[test]
+fn injected_errors() {
+    let fs = MemoryFs::new();
+    fs.add_file("a/sub/file", "1");
+    fs.add_file("b/sub/file", "2");
+    let fs = Arc::new(fs);
+    let retried = DiffOptions {
+        retry: RetryPolicy::new(2),
+        ..Default::default()
+    };
+
+    fs.fail("a/sub", io::ErrorKind::Interrupted, 2);
+    let expected = [("different", PathBuf::from("sub/file"))];
+    assert_eq!(diffs(&fs, &retried).unwrap(), expected);
+
+    fs.fail("b/sub/file", io::ErrorKind::TimedOut, 3);
+    assert!(diffs(&fs, &retried).is_err());
+    // All the failures were used up by the attempts.
+    assert_eq!(diffs(&fs, &retried).unwrap(), expected);
+
+    fs.fail("a/sub/file", io::ErrorKind::PermissionDenied, 1);
+    assert!(diffs(&fs, &retried).is_err());
+}
+
+/// Removes a directory of the first tree once a diff is found, before it is listed.
+struct Remover {
+    fs: Arc<MemoryFs>,
+    path: &'static str,
+}
+
+impl DiffHandler for Remover {
+    fn process(&self, _root1: &Path, _root2: &Path, diff: Diff) {
+        match diff {
+            Diff::InDir1Only(..) => assert!(self.fs.remove(self.path)),
+            diff => assert!(
+                matches!(&diff, Diff::Vanished(dir, name, Side::First) if dir.as_os_str().is_empty() && name == "sub"),
+                "{diff:?}"
+            ),
+        }
+    }
+}
+
+/// A directory removed from the first tree once listed in its parent, but before being listed
+/// itself, is reported as vanished rather than failing the comparison.
+#[test]
+fn vanished_directory() {
+    let fs = MemoryFs::new();
+    fs.add_file("a/trigger", "");
+    fs.add_file("a/sub/file", "");
+    fs.add_file("b/sub/file", "");
+    let fs = Arc::new(fs);
+    let handler = Arc::new(Remover {
+        fs: fs.clone(),
+        path: "a/sub",
+    });
+    // A single worker only lists the subdirectory once done with the root, which it pushed.
+    let stats = diff_dirs_in(
+        fs,
+        "a".into(),
+        "b".into(),
+        handler,
+        1,
+        &DiffOptions::default(),
+    )
+    .unwrap();
+    assert_eq!((stats.only1, stats.errors), (1, 1));
+}
+
+/// The options reading more than a `FileSystem` gives are refused.
+#[test]
+fn unsupported_options() {
+    let options = DiffOptions {
+        perms: true,
+        ..Default::default()
+    };
+    let handler = Arc::new(ChannelHandler::new(channel().0));
+    let fs = Arc::new(MemoryFs::new());
+    let result = diff_dirs_in(fs, "a".into(), "b".into(), handler, 1, &options);
+    assert!(result.is_err());
+}
+
+/// Replaces a file of the second tree by a symlink once a diff is found, after its directory
+/// was listed but before the file is compared.
+struct Swapper {
+    fs: Arc<MemoryFs>,
+    path: &'static str,
+}
+
+impl DiffHandler for Swapper {
+    fn process(&self, _root1: &Path, _root2: &Path, diff: Diff) {
+        if let Diff::InDir1Only(..) = diff {
+            self.fs.add_symlink(self.path, "elsewhere");
+        }
+    }
+}
+
+/// A file replaced by a symlink between the listing of its directory and its comparison is
+/// reported as such, rather than compared through the symlink.
+#[test]
+fn raced_type_change() {
+    let fs = MemoryFs::new();
+    fs.add_file("a/a_trigger", "");
+    fs.add_file("a/f", "content");
+    fs.add_file("b/f", "content");
+    let fs = Arc::new(fs);
+    let (sender, receiver) = channel();
+    let handler = Arc::new(TeeHandler::new(vec![
+        Arc::new(Swapper {
+            fs: fs.clone(),
+            path: "b/f",
+        }),
+        Arc::new(ChannelHandler::new(sender)),
+    ]));
+    diff_dirs_in(
+        fs,
+        "a".into(),
+        "b".into(),
+        handler,
+        1,
+        &DiffOptions::default(),
+    )
+    .unwrap();
+    let diffs: Vec<_> = receiver.iter().map(|d| (d.kind(), d.path())).collect();
+    assert_eq!(
+        diffs,
+        [
+            ("only1", PathBuf::from("a_trigger")),
+            ("raced_type", PathBuf::from("f")),
+        ]
+    );
+}
+
+/// Opening an entry which is not a regular file fails with `TypeChanged`, without following
+/// symlinks.
+#[test]
+fn open_non_regular() {
+    let is_type_changed = |e: io::Error| e.get_ref().is_some_and(|e| e.is::<TypeChanged>());
+    let fs = MemoryFs::new();
+    fs.add_file("file", "content");
+    fs.add_symlink("link", "file");
+    fs.add_dir("dir");
+    assert!(fs.open(Path::new("file")).is_ok());
+    assert!(is_type_changed(fs.open(Path::new("link")).unwrap_err()));
+    assert!(is_type_changed(fs.open(Path::new("dir")).unwrap_err()));
+    assert_eq!(
+        fs.open(Path::new("missing")).unwrap_err().kind(),
+        io::ErrorKind::NotFound
+    );
+
+    #[cfg(unix)]
+    {
+        let dir = crate::fixture::TempDir::new("open_non_regular");
+        std::fs::write(dir.path().join("file"), "content").unwrap();
+        std::os::unix::fs::symlink("file", dir.path().join("link")).unwrap();
+        assert!(RealFs.open(&dir.path().join("file")).is_ok());
+        assert!(is_type_changed(
+            RealFs.open(&dir.path().join("link")).unwrap_err()
+        ));
+        assert!(is_type_changed(RealFs.open(dir.path()).unwrap_err()));
+    }
+}
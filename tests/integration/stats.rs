@@ -0,0 +1,33 @@
+use crate::fixture::TempDir;
+use dirdiff::{diff_dirs, ChannelHandler, DiffOptions};
+use std::sync::{mpsc::channel, Arc};
+
+/// All the bytes of identical files are read, on both sides, however they are split in chunks.
+#[test]
+fn bytes_read_of_identical_trees() {
+    let files: [(&str, Vec<u8>); 4] = [
+        ("empty", vec![]),
+        ("small", b"hello".to_vec()),
+        ("sub/large", (0..(1 << 20) + 3).map(|i| i as u8).collect()),
+        ("sub/deeper/medium", vec![b'x'; 70_000]),
+    ];
+    let (tree1, tree2) = (TempDir::new("bytes1"), TempDir::new("bytes2"));
+    for (path, contents) in &files {
+        tree1.write(path, contents);
+        tree2.write(path, contents);
+    }
+    let size: usize = files.iter().map(|(_, contents)| contents.len()).sum();
+
+    let handler = Arc::new(ChannelHandler::new(channel().0));
+    let stats = diff_dirs(
+        tree1.path().into(),
+        tree2.path().into(),
+        handler,
+        4,
+        &DiffOptions::default(),
+    )
+    .unwrap();
+    assert_eq!(stats.bytes_read, 2 * size as u64);
+    assert_eq!(stats.files_compared, files.len() as u64);
+    assert_eq!(stats.different, 0);
+}
@@ -0,0 +1,57 @@
+use crate::fixture::TempDir;
+use dirdiff::{Diff, DiffOptions, DiffStream};
+use std::{sync::mpsc::channel, thread, time::Duration};
+
+/// More diffs than the channel of the stream holds, so that the workers block on it.
+const N_FILES: usize = 5000;
+
+fn only_in_first(name: &str) -> (TempDir, TempDir) {
+    let (tree1, tree2) = (
+        TempDir::new(&format!("{name}1")),
+        TempDir::new(&format!("{name}2")),
+    );
+    for i in 0..N_FILES {
+        tree1.write(&i.to_string(), "");
+    }
+    (tree1, tree2)
+}
+
+#[test]
+fn yields_all_diffs() {
+    let (tree1, tree2) = only_in_first("all");
+    let stream = DiffStream::new(
+        tree1.path().into(),
+        tree2.path().into(),
+        4,
+        DiffOptions::default(),
+    );
+    let diffs = stream.collect::<anyhow::Result<Vec<_>>>().unwrap();
+    assert_eq!(diffs.len(), N_FILES);
+    assert!(diffs.iter().all(|d| matches!(d, Diff::InDir1Only(..))));
+}
+
+/// Dropping the stream while the workers wait for room in its channel stops them, rather than
+/// leaving them blocked forever.
+#[test]
+fn drop_after_one_item_shuts_the_pool_down() {
+    let (tree1, tree2) = only_in_first("drop");
+    let mut stream = DiffStream::new(
+        tree1.path().into(),
+        tree2.path().into(),
+        4,
+        DiffOptions::default(),
+    );
+    assert!(stream.next().unwrap().is_ok());
+    // Gives the workers the time to fill the channel.
+    thread::sleep(Duration::from_millis(100));
+
+    let (done, dropped) = channel();
+    thread::spawn(move || {
+        // Joins the workers.
+        drop(stream);
+        done.send(()).unwrap();
+    });
+    dropped
+        .recv_timeout(Duration::from_secs(30))
+        .expect("the workers did not stop");
+}
@@ -0,0 +1,73 @@
+use crate::fixture::TempDir;
+use dirdiff::{DiffOptions, DiffStream};
+
+/// Number of comparisons of the same trees, each one a new chance for the workers to race.
+const ITERATIONS: usize = 200;
+/// Depth of the chains of directories.
+const DEPTH: usize = 40;
+/// Number of chains.
+const WIDTH: usize = 4;
+
+/// Workers must not stop while a unit of work is still queued, or about to be. The trees are
+/// chains of directories, so that most units push a single new one, which leaves the other
+/// workers idle and trying to steal just when it is pushed. Each directory holds a file only
+/// found in the first tree, so that every directory missed is a diff missed.
+#[test]
+fn no_diff_missed_when_work_is_scarce() {
+    let (tree1, tree2) = (TempDir::new("stress1"), TempDir::new("stress2"));
+    for chain in 0..WIDTH {
+        let mut dir = format!("{chain}");
+        for level in 0..DEPTH {
+            tree1.write(&format!("{dir}/only1"), "");
+            tree1.write(&format!("{dir}/both"), "");
+            tree2.write(&format!("{dir}/both"), "");
+            dir = format!("{dir}/{level}");
+        }
+    }
+
+    for iteration in 0..ITERATIONS {
+        let stream = DiffStream::new(
+            tree1.path().into(),
+            tree2.path().into(),
+            16,
+            DiffOptions::default(),
+        );
+        let diffs = stream.collect::<anyhow::Result<Vec<_>>>().unwrap();
+        assert_eq!(diffs.len(), WIDTH * DEPTH, "at iteration {iteration}");
+    }
+}
+
+/// Compares a tree of a few large files among many directories with and without threads
+/// comparing the files apart from the traversal, printing how long each took.
+///
+/// Run with `cargo test --release -- --ignored --nocapture compare_threads_benchmark`.
+#[test]
+#[ignore]
+fn compare_threads_benchmark() {
+    let (tree1, tree2) = (TempDir::new("bench1"), TempDir::new("bench2"));
+    let large = vec![0; 64 << 20];
+    for file in 0..4 {
+        tree1.write(&format!("large/{file}"), &large);
+        tree2.write(&format!("large/{file}"), &large);
+    }
+    for dir in 0..2000 {
+        tree1.write(&format!("{}/{dir}/f", dir % 20), "1");
+        tree2.write(&format!("{}/{dir}/f", dir % 20), "1");
+        tree1.write(&format!("{}/{dir}/only1", dir % 20), "");
+    }
+    for compare_threads in [0, 4] {
+        let start = std::time::Instant::now();
+        let options = DiffOptions {
+            compare_threads,
+            ..DiffOptions::default()
+        };
+        let stream = DiffStream::new(tree1.path().into(), tree2.path().into(), 4, options);
+        let diffs = stream.collect::<anyhow::Result<Vec<_>>>().unwrap();
+        assert_eq!(diffs.len(), 2000);
+        println!(
+            "--compare-threads {}: {:?}",
+            compare_threads,
+            start.elapsed()
+        );
+    }
+}
@@ -0,0 +1,55 @@
+//! Diffs forwarded to several handlers by [`TeeHandler`].
+
+use crate::fixture::TempDir;
+use dirdiff::{diff_dirs, Diff, DiffHandler, DiffOptions, TeeHandler};
+use std::{
+    io,
+    path::Path,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+};
+
+/// Counts the diffs, and the calls of the other methods.
+#[derive(Default)]
+struct Counting {
+    diffs: AtomicU64,
+    finished: AtomicU64,
+}
+
+impl DiffHandler for Counting {
+    fn process(&self, _root1: &Path, _root2: &Path, _diff: Diff) {
+        self.diffs.fetch_add(1, Ordering::SeqCst);
+    }
+
+    fn finish(&self) -> io::Result<()> {
+        self.finished.fetch_add(1, Ordering::SeqCst);
+        Ok(())
+    }
+}
+
+#[test]
+fn every_handler_sees_every_diff() {
+    let (tree1, tree2) = (TempDir::new("tee1"), TempDir::new("tee2"));
+    for i in 0..10 {
+        tree1.write(&format!("sub{i}/f"), "1");
+        tree2.write(&format!("sub{i}/f"), "2");
+        tree1.write(&format!("sub{i}/only1"), "");
+    }
+    let (first, second) = (Arc::new(Counting::default()), Arc::new(Counting::default()));
+    let tee = Arc::new(TeeHandler::new(vec![first.clone(), second.clone()]));
+    diff_dirs(
+        tree1.path().into(),
+        tree2.path().into(),
+        tee.clone(),
+        4,
+        &DiffOptions::default(),
+    )
+    .unwrap();
+    tee.finish().unwrap();
+    for handler in [&first, &second] {
+        assert_eq!(handler.diffs.load(Ordering::SeqCst), 20);
+        assert_eq!(handler.finished.load(Ordering::SeqCst), 1);
+    }
+}